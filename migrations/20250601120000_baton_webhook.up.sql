@@ -0,0 +1,6 @@
+CREATE TABLE baton_webhook (
+    plot INTEGER PRIMARY KEY REFERENCES plot(id),
+    url TEXT NOT NULL,
+    secret BYTEA NOT NULL, -- Signs the delivered bodies
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1 @@
+DROP TABLE baton_instance_trust;
@@ -0,0 +1,7 @@
+-- Trusts every plot registered on an instance
+CREATE TABLE baton_instance_trust (
+    id SERIAL PRIMARY KEY,
+    plot INTEGER NOT NULL REFERENCES plot(id),
+    instance INTEGER NOT NULL REFERENCES known_instance(id),
+    UNIQUE (plot, instance)
+);
@@ -0,0 +1,7 @@
+-- Named pub/sub channels, access follows the trust of the owner
+CREATE TABLE baton_channel (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    owner INTEGER NOT NULL REFERENCES plot(id),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,17 @@
+-- Rolling log of transfers so plot owners can see what happened to them
+CREATE TABLE baton_transfer_log (
+    id UUID PRIMARY KEY, -- Transfer or relay id
+    sender INTEGER NOT NULL, -- Not a reference, plots can be on other instances
+    receiver INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    outcome TEXT NOT NULL,
+    payload TEXT, -- JSON, only kept if TRANSFER_HISTORY_PAYLOADS is on
+    expires_at BIGINT NOT NULL, -- Unix timestamp in seconds
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX baton_transfer_log_sender ON baton_transfer_log (sender, created_at);
+CREATE INDEX baton_transfer_log_receiver ON baton_transfer_log (receiver, created_at);
+CREATE INDEX baton_transfer_log_created_at ON baton_transfer_log (created_at);
@@ -0,0 +1 @@
+ALTER TABLE baton_trust DROP COLUMN expires_at;
@@ -0,0 +1,4 @@
+-- NULL means the trust never lapses
+ALTER TABLE baton_trust ADD COLUMN expires_at TIMESTAMP;
+
+CREATE INDEX baton_trust_expires_at ON baton_trust (expires_at) WHERE expires_at IS NOT NULL;
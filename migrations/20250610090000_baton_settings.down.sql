@@ -0,0 +1 @@
+DROP TABLE baton_settings;
@@ -0,0 +1,5 @@
+-- Plots without a row use the defaults
+CREATE TABLE baton_settings (
+    plot INTEGER PRIMARY KEY REFERENCES plot(id),
+    mutual_trust BOOLEAN NOT NULL DEFAULT FALSE -- Only accept transfers from plots this plot trusts back
+);
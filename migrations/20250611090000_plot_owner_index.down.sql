@@ -0,0 +1 @@
+DROP INDEX plot_owner_uuid;
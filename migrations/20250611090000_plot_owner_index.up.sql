@@ -0,0 +1 @@
+CREATE INDEX plot_owner_uuid ON plot (owner_uuid, id);
@@ -0,0 +1 @@
+DROP TABLE plot_member;
@@ -0,0 +1,9 @@
+-- Players other than the owner that can act for a plot
+CREATE TABLE plot_member (
+    plot INTEGER NOT NULL REFERENCES plot(id),
+    member UUID NOT NULL, -- Minecraft uuid
+    create_keys BOOLEAN NOT NULL DEFAULT FALSE,
+    edit_trust BOOLEAN NOT NULL DEFAULT FALSE,
+    send_transfers BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (plot, member)
+);
@@ -0,0 +1 @@
+ALTER TABLE plot DROP COLUMN registered_at;
@@ -0,0 +1,3 @@
+-- Plots registered before this are left NULL, their registration time is unknown
+ALTER TABLE plot ADD COLUMN registered_at TIMESTAMP;
+ALTER TABLE plot ALTER COLUMN registered_at SET DEFAULT NOW();
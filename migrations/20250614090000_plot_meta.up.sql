@@ -0,0 +1,9 @@
+-- Optional details plots show in directories
+CREATE TABLE plot_meta (
+    plot INTEGER PRIMARY KEY REFERENCES plot(id),
+    name TEXT,
+    description TEXT,
+    tags TEXT[] NOT NULL DEFAULT '{}',
+    website TEXT,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
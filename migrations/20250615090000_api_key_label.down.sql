@@ -0,0 +1,4 @@
+ALTER TABLE api_key
+    DROP COLUMN label,
+    DROP COLUMN prefix,
+    DROP COLUMN last_used;
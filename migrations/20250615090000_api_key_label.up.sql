@@ -0,0 +1,5 @@
+-- Lets plots tell their keys apart without storing them
+ALTER TABLE api_key
+    ADD COLUMN label TEXT,
+    ADD COLUMN prefix TEXT,
+    ADD COLUMN last_used TIMESTAMP;
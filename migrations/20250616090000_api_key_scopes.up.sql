@@ -0,0 +1,2 @@
+-- NULL for keys that can do everything
+ALTER TABLE api_key ADD COLUMN scopes TEXT[];
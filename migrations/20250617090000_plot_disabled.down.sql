@@ -0,0 +1,3 @@
+ALTER TABLE plot
+    DROP COLUMN disabled_at,
+    DROP COLUMN disabled_reason;
@@ -0,0 +1,4 @@
+-- Plots the instance operator disabled can't authenticate
+ALTER TABLE plot
+    ADD COLUMN disabled_at TIMESTAMP,
+    ADD COLUMN disabled_reason TEXT;
@@ -0,0 +1,2 @@
+-- Set when a key was rotated, it stops working afterwards
+ALTER TABLE api_key ADD COLUMN disable_at TIMESTAMP;
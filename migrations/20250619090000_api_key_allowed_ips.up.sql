@@ -0,0 +1,2 @@
+-- Addresses and CIDR ranges the key can be used from, NULL for anywhere
+ALTER TABLE api_key ADD COLUMN allowed_ips TEXT[];
@@ -0,0 +1,12 @@
+-- Kept after plots unregister, so there is no foreign key
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    plot INTEGER,
+    event TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    ip TEXT,
+    detail TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_log_plot ON audit_log (plot, id);
@@ -0,0 +1,3 @@
+ALTER TABLE known_instance DROP COLUMN health_checked_at;
+ALTER TABLE known_instance DROP COLUMN health;
+ALTER TABLE known_instance DROP COLUMN registered_at;
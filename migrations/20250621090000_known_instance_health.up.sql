@@ -0,0 +1,4 @@
+ALTER TABLE known_instance ADD COLUMN registered_at TIMESTAMP NOT NULL DEFAULT NOW();
+-- Result of the last ping, NULL until it was pinged
+ALTER TABLE known_instance ADD COLUMN health TEXT;
+ALTER TABLE known_instance ADD COLUMN health_checked_at TIMESTAMP;
@@ -0,0 +1,4 @@
+ALTER TABLE known_instance DROP COLUMN apis;
+ALTER TABLE known_instance DROP COLUMN version;
+ALTER TABLE known_instance DROP COLUMN contact;
+ALTER TABLE known_instance DROP COLUMN name;
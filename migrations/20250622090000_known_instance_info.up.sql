@@ -0,0 +1,6 @@
+-- What the instance says about itself at /instance/v0/info, NULL until it was asked
+ALTER TABLE known_instance ADD COLUMN name TEXT;
+ALTER TABLE known_instance ADD COLUMN contact TEXT;
+ALTER TABLE known_instance ADD COLUMN version TEXT;
+-- Like `instance/v0`
+ALTER TABLE known_instance ADD COLUMN apis TEXT[];
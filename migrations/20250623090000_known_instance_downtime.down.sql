@@ -0,0 +1,3 @@
+ALTER TABLE known_instance DROP COLUMN down_since;
+ALTER TABLE known_instance DROP COLUMN failures;
+ALTER TABLE known_instance DROP COLUMN latency_ms;
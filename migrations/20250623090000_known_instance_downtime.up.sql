@@ -0,0 +1,6 @@
+-- Round trip of the last successful ping
+ALTER TABLE known_instance ADD COLUMN latency_ms INTEGER;
+-- Pings that failed in a row
+ALTER TABLE known_instance ADD COLUMN failures INTEGER NOT NULL DEFAULT 0;
+-- First failed ping of the current streak, NULL while it answers
+ALTER TABLE known_instance ADD COLUMN down_since TIMESTAMP;
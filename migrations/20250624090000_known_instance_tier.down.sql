@@ -0,0 +1 @@
+ALTER TABLE known_instance DROP COLUMN tier;
@@ -0,0 +1,3 @@
+-- Instances known before tiers were added by the operator, instances registering themselves start out open
+ALTER TABLE known_instance ADD COLUMN tier TEXT NOT NULL DEFAULT 'verified';
+ALTER TABLE known_instance ALTER COLUMN tier SET DEFAULT 'open';
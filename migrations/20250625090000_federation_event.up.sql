@@ -0,0 +1,10 @@
+-- Kept after instances are removed, so there is no foreign key
+CREATE TABLE federation_event (
+    id BIGSERIAL PRIMARY KEY,
+    event TEXT NOT NULL,
+    domain TEXT,
+    detail TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX federation_event_domain ON federation_event (LOWER(domain), id);
@@ -0,0 +1 @@
+ALTER TABLE baton_settings DROP COLUMN delivery;
@@ -0,0 +1,2 @@
+-- at_most_once or at_least_once, see DeliveryGuarantee
+ALTER TABLE baton_settings ADD COLUMN delivery TEXT NOT NULL DEFAULT 'at_most_once';
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+/// A single address or a CIDR range like `51.222.245.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|err| format!("{}: {}", s, err))?;
+        // Mapped addresses are matched as IPv4, like clients are
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("{}: prefix must be at most {}", s, max))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(value: IpRange) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Addresses DF sends requests from, the list can be replaced while the instance runs
+#[derive(Clone, Default)]
+pub struct DfIps(Arc<RwLock<Vec<IpRange>>>);
+
+impl DfIps {
+    pub fn new(ranges: Vec<IpRange>) -> Self {
+        Self(Arc::new(RwLock::new(ranges)))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0
+            .read()
+            .expect("DF ips shouldn't be poisoned")
+            .iter()
+            .any(|range| range.contains(ip))
+    }
+
+    pub fn replace(&self, ranges: Vec<IpRange>) {
+        *self.0.write().expect("DF ips shouldn't be poisoned") = ranges;
+    }
+
+    /// Keeps the list at `base` plus the ranges in the file forever, meant to be spawned once.
+    /// The file is read again whenever it is modified, a broken file keeps the previous list
+    pub async fn watch_file(self, base: Vec<IpRange>, path: PathBuf) {
+        let mut loaded: Option<SystemTime> = None;
+        loop {
+            let modified = std::fs::metadata(&path).and_then(|meta| meta.modified());
+            match modified {
+                Ok(modified) if loaded != Some(modified) => match read_ranges(&path) {
+                    Ok(ranges) => {
+                        info!(
+                            "Loaded {} DF ip ranges from {}",
+                            ranges.len(),
+                            path.display()
+                        );
+                        self.replace(base.iter().copied().chain(ranges).collect());
+                        loaded = Some(modified);
+                    }
+                    Err(err) => error!("Reading DF ips failed: {:?}", err),
+                },
+                Ok(_) => {}
+                Err(err) => error!("Reading DF ips from {} failed: {:?}", path.display(), err),
+            }
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        }
+    }
+}
+
+/// One range per line, `#` starts a comment
+fn read_ranges(path: &PathBuf) -> color_eyre::Result<Vec<IpRange>> {
+    let file = std::fs::read_to_string(path).wrap_err_with(|| path.display().to_string())?;
+    file.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse().map_err(|err: String| eyre!(err)))
+        .collect()
+}
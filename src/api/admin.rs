@@ -0,0 +1,521 @@
+use std::sync::Arc;
+
+use ascii_domain::dom::Domain;
+use poem::Request;
+use poem_openapi::{
+    param::{Path, Query},
+    payload::{Json, PlainText},
+    ApiResponse, Object, OpenApi,
+};
+use uuid::Uuid;
+
+use crate::{
+    instance::InstanceDomain,
+    store::{
+        activity::ActivitySummary,
+        admin::{
+            FederatedInstance, QueueStats, QueuedSummary, RemovedInstance, RemovedInstancePlots,
+        },
+        audit::{AuditEntry, AuditEvent, AuditRecord},
+        feed::{FederationEvent, FederationEventRecord},
+        instance::{AdminPlotFilter, InstanceFilter, InstanceTier},
+        Store,
+    },
+};
+
+use super::{
+    auth::{client_addr, AdminAuth, Plot},
+    decode_instance_key, key_fingerprint, PlotId,
+};
+
+/// For the instance operator, everything needs `ADMIN_TOKEN`
+pub struct AdminApi {
+    pub store: Arc<Store>,
+    pub domain: Domain<String>,
+}
+
+#[OpenApi]
+impl AdminApi {
+    /// List every registered plot with how many keys and trusts it has, lowest plot id first
+    #[oai(path = "/plots", method = "get")]
+    async fn list_plots(
+        &self,
+        /// Minecraft uuid of the owner
+        owner: Query<Option<Uuid>>,
+        /// Domain of the instance the plots are registered to
+        instance: Query<Option<String>>,
+        #[oai(default = "default_plots_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        #[oai(default)] offset: Query<u32>,
+        _auth: AdminAuth,
+    ) -> Json<Vec<AdminPlotResponse>> {
+        let instance = instance.0.map(|domain| {
+            if domain.eq_ignore_ascii_case(self.domain.as_inner()) {
+                InstanceFilter::Current
+            } else {
+                InstanceFilter::Domain(domain)
+            }
+        });
+        let filter = AdminPlotFilter {
+            owner: owner.0,
+            instance,
+        };
+        let plots = self
+            .store
+            .admin_plots(&filter, limit.0 as i64, offset.0 as i64)
+            .await
+            .expect("Store ops shouldn't fail");
+        Json(
+            plots
+                .into_iter()
+                .map(|it| {
+                    let key_fingerprint = key_fingerprint(&it.plot.instance.key);
+                    AdminPlotResponse {
+                        plot: it.plot.plot_id,
+                        owner: it.plot.owner,
+                        domain: self.plot_domain(&it.plot),
+                        key_fingerprint,
+                        active_keys: it.active_keys,
+                        trusted_plots: it.trusted_plots,
+                        trusted_instances: it.trusted_instances,
+                        registered_at: it.registered_at,
+                        disabled: it.disabled,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Counters of every plot added up
+    #[oai(path = "/stats", method = "get")]
+    async fn stats(
+        &self,
+        /// Plots seen within this many days count as active
+        #[oai(default = "default_active_days")]
+        active_days: Query<u64>,
+        _auth: AdminAuth,
+    ) -> Json<ActivitySummary> {
+        Json(
+            self.store
+                .activity_summary(active_days.0.saturating_mul(60 * 60 * 24))
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// List registered plots that haven't made a request in `days` days, lowest plot id first
+    #[oai(path = "/plots/idle", method = "get")]
+    async fn idle_plots(
+        &self,
+        #[oai(default = "default_active_days")] days: Query<u64>,
+        #[oai(default = "default_plots_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        _auth: AdminAuth,
+    ) -> Json<Vec<PlotId>> {
+        Json(
+            self.store
+                .idle_plots(days.0.saturating_mul(60 * 60 * 24), limit.0 as i64)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Stop a plot from authenticating, with a reason for the records
+    #[oai(path = "/plots/:id/disabled", method = "put")]
+    async fn disable_plot(
+        &self,
+        id: Path<PlotId>,
+        #[oai(validator(max_length = 256))] reason: Query<Option<String>>,
+        _auth: AdminAuth,
+    ) -> PlotToggleResult {
+        if self
+            .store
+            .disable_plot(id.0, reason.0.as_deref())
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            PlotToggleResult::Ok
+        } else {
+            PlotToggleResult::NotFound
+        }
+    }
+
+    /// Let a disabled plot authenticate again
+    #[oai(path = "/plots/:id/disabled", method = "delete")]
+    async fn enable_plot(&self, id: Path<PlotId>, _auth: AdminAuth) -> PlotToggleResult {
+        if self
+            .store
+            .enable_plot(id.0)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            PlotToggleResult::Ok
+        } else {
+            PlotToggleResult::NotFound
+        }
+    }
+
+    /// Drop everything cached about a plot, for when postgres was edited by hand
+    #[oai(path = "/plots/:id/cache", method = "delete")]
+    async fn invalidate_plot(&self, id: Path<PlotId>, _auth: AdminAuth) {
+        self.store
+            .invalidate_plot(id.0)
+            .await
+            .expect("Store ops shouldn't fail");
+    }
+
+    /// Transfers waiting in a plot's queue, oldest first, without their payload
+    #[oai(path = "/plots/:id/queue", method = "get")]
+    async fn plot_queue(&self, id: Path<PlotId>, _auth: AdminAuth) -> Json<Vec<QueuedSummary>> {
+        Json(
+            self.store
+                .inspect_queue(id.0)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Sizes of the relay, webhook and schedule queues
+    #[oai(path = "/queues", method = "get")]
+    async fn queues(&self, _auth: AdminAuth) -> Json<QueueStats> {
+        Json(
+            self.store
+                .queue_stats()
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Audit log of every plot and the instance, newest first
+    ///
+    /// Pass the id of the last record as `before` for the next page
+    #[oai(path = "/audit", method = "get")]
+    async fn audit_log(
+        &self,
+        /// Only records of this plot
+        plot: Query<Option<PlotId>>,
+        before: Query<Option<i64>>,
+        #[oai(default = "default_plots_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        _auth: AdminAuth,
+    ) -> Json<Vec<AuditRecord>> {
+        Json(
+            self.store
+                .audit_log(plot.0, before.0, limit.0)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// What happened to other instances, newest first
+    ///
+    /// Pass the id of the last event as `before` for the next page
+    #[oai(path = "/federation/events", method = "get")]
+    async fn federation_events(
+        &self,
+        /// Only events of this instance
+        domain: Query<Option<String>>,
+        before: Query<Option<i64>>,
+        #[oai(default = "default_plots_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        _auth: AdminAuth,
+    ) -> Json<Vec<FederationEventRecord>> {
+        Json(
+            self.store
+                .federation_events(domain.0.as_deref(), before.0, limit.0)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Known instances with their plots and relays waiting on them
+    #[oai(path = "/federation", method = "get")]
+    async fn federation(&self, _auth: AdminAuth) -> Json<Vec<FederatedInstance>> {
+        Json(
+            self.store
+                .federation_status()
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Reject a server token before it expires
+    #[oai(path = "/tokens/:jti", method = "delete")]
+    async fn revoke_token(&self, jti: Path<Uuid>, _auth: AdminAuth) {
+        self.store
+            .revoke_server_token(jti.0)
+            .await
+            .expect("Store ops shouldn't fail");
+        self.store
+            .federation_event(
+                FederationEvent::TokensRevoked,
+                None,
+                Some(format!("Token {}", jti.0)),
+            )
+            .await;
+    }
+
+    /// Reject every server token issued to an instance so far, it can fetch a new one
+    #[oai(path = "/federation/:domain/tokens", method = "delete")]
+    async fn revoke_instance_tokens(&self, domain: Path<String>, _auth: AdminAuth) {
+        self.store
+            .revoke_instance_tokens(&domain.0)
+            .await
+            .expect("Store ops shouldn't fail");
+        self.store
+            .federation_event(
+                FederationEvent::TokensRevoked,
+                Some(&domain.0),
+                Some("Every token issued so far".to_string()),
+            )
+            .await;
+    }
+
+    /// Forget an instance, plots can't be registered to it anymore
+    ///
+    /// Its plots are moved to this instance, or disabled with `plots=orphan`.
+    /// Trust in it is dropped, its server tokens are revoked and relays waiting on it fail
+    #[oai(path = "/federation/:domain", method = "delete")]
+    async fn remove_instance(
+        &self,
+        req: &Request,
+        domain: Path<String>,
+        #[oai(default = "default_removed_plots")] plots: Query<RemovedInstancePlots>,
+        /// Also block its key, like POST `/federation/blocked-keys`
+        #[oai(default)]
+        block: Query<bool>,
+        _auth: AdminAuth,
+    ) -> RemoveInstanceResult {
+        let Some(removed) = self
+            .store
+            .remove_instance(&domain.0, plots.0, block.0)
+            .await
+            .expect("Store ops shouldn't fail")
+        else {
+            return RemoveInstanceResult::NotFound;
+        };
+        let ip = client_addr(req);
+        for plot in &removed.plots {
+            self.store
+                .audit(AuditEntry {
+                    plot: Some(*plot),
+                    event: AuditEvent::PlotMoved,
+                    actor: "admin".to_string(),
+                    ip,
+                    detail: Some(match plots.0 {
+                        RemovedInstancePlots::Current => {
+                            format!("{} was removed, moved to this instance", removed.domain)
+                        }
+                        RemovedInstancePlots::Orphan => {
+                            format!("{} was removed, disabled", removed.domain)
+                        }
+                    }),
+                })
+                .await;
+        }
+        self.store
+            .audit(AuditEntry {
+                plot: None,
+                event: AuditEvent::InstanceRemoved,
+                actor: "admin".to_string(),
+                ip,
+                detail: Some(removed.domain.clone()),
+            })
+            .await;
+        self.store
+            .federation_event(
+                FederationEvent::InstanceRemoved,
+                Some(&removed.domain),
+                Some(format!("{} plots {:?}", removed.plots.len(), plots.0).to_lowercase()),
+            )
+            .await;
+        RemoveInstanceResult::Ok(Json(removed))
+    }
+
+    /// Set how much an instance is trusted
+    ///
+    /// `open` instances can't be trusted as a whole by plots and only reach plots that trust
+    /// the sender back, `partner` plots get larger transfer quotas here
+    #[oai(path = "/federation/:domain/tier", method = "put")]
+    async fn set_instance_tier(
+        &self,
+        req: &Request,
+        domain: Path<String>,
+        tier: Query<InstanceTier>,
+        _auth: AdminAuth,
+    ) -> SetInstanceTierResult {
+        if !self
+            .store
+            .set_instance_tier(&domain.0, tier.0)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            return SetInstanceTierResult::NotFound;
+        }
+        self.store
+            .audit(AuditEntry {
+                plot: None,
+                event: AuditEvent::InstanceTierChanged,
+                actor: "admin".to_string(),
+                ip: client_addr(req),
+                detail: Some(format!("{} is now {:?}", domain.0, tier.0).to_lowercase()),
+            })
+            .await;
+        self.store
+            .federation_event(
+                FederationEvent::TierChanged,
+                Some(&domain.0),
+                Some(format!("{:?}", tier.0).to_lowercase()),
+            )
+            .await;
+        SetInstanceTierResult::Ok
+    }
+
+    /// Instance keys that can't get or use server tokens, base64 encoded
+    #[oai(path = "/federation/blocked-keys", method = "get")]
+    async fn blocked_keys(&self, _auth: AdminAuth) -> Json<Vec<String>> {
+        Json(
+            self.store
+                .blocked_instance_keys()
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Cut off a compromised instance, its tokens stop working and it can't fetch new ones
+    ///
+    /// The body is its base64 encoded key
+    #[oai(path = "/federation/blocked-keys", method = "post")]
+    async fn block_key(&self, key: PlainText<String>, _auth: AdminAuth) -> BlockKeyResult {
+        let key = match decode_instance_key(key.0.trim()) {
+            Ok(key) => key,
+            Err(err) => return BlockKeyResult::InvalidKey(PlainText(err)),
+        };
+        if self
+            .store
+            .block_instance_key(&key)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            self.store
+                .federation_event(
+                    FederationEvent::KeyBlocked,
+                    None,
+                    Some(key_fingerprint(&key)),
+                )
+                .await;
+            BlockKeyResult::Ok
+        } else {
+            BlockKeyResult::Unchanged
+        }
+    }
+
+    /// Let a blocked instance key fetch server tokens again
+    #[oai(path = "/federation/blocked-keys", method = "delete")]
+    async fn unblock_key(&self, key: PlainText<String>, _auth: AdminAuth) -> BlockKeyResult {
+        let key = match decode_instance_key(key.0.trim()) {
+            Ok(key) => key,
+            Err(err) => return BlockKeyResult::InvalidKey(PlainText(err)),
+        };
+        if self
+            .store
+            .unblock_instance_key(&key)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            self.store
+                .federation_event(
+                    FederationEvent::KeyUnblocked,
+                    None,
+                    Some(key_fingerprint(&key)),
+                )
+                .await;
+            BlockKeyResult::Ok
+        } else {
+            BlockKeyResult::Unchanged
+        }
+    }
+}
+
+impl AdminApi {
+    fn plot_domain(&self, plot: &Plot) -> String {
+        match &plot.instance.domain {
+            InstanceDomain::External(ext) => ext.inner().as_inner().to_string(),
+            InstanceDomain::Current => self.domain.as_inner().to_string(),
+        }
+    }
+}
+
+fn default_plots_limit() -> u32 {
+    50
+}
+
+fn default_active_days() -> u64 {
+    30
+}
+
+#[derive(Object)]
+pub struct AdminPlotResponse {
+    plot: PlotId,
+    /// Minecraft uuid of the plot owner
+    owner: Uuid,
+    /// Domain of the instance the plot is registered to
+    domain: String,
+    /// First 16 bytes of the SHA-256 of the instance key, hex encoded
+    key_fingerprint: String,
+    /// API keys that haven't been purged
+    active_keys: u32,
+    /// Plots this plot trusts
+    trusted_plots: u32,
+    /// Instances this plot trusts
+    trusted_instances: u32,
+    /// Unix timestamp in seconds, missing for plots registered before it was recorded
+    registered_at: Option<i64>,
+    disabled: bool,
+}
+
+#[derive(ApiResponse)]
+enum PlotToggleResult {
+    #[oai(status = 204)]
+    Ok,
+    /// Plot isn't registered, or wasn't disabled when enabling it
+    #[oai(status = 404)]
+    NotFound,
+}
+
+fn default_removed_plots() -> RemovedInstancePlots {
+    RemovedInstancePlots::Current
+}
+
+#[derive(ApiResponse)]
+enum RemoveInstanceResult {
+    #[oai(status = 200)]
+    Ok(Json<RemovedInstance>),
+    /// Instance isn't known
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+enum SetInstanceTierResult {
+    #[oai(status = 204)]
+    Ok,
+    /// Instance isn't registered
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+enum BlockKeyResult {
+    #[oai(status = 204)]
+    Ok,
+    /// Key was already blocked, or wasn't blocked when unblocking it
+    #[oai(status = 409)]
+    Unchanged,
+    #[oai(status = 400)]
+    InvalidKey(PlainText<String>),
+}
@@ -1,21 +1,36 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    net::IpAddr,
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use poem::{error::ResponseError, Request};
-use poem_openapi::{auth::ApiKey, Object, SecurityScheme};
+use base64::Engine;
+use ed25519_dalek::Signature;
+use poem::{error::ResponseError, Request, RequestBody};
+use poem_openapi::{
+    auth::{ApiKey, Bearer, BearerAuthorization},
+    registry::Registry,
+    ApiExtractor, ApiExtractorType, Enum, ExtractParamOptions, Object, SecurityScheme,
+};
 use redis_macros::{FromRedisValue, ToRedisArgs};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::prelude::FromRow;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 use crate::{
+    allowlist::{DfIps, IpRange},
     instance::{Instance, SendInstance},
-    store::Store,
+    signature::{request_message, BodyHash},
+    store::{
+        audit::{AuditEntry, AuditEvent},
+        key::{key_prefix, scopes_allow, KeyGrant, Scope},
+        member::{Abilities, Ability},
+        ops::AuthStore,
+    },
+    BASE64,
 };
 
 use super::PlotId;
@@ -28,6 +43,22 @@ pub struct ExternalServer {
     pub jti: Uuid,
 }
 
+/// Another instance, authorized with a server token or by signing the request
+#[derive(SecurityScheme)]
+pub enum ExternalServerAuth {
+    Token(ServerTokenAuth),
+    Signature(ServerSignatureAuth),
+}
+
+impl ExternalServerAuth {
+    pub fn request(self) -> ServerRequest {
+        match self {
+            ExternalServerAuth::Token(auth) => auth.0,
+            ExternalServerAuth::Signature(auth) => auth.0,
+        }
+    }
+}
+
 #[derive(SecurityScheme)]
 #[oai(
     ty = "api_key",
@@ -35,12 +66,41 @@ pub struct ExternalServer {
     key_in = "header",
     checker = "check_server"
 )]
-pub struct ExternalServerAuth(pub ExternalServer);
+pub struct ServerTokenAuth(pub ServerRequest);
+
+/// Base64 encoded ed25519 signature of [crate::signature::request_message] by the instance key,
+/// the instance needs plots registered to it here
+#[derive(SecurityScheme)]
+#[oai(
+    ty = "api_key",
+    key_name = "X-Signature",
+    key_in = "header",
+    checker = "check_signature"
+)]
+pub struct ServerSignatureAuth(pub ServerRequest);
+
+/// A verified instance along with the nonce of the request
+pub struct ServerRequest {
+    pub sub: SendInstance,
+    /// Already checked to be single use
+    pub nonce: String,
+}
 
 const JWT_VERSION: u64 = 1747450744;
+/// Seconds a server token is valid for
+pub const JWT_EXPIRY: u64 = 60 * 60 * 3;
+/// Header carrying the per request nonce of external servers, at most 64 bytes
+pub const NONCE_HEADER: &str = "X-Request-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// Domain of the instance that signed the request
+pub const SIGNATURE_DOMAIN_HEADER: &str = "X-Signature-Domain";
+/// Unix timestamp in seconds of when the request was signed
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+/// Seconds a signed request is accepted before or after its timestamp
+pub const SIGNATURE_MAX_SKEW: u64 = 60 * 5;
 
-pub async fn check_server(req: &Request, key: ApiKey) -> poem::Result<ExternalServer> {
-    let store = req.data::<Store>().expect("Store should here");
+pub async fn check_server(req: &Request, key: ApiKey) -> poem::Result<ServerRequest> {
+    let store: &Arc<dyn AuthStore> = req.data().expect("Store should be there");
     let server = store
         .verify_jwt::<ExternalServer>(&key.key)
         .ok_or(ServerAuthError::CannotVerify)?;
@@ -56,7 +116,107 @@ pub async fn check_server(req: &Request, key: ApiKey) -> poem::Result<ExternalSe
     if server.exp < time {
         return Err(ServerAuthError::Expired.into());
     }
-    Ok(server)
+    if store
+        .is_server_token_revoked(server.jti, &server.sub.domain, &server.sub.key, server.iat)
+        .await
+        .map_err(|err| {
+            error!("Checking token revocation failed: {:?}", err);
+            ServerAuthError::CannotVerify
+        })?
+    {
+        return Err(ServerAuthError::Revoked.into());
+    }
+
+    // Each token and nonce pair is single use so captured requests can't be replayed
+    let nonce = claim_nonce(store, &server.jti.to_string(), req, server.exp - time).await?;
+    Ok(ServerRequest {
+        sub: server.sub,
+        nonce,
+    })
+}
+
+pub async fn check_signature(req: &Request, signature: ApiKey) -> poem::Result<ServerRequest> {
+    let store: &Arc<dyn AuthStore> = req.data().expect("Store should be there");
+    let domain = req
+        .header(SIGNATURE_DOMAIN_HEADER)
+        .ok_or(ServerAuthError::MissingSignatureHeaders)?;
+    let timestamp: u64 = req
+        .header(SIGNATURE_TIMESTAMP_HEADER)
+        .and_then(|timestamp| timestamp.parse().ok())
+        .ok_or(ServerAuthError::MissingSignatureHeaders)?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    if time.abs_diff(timestamp) > SIGNATURE_MAX_SKEW {
+        return Err(ServerAuthError::Expired.into());
+    }
+
+    let sub = store
+        .get_known_instance(domain)
+        .await
+        .map_err(|err| {
+            error!("Getting known instance failed: {:?}", err);
+            ServerAuthError::CannotVerify
+        })?
+        .ok_or(ServerAuthError::UnknownInstance)?;
+    let instance = sub.parse().map_err(|_| ServerAuthError::CannotVerify)?;
+    let signature = BASE64
+        .decode(&signature.key)
+        .ok()
+        .and_then(|sig| Signature::from_slice(&sig).ok())
+        .ok_or(ServerAuthError::CannotVerify)?;
+    let nonce = req.header(NONCE_HEADER).unwrap_or_default();
+    let body_hash = match req.data::<BodyHash>() {
+        Some(hash) => hash.0,
+        None => Sha256::digest([]).into(),
+    };
+    let path = req
+        .original_uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let msg = request_message(req.method(), path, timestamp, nonce, &body_hash);
+    if instance.key.verify_strict(&msg, &signature).is_err() {
+        return Err(ServerAuthError::CannotVerify.into());
+    }
+    if store
+        .is_instance_key_blocked(&sub.key)
+        .await
+        .map_err(|err| {
+            error!("Checking blocked keys failed: {:?}", err);
+            ServerAuthError::CannotVerify
+        })?
+    {
+        return Err(ServerAuthError::Revoked.into());
+    }
+
+    // The nonce only has to be remembered for as long as the timestamp is accepted
+    let nonce = claim_nonce(store, &sub.key, req, SIGNATURE_MAX_SKEW * 2).await?;
+    Ok(ServerRequest { sub, nonce })
+}
+
+async fn claim_nonce(
+    store: &Arc<dyn AuthStore>,
+    issuer: &str,
+    req: &Request,
+    ttl: u64,
+) -> Result<String, ServerAuthError> {
+    let nonce = req
+        .header(NONCE_HEADER)
+        .filter(|nonce| !nonce.is_empty() && nonce.len() <= 64)
+        .ok_or(ServerAuthError::MissingNonce)?;
+    if !store
+        .claim_server_nonce(issuer, nonce, ttl)
+        .await
+        .map_err(|err| {
+            error!("Claiming nonce failed: {:?}", err);
+            ServerAuthError::CannotVerify
+        })?
+    {
+        return Err(ServerAuthError::Replayed);
+    }
+    Ok(nonce.to_string())
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -65,8 +225,18 @@ pub enum ServerAuthError {
     CannotVerify,
     #[error("Token expired")]
     Expired,
+    #[error("Token was revoked by the instance operator")]
+    Revoked,
     #[error("Version mismatch (please regenerate token)")]
     VersionMismatch,
+    #[error("Missing or invalid request nonce")]
+    MissingNonce,
+    #[error("Request nonce already used with this token")]
+    Replayed,
+    #[error("Signed requests need X-Signature-Domain and X-Signature-Timestamp")]
+    MissingSignatureHeaders,
+    #[error("No plots are registered to the instance, fetch a server token instead")]
+    UnknownInstance,
 }
 
 impl ResponseError for ServerAuthError {
@@ -78,6 +248,65 @@ impl ResponseError for ServerAuthError {
 pub struct UnregisteredPlot {
     pub plot_id: PlotId,
     pub owner: String,
+    /// Version of the DF client that sent the request
+    pub version: ClientVersion,
+    /// Node the plot runs on, like `node3`, if the client sent it
+    pub node: Option<String>,
+    pub size: Option<PlotSize>,
+}
+
+/// Plot size DF reports, bigger plots can be given bigger quotas
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PlotSize {
+    Basic,
+    Large,
+    Massive,
+    Mega,
+}
+
+impl PlotSize {
+    fn parse(size: &str) -> Option<Self> {
+        Some(match size.to_ascii_lowercase().as_str() {
+            "basic" => PlotSize::Basic,
+            "large" => PlotSize::Large,
+            "massive" => PlotSize::Massive,
+            "mega" => PlotSize::Mega,
+            _ => return None,
+        })
+    }
+}
+
+/// Hypercube version from the User-Agent, compares part by part so `7.10` is newer than `7.2`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClientVersion(pub Vec<u32>);
+
+impl ClientVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        version
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<Vec<u32>>>()
+            .map(ClientVersion)
+    }
+}
+
+impl std::fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+/// Hypercube versions from `HYPERCUBE_VERSIONS` whose requests are accepted, `*` accepts any
+#[derive(Clone)]
+pub struct AcceptedClients(pub Vec<String>);
+
+impl AcceptedClients {
+    fn accepts(&self, version: &str) -> bool {
+        self.0.iter().any(|it| it == "*" || it == version)
+    }
 }
 
 #[derive(SecurityScheme)]
@@ -90,19 +319,17 @@ pub struct UnregisteredPlot {
 pub struct UnregisteredAuth(pub UnregisteredPlot);
 
 pub async fn check_unreg_plot(req: &Request, user_agent: ApiKey) -> poem::Result<UnregisteredPlot> {
-    let addr = req
-        .remote_addr()
-        .as_socket_addr()
-        .ok_or(PlotAuthError::NotInternetSocketAddr)?;
-    let remote_addr = match *addr {
-        SocketAddr::V4(addr) => addr,
-        SocketAddr::V6(_) => return Err(PlotAuthError::NotIpv4.into()),
-    };
-    if !DF_IPS.contains(remote_addr.ip()) {
-        info!("Denied ip {}", req.remote_addr());
+    let ip = client_ip(req)?;
+    if !is_df_ip(req, ip) {
+        info!("Denied ip {} (peer {})", ip, req.remote_addr());
         return Err(PlotAuthError::InvalidIp.into());
     }
+    let accepted: &AcceptedClients = req.data().expect("Accepted clients should be there");
     if let Some(plot) = parse_user_agent(&user_agent.key) {
+        if !accepted.accepts(&plot.version.to_string()) {
+            info!("Denied client version {}", plot.version);
+            return Err(PlotAuthError::UnsupportedVersion(plot.version.to_string()).into());
+        }
         Ok(plot)
     } else {
         error!("Malformed user agent {}", user_agent.key);
@@ -110,21 +337,385 @@ pub async fn check_unreg_plot(req: &Request, user_agent: ApiKey) -> poem::Result
     }
 }
 
-#[derive(SecurityScheme)]
+/// Reverse proxies from `TRUSTED_PROXIES` whose `X-Forwarded-For` is believed
+#[derive(Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpRange>);
+
+/// Plot the request claims to be sent by, only believed from DF's address
+pub(crate) fn claimed_plot(req: &Request) -> Option<PlotId> {
+    let ip = client_ip(req).ok()?;
+    if !is_df_ip(req, ip) {
+        return None;
+    }
+    parse_user_agent(req.header("User-Agent")?).map(|plot| plot.plot_id)
+}
+
+/// Like [client_ip], but falls back to the peer's address for anything it rejects
+pub(crate) fn client_addr(req: &Request) -> Option<IpAddr> {
+    client_ip(req).ok().or_else(|| {
+        req.remote_addr()
+            .as_socket_addr()
+            .map(|addr| addr.ip().to_canonical())
+    })
+}
+
+/// Whether the address is in `DF_IPS`, in debug builds localhost is DF too
+fn is_df_ip(req: &Request, ip: IpAddr) -> bool {
+    (cfg!(debug_assertions) && ip.is_loopback())
+        || req.data::<DfIps>().is_some_and(|ips| ips.contains(ip))
+}
+
+/// Address of the client, the one a trusted proxy forwarded for if there is one.
+/// IPv4 clients of dual stack sockets come as IPv4 mapped IPv6 addresses, they are turned back into IPv4
+fn client_ip(req: &Request) -> Result<IpAddr, PlotAuthError> {
+    let peer = req
+        .remote_addr()
+        .as_socket_addr()
+        .ok_or(PlotAuthError::NotInternetSocketAddr)?
+        .ip()
+        .to_canonical();
+    let trusted = req
+        .data::<TrustedProxies>()
+        .is_some_and(|proxies| proxies.0.iter().any(|range| range.contains(peer)));
+    if !trusted {
+        return Ok(peer);
+    }
+    // The proxy appends the address it got the request from, anything before it is up to the client
+    let forwarded = if let Some(header) = req.header("X-Forwarded-For") {
+        header.rsplit(',').next().unwrap_or_default().trim()
+    } else if let Some(header) = req.header("Forwarded") {
+        forwarded_for(header).ok_or(PlotAuthError::MalformedForwardedFor)?
+    } else {
+        return Err(PlotAuthError::MissingForwardedFor);
+    };
+    forwarded
+        .parse()
+        .map(|ip: IpAddr| ip.to_canonical())
+        .map_err(|_| PlotAuthError::MalformedForwardedFor)
+}
+
+/// Address in the `for` of the last element of a `Forwarded` header (RFC 7239),
+/// like `for=192.0.2.60;proto=https` or `for="[2001:db8::1]:4711"`
+fn forwarded_for(header: &str) -> Option<&str> {
+    let element = header.rsplit(',').next()?;
+    let value = element.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("for").then_some(value)
+    })?;
+    let value = value.trim_matches('"');
+    if let Some(v6) = value.strip_prefix('[') {
+        return v6.split_once(']').map(|(addr, _)| addr);
+    }
+    // IPv4 may come with a port
+    Some(value.split(':').next().unwrap_or(value))
+}
+
+/// Any way a plot can authorize, tried in order like a derived security scheme enum.
+/// The derive would report the error of the last scheme tried, hiding why the credential
+/// the caller did send was rejected, so the error of the scheme it belongs to is reported
 pub enum Auth {
     KeyAuth(KeyAuth),
     PlotAuth(PlotAuth),
+    SessionAuth(SessionAuth),
+    DelegatedAuth(DelegatedAuth),
+}
+
+impl<'a> ApiExtractor<'a> for Auth {
+    const TYPES: &'static [ApiExtractorType] = &[ApiExtractorType::SecurityScheme];
+
+    type ParamType = ();
+    type ParamRawType = ();
+
+    fn register(registry: &mut Registry) {
+        KeyAuth::register(registry);
+        PlotAuth::register(registry);
+        SessionAuth::register(registry);
+        DelegatedAuth::register(registry);
+    }
+
+    fn security_schemes() -> Vec<&'static str> {
+        [
+            KeyAuth::security_schemes(),
+            PlotAuth::security_schemes(),
+            SessionAuth::security_schemes(),
+            DelegatedAuth::security_schemes(),
+        ]
+        .concat()
+    }
+
+    async fn from_request(
+        req: &'a Request,
+        body: &mut RequestBody,
+        param_opts: ExtractParamOptions<Self::ParamType>,
+    ) -> poem::Result<Self> {
+        let key = match KeyAuth::from_request(req, body, param_opts.clone()).await {
+            Ok(auth) => return Ok(Auth::KeyAuth(auth)),
+            Err(err) => err,
+        };
+        let plot = match PlotAuth::from_request(req, body, param_opts.clone()).await {
+            Ok(auth) => return Ok(Auth::PlotAuth(auth)),
+            Err(err) => err,
+        };
+        let session = match SessionAuth::from_request(req, body, param_opts.clone()).await {
+            Ok(auth) => return Ok(Auth::SessionAuth(auth)),
+            Err(err) => err,
+        };
+        let delegated = match DelegatedAuth::from_request(req, body, param_opts).await {
+            Ok(auth) => return Ok(Auth::DelegatedAuth(auth)),
+            Err(err) => err,
+        };
+        if req.headers().contains_key("X-API-Key") {
+            return Err(key);
+        }
+        let Ok(bearer) = Bearer::from_request(req) else {
+            // Every client sends a User-Agent, so it's the credential left
+            return Err(plot);
+        };
+        // Sessions and delegated tokens share the header, the claims tell them apart
+        let store: &Arc<dyn AuthStore> = req.data().expect("Store should be there");
+        if store.verify_jwt::<DelegatedToken>(&bearer.token).is_some() {
+            Err(delegated)
+        } else {
+            Err(session)
+        }
+    }
 }
 
 impl Auth {
     pub fn plot(self) -> Plot {
         match self {
-            Auth::KeyAuth(a) => a.0,
-            Auth::PlotAuth(a) => a.0,
+            Auth::KeyAuth(a) => a.0.plot,
+            Auth::PlotAuth(a) => a.0.plot,
+            Auth::SessionAuth(a) => a.0.plot,
+            Auth::DelegatedAuth(a) => a.0.plot,
+        }
+    }
+
+    /// The plot if the caller may use the ability, API keys need its scope
+    pub fn require(self, ability: Ability) -> Result<Plot, ForbiddenError> {
+        match self {
+            Auth::KeyAuth(a) => a.require(ability.scope()),
+            Auth::PlotAuth(a) => a.0.require(ability),
+            Auth::SessionAuth(a) => Ok(a.0.plot),
+            Auth::DelegatedAuth(a) => a.require(ability.scope()),
+        }
+    }
+
+    /// The plot if the caller is its owner or uses an API key with `plot:write`
+    pub fn require_owner(self) -> Result<Plot, ForbiddenError> {
+        match self {
+            Auth::KeyAuth(a) => a.require(Scope::PlotWrite),
+            Auth::PlotAuth(a) => a.0.require_owner(),
+            Auth::SessionAuth(a) => Ok(a.0.plot),
+            Auth::DelegatedAuth(a) => a.require(Scope::PlotWrite),
+        }
+    }
+
+    /// Who is making the request, for the audit log
+    pub fn actor(&self) -> String {
+        match self {
+            Auth::KeyAuth(a) => format!("key:{}", a.0.id),
+            Auth::PlotAuth(a) => a.0.actor(),
+            Auth::SessionAuth(a) => format!("session:{}", a.0.claims.owner),
+            Auth::DelegatedAuth(a) => format!("token:{}", a.0.jti),
+        }
+    }
+
+    /// The plot if the caller isn't an API key lacking the scope or a member lacking its ability
+    pub fn require_scope(self, scope: Scope) -> Result<Plot, ForbiddenError> {
+        match self {
+            Auth::KeyAuth(a) => a.require(scope),
+            Auth::PlotAuth(a) => a.0.require_scope(scope),
+            Auth::SessionAuth(a) => Ok(a.0.plot),
+            Auth::DelegatedAuth(a) => a.require(scope),
         }
     }
 }
 
+// admin auth
+
+/// Token of the instance operator from `ADMIN_TOKEN`, admin endpoints are disabled without it
+#[derive(Clone)]
+pub struct AdminToken(pub Option<String>);
+
+/// Instance operator authorization
+#[derive(SecurityScheme)]
+#[oai(ty = "bearer", checker = "admin_checker")]
+pub struct AdminAuth(());
+
+async fn admin_checker(req: &Request, bearer: Bearer) -> poem::Result<()> {
+    let token: &AdminToken = req.data().expect("Admin token should be there");
+    let Some(token) = &token.0 else {
+        return Err(AdminAuthError::Disabled.into());
+    };
+    // Comparing digests so the time taken doesn't tell how much of the token matched
+    if Sha256::digest(token.as_bytes()) != Sha256::digest(bearer.token.as_bytes()) {
+        return Err(AdminAuthError::InvalidToken.into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+enum AdminAuthError {
+    #[error("Admin API is disabled, set ADMIN_TOKEN to enable it")]
+    Disabled,
+    #[error("Invalid admin token")]
+    InvalidToken,
+}
+
+impl ResponseError for AdminAuthError {
+    fn status(&self) -> reqwest::StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+// session auth
+
+/// Seconds an owner session is valid for
+pub const SESSION_EXPIRY: u64 = 60 * 60 * 24;
+
+/// Claims of a session the plot owner got with a login code
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OwnerSession {
+    pub plot: PlotId,
+    /// Minecraft uuid of the owner, the session ends when the plot changes owner
+    pub owner: Uuid,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: Uuid,
+}
+
+/// Plot owner authorization with a session from `/login/complete`
+#[derive(SecurityScheme)]
+#[oai(ty = "bearer", checker = "session_checker")]
+pub struct SessionAuth(pub Session);
+
+pub struct Session {
+    pub plot: Plot,
+    pub claims: OwnerSession,
+}
+
+async fn session_checker(req: &Request, bearer: Bearer) -> poem::Result<Session> {
+    let store: &Arc<dyn AuthStore> = req.data().expect("Store should be there");
+    let claims = store
+        .verify_jwt::<OwnerSession>(&bearer.token)
+        .ok_or(SessionAuthError::InvalidSession)?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    if claims.exp < time {
+        return Err(SessionAuthError::Expired.into());
+    }
+    if store
+        .is_session_revoked(claims.jti)
+        .await
+        .expect("session check shouldn't fail")
+    {
+        return Err(SessionAuthError::InvalidSession.into());
+    }
+    let plot = store
+        .get_plot(claims.plot)
+        .await
+        .expect("Cannot get plot")
+        .filter(|plot| plot.owner == claims.owner)
+        .ok_or(SessionAuthError::InvalidSession)?;
+    ensure_enabled(store, plot.plot_id).await?;
+    record_request(store, plot.plot_id).await;
+    Ok(Session { plot, claims })
+}
+
+#[derive(Debug, thiserror::Error)]
+enum SessionAuthError {
+    #[error("Invalid session")]
+    InvalidSession,
+    #[error("Session expired, log in again")]
+    Expired,
+}
+
+impl ResponseError for SessionAuthError {
+    fn status(&self) -> reqwest::StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+// delegated auth
+
+/// Longest a delegated token can be valid for in seconds
+pub const DELEGATED_MAX_EXPIRY: u64 = 60 * 60;
+
+/// Claims of a token a plot handed to another service, it can only do what its scopes allow
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DelegatedToken {
+    pub plot: PlotId,
+    /// Like the scopes of an API key
+    pub scopes: Vec<String>,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: Uuid,
+}
+
+/// Short lived token from `/delegate`
+#[derive(SecurityScheme)]
+#[oai(ty = "bearer", checker = "delegated_checker")]
+pub struct DelegatedAuth(pub Delegated);
+
+pub struct Delegated {
+    pub plot: Plot,
+    pub scopes: Vec<String>,
+    pub jti: Uuid,
+}
+
+impl DelegatedAuth {
+    pub fn require(self, scope: Scope) -> Result<Plot, ForbiddenError> {
+        if scopes_allow(&self.0.scopes, scope) {
+            Ok(self.0.plot)
+        } else {
+            Err(ForbiddenError::MissingScope(scope))
+        }
+    }
+}
+
+async fn delegated_checker(req: &Request, bearer: Bearer) -> poem::Result<Delegated> {
+    let store: &Arc<dyn AuthStore> = req.data().expect("Store should be there");
+    let claims = store
+        .verify_jwt::<DelegatedToken>(&bearer.token)
+        .ok_or(DelegatedAuthError::InvalidToken)?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    if claims.exp < time {
+        return Err(DelegatedAuthError::Expired.into());
+    }
+    let plot = store
+        .get_plot(claims.plot)
+        .await
+        .expect("Cannot get plot")
+        .ok_or(DelegatedAuthError::InvalidToken)?;
+    ensure_enabled(store, plot.plot_id).await?;
+    record_request(store, plot.plot_id).await;
+    Ok(Delegated {
+        plot,
+        scopes: claims.scopes,
+        jti: claims.jti,
+    })
+}
+
+#[derive(Debug, thiserror::Error)]
+enum DelegatedAuthError {
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Token expired")]
+    Expired,
+}
+
+impl ResponseError for DelegatedAuthError {
+    fn status(&self) -> reqwest::StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
 // key auth
 
 /// Guaranteed to be registered
@@ -142,26 +733,130 @@ pub struct Plot {
     key_in = "header",
     checker = "key_checker"
 )]
-pub struct KeyAuth(pub Plot);
+pub struct KeyAuth(pub KeyGrant);
+
+impl KeyAuth {
+    pub fn require(self, scope: Scope) -> Result<Plot, ForbiddenError> {
+        if self.0.allows(scope) {
+            Ok(self.0.plot)
+        } else {
+            Err(ForbiddenError::MissingScope(scope))
+        }
+    }
+}
 
-async fn key_checker(req: &Request, auth: ApiKey) -> poem::Result<Plot> {
-    let store: &Arc<Store> = req.data().expect("Store should be there");
-    Ok(store
+async fn key_checker(req: &Request, auth: ApiKey) -> poem::Result<KeyGrant> {
+    let store: &Arc<dyn AuthStore> = req.data().expect("Store should be there");
+    let ip = client_addr(req);
+    let lockout = match ip {
+        Some(ip) => store
+            .key_lockout(ip)
+            .await
+            .expect("key check shouldn't fail"),
+        None => None,
+    };
+    if let Some(retry_after) = lockout {
+        return Err(KeyAuthError::LockedOut(retry_after).into());
+    }
+    let Some(grant) = store
         .verify_key(&auth.key)
         .await
         .expect("key check shouldn't fail")
-        .ok_or(KeyAuthError::InvalidApiKey)?)
+    else {
+        if let Some(ip) = ip {
+            record_key_failure(store, ip).await;
+        }
+        store
+            .audit(AuditEntry {
+                plot: None,
+                event: AuditEvent::KeyRejected,
+                actor: "unknown".to_string(),
+                ip,
+                detail: key_prefix(&auth.key).map(|prefix| format!("Invalid key {prefix}")),
+            })
+            .await;
+        return Err(match key_prefix(&auth.key) {
+            Some(prefix) => KeyAuthError::UnknownKey(prefix.to_string()),
+            None => KeyAuthError::InvalidApiKey,
+        }
+        .into());
+    };
+    // Without an address only keys usable from anywhere get through
+    if grant.allowed_ips.is_some() && !ip.is_some_and(|ip| grant.allows_ip(ip)) {
+        store
+            .audit(AuditEntry {
+                plot: Some(grant.plot.plot_id),
+                event: AuditEvent::KeyRejected,
+                actor: format!("key:{}", grant.id),
+                ip,
+                detail: Some("Address not allowed".to_string()),
+            })
+            .await;
+        return Err(KeyAuthError::AddressNotAllowed.into());
+    }
+    ensure_enabled(store, grant.plot.plot_id).await?;
+    record_request(store, grant.plot.plot_id).await;
+    if let Err(err) = store.record_key_use(grant.id).await {
+        warn!("Recording use of key {} failed: {:?}", grant.id, err);
+    }
+    Ok(grant)
+}
+
+/// Rejects plots the instance operator disabled
+async fn ensure_enabled(store: &Arc<dyn AuthStore>, plot_id: PlotId) -> Result<(), PlotDisabled> {
+    if store
+        .is_plot_disabled(plot_id)
+        .await
+        .expect("disabled check shouldn't fail")
+    {
+        Err(PlotDisabled)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Plot was disabled by the instance operator")]
+struct PlotDisabled;
+
+impl ResponseError for PlotDisabled {
+    fn status(&self) -> reqwest::StatusCode {
+        StatusCode::FORBIDDEN
+    }
+}
+
+/// Counts the request towards the plot's activity, failures are only logged
+async fn record_key_failure(store: &Arc<dyn AuthStore>, ip: IpAddr) {
+    if let Err(err) = store.record_key_failure(ip).await {
+        warn!("Recording invalid key from {} failed: {:?}", ip, err);
+    }
+}
+
+async fn record_request(store: &Arc<dyn AuthStore>, plot_id: PlotId) {
+    if let Err(err) = store.record_request(plot_id).await {
+        warn!("Recording request of {} failed: {:?}", plot_id, err);
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 enum KeyAuthError {
     #[error("Invalid API key")]
     InvalidApiKey,
+    #[error("API key {0} doesn't exist or was disabled")]
+    UnknownKey(String),
+    #[error("Too many invalid API keys, try again in {0} seconds")]
+    LockedOut(u64),
+    #[error("API key can't be used from this address")]
+    AddressNotAllowed,
 }
 
 impl ResponseError for KeyAuthError {
     fn status(&self) -> reqwest::StatusCode {
-        StatusCode::UNAUTHORIZED
+        match self {
+            KeyAuthError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+            KeyAuthError::AddressNotAllowed => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
     }
 }
 
@@ -175,29 +870,115 @@ impl ResponseError for KeyAuthError {
     key_in = "header",
     checker = "plot_checker"
 )]
-pub struct PlotAuth(pub Plot);
-
-#[cfg(debug_assertions)]
-const DF_IPS: [Ipv4Addr; 2] = [
-    Ipv4Addr::new(127, 0, 0, 1),
-    Ipv4Addr::new(51, 222, 245, 229),
-];
-/// DynamicCake: I will only add IPs I see with my own two eyes
-#[cfg(not(debug_assertions))]
-const DF_IPS: [Ipv4Addr; 1] = [Ipv4Addr::new(51, 222, 245, 229)];
-
-async fn plot_checker(req: &Request, user_agent: ApiKey) -> poem::Result<Plot> {
+pub struct PlotAuth(pub PlotActor);
+
+/// The player acting for a plot, either its owner or a member
+pub struct PlotActor {
+    pub plot: Plot,
+    /// None if the player is the owner
+    pub member: Option<Uuid>,
+    pub abilities: Abilities,
+    /// Version of the DF client that sent the request
+    pub version: ClientVersion,
+    pub node: Option<String>,
+    pub size: Option<PlotSize>,
+}
+
+impl PlotActor {
+    /// The player making the request, for the audit log
+    pub fn actor(&self) -> String {
+        format!("player:{}", self.member.unwrap_or(self.plot.owner))
+    }
+
+    pub fn require(self, ability: Ability) -> Result<Plot, ForbiddenError> {
+        if self.abilities.has(ability) {
+            Ok(self.plot)
+        } else {
+            Err(ForbiddenError::MissingAbility(ability))
+        }
+    }
+
+    pub fn require_owner(self) -> Result<Plot, ForbiddenError> {
+        if self.member.is_none() {
+            Ok(self.plot)
+        } else {
+            Err(ForbiddenError::NotOwner)
+        }
+    }
+
+    /// Members need the ability matching the scope, `plot:write` is for the owner only
+    pub fn require_scope(self, scope: Scope) -> Result<Plot, ForbiddenError> {
+        match (scope, Ability::for_scope(scope)) {
+            (Scope::PlotWrite, _) => self.require_owner(),
+            (_, Some(ability)) => self.require(ability),
+            (_, None) => Ok(self.plot),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForbiddenError {
+    #[error("Plot member lacks the {} ability", .0.name())]
+    MissingAbility(Ability),
+    #[error("Only the plot owner can do this")]
+    NotOwner,
+    #[error("API key or token lacks the {} scope", .0.name())]
+    MissingScope(Scope),
+    #[error("Delegated tokens can't do this")]
+    Delegated,
+}
+
+impl ResponseError for ForbiddenError {
+    fn status(&self) -> reqwest::StatusCode {
+        StatusCode::FORBIDDEN
+    }
+}
+
+async fn plot_checker(req: &Request, user_agent: ApiKey) -> poem::Result<PlotActor> {
     let unreg = check_unreg_plot(req, user_agent).await?;
-    let store: &Arc<Store> = req.data().expect("Server should have store");
+    let store: &Arc<dyn AuthStore> = req.data().expect("Server should have store");
     let plot = store
         .get_plot(unreg.plot_id)
         .await
         .expect("Cannot get plot")
         .ok_or(PlotAuthError::PlotNotRegistered)?;
-    Ok(Plot {
+    ensure_enabled(store, unreg.plot_id).await?;
+    let player = store
+        .get_uuid(&unreg.owner)
+        .await
+        .map_err(|err| {
+            error!("Fetching uuid of {} failed: {:?}", unreg.owner, err);
+            PlotAuthError::CannotFetchUuid
+        })?
+        .ok_or(PlotAuthError::CannotFetchUuid)?;
+    let plot = Plot {
         plot_id: unreg.plot_id,
         owner: plot.owner,
         instance: plot.instance,
+    };
+    record_request(store, plot.plot_id).await;
+    if player == plot.owner {
+        return Ok(PlotActor {
+            plot,
+            member: None,
+            abilities: Abilities::all(),
+            version: unreg.version,
+            node: unreg.node,
+            size: unreg.size,
+        });
+    }
+    let abilities = store
+        .get_member(plot.plot_id, player)
+        .await
+        .expect("Cannot get member")
+        .ok_or(PlotAuthError::NotAMember)?;
+    Ok(PlotActor {
+        plot,
+        member: Some(player),
+        abilities,
+        version: unreg.version,
+        node: unreg.node,
+        size: unreg.size,
     })
 }
 
@@ -207,15 +988,20 @@ enum PlotAuthError {
     PlotNotRegistered,
     #[error("Must be socket error for plot auth")]
     NotInternetSocketAddr,
-    #[error("Must be ipv4 for plot auth")]
-    NotIpv4,
-    #[error(
-        "Ip doesn't match ips: {:?}\nDid you mean to use X-API-Key auth?",
-        DF_IPS
-    )]
+    #[error("Trusted proxy sent a malformed X-Forwarded-For")]
+    MalformedForwardedFor,
+    #[error("Ip isn't one of DiamondFire's\nDid you mean to use X-API-Key auth?")]
     InvalidIp,
     #[error("Malfored User-Agent")]
     MalformedUserAgent,
+    #[error("Hypercube version {0} is not accepted by this instance")]
+    UnsupportedVersion(String),
+    #[error("Trusted proxy sent neither X-Forwarded-For nor Forwarded")]
+    MissingForwardedFor,
+    #[error("Cannot fetch uuid of player")]
+    CannotFetchUuid,
+    #[error("Player is neither the owner nor a member of the plot")]
+    NotAMember,
 }
 
 impl ResponseError for PlotAuthError {
@@ -226,17 +1012,25 @@ impl ResponseError for PlotAuthError {
 
 fn parse_user_agent(header: &str) -> Option<UnregisteredPlot> {
     // Hypercube/7.2 (23612, DynamicCake)
-    //
-    let start = "Hypercube/7.2 (";
-    if !header.starts_with(start) {
-        return None;
-    }
-    let (_, right) = header.split_once("(")?;
-    let (plot_id, username) = right.split_once(", ")?;
-    let (username, _) = username.split_once(")")?;
-    let plot_id: PlotId = plot_id.parse().ok()?;
+    // Hypercube/7.2 (23612, DynamicCake, node3, mega)
+    let rest = header.strip_prefix("Hypercube/")?;
+    let (version, right) = rest.split_once(" (")?;
+    let version = ClientVersion::parse(version)?;
+    let (fields, _) = right.split_once(")")?;
+    let mut fields = fields.split(", ");
+    let plot_id: PlotId = fields.next()?.parse().ok()?;
+    let username = fields.next()?;
+    let node = fields
+        .next()
+        .filter(|node| !node.is_empty())
+        .map(str::to_string);
+    // Sizes this doesn't know yet are left out rather than denied
+    let size = fields.next().and_then(PlotSize::parse);
     Some(UnregisteredPlot {
         plot_id,
         owner: username.to_string(),
+        version,
+        node,
+        size,
     })
 }
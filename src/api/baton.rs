@@ -1,21 +1,57 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use futures::{stream, StreamExt};
-use poem_openapi::{param::Query, payload::Json, ApiResponse, OpenApi};
+use base64::Engine;
+use ed25519_dalek::Signature;
+use futures::{stream::BoxStream, StreamExt};
+use poem::Request;
+use poem_openapi::{
+    param::{Header, Path, Query},
+    payload::{EventStream, Json, PlainText},
+    ApiResponse, Enum, Object, OpenApi, Union,
+};
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::{dfjson::DfJson, store::Store};
+use crate::{
+    dfjson::{self, DfJson, DfJsonLimits, DfJsonViolation},
+    instance::{Instance, InstanceDomain},
+    store::{
+        audit::{AuditEntry, AuditEvent},
+        baton::{
+            unix_now, BatonSettings, InstanceTrustSetError, Origin, PlotTrustSetError,
+            QueuedTransfer, TransferAckError, TransferQueueError,
+        },
+        history::{HistoryDirection, HistoryEntry, HistoryFilter},
+        idempotency::{IdempotencyClaim, IdempotencyKey},
+        instance::InstanceTier,
+        key::Scope,
+        member::Ability,
+        ops::BatonStore,
+        patch::PatchError,
+        relay::RelayState,
+        reply::ReplyError,
+        Store,
+    },
+    BASE64,
+};
 
 use super::{
-    auth::{Auth, ExternalServerAuth},
-    PlotId,
+    auth::{client_addr, Auth, ExternalServerAuth},
+    decode_instance_key, key_fingerprint, PlotId,
 };
 
-pub struct BatonApi {
-    pub store: Arc<Store>,
+pub struct BatonApi<S: BatonStore = Store> {
+    pub store: Arc<S>,
+    /// Largest transfer payload accepted in bytes
+    pub max_transfer_bytes: usize,
+    /// Limits DfJson payloads are validated against
+    pub dfjson_limits: DfJsonLimits,
 }
 
 #[OpenApi]
-impl BatonApi {
+impl<S: BatonStore> BatonApi<S> {
     /// List trusted plots that can set transfer
     #[oai(path = "/trusted", method = "get")]
     async fn get_trusted(&self, auth: Auth) -> Json<Vec<PlotId>> {
@@ -29,54 +65,849 @@ impl BatonApi {
 
     /// Replace all trusted plots
     #[oai(path = "/trusted", method = "post")]
-    async fn set_trusted(&self, auth: Auth, trusted: Json<Vec<PlotId>>) -> SetTrustedResult {
-        async fn plot_not_exists(store: &Store, id: PlotId) -> Option<PlotId> {
-            if store
-                .plot_exists(id)
+    async fn set_trusted(
+        &self,
+        auth: Auth,
+        trusted: Json<Vec<PlotId>>,
+        req: &Request,
+    ) -> poem::Result<SetTrustedResult> {
+        let actor = auth.actor();
+        let plot = auth.require(Ability::EditTrust)?;
+        let found = self
+            .store
+            .get_plots(&trusted.0)
+            .await
+            .expect("Store ops shouldn't fail");
+        let errors: Vec<PlotId> = trusted
+            .0
+            .iter()
+            .copied()
+            .filter(|id| found.binary_search_by_key(id, |plot| plot.plot_id).is_err())
+            .collect();
+
+        if errors.is_empty() {
+            let detail = format!("Set to {:?}", trusted.0);
+            if let Err(_err) = self
+                .store
+                .set_plot_trust(plot.plot_id, trusted.0)
                 .await
-                .expect("plot_exists shouldn't fail")
+                .expect("Store ops shouldn't fail")
             {
-                None
-            } else {
-                Some(id)
+                return Ok(SetTrustedResult::PlotNotFound);
             }
+            self.store
+                .audit(AuditEntry {
+                    plot: Some(plot.plot_id),
+                    event: AuditEvent::TrustChanged,
+                    actor,
+                    ip: client_addr(req),
+                    detail: Some(detail),
+                })
+                .await;
+            Ok(SetTrustedResult::Success)
+        } else {
+            Ok(SetTrustedResult::OtherPlotNotRegistered(Json(errors)))
         }
-        let errors = stream::iter(&trusted.0)
-            .filter_map(|id| plot_not_exists(&self.store, *id))
-            .collect::<Vec<_>>()
-            .await;
+    }
 
-        if errors.is_empty() {
-            if let Err(_err) = self
+    /// Trust a single plot, leaving the rest of the list alone
+    ///
+    /// The trust lapses at `expires_at`, a unix timestamp in seconds, if it is set
+    #[oai(path = "/trusted/:plot_id", method = "put")]
+    async fn add_trusted(
+        &self,
+        plot_id: Path<PlotId>,
+        expires_at: Query<Option<u64>>,
+        auth: Auth,
+        req: &Request,
+    ) -> poem::Result<AddTrustedResult> {
+        let actor = auth.actor();
+        let plot = auth.require(Ability::EditTrust)?;
+        if expires_at.0.is_some_and(|at| at <= unix_now()) {
+            return Ok(AddTrustedResult::AlreadyExpired);
+        }
+        if !self
+            .store
+            .plot_exists(plot_id.0)
+            .await
+            .expect("plot_exists shouldn't fail")
+        {
+            return Ok(AddTrustedResult::OtherPlotNotRegistered);
+        }
+        Ok(
+            match self
                 .store
-                .set_plot_trust(auth.plot().plot_id, trusted.0)
+                .add_plot_trust(plot.plot_id, plot_id.0, expires_at.0)
                 .await
                 .expect("Store ops shouldn't fail")
             {
-                return SetTrustedResult::PlotNotFound;
-            }
-            SetTrustedResult::Success
+                Ok(true) => {
+                    self.store
+                        .audit(AuditEntry {
+                            plot: Some(plot.plot_id),
+                            event: AuditEvent::TrustChanged,
+                            actor,
+                            ip: client_addr(req),
+                            detail: Some(format!("Added {}", plot_id.0)),
+                        })
+                        .await;
+                    AddTrustedResult::Added
+                }
+                Ok(false) => AddTrustedResult::AlreadyTrusted,
+                Err(PlotTrustSetError::PlotNotFound) => AddTrustedResult::PlotNotFound,
+            },
+        )
+    }
+
+    /// Stop trusting a single plot, leaving the rest of the list alone
+    #[oai(path = "/trusted/:plot_id", method = "delete")]
+    async fn remove_trusted(
+        &self,
+        plot_id: Path<PlotId>,
+        auth: Auth,
+        req: &Request,
+    ) -> poem::Result<RemoveTrustedResult> {
+        let actor = auth.actor();
+        let plot = auth.require(Ability::EditTrust)?;
+        if self
+            .store
+            .remove_plot_trust(plot.plot_id, plot_id.0)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            self.store
+                .audit(AuditEntry {
+                    plot: Some(plot.plot_id),
+                    event: AuditEvent::TrustChanged,
+                    actor,
+                    ip: client_addr(req),
+                    detail: Some(format!("Removed {}", plot_id.0)),
+                })
+                .await;
+            Ok(RemoveTrustedResult::Removed)
         } else {
-            SetTrustedResult::OtherPlotNotRegistered(Json(errors))
+            Ok(RemoveTrustedResult::NotTrusted)
         }
     }
 
-    /// TODO: Finish making this function lol
+    /// List instances, by their base64 encoded key, whose plots can all set transfer
+    #[oai(path = "/trusted/instances", method = "get")]
+    async fn get_trusted_instances(&self, auth: Auth) -> Json<Vec<String>> {
+        Json(
+            self.store
+                .fetch_instance_trust(auth.plot().plot_id)
+                .await
+                .expect("Store ops shouldn't fail")
+                .into_iter()
+                .map(|key| BASE64.encode(key))
+                .collect(),
+        )
+    }
+
+    /// Replace all trusted instances, by their base64 encoded key
+    #[oai(path = "/trusted/instances", method = "post")]
+    async fn set_trusted_instances(
+        &self,
+        auth: Auth,
+        trusted: Json<Vec<String>>,
+        req: &Request,
+    ) -> poem::Result<SetTrustedInstancesResult> {
+        let actor = auth.actor();
+        let plot = auth.require(Ability::EditTrust)?;
+        let keys = match trusted
+            .0
+            .iter()
+            .map(|key| decode_instance_key(key))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(keys) => keys,
+            Err(err) => return Ok(SetTrustedInstancesResult::InvalidKeyFormat(PlainText(err))),
+        };
+        Ok(
+            match self
+                .store
+                .set_instance_trust(plot.plot_id, keys)
+                .await
+                .expect("Store ops shouldn't fail")
+            {
+                Ok(()) => {
+                    self.store
+                        .audit(AuditEntry {
+                            plot: Some(plot.plot_id),
+                            event: AuditEvent::InstanceTrustChanged,
+                            actor,
+                            ip: client_addr(req),
+                            detail: Some(format!("Set to {:?}", trusted.0)),
+                        })
+                        .await;
+                    SetTrustedInstancesResult::Success
+                }
+                Err(InstanceTrustSetError::PlotNotFound) => SetTrustedInstancesResult::PlotNotFound,
+                Err(InstanceTrustSetError::InstanceNotFound(keys)) => {
+                    SetTrustedInstancesResult::InstanceNotRegistered(Json(
+                        keys.into_iter().map(|key| BASE64.encode(key)).collect(),
+                    ))
+                }
+                Err(InstanceTrustSetError::NotTrustable(keys)) => {
+                    SetTrustedInstancesResult::InstanceNotTrustable(Json(
+                        keys.into_iter().map(|key| BASE64.encode(key)).collect(),
+                    ))
+                }
+            },
+        )
+    }
+
+    /// Get the baton settings of this plot
+    #[oai(path = "/settings", method = "get")]
+    async fn get_settings(&self, auth: Auth) -> Json<BatonSettings> {
+        Json(
+            self.store
+                .get_baton_settings(auth.plot().plot_id)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Replace the baton settings of this plot
+    #[oai(path = "/settings", method = "put")]
+    async fn set_settings(
+        &self,
+        auth: Auth,
+        settings: Json<BatonSettings>,
+    ) -> poem::Result<SetSettingsResult> {
+        let plot = auth.require(Ability::EditTrust)?;
+        Ok(
+            match self
+                .store
+                .set_baton_settings(plot.plot_id, &settings.0)
+                .await
+                .expect("Store ops shouldn't fail")
+            {
+                Ok(()) => SetSettingsResult::Success,
+                Err(PlotTrustSetError::PlotNotFound) => SetSettingsResult::PlotNotFound,
+            },
+        )
+    }
+
+    /// Send a transfer to a plot
     #[oai(path = "/transfer", method = "post")]
-    async fn transfer(&self, dest: Query<PlotId>) -> SetTransferResult {
-        todo!();
-        let found = if let Some(it) = self
+    async fn transfer(
+        &self,
+        dest: Query<PlotId>,
+        /// Unix timestamp in seconds, the transfer is held until then
+        deliver_at: Query<Option<u64>>,
+        payload: Json<TransferPayload>,
+        /// Retrying with the same key returns the original result instead of sending again
+        #[oai(name = "Idempotency-Key", validator(max_length = 255))]
+        idempotency_key: Header<Option<String>>,
+        auth: Auth,
+    ) -> poem::Result<SetTransferResult> {
+        let plot = auth.require(Ability::SendTransfers)?;
+        Ok(self
+            .send_transfer(
+                plot.plot_id,
+                dest.0,
+                deliver_at.0,
+                TransferBody::Full(payload.0),
+                idempotency_key.0,
+            )
+            .await)
+    }
+
+    /// Send a transfer to a plot as an RFC 6902 patch of the last payload sent to it
+    ///
+    /// Paths start at the payload, like `/data/val/players`
+    #[oai(path = "/transfer/patch", method = "post")]
+    async fn transfer_patch(
+        &self,
+        dest: Query<PlotId>,
+        /// Unix timestamp in seconds, the transfer is held until then
+        deliver_at: Query<Option<u64>>,
+        patch: Json<Vec<PatchOperation>>,
+        /// Retrying with the same key returns the original result instead of sending again
+        #[oai(name = "Idempotency-Key", validator(max_length = 255))]
+        idempotency_key: Header<Option<String>>,
+        auth: Auth,
+    ) -> poem::Result<SetTransferResult> {
+        let plot = auth.require(Ability::SendTransfers)?;
+        let patch = match patch
+            .0
+            .iter()
+            .map(|op| serde_json::to_value(op).and_then(serde_json::from_value))
+            .collect::<Result<Vec<json_patch::PatchOperation>, _>>()
+        {
+            Ok(patch) => patch,
+            Err(err) => {
+                return Ok(SetTransferResult::MalformedPatch(PlainText(
+                    err.to_string(),
+                )))
+            }
+        };
+        Ok(self
+            .send_transfer(
+                plot.plot_id,
+                dest.0,
+                deliver_at.0,
+                TransferBody::Patch(patch),
+                idempotency_key.0,
+            )
+            .await)
+    }
+
+    /// Send the same transfer to multiple plots
+    ///
+    /// Every destination is handled on its own, check the result of each
+    #[oai(path = "/transfer/broadcast", method = "post")]
+    async fn broadcast(
+        &self,
+        body: Json<BroadcastRequest>,
+        /// Retrying with the same key returns the original result instead of sending again
+        #[oai(name = "Idempotency-Key", validator(max_length = 255))]
+        idempotency_key: Header<Option<String>>,
+        auth: Auth,
+    ) -> poem::Result<BroadcastResult> {
+        let plot = auth.require(Ability::SendTransfers)?;
+        let body = body.0;
+        let size = match body
+            .payload
+            .check(self.max_transfer_bytes, &self.dfjson_limits)
+        {
+            Ok(size) => size,
+            Err(err) => return Ok(err.into()),
+        };
+        if let Err(err) = check_deliver_at(body.deliver_at) {
+            return Ok(BroadcastResult::InvalidDeliverAt(PlainText(err)));
+        }
+        let mut destinations = body.destinations;
+        destinations.sort_unstable();
+        destinations.dedup();
+        if destinations.len() > MAX_BROADCAST_DESTINATIONS {
+            return Ok(BroadcastResult::TooManyDestinations(PlainText(format!(
+                "At most {} destinations are allowed",
+                MAX_BROADCAST_DESTINATIONS
+            ))));
+        }
+
+        let from = plot.plot_id;
+        let key = idempotency_key
+            .0
+            .map(|key| IdempotencyKey::new("broadcast", from, &key));
+        if let Some(key) = &key {
+            match self
+                .store
+                .claim_idempotency_key(key)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                IdempotencyClaim::Claimed => {}
+                IdempotencyClaim::InProgress => return Ok(BroadcastResult::InProgress),
+                IdempotencyClaim::Done(results) => return Ok(BroadcastResult::Ok(Json(results))),
+            }
+        }
+        // Every destination counts as a transfer of its own
+        if let Err(err) = self
             .store
-            .get_plot(dest.0)
+            .consume_transfer_quota(
+                from,
+                destinations.len() as u32,
+                (size * destinations.len()) as u64,
+            )
             .await
-            .expect("Get plot shouldn't fail")
+            .expect("store ops shouldn't fail")
         {
-            it
-        } else {
-            return SetTransferResult::PlotNotFound;
+            if let Some(key) = &key {
+                self.store
+                    .finish_idempotency_key::<()>(key, None)
+                    .await
+                    .expect("store ops shouldn't fail");
+            }
+            return Ok(BroadcastResult::RateLimited(
+                PlainText(err.to_string()),
+                err.retry_after,
+            ));
+        }
+        let mut results = HashMap::with_capacity(destinations.len());
+        for dest in destinations {
+            let outcome = self
+                .send(from, dest, body.payload.clone(), body.deliver_at)
+                .await;
+            results.insert(dest, outcome.into());
+        }
+        if let Some(key) = &key {
+            // Some destinations may have been sent to, so the result is always kept
+            self.store
+                .finish_idempotency_key(key, Some(&results))
+                .await
+                .expect("store ops shouldn't fail");
+        }
+        Ok(BroadcastResult::Ok(Json(results)))
+    }
+
+    /// Remaining transfers and bytes this plot can send
+    #[oai(path = "/quota", method = "get")]
+    async fn quota(&self, auth: Auth) -> poem::Result<Json<QuotaResponse>> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        let quota = self
+            .store
+            .get_transfer_quota(plot_id)
+            .await
+            .expect("store ops shouldn't fail");
+        Ok(Json(QuotaResponse {
+            transfers_remaining: quota.transfers_remaining,
+            transfers_reset: quota.transfers_reset,
+            bytes_remaining: quota.bytes_remaining,
+            bytes_reset: quota.bytes_reset,
+        }))
+    }
+
+    /// Take the oldest pending transfer for this plot, if there is one
+    ///
+    /// Transfers not matching `kind` are left in the queue.
+    /// With `in_order`, transfers from the same sender are taken by their sequence number
+    #[oai(path = "/transfer", method = "get")]
+    async fn take_transfer(
+        &self,
+        kind: Query<Option<PayloadKind>>,
+        #[oai(default)] in_order: Query<bool>,
+        auth: Auth,
+    ) -> poem::Result<TakeTransferResult> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        Ok(
+            match self
+                .store
+                .take_transfer(plot_id, kind.0, in_order.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Some(transfer) => TakeTransferResult::Ok(Json(transfer.into())),
+                None => TakeTransferResult::NoTransfer,
+            },
+        )
+    }
+
+    /// Stream incoming transfers as server sent events
+    ///
+    /// Transfers sent through the stream are taken from the queue.
+    /// With `in_order`, transfers from the same sender are sent by their sequence number
+    #[oai(path = "/stream", method = "get")]
+    async fn stream(
+        &self,
+        #[oai(default)] in_order: Query<bool>,
+        auth: Auth,
+    ) -> poem::Result<EventStream<BoxStream<'static, Transfer>>> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        let transfers = self
+            .store
+            .clone()
+            .stream_transfers(plot_id, in_order.0)
+            .await
+            .expect("store ops shouldn't fail");
+        Ok(EventStream::new(transfers.map(Transfer::from).boxed())
+            .keep_alive(Duration::from_secs(30)))
+    }
+
+    /// Get the webhook incoming transfers are delivered to
+    #[oai(path = "/webhook", method = "get")]
+    async fn get_webhook(&self, auth: Auth) -> GetWebhookResult {
+        match self
+            .store
+            .get_webhook(auth.plot().plot_id)
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            Some(webhook) => GetWebhookResult::Ok(Json(WebhookUrl { url: webhook.url })),
+            None => GetWebhookResult::NotFound,
+        }
+    }
+
+    /// Deliver incoming transfers to a webhook instead of waiting for them to be taken
+    ///
+    /// Returns the base64 encoded secret, deliveries are signed with HMAC-SHA256
+    /// in the `X-Dftools-Signature` header. Replaces the existing webhook and secret
+    #[oai(path = "/webhook", method = "put")]
+    async fn set_webhook(
+        &self,
+        body: Json<WebhookUrl>,
+        auth: Auth,
+    ) -> poem::Result<SetWebhookResult> {
+        let plot_id = auth.require_scope(Scope::TrustWrite)?.plot_id;
+        let url = match Url::parse(&body.0.url) {
+            Ok(url) => url,
+            Err(err) => return Ok(SetWebhookResult::InvalidUrl(PlainText(err.to_string()))),
+        };
+        #[cfg(not(debug_assertions))]
+        if url.scheme() != "https" {
+            return Ok(SetWebhookResult::InvalidUrl(PlainText(
+                "Must be https".to_string(),
+            )));
+        }
+        let secret = self
+            .store
+            .set_webhook(plot_id, url.as_str())
+            .await
+            .expect("store ops shouldn't fail");
+        Ok(SetWebhookResult::Ok(PlainText(secret)))
+    }
+
+    /// Stop delivering transfers to the webhook
+    #[oai(path = "/webhook", method = "delete")]
+    async fn delete_webhook(&self, auth: Auth) -> poem::Result<DeleteWebhookResult> {
+        let plot_id = auth.require_scope(Scope::TrustWrite)?.plot_id;
+        Ok(
+            if self
+                .store
+                .delete_webhook(plot_id)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                DeleteWebhookResult::Ok
+            } else {
+                DeleteWebhookResult::NotFound
+            },
+        )
+    }
+
+    /// Reply to a transfer this plot received, the reply goes to the plot that sent it
+    ///
+    /// `reply_to` is the id of the received transfer, each transfer can be replied to once
+    #[oai(path = "/transfer/reply", method = "post")]
+    async fn reply(
+        &self,
+        reply_to: Query<Uuid>,
+        payload: Json<TransferPayload>,
+        auth: Auth,
+    ) -> poem::Result<SendReplyResult> {
+        let plot = auth.require(Ability::SendTransfers)?;
+        let size = match payload
+            .0
+            .check(self.max_transfer_bytes, &self.dfjson_limits)
+        {
+            Ok(size) => size,
+            Err(err) => return Ok(err.into()),
         };
-        // let str: Option<String> = found.instance.into();
-        SetTransferResult::Ok
+        let from = plot.plot_id;
+        if let Err(err) = self
+            .store
+            .consume_transfer_quota(from, 1, size as u64)
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            return Ok(SendReplyResult::RateLimited(
+                PlainText(err.to_string()),
+                err.retry_after,
+            ));
+        }
+        Ok(
+            match self
+                .store
+                .send_reply(from, reply_to.0, payload.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Ok(id) => SendReplyResult::Ok(Json(id)),
+                Err(ReplyError::RequestNotFound) => SendReplyResult::RequestNotFound,
+                Err(ReplyError::AlreadyReplied) => SendReplyResult::AlreadyReplied,
+            },
+        )
+    }
+
+    /// Wait for the reply to a transfer this plot sent
+    ///
+    /// `correlation` is the id of the sent transfer, `timeout` is in seconds
+    #[oai(path = "/reply", method = "get")]
+    async fn wait_reply(
+        &self,
+        correlation: Query<Uuid>,
+        #[oai(default = "default_reply_timeout", validator(maximum(value = "30")))] timeout: Query<
+            u64,
+        >,
+        auth: Auth,
+    ) -> poem::Result<WaitReplyResult> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        Ok(
+            match self
+                .store
+                .wait_reply(plot_id, correlation.0, Duration::from_secs(timeout.0))
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Ok(Some(reply)) => WaitReplyResult::Ok(Json(reply.into())),
+                Ok(None) => WaitReplyResult::NoReply,
+                Err(_) => WaitReplyResult::RequestNotFound,
+            },
+        )
+    }
+
+    /// Create a channel owned by this plot
+    ///
+    /// The owner and every plot it trusts can publish and subscribe
+    #[oai(path = "/channel/:name", method = "put")]
+    async fn create_channel(
+        &self,
+        name: Path<String>,
+        auth: Auth,
+    ) -> poem::Result<CreateChannelResult> {
+        let plot_id = auth.require_scope(Scope::TrustWrite)?.plot_id;
+        if let Err(err) = check_channel_name(&name.0) {
+            return Ok(CreateChannelResult::InvalidName(PlainText(err)));
+        }
+        Ok(
+            if self
+                .store
+                .create_channel(plot_id, &name.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                CreateChannelResult::Created
+            } else {
+                CreateChannelResult::NameTaken
+            },
+        )
+    }
+
+    /// Delete a channel owned by this plot
+    #[oai(path = "/channel/:name", method = "delete")]
+    async fn delete_channel(
+        &self,
+        name: Path<String>,
+        auth: Auth,
+    ) -> poem::Result<DeleteChannelResult> {
+        let plot_id = auth.require_scope(Scope::TrustWrite)?.plot_id;
+        Ok(
+            if self
+                .store
+                .delete_channel(plot_id, &name.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                DeleteChannelResult::Deleted
+            } else {
+                DeleteChannelResult::NotFound
+            },
+        )
+    }
+
+    /// Publish a message to everyone subscribed to a channel
+    #[oai(path = "/channel/:name/publish", method = "post")]
+    async fn publish_channel(
+        &self,
+        name: Path<String>,
+        data: Json<DfJson>,
+        auth: Auth,
+    ) -> poem::Result<PublishChannelResult> {
+        let from = auth.require_scope(Scope::BatonSend)?.plot_id;
+        match self.channel_access(&name.0, from).await {
+            ChannelAccess::Allowed => {}
+            ChannelAccess::NotFound => return Ok(PublishChannelResult::NotFound),
+            ChannelAccess::NotTrusted => return Ok(PublishChannelResult::NotTrusted),
+        }
+        if let Err(err) = data.0.validate(&self.dfjson_limits) {
+            return Ok(PublishChannelResult::InvalidDfJson(Json(err.into())));
+        }
+        let size = serde_json::to_vec(&data.0)
+            .expect("DfJson should serialize")
+            .len();
+        if size > self.max_transfer_bytes {
+            return Ok(PublishChannelResult::PayloadTooLarge(PlainText(
+                PayloadError::TooLarge {
+                    size,
+                    limit: self.max_transfer_bytes,
+                }
+                .to_string(),
+            )));
+        }
+        if let Err(err) = self
+            .store
+            .consume_transfer_quota(from, 1, size as u64)
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            return Ok(PublishChannelResult::RateLimited(
+                PlainText(err.to_string()),
+                err.retry_after,
+            ));
+        }
+        let received = self
+            .store
+            .publish_channel(
+                &name.0,
+                &ChannelMessage {
+                    from,
+                    data: Box::new(data.0),
+                },
+            )
+            .await
+            .expect("store ops shouldn't fail");
+        Ok(PublishChannelResult::Ok(Json(received)))
+    }
+
+    /// Stream messages published to a channel as server sent events
+    #[oai(path = "/channel/:name/stream", method = "get")]
+    async fn subscribe_channel(
+        &self,
+        name: Path<String>,
+        auth: Auth,
+    ) -> poem::Result<SubscribeChannelResult> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        match self.channel_access(&name.0, plot_id).await {
+            ChannelAccess::Allowed => {}
+            ChannelAccess::NotFound => return Ok(SubscribeChannelResult::NotFound),
+            ChannelAccess::NotTrusted => return Ok(SubscribeChannelResult::NotTrusted),
+        }
+        let messages = self
+            .store
+            .subscribe_channel(&name.0)
+            .await
+            .expect("store ops shouldn't fail");
+        Ok(SubscribeChannelResult::Ok(
+            EventStream::new(messages).keep_alive(Duration::from_secs(30)),
+        ))
+    }
+
+    /// Transfers this plot sent or received, newest first
+    ///
+    /// `peer` only keeps transfers to or from that plot
+    #[oai(path = "/history", method = "get")]
+    async fn history(
+        &self,
+        direction: Query<Option<HistoryDirection>>,
+        peer: Query<Option<PlotId>>,
+        status: Query<Option<TransferStatus>>,
+        #[oai(default = "default_history_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        #[oai(default)] offset: Query<u32>,
+        auth: Auth,
+    ) -> poem::Result<Json<Vec<HistoryEntryResponse>>> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        let entries = self
+            .store
+            .transfer_history(
+                plot_id,
+                HistoryFilter {
+                    direction: direction.0,
+                    peer: peer.0,
+                    status: status.0,
+                    limit: limit.0 as i64,
+                    offset: offset.0 as i64,
+                },
+            )
+            .await
+            .expect("store ops shouldn't fail");
+        Ok(Json(
+            entries
+                .into_iter()
+                .map(HistoryEntryResponse::from)
+                .collect(),
+        ))
+    }
+
+    /// Look at the oldest pending transfer for this plot without taking it
+    #[oai(path = "/transfer/peek", method = "get")]
+    async fn peek_transfer(&self, auth: Auth) -> poem::Result<TakeTransferResult> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        Ok(
+            match self
+                .store
+                .peek_transfer(plot_id)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Some(transfer) => TakeTransferResult::Ok(Json(transfer.into())),
+                None => TakeTransferResult::NoTransfer,
+            },
+        )
+    }
+
+    /// Acknowledge a transfer this plot has taken
+    #[oai(path = "/transfer/:id/ack", method = "post")]
+    async fn ack_transfer(&self, id: Path<Uuid>, auth: Auth) -> poem::Result<AckTransferResult> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        Ok(
+            match self
+                .store
+                .ack_transfer(plot_id, id.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Ok(()) => AckTransferResult::Ok,
+                Err(TransferAckError::NotFound) => AckTransferResult::NotFound,
+                Err(TransferAckError::NotDelivered) => AckTransferResult::NotDelivered,
+            },
+        )
+    }
+
+    /// Get the status of a transfer this plot sent or received
+    ///
+    /// Transfers to plots on other instances are relayed or failed once they leave this instance
+    #[oai(path = "/transfer/status", method = "get")]
+    async fn transfer_status(
+        &self,
+        id: Query<Uuid>,
+        auth: Auth,
+    ) -> poem::Result<TransferStatusResult> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        if let Some(record) = self
+            .store
+            .get_transfer_record(id.0)
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            if record.from != plot_id && record.to != plot_id {
+                return Ok(TransferStatusResult::NotFound);
+            }
+            return Ok(TransferStatusResult::Ok(Json(TransferStatusResponse {
+                id: id.0,
+                from: record.from,
+                to: record.to,
+                status: record.status,
+            })));
+        }
+        Ok(
+            match self
+                .store
+                .get_relay(id.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Some(job) if job.from == plot_id => {
+                    TransferStatusResult::Ok(Json(TransferStatusResponse {
+                        id: id.0,
+                        from: job.from,
+                        to: job.to,
+                        status: job.state.into(),
+                    }))
+                }
+                _ => TransferStatusResult::NotFound,
+            },
+        )
+    }
+
+    /// Get the progress of relaying a transfer to another instance
+    #[oai(path = "/transfer/relay", method = "get")]
+    async fn relay_status(&self, id: Query<Uuid>, auth: Auth) -> poem::Result<RelayStatusResult> {
+        let plot_id = auth.require_scope(Scope::BatonRead)?.plot_id;
+        Ok(
+            match self
+                .store
+                .get_relay(id.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Some(job) if job.from == plot_id => {
+                    RelayStatusResult::Ok(Json(RelayStatusResponse {
+                        id: job.id,
+                        to: job.to,
+                        domain: job.domain.inner().as_inner().clone(),
+                        state: job.state,
+                        attempts: job.attempts,
+                        last_error: job.last_error,
+                        next_attempt: job.next_attempt,
+                    }))
+                }
+                _ => RelayStatusResult::NotFound,
+            },
+        )
     }
 
     /*
@@ -90,64 +921,1205 @@ impl BatonApi {
         */
 
     /// [EXT] Set transfer to a plot managed by this instance
+    ///
+    /// `sent_at` is when the sending plot sent it, unix timestamp in seconds.
+    /// `trusted_back` is whether the sending plot trusts the receiving plot
     #[oai(path = "/send/transfer", method = "post")]
+    #[allow(clippy::too_many_arguments)]
     async fn transfer_recv(
         &self,
         from_plot_id: Query<PlotId>,
         to_plot_id: Query<PlotId>,
-        payload: Json<DfJson>,
+        sent_at: Query<Option<u64>>,
+        #[oai(default)] trusted_back: Query<bool>,
+        /// A [TransferPayload], DfJson from older versions gets upgraded
+        payload: Json<serde_json::Value>,
+        /// Retrying with the same key returns the original result instead of queueing again
+        #[oai(name = "Idempotency-Key", validator(max_length = 255))]
+        idempotency_key: Header<Option<String>>,
+        /// Base64 encoded ed25519 signature of the payload by the sending instance
+        #[oai(name = "X-Transfer-Signature")]
+        signature: Header<String>,
         auth: ExternalServerAuth,
     ) -> TransferSendResult {
-        let auth = auth
-            .0
+        let raw = payload.0;
+        let payload = match TransferPayload::deserialize(&raw) {
+            Ok(payload) => payload,
+            Err(err) => return PayloadError::Malformed(err.to_string()).into(),
+        };
+        let size = match payload.check(self.max_transfer_bytes, &self.dfjson_limits) {
+            Ok(size) => size,
+            Err(err) => return err.into(),
+        };
+        let auth = auth.request();
+        let instance: Instance = auth
             .sub
             .parse()
             .expect("Server should create good send instances");
-        let trust = self
+        let signature = match BASE64
+            .decode(&signature.0)
+            .ok()
+            .and_then(|sig| Signature::from_slice(&sig).ok())
+        {
+            Some(sig) => sig,
+            None => return TransferSendResult::InvalidSignature,
+        };
+        let msg = signing_message(from_plot_id.0, to_plot_id.0, &auth.nonce, &raw);
+        if instance.key.verify_strict(&msg, &signature).is_err() {
+            return TransferSendResult::InvalidSignature;
+        }
+        let InstanceDomain::External(domain) = &instance.domain else {
+            return TransferSendResult::NotTrusted;
+        };
+        if !self
             .store
-            .fetch_plot_trust(to_plot_id.0)
+            .federation_allows(domain.inner().as_inner(), &instance.key)
+        {
+            return TransferSendResult::NotAllowed;
+        }
+        // Instances that got a server token without being registered are treated as open
+        let tier = self
+            .store
+            .instance_tier(domain.inner().as_inner())
             .await
-            .expect("store ops shouldn't fail");
-
+            .expect("store ops shouldn't fail")
+            .unwrap_or(InstanceTier::Open);
         let from = from_plot_id.0;
-        if !trust.contains(&from) {
-            return TransferSendResult::NotTrusted;
-        }
-        let plot = self
+        // A plot registered here can only be sent from by the instance it is registered to
+        if self
             .store
             .get_plot(from)
             .await
             .expect("Store ops shouldn't fail")
-            .expect("Trust contains from");
-        // plot.instance
-        if auth != plot.instance {
+            .is_some_and(|plot| plot.instance != instance)
+        {
             return TransferSendResult::NotTrusted;
         }
-
-        self.store
-            .set_transfer(from, payload.0)
+        let trusted = self
+            .store
+            .fetch_plot_trust(to_plot_id.0)
             .await
-            .expect("store ops shouldn't fail");
-        TransferSendResult::Ok
-    }
-}
+            .expect("store ops shouldn't fail")
+            .contains(&from)
+            || tier.allows_instance_trust()
+                && self
+                    .store
+                    .fetch_instance_trust(to_plot_id.0)
+                    .await
+                    .expect("store ops shouldn't fail")
+                    .contains(&instance.key);
+        if !trusted {
+            return TransferSendResult::NotTrusted;
+        }
+        if !trusted_back.0
+            && (tier.requires_mutual_trust()
+                || self
+                    .store
+                    .get_baton_settings(to_plot_id.0)
+                    .await
+                    .expect("store ops shouldn't fail")
+                    .mutual_trust)
+        {
+            return TransferSendResult::NotTrusted;
+        }
+
+        let key = idempotency_key
+            .0
+            .map(|key| IdempotencyKey::new("recv", from, &key));
+        if let Some(key) = &key {
+            match self
+                .store
+                .claim_idempotency_key(key)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                IdempotencyClaim::Claimed => {}
+                IdempotencyClaim::InProgress => return TransferSendResult::InProgress,
+                IdempotencyClaim::Done(id) => return TransferSendResult::Ok(Json(id)),
+            }
+        }
+        // Only charged once the key is claimed, so a retried relay doesn't count twice
+        if let Err(err) = self
+            .store
+            .consume_scaled_transfer_quota(from, 1, size as u64, tier.quota_factor())
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            if let Some(key) = &key {
+                self.store
+                    .finish_idempotency_key::<Uuid>(key, None)
+                    .await
+                    .expect("store ops shouldn't fail");
+            }
+            return TransferSendResult::RateLimited(PlainText(err.to_string()), err.retry_after);
+        }
+        let queued = self
+            .store
+            .enqueue_transfer(
+                from,
+                to_plot_id.0,
+                payload,
+                Origin {
+                    domain: auth.sub.domain.clone(),
+                    key: instance.key,
+                    sent_at: sent_at.0.unwrap_or_else(unix_now),
+                },
+            )
+            .await
+            .expect("store ops shouldn't fail");
+        if let Some(key) = &key {
+            self.store
+                .finish_idempotency_key(key, queued.as_ref().ok())
+                .await
+                .expect("store ops shouldn't fail");
+        }
+        match queued {
+            Ok(id) => TransferSendResult::Ok(Json(id)),
+            Err(TransferQueueError::QueueFull) => TransferSendResult::QueueFull,
+        }
+    }
+}
+
+#[derive(Serialize, Object)]
+pub struct Transfer {
+    /// Used to acknowledge the transfer
+    pub id: Uuid,
+    /// Plot that sent the transfer
+    pub from: PlotId,
+    /// Counts up per sender, starting at 1, 0 for replies
+    pub seq: u64,
+    /// Missing for transfers queued before origins were tracked
+    pub origin: Option<TransferOrigin>,
+    pub payload: TransferPayload,
+    /// SHA-256 of the canonical JSON of the payload, hex encoded.
+    /// Equal payloads have equal hashes
+    pub hash: String,
+    /// Seconds until the transfer expires
+    pub ttl: u64,
+}
+
+impl From<QueuedTransfer> for Transfer {
+    fn from(value: QueuedTransfer) -> Self {
+        Self {
+            id: value.id,
+            from: value.from,
+            seq: value.seq,
+            origin: value.origin.map(|origin| TransferOrigin {
+                domain: origin.domain,
+                key_fingerprint: key_fingerprint(&origin.key),
+                sent_at: origin.sent_at,
+                received_at: value.received_at,
+            }),
+            hash: value.payload.hash(),
+            payload: value.payload,
+            ttl: value.expires_at.saturating_sub(unix_now()),
+        }
+    }
+}
+
+/// Where a transfer comes from, the sending plot is `from` of the transfer
+#[derive(Serialize, Object)]
+pub struct TransferOrigin {
+    /// Domain of the instance the sending plot is registered to
+    pub domain: String,
+    /// First 16 bytes of the SHA-256 of that instance's key, hex encoded
+    pub key_fingerprint: String,
+    /// When the sending plot sent it, unix timestamp in seconds
+    pub sent_at: u64,
+    /// When this instance received it, unix timestamp in seconds
+    pub received_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TransferStatus {
+    /// Held until its delivery time
+    Scheduled,
+    /// Waiting to be taken by the destination plot
+    Queued,
+    /// Taken by the destination plot
+    Delivered,
+    /// Acknowledged by the destination plot
+    Acked,
+    /// Never taken before it expired
+    Expired,
+    /// Accepted by the instance the destination plot is registered to
+    Relayed,
+    /// Rejected by the instance the destination plot is registered to, or it was unreachable.
+    /// Scheduled transfers fail if the queue of the destination plot is full when they are due
+    Failed,
+}
+
+impl From<RelayState> for TransferStatus {
+    fn from(value: RelayState) -> Self {
+        match value {
+            RelayState::Pending => TransferStatus::Queued,
+            RelayState::Relayed => TransferStatus::Relayed,
+            RelayState::Failed => TransferStatus::Failed,
+        }
+    }
+}
+
+#[derive(Object)]
+pub struct TransferStatusResponse {
+    pub id: Uuid,
+    pub from: PlotId,
+    pub to: PlotId,
+    pub status: TransferStatus,
+}
+
+#[derive(Object)]
+pub struct RelayStatusResponse {
+    pub id: Uuid,
+    pub to: PlotId,
+    /// Domain of the instance the destination plot is registered to
+    pub domain: String,
+    pub state: RelayState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Unix timestamp in seconds, only meaningful while pending
+    pub next_attempt: u64,
+}
+
+/// A transfer payload tagged with its kind
+#[derive(Serialize, Deserialize, Union, ToRedisArgs, FromRedisValue, Clone)]
+#[oai(discriminator_name = "kind", rename_all = "snake_case")]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum TransferPayload {
+    Dfjson(DfJsonPayload),
+    OpaqueBase64(OpaqueBase64Payload),
+    Text(TextPayload),
+}
+
+#[derive(Serialize, Object, Clone)]
+pub struct DfJsonPayload {
+    /// Schema version of `data`, see `/instance/v0/schema/dfjson`. Always the current version once parsed
+    #[oai(default = "current_schema_version")]
+    #[serde(serialize_with = "serialize_current_schema_version")]
+    pub schema: u32,
+    pub data: Box<DfJson>,
+}
+
+/// Stored and relayed payloads go through here, `data` gets upgraded before parsing.
+/// Payloads without a version are from before versioning, which is version 1
+impl<'de> Deserialize<'de> for DfJsonPayload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            #[serde(default)]
+            schema: Option<u32>,
+            data: serde_json::Value,
+        }
+        let Versioned { schema, mut data } = Versioned::deserialize(deserializer)?;
+        dfjson::migrate(&mut data, schema.unwrap_or(1)).map_err(serde::de::Error::custom)?;
+        Ok(Self {
+            schema: dfjson::SCHEMA_VERSION,
+            data: serde_json::from_value(data).map_err(serde::de::Error::custom)?,
+        })
+    }
+}
+
+fn current_schema_version() -> u32 {
+    dfjson::SCHEMA_VERSION
+}
+
+/// Parsed data is always in the current version, whatever version it was sent with
+fn serialize_current_schema_version<S: serde::Serializer>(
+    _: &u32,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u32(dfjson::SCHEMA_VERSION)
+}
+#[derive(Serialize, Deserialize, Object, Clone)]
+pub struct OpaqueBase64Payload {
+    /// Base64 (url safe) encoded bytes
+    pub data: String,
+}
+#[derive(Serialize, Deserialize, Object, Clone)]
+pub struct TextPayload {
+    pub data: String,
+}
+
+/// Bytes signed by the sending instance when relaying, the payload is in canonical JSON form.
+/// The request nonce is part of it so a captured request can't be sent again with a new nonce.
+/// Takes the payload as it was sent so receivers can check it before upgrading it
+pub fn signing_message(
+    from: PlotId,
+    to: PlotId,
+    nonce: &str,
+    payload: &serde_json::Value,
+) -> Vec<u8> {
+    let mut msg = format!("DFTOOLS TRANSFER {} {} {}\n", from, to, nonce).into_bytes();
+    msg.extend(dfjson::canonical_json(payload));
+    msg
+}
+
+/// Which kind of payload a [TransferPayload] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadKind {
+    Dfjson,
+    OpaqueBase64,
+    Text,
+}
+
+impl PayloadKind {
+    /// Maximum size of a payload of this kind in bytes, never above `max_transfer_bytes`
+    pub fn max_size(self, max_transfer_bytes: usize) -> usize {
+        match self {
+            PayloadKind::Dfjson => max_transfer_bytes,
+            PayloadKind::OpaqueBase64 => max_transfer_bytes.min(32 * 1024),
+            PayloadKind::Text => max_transfer_bytes.min(16 * 1024),
+        }
+    }
+}
+
+impl TransferPayload {
+    pub fn kind(&self) -> PayloadKind {
+        match self {
+            TransferPayload::Dfjson(_) => PayloadKind::Dfjson,
+            TransferPayload::OpaqueBase64(_) => PayloadKind::OpaqueBase64,
+            TransferPayload::Text(_) => PayloadKind::Text,
+        }
+    }
+    /// Bytes signed by the sending instance when relaying, see [signing_message]
+    pub fn signing_message(&self, from: PlotId, to: PlotId, nonce: &str) -> Vec<u8> {
+        signing_message(
+            from,
+            to,
+            nonce,
+            &serde_json::to_value(self).expect("Payload should serialize"),
+        )
+    }
+
+    /// Canonical JSON of the payload, see [dfjson::canonical_json]
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        dfjson::canonical_json(&serde_json::to_value(self).expect("Payload should serialize"))
+    }
+
+    /// SHA-256 of the canonical JSON of the payload, hex encoded
+    pub fn hash(&self) -> String {
+        dfjson::hex_digest(&self.canonical_bytes())
+    }
+
+    /// Checks that the payload is well formed and within the limits of its kind, returns its size
+    pub fn check(
+        &self,
+        max_transfer_bytes: usize,
+        dfjson_limits: &DfJsonLimits,
+    ) -> Result<usize, PayloadError> {
+        let size = match self {
+            TransferPayload::Dfjson(it) => {
+                it.data.validate(dfjson_limits)?;
+                serde_json::to_vec(&it.data)
+                    .expect("DfJson should serialize")
+                    .len()
+            }
+            TransferPayload::OpaqueBase64(it) => BASE64
+                .decode(&it.data)
+                .map_err(|err| PayloadError::Malformed(err.to_string()))?
+                .len(),
+            TransferPayload::Text(it) => it.data.len(),
+        };
+        let limit = self.kind().max_size(max_transfer_bytes);
+        if size > limit {
+            return Err(PayloadError::TooLarge { size, limit });
+        }
+        Ok(size)
+    }
+}
+
+/// Why a DfJson value was rejected
+#[derive(Object)]
+pub struct InvalidDfJson {
+    pub message: String,
+    /// Offending values, like `$.players[3]`
+    pub paths: Vec<String>,
+}
+
+impl From<DfJsonViolation> for InvalidDfJson {
+    fn from(value: DfJsonViolation) -> Self {
+        Self {
+            message: value.to_string(),
+            paths: value.paths(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PayloadError {
+    #[error("Malformed payload: {0}")]
+    Malformed(String),
+    #[error("Payload is {size} bytes, the limit for this kind is {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+    #[error(transparent)]
+    InvalidDfJson(#[from] DfJsonViolation),
+}
+
+fn default_reply_timeout() -> u64 {
+    10
+}
+
+fn default_history_limit() -> u32 {
+    50
+}
+
+/// Lowercase segments of letters, digits, `-` and `_` separated by `:`, like `network:chat`
+fn check_channel_name(name: &str) -> Result<(), String> {
+    /// Longest channel name allowed
+    const MAX_LEN: usize = 64;
+    if name.len() > MAX_LEN {
+        return Err(format!("Channel names are at most {} bytes", MAX_LEN));
+    }
+    let valid = name.split(':').all(|segment| {
+        !segment.is_empty()
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+    });
+    if !valid {
+        return Err(
+            "Channel names are lowercase segments of a-z, 0-9, - and _ separated by :".to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Transfers can be scheduled at most this far ahead
+pub const MAX_DELIVERY_DELAY: u64 = 60 * 60 * 24;
+/// Most plots a single broadcast can go to
+pub const MAX_BROADCAST_DESTINATIONS: usize = 64;
+
+fn check_deliver_at(deliver_at: Option<u64>) -> Result<(), String> {
+    match deliver_at {
+        Some(at) if at > unix_now() + MAX_DELIVERY_DELAY => Err(format!(
+            "deliver_at can be at most {} seconds in the future",
+            MAX_DELIVERY_DELAY
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl<S: BatonStore> BatonApi<S> {
+    /// Queues, schedules or relays a transfer that is already checked
+    async fn send(
+        &self,
+        from: PlotId,
+        dest: PlotId,
+        payload: TransferPayload,
+        deliver_at: Option<u64>,
+    ) -> SendOutcome {
+        let found = if let Some(it) = self
+            .store
+            .get_plot(dest)
+            .await
+            .expect("Get plot shouldn't fail")
+        {
+            it
+        } else {
+            return SendOutcome::PlotNotFound;
+        };
+        let trusted_back = self
+            .store
+            .fetch_plot_trust(from)
+            .await
+            .expect("store ops shouldn't fail")
+            .contains(&dest);
+        if let InstanceDomain::External(domain) = found.instance.domain {
+            if self
+                .store
+                .instance_down_since(domain.inner().as_inner())
+                .await
+                .expect("store ops shouldn't fail")
+                .is_some()
+            {
+                return SendOutcome::InstanceDown;
+            }
+            // Trust gets checked by the instance the plot is registered to
+            let id = self
+                .store
+                .queue_relay(
+                    from,
+                    dest,
+                    domain,
+                    payload.clone(),
+                    deliver_at,
+                    trusted_back,
+                )
+                .await
+                .expect("store ops shouldn't fail");
+            self.store
+                .remember_sent_payload(from, dest, &payload)
+                .await
+                .expect("store ops shouldn't fail");
+            return SendOutcome::Sent(id);
+        }
+        let trust = self
+            .store
+            .fetch_plot_trust(dest)
+            .await
+            .expect("store ops shouldn't fail");
+        if !trust.contains(&from) {
+            return SendOutcome::NotTrusted;
+        }
+        if !trusted_back
+            && self
+                .store
+                .get_baton_settings(dest)
+                .await
+                .expect("store ops shouldn't fail")
+                .mutual_trust
+        {
+            return SendOutcome::NotTrusted;
+        }
+
+        let outcome = if let Some(deliver_at) = deliver_at {
+            let id = self
+                .store
+                .schedule_transfer(from, dest, payload.clone(), deliver_at)
+                .await
+                .expect("store ops shouldn't fail");
+            SendOutcome::Sent(id)
+        } else {
+            match self
+                .store
+                .enqueue_transfer(
+                    from,
+                    dest,
+                    payload.clone(),
+                    self.store.local_origin(unix_now()),
+                )
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Ok(id) => SendOutcome::Sent(id),
+                Err(TransferQueueError::QueueFull) => SendOutcome::QueueFull,
+            }
+        };
+        // A patch applies to what the destination got, not to a send that was refused
+        if let SendOutcome::Sent(_) = outcome {
+            self.store
+                .remember_sent_payload(from, dest, &payload)
+                .await
+                .expect("store ops shouldn't fail");
+        }
+        outcome
+    }
+}
+
+impl<S: BatonStore> BatonApi<S> {
+    async fn send_transfer(
+        &self,
+        from: PlotId,
+        dest: PlotId,
+        deliver_at: Option<u64>,
+        body: TransferBody,
+        idempotency_key: Option<String>,
+    ) -> SetTransferResult {
+        if let Err(err) = check_deliver_at(deliver_at) {
+            return SetTransferResult::InvalidDeliverAt(PlainText(err));
+        }
+        let key = idempotency_key.map(|key| IdempotencyKey::new("transfer", from, &key));
+        if let Some(key) = &key {
+            match self
+                .store
+                .claim_idempotency_key(key)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                IdempotencyClaim::Claimed => {}
+                IdempotencyClaim::InProgress => return SetTransferResult::InProgress,
+                IdempotencyClaim::Done(id) => return SetTransferResult::Ok(Json(id)),
+            }
+        }
+        let result = self
+            .send_claimed_transfer(from, dest, deliver_at, body)
+            .await;
+        if let Some(key) = &key {
+            // Failures aren't remembered so they can be retried
+            let sent = match &result {
+                SetTransferResult::Ok(id) => Some(&id.0),
+                _ => None,
+            };
+            self.store
+                .finish_idempotency_key(key, sent)
+                .await
+                .expect("store ops shouldn't fail");
+        }
+        result
+    }
+
+    /// Patches are only applied once the idempotency key is claimed, a retry doesn't apply it twice
+    async fn send_claimed_transfer(
+        &self,
+        from: PlotId,
+        dest: PlotId,
+        deliver_at: Option<u64>,
+        body: TransferBody,
+    ) -> SetTransferResult {
+        let payload = match body {
+            TransferBody::Full(payload) => payload,
+            TransferBody::Patch(patch) => match self
+                .store
+                .patch_sent_payload(from, dest, &patch)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Ok(payload) => payload,
+                Err(PatchError::NoBase) => return SetTransferResult::NoPatchBase,
+                Err(err) => return SetTransferResult::PatchFailed(PlainText(err.to_string())),
+            },
+        };
+        let size = match payload.check(self.max_transfer_bytes, &self.dfjson_limits) {
+            Ok(size) => size,
+            Err(err) => return err.into(),
+        };
+        if let Err(err) = self
+            .store
+            .consume_transfer_quota(from, 1, size as u64)
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            return SetTransferResult::RateLimited(PlainText(err.to_string()), err.retry_after);
+        }
+        match self.send(from, dest, payload, deliver_at).await {
+            SendOutcome::Sent(id) => SetTransferResult::Ok(Json(id)),
+            SendOutcome::PlotNotFound => SetTransferResult::PlotNotFound,
+            SendOutcome::NotTrusted => SetTransferResult::NotTrusted,
+            SendOutcome::QueueFull => SetTransferResult::QueueFull,
+            SendOutcome::InstanceDown => SetTransferResult::InstanceDown,
+        }
+    }
+
+    async fn channel_access(&self, name: &str, plot_id: PlotId) -> ChannelAccess {
+        let Some(owner) = self
+            .store
+            .get_channel_owner(name)
+            .await
+            .expect("store ops shouldn't fail")
+        else {
+            return ChannelAccess::NotFound;
+        };
+        if self
+            .store
+            .is_trusted_by(owner, plot_id)
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            ChannelAccess::Allowed
+        } else {
+            ChannelAccess::NotTrusted
+        }
+    }
+}
+
+enum ChannelAccess {
+    Allowed,
+    NotFound,
+    NotTrusted,
+}
+
+enum TransferBody {
+    Full(TransferPayload),
+    Patch(Vec<json_patch::PatchOperation>),
+}
+
+/// RFC 6902 operation
+#[derive(Serialize, Object)]
+pub struct PatchOperation {
+    pub op: PatchOp,
+    /// JSON pointer into the payload
+    pub path: String,
+    /// Value for `add`, `replace` and `test`
+    #[oai(skip_serializing_if_is_none)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// Source pointer for `move` and `copy`
+    #[oai(skip_serializing_if_is_none)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PatchOp {
+    Add,
+    Remove,
+    Replace,
+    Move,
+    Copy,
+    Test,
+}
+
+enum SendOutcome {
+    Sent(Uuid),
+    PlotNotFound,
+    NotTrusted,
+    QueueFull,
+    InstanceDown,
+}
+
+#[derive(Object)]
+pub struct HistoryEntryResponse {
+    /// Transfer id, or relay id for transfers to other instances
+    pub id: Uuid,
+    pub from: PlotId,
+    pub to: PlotId,
+    pub kind: PayloadKind,
+    /// Size of the payload as JSON in bytes
+    pub size: u32,
+    pub status: TransferStatus,
+    /// Only kept if the instance has `TRANSFER_HISTORY_PAYLOADS` on
+    pub payload: Option<TransferPayload>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds of the last status change
+    pub updated_at: i64,
+}
+
+impl From<HistoryEntry> for HistoryEntryResponse {
+    fn from(value: HistoryEntry) -> Self {
+        Self {
+            id: value.id,
+            from: value.from,
+            to: value.to,
+            kind: value.kind,
+            size: value.size,
+            status: value.status,
+            payload: value.payload,
+            created_at: value.created_at,
+            updated_at: value.updated_at,
+        }
+    }
+}
+
+#[derive(Object)]
+pub struct QuotaResponse {
+    pub transfers_remaining: u32,
+    /// Seconds until the transfer count resets
+    pub transfers_reset: u64,
+    pub bytes_remaining: u64,
+    /// Seconds until the byte count resets
+    pub bytes_reset: u64,
+}
+
+#[derive(Object)]
+pub struct BroadcastRequest {
+    pub destinations: Vec<PlotId>,
+    pub payload: TransferPayload,
+    /// Unix timestamp in seconds, the transfers are held until then
+    pub deliver_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum BroadcastStatus {
+    Sent,
+    PlotNotFound,
+    NotTrusted,
+    QueueFull,
+    InstanceDown,
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct BroadcastOutcome {
+    pub status: BroadcastStatus,
+    /// Transfer id if it was sent
+    pub id: Option<Uuid>,
+}
+
+impl From<SendOutcome> for BroadcastOutcome {
+    fn from(value: SendOutcome) -> Self {
+        let (status, id) = match value {
+            SendOutcome::Sent(id) => (BroadcastStatus::Sent, Some(id)),
+            SendOutcome::PlotNotFound => (BroadcastStatus::PlotNotFound, None),
+            SendOutcome::NotTrusted => (BroadcastStatus::NotTrusted, None),
+            SendOutcome::QueueFull => (BroadcastStatus::QueueFull, None),
+            SendOutcome::InstanceDown => (BroadcastStatus::InstanceDown, None),
+        };
+        Self { status, id }
+    }
+}
+
+#[derive(ApiResponse)]
+enum BroadcastResult {
+    /// `deliver_at` is too far in the future
+    #[oai(status = 400)]
+    InvalidDeliverAt(PlainText<String>),
+    /// Rate limit or quota of the sending plot ran out
+    #[oai(status = 429)]
+    RateLimited(PlainText<String>, #[oai(header = "Retry-After")] u64),
+    /// A request with the same idempotency key is still being processed
+    #[oai(status = 409)]
+    InProgress,
+    /// Too many destinations
+    #[oai(status = 400)]
+    TooManyDestinations(PlainText<String>),
+    /// Payload is malformed
+    #[oai(status = 400)]
+    MalformedPayload(PlainText<String>),
+    /// Payload exceeds the size limit of its kind
+    #[oai(status = 413)]
+    PayloadTooLarge(PlainText<String>),
+    /// DfJson payload is nested too deep, has too many values or too long strings
+    #[oai(status = 422)]
+    InvalidDfJson(Json<InvalidDfJson>),
+    /// Ok, result of each destination
+    #[oai(status = 200)]
+    Ok(Json<HashMap<PlotId, BroadcastOutcome>>),
+}
+
+impl From<PayloadError> for BroadcastResult {
+    fn from(value: PayloadError) -> Self {
+        match value {
+            PayloadError::Malformed(_) => Self::MalformedPayload(PlainText(value.to_string())),
+            PayloadError::TooLarge { .. } => Self::PayloadTooLarge(PlainText(value.to_string())),
+            PayloadError::InvalidDfJson(err) => Self::InvalidDfJson(Json(err.into())),
+        }
+    }
+}
 
 #[derive(ApiResponse)]
 enum TransferSendResult {
+    /// Payload signature is missing or doesn't match the sending instance
+    #[oai(status = 400)]
+    InvalidSignature,
+    /// The instance operator doesn't federate with the sending instance
+    #[oai(status = 403)]
+    NotAllowed,
+    /// Rate limit or quota of the sending plot ran out, plots of partner instances get larger ones
+    #[oai(status = 429)]
+    RateLimited(PlainText<String>, #[oai(header = "Retry-After")] u64),
+    /// A request with the same idempotency key is still being processed
+    #[oai(status = 409)]
+    InProgress,
     #[oai(status = 409)]
     NotTrusted,
+    /// Payload is malformed
+    #[oai(status = 400)]
+    MalformedPayload(PlainText<String>),
+    /// Payload exceeds the size limit of its kind
+    #[oai(status = 413)]
+    PayloadTooLarge(PlainText<String>),
+    /// DfJson payload is nested too deep, has too many values or too long strings
+    #[oai(status = 422)]
+    InvalidDfJson(Json<InvalidDfJson>),
+    /// Transfer queue of the destination plot is full
+    #[oai(status = 429)]
+    QueueFull,
+    /// Ok, returns the transfer id
+    #[oai(status = 200)]
+    Ok(Json<Uuid>),
+}
+
+impl From<PayloadError> for TransferSendResult {
+    fn from(value: PayloadError) -> Self {
+        match value {
+            PayloadError::Malformed(_) => Self::MalformedPayload(PlainText(value.to_string())),
+            PayloadError::TooLarge { .. } => Self::PayloadTooLarge(PlainText(value.to_string())),
+            PayloadError::InvalidDfJson(err) => Self::InvalidDfJson(Json(err.into())),
+        }
+    }
+}
+
+#[derive(ApiResponse)]
+enum AckTransferResult {
+    /// Transfer not found
+    #[oai(status = 404)]
+    NotFound,
+    /// Transfer has not been taken yet or has expired
+    #[oai(status = 409)]
+    NotDelivered,
+    /// Ok
+    #[oai(status = 200)]
+    Ok,
+}
+
+#[derive(ApiResponse)]
+enum TransferStatusResult {
+    /// Transfer not found
+    #[oai(status = 404)]
+    NotFound,
+    /// Ok
+    #[oai(status = 200)]
+    Ok(Json<TransferStatusResponse>),
+}
+
+#[derive(Object)]
+pub struct WebhookUrl {
+    pub url: String,
+}
+
+#[derive(ApiResponse)]
+enum GetWebhookResult {
+    /// No webhook set
+    #[oai(status = 404)]
+    NotFound,
+    /// Ok
+    #[oai(status = 200)]
+    Ok(Json<WebhookUrl>),
+}
+
+#[derive(ApiResponse)]
+enum SetWebhookResult {
+    /// Invalid url
+    #[oai(status = 400)]
+    InvalidUrl(PlainText<String>),
+    /// Ok, returns the secret
+    #[oai(status = 200)]
+    Ok(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum DeleteWebhookResult {
+    /// No webhook set
+    #[oai(status = 404)]
+    NotFound,
+    /// Ok
     #[oai(status = 200)]
     Ok,
 }
 
+#[derive(ApiResponse)]
+enum RelayStatusResult {
+    /// Relay not found
+    #[oai(status = 404)]
+    NotFound,
+    /// Ok
+    #[oai(status = 200)]
+    Ok(Json<RelayStatusResponse>),
+}
+
+#[derive(ApiResponse)]
+enum SendReplyResult {
+    /// No transfer with this id was sent to this plot
+    #[oai(status = 404)]
+    RequestNotFound,
+    /// The transfer was already replied to
+    #[oai(status = 409)]
+    AlreadyReplied,
+    /// Payload is malformed
+    #[oai(status = 400)]
+    MalformedPayload(PlainText<String>),
+    /// Payload exceeds the size limit of its kind
+    #[oai(status = 413)]
+    PayloadTooLarge(PlainText<String>),
+    /// DfJson payload is nested too deep, has too many values or too long strings
+    #[oai(status = 422)]
+    InvalidDfJson(Json<InvalidDfJson>),
+    /// Rate limit or quota of the sending plot ran out
+    #[oai(status = 429)]
+    RateLimited(PlainText<String>, #[oai(header = "Retry-After")] u64),
+    /// Ok, returns the id of the reply
+    #[oai(status = 200)]
+    Ok(Json<Uuid>),
+}
+
+impl From<PayloadError> for SendReplyResult {
+    fn from(value: PayloadError) -> Self {
+        match value {
+            PayloadError::Malformed(_) => Self::MalformedPayload(PlainText(value.to_string())),
+            PayloadError::TooLarge { .. } => Self::PayloadTooLarge(PlainText(value.to_string())),
+            PayloadError::InvalidDfJson(err) => Self::InvalidDfJson(Json(err.into())),
+        }
+    }
+}
+
+#[derive(ApiResponse)]
+enum WaitReplyResult {
+    /// No transfer with this id was sent from this plot
+    #[oai(status = 404)]
+    RequestNotFound,
+    /// No reply arrived before the timeout
+    #[oai(status = 204)]
+    NoReply,
+    /// Ok
+    #[oai(status = 200)]
+    Ok(Json<Transfer>),
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct ChannelMessage {
+    /// Plot that published the message
+    pub from: PlotId,
+    pub data: Box<DfJson>,
+}
+
+#[derive(ApiResponse)]
+enum CreateChannelResult {
+    /// Invalid channel name
+    #[oai(status = 400)]
+    InvalidName(PlainText<String>),
+    /// Another channel has this name
+    #[oai(status = 409)]
+    NameTaken,
+    #[oai(status = 201)]
+    Created,
+}
+
+#[derive(ApiResponse)]
+enum DeleteChannelResult {
+    /// No channel with this name is owned by this plot
+    #[oai(status = 404)]
+    NotFound,
+    #[oai(status = 204)]
+    Deleted,
+}
+
+#[derive(ApiResponse)]
+enum PublishChannelResult {
+    /// Channel not found
+    #[oai(status = 404)]
+    NotFound,
+    /// This plot is not trusted by the owner of the channel
+    #[oai(status = 403)]
+    NotTrusted,
+    /// Message exceeds the size limit
+    #[oai(status = 413)]
+    PayloadTooLarge(PlainText<String>),
+    /// Message is nested too deep, has too many values or too long strings
+    #[oai(status = 422)]
+    InvalidDfJson(Json<InvalidDfJson>),
+    /// Rate limit or quota of the sending plot ran out
+    #[oai(status = 429)]
+    RateLimited(PlainText<String>, #[oai(header = "Retry-After")] u64),
+    /// Ok, returns how many subscribers got the message
+    #[oai(status = 200)]
+    Ok(Json<usize>),
+}
+
+#[derive(ApiResponse)]
+enum SubscribeChannelResult {
+    /// Channel not found
+    #[oai(status = 404)]
+    NotFound,
+    /// This plot is not trusted by the owner of the channel
+    #[oai(status = 403)]
+    NotTrusted,
+    #[oai(status = 200)]
+    Ok(EventStream<BoxStream<'static, ChannelMessage>>),
+}
+
+#[derive(ApiResponse)]
+enum TakeTransferResult {
+    /// No pending transfer
+    #[oai(status = 404)]
+    NoTransfer,
+    /// Ok
+    #[oai(status = 200)]
+    Ok(Json<Transfer>),
+}
+
 #[derive(ApiResponse)]
 enum SetTransferResult {
+    /// `deliver_at` is too far in the future
+    #[oai(status = 400)]
+    InvalidDeliverAt(PlainText<String>),
+    /// Rate limit or quota of the sending plot ran out
+    #[oai(status = 429)]
+    RateLimited(PlainText<String>, #[oai(header = "Retry-After")] u64),
+    /// A request with the same idempotency key is still being processed
+    #[oai(status = 409)]
+    InProgress,
     /// Plot not found
     #[oai(status = 404)]
     PlotNotFound,
-    /// Ok
+    /// Sender is not trusted by the destination plot
+    #[oai(status = 409)]
+    NotTrusted,
+    /// Payload is malformed
+    #[oai(status = 400)]
+    MalformedPayload(PlainText<String>),
+    /// Payload exceeds the size limit of its kind
+    #[oai(status = 413)]
+    PayloadTooLarge(PlainText<String>),
+    /// DfJson payload is nested too deep, has too many values or too long strings
+    #[oai(status = 422)]
+    InvalidDfJson(Json<InvalidDfJson>),
+    /// Transfer queue of the destination plot is full
+    #[oai(status = 429)]
+    QueueFull,
+    /// The instance the destination plot is registered to has been unreachable for hours
+    #[oai(status = 503)]
+    InstanceDown,
+    /// Patch operation is missing its `value` or `from`
+    #[oai(status = 400)]
+    MalformedPatch(PlainText<String>),
+    /// Nothing was sent to this plot in the last week to patch
+    #[oai(status = 409)]
+    NoPatchBase,
+    /// Patch can't be applied or the patched payload is malformed
+    #[oai(status = 422)]
+    PatchFailed(PlainText<String>),
+    /// Ok, returns the transfer id
     #[oai(status = 200)]
-    Ok,
+    Ok(Json<Uuid>),
+}
+
+impl From<PayloadError> for SetTransferResult {
+    fn from(value: PayloadError) -> Self {
+        match value {
+            PayloadError::Malformed(_) => Self::MalformedPayload(PlainText(value.to_string())),
+            PayloadError::TooLarge { .. } => Self::PayloadTooLarge(PlainText(value.to_string())),
+            PayloadError::InvalidDfJson(err) => Self::InvalidDfJson(Json(err.into())),
+        }
+    }
+}
+
+#[derive(ApiResponse)]
+enum SetTrustedInstancesResult {
+    #[oai(status = 404)]
+    PlotNotFound,
+    /// Invalid key format
+    #[oai(status = 400)]
+    InvalidKeyFormat(PlainText<String>),
+    /// Some instances are not registered on this instance.
+    /// Register these instances before trying again
+    #[oai(status = 409)]
+    InstanceNotRegistered(Json<Vec<String>>),
+    /// Some instances are `open`, their plots can only be trusted one by one
+    #[oai(status = 403)]
+    InstanceNotTrustable(Json<Vec<String>>),
+    #[oai(status = 200)]
+    Success,
+}
+
+#[derive(ApiResponse)]
+enum SetSettingsResult {
+    #[oai(status = 404)]
+    PlotNotFound,
+    #[oai(status = 200)]
+    Success,
+}
+
+#[derive(ApiResponse)]
+enum AddTrustedResult {
+    /// `expires_at` is in the past
+    #[oai(status = 400)]
+    AlreadyExpired,
+    #[oai(status = 404)]
+    PlotNotFound,
+    /// The plot to trust is not registered on this instance
+    #[oai(status = 409)]
+    OtherPlotNotRegistered,
+    #[oai(status = 201)]
+    Added,
+    #[oai(status = 200)]
+    AlreadyTrusted,
+}
+
+#[derive(ApiResponse)]
+enum RemoveTrustedResult {
+    #[oai(status = 204)]
+    Removed,
+    /// The plot wasn't trusted to begin with
+    #[oai(status = 404)]
+    NotTrusted,
 }
 
 #[derive(ApiResponse)]
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use poem::{
+    handler,
+    web::{Data, Json},
+};
+use serde::{Deserialize, Serialize};
+
+/// Served at `/.well-known/dftools`, tells other instances where the APIs of this one are
+/// so it can live behind a path prefix
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryDocument {
+    pub domain: String,
+    /// Base64 encoded instance key
+    pub key: String,
+    pub name: Option<String>,
+    pub contact: Option<String>,
+    /// dftools build version
+    pub version: String,
+    /// Base path and supported versions of each API, like `instance` and `baton`
+    pub apis: HashMap<String, DiscoveredApi>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveredApi {
+    /// Path the versions are under, like `/dftools/instance` for `/dftools/instance/v0`
+    pub base: String,
+    pub versions: Vec<String>,
+}
+
+impl DiscoveredApi {
+    /// Bases other instances would use to reach another host, like `@example.com`, are ignored
+    pub fn valid_base(&self) -> bool {
+        self.base.starts_with('/')
+            && self
+                .base
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'/' | b'-' | b'_' | b'.'))
+    }
+}
+
+#[handler]
+pub fn well_known(Data(document): Data<&DiscoveryDocument>) -> Json<DiscoveryDocument> {
+    Json(document.clone())
+}
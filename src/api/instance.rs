@@ -1,36 +1,166 @@
 use std::{
+    collections::HashMap,
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use ascii_domain::dom::Domain;
 use base64::Engine;
-use ed25519_dalek::VerifyingKey;
+use ed25519_dalek::{Signature, VerifyingKey};
+use poem::Request;
 use poem_openapi::{
-    param::Query,
+    param::{Header, Path, Query},
     payload::{Json, PlainText},
     ApiResponse, Object, OpenApi,
 };
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use uuid::Uuid;
 
 use crate::{
-    instance::{InstanceDomain, SendInstance},
+    allowlist::IpRange,
+    compress::ENCODINGS,
+    dfjson::{self, DfJson, DfJsonLimits},
+    instance::{ExternalDomain, Instance, InstanceDomain, SendInstance},
+    signature::check_challenge,
     store::{
-        instance::{PlotEditError, RegisterError},
+        activity::PlotActivity,
+        audit::{AuditEntry, AuditEvent, AuditRecord},
+        baton::{unix_now, Origin},
+        feed::FederationEvent,
+        handoff::{handoff_message, ImportError, PlotExport},
+        instance::{
+            InstanceHealthInfo, KnownInstanceInfo, PlotEditError, RegisterError, DNS_RECORD_PREFIX,
+        },
+        key::{
+            granted_scopes, key_prefix, scopes_allow, valid_scope, ApiKeyInfo, DisableKeyError,
+            Scope,
+        },
+        member::{Abilities, Ability, Member},
+        meta::PlotMeta,
+        session::{PendingLogin, LOGIN_CODE_TTL},
+        verify::{PendingRegistration, VERIFICATION_TTL},
         Store,
     },
-    BASE64,
+    template, BASE64,
 };
 
 use super::{
-    auth::{Auth, ExternalServer, PlotAuth, UnregisteredAuth},
-    PlotId,
+    auth::{
+        client_addr, Auth, DelegatedToken, ExternalServer, ExternalServerAuth, ForbiddenError,
+        OwnerSession, Plot, PlotAuth, PlotSize, SessionAuth, UnregisteredAuth,
+        DELEGATED_MAX_EXPIRY, JWT_EXPIRY, SESSION_EXPIRY,
+    },
+    decode_instance_key, key_fingerprint, PlotId,
 };
 
 pub struct InstanceApi {
     pub store: Arc<Store>,
     pub domain: Domain<String>,
+    pub started: Instant,
+    /// Optional subsystems this instance serves
+    pub subsystems: Vec<String>,
+    /// Seconds a rotated API key keeps working unless asked otherwise
+    pub key_rotation_grace: u64,
+    /// Display name shown to other instances
+    pub name: Option<String>,
+    /// How to reach the operator
+    pub contact: Option<String>,
+    /// Limits advertised at `/capabilities`
+    pub limits: CapabilityLimits,
+    /// DNS over HTTPS resolver checking the `_dftools` TXT record of registering instances,
+    /// they don't need one if it is None
+    pub dns_resolver: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct VersionResponse {
+    /// dftools build version
+    pub version: String,
+    /// Supported protocol versions of each API
+    pub protocols: HashMap<String, Vec<String>>,
+    /// Seconds since the instance started
+    pub uptime: u64,
+    pub subsystems: Vec<String>,
+    /// Content encodings accepted for request bodies
+    #[oai(default)]
+    #[serde(default)]
+    pub encodings: Vec<String>,
+}
+
+/// What an instance tells other instances about itself
+#[derive(Serialize, Deserialize, Object)]
+pub struct InstanceInfo {
+    /// Display name chosen by the operator
+    pub name: Option<String>,
+    /// How to reach the operator, like an email address or a Discord username
+    pub contact: Option<String>,
+    /// dftools build version
+    pub version: String,
+    /// Supported protocol versions of each API
+    pub apis: HashMap<String, Vec<String>>,
+}
+
+/// What an instance supports, so others can adapt to it instead of failing halfway
+#[derive(Serialize, Deserialize, Object)]
+pub struct CapabilitiesResponse {
+    /// Supported protocol versions of each API
+    pub apis: HashMap<String, Vec<String>>,
+    /// Optional features, like `webhooks`, `channels` or `request_signatures`
+    pub features: Vec<String>,
+    /// Content encodings accepted for request bodies
+    pub encodings: Vec<String>,
+    /// Content types accepted and returned besides JSON
+    pub formats: Vec<String>,
+    pub limits: CapabilityLimits,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct CapabilityLimits {
+    /// Largest transfer payload accepted in bytes
+    pub max_transfer_bytes: usize,
+    /// Most transfers queued for a single plot
+    pub max_queue_depth: usize,
+    /// Seconds a transfer stays queued before it expires
+    pub transfer_ttl: u64,
+    /// Seconds a transfer can be scheduled ahead
+    pub max_delivery_delay: u64,
+    /// Most plots a single broadcast can go to
+    pub max_broadcast_destinations: usize,
+    pub dfjson: DfJsonCapabilityLimits,
+}
+
+/// Limits DfJson payloads are checked against
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct DfJsonCapabilityLimits {
+    pub max_depth: usize,
+    pub max_nodes: usize,
+    pub max_string_bytes: usize,
+    pub max_dict_keys: usize,
+    pub max_list_entries: usize,
+    pub max_string_chars: usize,
+}
+
+impl From<DfJsonLimits> for DfJsonCapabilityLimits {
+    fn from(value: DfJsonLimits) -> Self {
+        Self {
+            max_depth: value.max_depth,
+            max_nodes: value.max_nodes,
+            max_string_bytes: value.max_string_bytes,
+            max_dict_keys: value.max_dict_keys,
+            max_list_entries: value.max_list_entries,
+            max_string_chars: value.max_string_chars,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct SchemaResponse {
+    /// Bumped whenever the schema changes
+    pub version: u32,
+    /// JSON schema
+    pub schema: serde_json::Value,
 }
 
 #[derive(Serialize, Deserialize, Object)]
@@ -41,6 +171,30 @@ pub struct VerificationResponse {
     pub signature: String,
 }
 
+#[derive(ApiResponse)]
+enum DecodeTemplateResult {
+    /// Not base64, gzip or DfJson
+    #[oai(status = 400)]
+    Invalid(PlainText<String>),
+    #[oai(status = 200)]
+    Ok(Json<Box<DfJson>>),
+}
+
+#[derive(ApiResponse)]
+enum PingResponse {
+    #[oai(status = 204)]
+    Ok(#[oai(header = "X-Dftools-Version")] String),
+}
+
+#[derive(ApiResponse)]
+enum SignResult {
+    /// Challenge is malformed, too long or expired
+    #[oai(status = 400)]
+    InvalidChallenge(PlainText<String>),
+    #[oai(status = 200)]
+    Ok(Json<VerificationResponse>),
+}
+
 #[derive(ApiResponse)]
 pub enum FetchTokenResponse {
     /// Internal domain used
@@ -56,6 +210,12 @@ pub enum FetchTokenResponse {
     /// Inconsistent Keys, returned body is the actual key
     #[oai(status = 403)]
     InconsistentKeys(PlainText<String>),
+    /// The instance key was blocked by the instance operator
+    #[oai(status = 403)]
+    KeyBlocked,
+    /// The instance operator doesn't federate with this instance
+    #[oai(status = 403)]
+    NotAllowed,
     /// Ok
     #[oai(status = 200)]
     Ok(PlainText<String>),
@@ -64,21 +224,225 @@ pub enum FetchTokenResponse {
 #[OpenApi]
 impl InstanceApi {
     /// Get the server's public key
+    ///
+    /// `tosign` is a challenge like `DFTOOLS CHALLENGE {requester domain} {nonce} {expires_at}`,
+    /// anything else isn't signed
     #[oai(path = "/sign", method = "get")]
-    async fn vibecheck(&self, tosign: Query<String>) -> Json<VerificationResponse> {
+    async fn vibecheck(&self, tosign: Query<String>) -> SignResult {
+        if let Err(err) = check_challenge(&tosign.0, unix_now()) {
+            return SignResult::InvalidChallenge(PlainText(err));
+        }
         let sig = self.store.sign(tosign.0.as_bytes()).await;
-        Json(VerificationResponse {
+        SignResult::Ok(Json(VerificationResponse {
             server_key: BASE64.encode(self.store.public_key()),
             signature: BASE64.encode(sig.to_bytes()),
+        }))
+    }
+
+    /// Check that the instance is up without doing any work
+    #[oai(path = "/ping", method = "get")]
+    async fn ping(&self) -> PingResponse {
+        PingResponse::Ok(env!("CARGO_PKG_VERSION").to_string())
+    }
+
+    /// Get the instance version, uptime and enabled subsystems
+    #[oai(path = "/version", method = "get")]
+    async fn version(&self) -> Json<VersionResponse> {
+        Json(VersionResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocols: protocols(),
+            uptime: self.started.elapsed().as_secs(),
+            subsystems: self.subsystems.clone(),
+            encodings: ENCODINGS.iter().map(|it| it.to_string()).collect(),
+        })
+    }
+
+    /// Get the name and operator contact of the instance, other instances keep them
+    #[oai(path = "/info", method = "get")]
+    async fn info(&self) -> Json<InstanceInfo> {
+        Json(InstanceInfo {
+            name: self.name.clone(),
+            contact: self.contact.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            apis: protocols(),
+        })
+    }
+
+    /// Get the API versions, optional features and limits of the instance
+    #[oai(path = "/capabilities", method = "get")]
+    async fn capabilities(&self) -> Json<CapabilitiesResponse> {
+        Json(CapabilitiesResponse {
+            apis: protocols(),
+            features: FEATURES.iter().map(|it| it.to_string()).collect(),
+            encodings: ENCODINGS.iter().map(|it| it.to_string()).collect(),
+            formats: vec![
+                "application/cbor".to_string(),
+                "application/msgpack".to_string(),
+            ],
+            limits: self.limits.clone(),
         })
     }
 
+    /// Get the JSON schema of DfJson, the format of transfer payloads and channel messages
+    #[oai(path = "/schema/dfjson", method = "get")]
+    async fn dfjson_schema(&self) -> Json<SchemaResponse> {
+        Json(SchemaResponse {
+            version: dfjson::SCHEMA_VERSION,
+            schema: serde_json::to_value(dfjson::schema()).expect("Schema should serialize"),
+        })
+    }
+
+    /// Encode DfJson as DF template data (gzipped and base64 encoded)
+    #[oai(path = "/convert/template", method = "post")]
+    async fn encode_template(&self, value: Json<DfJson>) -> PlainText<String> {
+        PlainText(template::encode(&value.0))
+    }
+
+    /// Decode DF template data (gzipped and base64 encoded) into DfJson
+    #[oai(path = "/convert/template/decode", method = "post")]
+    async fn decode_template(&self, template: PlainText<String>) -> DecodeTemplateResult {
+        match template::decode(&template.0) {
+            Ok(value) => DecodeTemplateResult::Ok(Json(Box::new(value))),
+            Err(err) => DecodeTemplateResult::Invalid(PlainText(err.to_string())),
+        }
+    }
+
+    /// Instances plots can be registered to or moved to, ordered by domain
+    #[oai(path = "/instances", method = "get")]
+    async fn list_instances(
+        &self,
+        #[oai(default = "default_plots_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        #[oai(default)] offset: Query<u32>,
+    ) -> Json<Vec<KnownInstanceInfo>> {
+        Json(
+            self.store
+                .list_instances(limit.0, offset.0)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// How every registered instance answered its pings, ordered by domain
+    #[oai(path = "/instances/health", method = "get")]
+    async fn instances_health(&self) -> Json<Vec<InstanceHealthInfo>> {
+        Json(
+            self.store
+                .instance_health()
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Register another instance so plots can be registered to, trust or move to it
+    ///
+    /// The body is its domain, it proves it has its key by signing a challenge at `/sign`
+    #[oai(path = "/instance", method = "post")]
+    async fn register_instance(
+        &self,
+        domain: PlainText<String>,
+        req: &Request,
+    ) -> RegisterInstanceResult {
+        let domain = match ExternalDomain::try_from(domain.0.trim().to_ascii_lowercase()) {
+            Ok(domain) => domain,
+            Err(err) => return RegisterInstanceResult::InvalidDomain(PlainText(err.to_string())),
+        };
+        if domain.inner().as_inner() == self.domain.as_inner() {
+            return RegisterInstanceResult::InternalDomainUsed;
+        }
+        let key = match self.store.verify_instance_key(&domain).await {
+            Ok(key) => key,
+            Err(err) => {
+                return RegisterInstanceResult::CannotPingInstance(PlainText(err.to_string()))
+            }
+        };
+        if self.store.public_key() == key {
+            return RegisterInstanceResult::InternalDomainUsed;
+        }
+        if self
+            .store
+            .is_instance_key_blocked(&BASE64.encode(key))
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            return RegisterInstanceResult::KeyBlocked;
+        }
+        if !self
+            .store
+            .federation_allows(domain.inner().as_inner(), &key)
+        {
+            return RegisterInstanceResult::NotAllowed;
+        }
+        if let Some(resolver) = &self.dns_resolver {
+            match self
+                .store
+                .verify_instance_dns(resolver, &domain, &key)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    return RegisterInstanceResult::DnsNotVerified(PlainText(format!(
+                        "Expected a TXT record at _dftools.{} with {}{}",
+                        domain.host(),
+                        DNS_RECORD_PREFIX,
+                        key_fingerprint(&key)
+                    )));
+                }
+                Err(err) => {
+                    warn!(
+                        "Looking up the TXT record of {} failed: {:?}",
+                        domain.inner().as_inner(),
+                        err
+                    );
+                    return RegisterInstanceResult::DnsLookupFailed;
+                }
+            }
+        }
+        match self
+            .store
+            .register_instance(&domain, &key)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            Ok(true) => {
+                if let Err(err) = self.store.refresh_instance_info(&domain).await {
+                    warn!(
+                        "Fetching info of {} failed: {:?}",
+                        domain.inner().as_inner(),
+                        err
+                    );
+                }
+                self.store
+                    .audit(AuditEntry {
+                        plot: None,
+                        event: AuditEvent::InstanceRegistered,
+                        actor: format!("instance:{}", domain.inner().as_inner()),
+                        ip: client_addr(req),
+                        detail: Some(BASE64.encode(key)),
+                    })
+                    .await;
+                self.store
+                    .federation_event(
+                        FederationEvent::InstanceRegistered,
+                        Some(domain.inner().as_inner()),
+                        Some(key_fingerprint(&key)),
+                    )
+                    .await;
+                RegisterInstanceResult::Registered(PlainText(BASE64.encode(key)))
+            }
+            Ok(false) => RegisterInstanceResult::AlreadyRegistered(PlainText(BASE64.encode(key))),
+            Err(err) => RegisterInstanceResult::Conflict(PlainText(err.to_string())),
+        }
+    }
+
     /// Provide your server domain and identity key for a jwt to communicate with the server
     #[oai(path = "/server-token", method = "get")]
     async fn get_server_token(
         &self,
         key: Query<String>,
         domain: Query<String>,
+        req: &Request,
     ) -> FetchTokenResponse {
         let send_instance = SendInstance {
             key: key.0,
@@ -96,16 +460,41 @@ impl InstanceApi {
         if self.store.public_key() == claimed_instance.key {
             return FetchTokenResponse::InternalDomainUsed;
         }
-        let tok = if let Ok(tok) = self.store.ping_instance(&domain).await {
+        let mut tok = if let Ok(tok) = self.store.instance_key(&domain).await {
             tok
         } else {
             return FetchTokenResponse::CannotPingInstance;
         };
+        if claimed_instance.key != tok {
+            // The cached key could be outdated, only a fresh signature settles it
+            self.store
+                .invalidate_instance_key(domain.inner().as_inner())
+                .await
+                .expect("Store ops shouldn't fail");
+            tok = if let Ok(tok) = self.store.verify_instance_key(&domain).await {
+                tok
+            } else {
+                return FetchTokenResponse::CannotPingInstance;
+            };
+        }
         if claimed_instance.key != tok {
             return FetchTokenResponse::InconsistentKeys(PlainText(BASE64.encode(tok)));
         }
+        if self
+            .store
+            .is_instance_key_blocked(&BASE64.encode(tok))
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            return FetchTokenResponse::KeyBlocked;
+        }
+        if !self
+            .store
+            .federation_allows(domain.inner().as_inner(), &tok)
+        {
+            return FetchTokenResponse::NotAllowed;
+        }
 
-        const JWT_EXPIRY: u64 = 60 * 60 * 3;
         let issued = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
@@ -117,28 +506,286 @@ impl InstanceApi {
             jti: Uuid::new_v4(),
         };
         let signed = self.store.sign_jwt(&token).expect("signing failed");
+        self.store
+            .audit(AuditEntry {
+                plot: None,
+                event: AuditEvent::ServerTokenIssued,
+                actor: format!("instance:{}", token.sub.domain),
+                ip: client_addr(req),
+                detail: Some(token.jti.to_string()),
+            })
+            .await;
+        self.store
+            .federation_event(
+                FederationEvent::TokenIssued,
+                Some(&token.sub.domain),
+                Some(token.jti.to_string()),
+            )
+            .await;
 
         FetchTokenResponse::Ok(PlainText(signed))
     }
 
-    /// Get the plot id
+    /// Get the plot along with how it is set up
     #[oai(path = "/whoami", method = "get")]
-    async fn whoami(&self, auth: Auth) -> Json<PlotId> {
-        Json(auth.plot().plot_id)
+    async fn whoami(&self, auth: Auth) -> Json<WhoamiResponse> {
+        let (node, size) = match &auth {
+            Auth::PlotAuth(actor) => (actor.0.node.clone(), actor.0.size),
+            Auth::KeyAuth(_) | Auth::SessionAuth(_) | Auth::DelegatedAuth(_) => (None, None),
+        };
+        let plot = auth.plot();
+        let stats = self
+            .store
+            .plot_stats(plot.plot_id)
+            .await
+            .expect("Store ops shouldn't fail");
+        let trusted_plots = self
+            .store
+            .fetch_plot_trust(plot.plot_id)
+            .await
+            .expect("Store ops shouldn't fail")
+            .len();
+        let trusted_instances = self
+            .store
+            .fetch_instance_trust(plot.plot_id)
+            .await
+            .expect("Store ops shouldn't fail")
+            .len();
+        let instance_down_since = match &plot.instance.domain {
+            InstanceDomain::External(domain) => self
+                .store
+                .instance_down_since(domain.inner().as_inner())
+                .await
+                .expect("Store ops shouldn't fail"),
+            InstanceDomain::Current => None,
+        };
+        let key_fingerprint = key_fingerprint(&plot.instance.key);
+        let plot = self.plot_response(plot);
+        Json(WhoamiResponse {
+            plot: plot.plot,
+            owner: plot.owner,
+            domain: plot.domain,
+            key_fingerprint,
+            active_keys: stats.active_keys,
+            trusted_plots: trusted_plots as u32,
+            trusted_instances: trusted_instances as u32,
+            registered_at: stats.registered_at,
+            node,
+            size,
+            instance_down_since,
+        })
     }
 
-    /// Get the plot's instance
+    /// Get the plot's owner and instance
+    ///
+    /// With `Accept: text/plain` it returns the old `domain;base64key` format instead
     #[oai(path = "/plot", method = "get")]
-    async fn get_plot_instance(&self, id: Query<PlotId>) -> PlotFetchResult {
-        if let Some(plot) = self
+    async fn get_plot_instance(
+        &self,
+        id: Query<PlotId>,
+        #[oai(name = "Accept")] accept: Header<Option<String>>,
+    ) -> PlotFetchResult {
+        let Some(plot) = self
             .store
             .get_plot(id.0)
             .await
             .expect("Store ops shouldn't fail")
+        else {
+            return PlotFetchResult::NotFound;
+        };
+        let legacy = accept
+            .0
+            .is_some_and(|accept| accept.starts_with("text/plain"));
+        if legacy {
+            return PlotFetchResult::Legacy(PlainText(plot.instance.encode(&self.domain)));
+        }
+        PlotFetchResult::Ok(Json(self.plot_response(plot)))
+    }
+
+    /// List the plots registered under an owner, lowest plot id first
+    #[oai(path = "/plots", method = "get")]
+    async fn get_owner_plots(
+        &self,
+        /// Minecraft uuid of the owner
+        owner: Query<Uuid>,
+        #[oai(default = "default_plots_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        #[oai(default)] offset: Query<u32>,
+    ) -> Json<Vec<PlotResponse>> {
+        let plots = self
+            .store
+            .plots_by_owner(owner.0, limit.0 as i64, offset.0 as i64)
+            .await
+            .expect("Store ops shouldn't fail");
+        Json(
+            plots
+                .into_iter()
+                .map(|plot| self.plot_response(plot))
+                .collect(),
+        )
+    }
+
+    /// Unregister the plot, removing its keys, trust and everything else stored about it
+    ///
+    /// Without `confirm` nothing is removed and a token is returned instead,
+    /// pass it as `confirm` within 5 minutes to go through with it
+    #[oai(path = "/plot", method = "delete")]
+    async fn unregister(
+        &self,
+        confirm: Query<Option<String>>,
+        auth: PlotAuth,
+        req: &Request,
+    ) -> poem::Result<UnregisterResult> {
+        let actor = auth.0.actor();
+        let plot_id = auth.0.require_owner()?.plot_id;
+        let Some(token) = confirm.0 else {
+            let token = self
+                .store
+                .unregister_token(plot_id)
+                .await
+                .expect("Store ops shouldn't fail");
+            return Ok(UnregisterResult::ConfirmationRequired(PlainText(token)));
+        };
+        if self
+            .store
+            .unregister_plot(plot_id, &token)
+            .await
+            .expect("Store ops shouldn't fail")
         {
-            PlotFetchResult::Ok(PlainText(plot.instance.encode(&self.domain)))
+            self.store
+                .audit(AuditEntry {
+                    plot: Some(plot_id),
+                    event: AuditEvent::PlotUnregistered,
+                    actor,
+                    ip: client_addr(req),
+                    detail: None,
+                })
+                .await;
+            Ok(UnregisterResult::Unregistered)
         } else {
-            PlotFetchResult::NotFound
+            Ok(UnregisterResult::InvalidConfirmation)
+        }
+    }
+
+    /// Get how much the plot has been used
+    #[oai(path = "/plot/stats", method = "get")]
+    async fn plot_stats(&self, auth: Auth) -> Json<PlotActivity> {
+        Json(
+            self.store
+                .plot_activity(auth.plot().plot_id)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Get the name, description, tags and website of a plot
+    #[oai(path = "/plot/meta", method = "get")]
+    async fn get_plot_meta(&self, id: Query<PlotId>) -> PlotMetaResult {
+        if !self
+            .store
+            .plot_exists(id.0)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            return PlotMetaResult::NotFound;
+        }
+        PlotMetaResult::Ok(Json(
+            self.store
+                .get_plot_meta(id.0)
+                .await
+                .expect("Store ops shouldn't fail"),
+        ))
+    }
+
+    /// Replace the name, description, tags and website of the plot
+    #[oai(path = "/plot/meta", method = "put")]
+    async fn set_plot_meta(
+        &self,
+        meta: Json<PlotMeta>,
+        auth: Auth,
+    ) -> poem::Result<SetPlotMetaResult> {
+        let plot = auth.require_owner()?;
+        let mut meta = meta.0;
+        if let Some(tag) = meta.tags.iter().find(|tag| !valid_tag(tag)) {
+            return Ok(SetPlotMetaResult::InvalidTag(PlainText(format!(
+                "Tag {:?} must be 1 to 32 lowercase letters, digits and dashes",
+                tag
+            ))));
+        }
+        meta.tags.sort_unstable();
+        meta.tags.dedup();
+        if let Some(website) = &meta.website {
+            let url = match Url::parse(website) {
+                Ok(url) => url,
+                Err(err) => {
+                    return Ok(SetPlotMetaResult::InvalidWebsite(PlainText(
+                        err.to_string(),
+                    )))
+                }
+            };
+            if url.scheme() != "https" {
+                return Ok(SetPlotMetaResult::InvalidWebsite(PlainText(
+                    "Must be https".to_string(),
+                )));
+            }
+        }
+        self.store
+            .set_plot_meta(plot.plot_id, &meta)
+            .await
+            .expect("Store ops shouldn't fail");
+        Ok(SetPlotMetaResult::Success)
+    }
+
+    /// List the members of the plot and what they can do
+    #[oai(path = "/plot/members", method = "get")]
+    async fn get_members(&self, auth: Auth) -> Json<Vec<Member>> {
+        Json(
+            self.store
+                .list_members(auth.plot().plot_id)
+                .await
+                .expect("Store ops shouldn't fail"),
+        )
+    }
+
+    /// Add a member to the plot or replace what they can do, only the owner can
+    #[oai(path = "/plot/members/:uuid", method = "put")]
+    async fn set_member(
+        &self,
+        /// Minecraft uuid of the member
+        uuid: Path<Uuid>,
+        abilities: Json<Abilities>,
+        auth: Auth,
+    ) -> poem::Result<SetMemberResult> {
+        let plot = auth.require_owner()?;
+        if uuid.0 == plot.owner {
+            return Ok(SetMemberResult::IsOwner);
+        }
+        self.store
+            .set_member(plot.plot_id, uuid.0, &abilities.0)
+            .await
+            .expect("Store ops shouldn't fail");
+        Ok(SetMemberResult::Success)
+    }
+
+    /// Remove a member from the plot, only the owner can
+    #[oai(path = "/plot/members/:uuid", method = "delete")]
+    async fn remove_member(
+        &self,
+        /// Minecraft uuid of the member
+        uuid: Path<Uuid>,
+        auth: Auth,
+    ) -> poem::Result<RemoveMemberResult> {
+        let plot = auth.require_owner()?;
+        if self
+            .store
+            .remove_member(plot.plot_id, uuid.0)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            Ok(RemoveMemberResult::Removed)
+        } else {
+            Ok(RemoveMemberResult::NotAMember)
         }
     }
 
@@ -148,6 +795,7 @@ impl InstanceApi {
         &self,
         instance_key: Json<Option<String>>,
         auth: UnregisteredAuth,
+        req: &Request,
     ) -> RegisterResult {
         let plot = auth.0;
         let uuid = if let Some(id) = self
@@ -193,7 +841,18 @@ impl InstanceApi {
             .await
             .expect("store shouldn't fail")
         {
-            Ok(_) => RegisterResult::Ok,
+            Ok(_) => {
+                self.store
+                    .audit(AuditEntry {
+                        plot: Some(plot.plot_id),
+                        event: AuditEvent::PlotRegistered,
+                        actor: format!("player:{uuid}"),
+                        ip: client_addr(req),
+                        detail: instance_key.0,
+                    })
+                    .await;
+                RegisterResult::Ok
+            }
             Err(err) => match err {
                 RegisterError::PlotTaken => RegisterResult::PlotAlreadyExists,
                 RegisterError::InstanceNotFound => {
@@ -203,76 +862,774 @@ impl InstanceApi {
         }
     }
 
+    /// Look up the owner and instance of many plots at once
+    ///
+    /// Plots that aren't registered are listed in `missing`
+    #[oai(path = "/plots/lookup", method = "post")]
+    async fn lookup_plots(&self, ids: Json<Vec<PlotId>>) -> LookupPlotsResult {
+        /// Most plots a single lookup can ask for
+        const MAX_LOOKUP: usize = 100;
+        let mut ids = ids.0;
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.len() > MAX_LOOKUP {
+            return LookupPlotsResult::TooManyPlots(PlainText(format!(
+                "At most {} plots can be looked up at once",
+                MAX_LOOKUP
+            )));
+        }
+        let plots = self
+            .store
+            .get_plots(&ids)
+            .await
+            .expect("Store ops shouldn't fail");
+        let missing = ids
+            .into_iter()
+            .filter(|id| plots.binary_search_by_key(id, |plot| plot.plot_id).is_err())
+            .collect();
+        LookupPlotsResult::Ok(Json(PlotLookupResponse {
+            plots: plots
+                .into_iter()
+                .map(|plot| self.plot_response(plot))
+                .collect(),
+            missing,
+        }))
+    }
+
+    /// Start registering a plot with a short code instead of its instance key
+    ///
+    /// The plot completes the registration by sending the code to `/plot/verify/complete`
+    /// before it expires
+    #[oai(path = "/plot/verify", method = "post")]
+    async fn start_verification(&self, body: Json<VerificationRequest>) -> StartVerificationResult {
+        let body = body.0;
+        let instance_key = match body.instance_key.as_deref().map(decode_instance_key) {
+            Some(Ok(key)) => Some(key),
+            Some(Err(err)) => return StartVerificationResult::InvalidKeyFormat(PlainText(err)),
+            None => None,
+        };
+        if self
+            .store
+            .plot_exists(body.plot_id)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            return StartVerificationResult::PlotAlreadyExists;
+        }
+        let code = self
+            .store
+            .issue_verification(&PendingRegistration {
+                plot_id: body.plot_id,
+                instance_key,
+            })
+            .await
+            .expect("Store ops shouldn't fail");
+        StartVerificationResult::Ok(Json(VerificationCode {
+            code,
+            expires_in: VERIFICATION_TTL,
+        }))
+    }
+
+    /// Complete a registration started with `/plot/verify` by sending its code from the plot
+    ///
+    /// The player in the User-Agent becomes the owner
+    #[oai(path = "/plot/verify/complete", method = "post")]
+    async fn complete_verification(
+        &self,
+        code: PlainText<String>,
+        auth: UnregisteredAuth,
+        req: &Request,
+    ) -> RegisterResult {
+        let plot = auth.0;
+        let Some(pending) = self
+            .store
+            .take_verification(&code.0, plot.plot_id)
+            .await
+            .expect("Store ops shouldn't fail")
+        else {
+            return RegisterResult::InvalidCode;
+        };
+        let uuid = if let Some(id) = self
+            .store
+            .get_uuid(&plot.owner)
+            .await
+            .expect("Store ops shouldn't fail")
+        {
+            id
+        } else {
+            return RegisterResult::CannotFetchUuid;
+        };
+        match self
+            .store
+            .register_plot(plot.plot_id, uuid, pending.instance_key.as_ref())
+            .await
+            .expect("store shouldn't fail")
+        {
+            Ok(_) => {
+                self.store
+                    .audit(AuditEntry {
+                        plot: Some(plot.plot_id),
+                        event: AuditEvent::PlotRegistered,
+                        actor: format!("player:{uuid}"),
+                        ip: client_addr(req),
+                        detail: pending
+                            .instance_key
+                            .map(|key| BASE64.encode(key.as_bytes())),
+                    })
+                    .await;
+                RegisterResult::Ok
+            }
+            Err(RegisterError::PlotTaken) => RegisterResult::PlotAlreadyExists,
+            Err(RegisterError::InstanceNotFound) => {
+                RegisterResult::InstanceNotRegistered(PlainText("Instance not registered"))
+            }
+        }
+    }
+
     /// Change the plot instance with the public key
+    ///
+    /// Unless `handoff` is false, trust, settings, members, metadata and pending transfers
+    /// are handed to the new instance first and nothing changes if it doesn't take them.
+    /// The plot has to be registered there already
     #[oai(path = "/plot", method = "put")]
     async fn replace_instance(
         &self,
         instance_key: Json<Option<String>>,
+        #[oai(default = "default_handoff")] handoff: Query<bool>,
         auth: Auth,
-    ) -> ReplaceInstanceResult {
-        let plot = auth.plot();
+        req: &Request,
+    ) -> poem::Result<ReplaceInstanceResult> {
+        let actor = auth.actor();
+        let plot = auth.require_owner()?;
 
         let key = if let Some(key) = &instance_key.0 {
             let key = match BASE64.decode(key) {
                 Ok(key) => key,
                 Err(err) => {
-                    return ReplaceInstanceResult::InvalidKeyFormat(PlainText(format!(
+                    return Ok(ReplaceInstanceResult::InvalidKeyFormat(PlainText(format!(
                         "base64 decode: {}",
                         err
-                    )))
+                    ))))
                 }
             };
             let key: [u8; 32] = match key.as_slice().try_into() {
                 Ok(key) => key,
                 Err(err) => {
-                    return ReplaceInstanceResult::InvalidKeyFormat(PlainText(err.to_string()))
+                    return Ok(ReplaceInstanceResult::InvalidKeyFormat(PlainText(
+                        err.to_string(),
+                    )))
                 }
             };
             match VerifyingKey::from_bytes(&key) {
                 Ok(key) => Some(key),
                 Err(err) => {
-                    return ReplaceInstanceResult::InvalidKeyFormat(PlainText(format!(
+                    return Ok(ReplaceInstanceResult::InvalidKeyFormat(PlainText(format!(
                         "converting to verify key failed: {}",
                         err
-                    )))
+                    ))))
                 }
             }
         } else {
             None
         };
+        let handoff_to = match (handoff.0, &key) {
+            (true, Some(key)) => self
+                .store
+                .instance_domain(key)
+                .await
+                .expect("store ops shouldn't fail"),
+            _ => None,
+        };
+        if let Some(domain) = handoff_to {
+            let handed_off = self
+                .store
+                .hand_off_plot(plot.plot_id, &domain)
+                .await
+                .expect("store ops shouldn't fail");
+            if let Err(err) = handed_off {
+                return Ok(ReplaceInstanceResult::HandoffFailed(PlainText(
+                    err.to_string(),
+                )));
+            }
+        }
         if let Err(err) = self
             .store
             .edit_plot(plot.plot_id, key.as_ref())
             .await
             .expect("store ops shouldn't fail")
         {
-            match err {
+            Ok(match err {
                 PlotEditError::PlotNotFound => ReplaceInstanceResult::PlotNotFound,
                 PlotEditError::InstanceNotFound => ReplaceInstanceResult::InstanceNotRegisterd,
-            }
+            })
         } else {
-            ReplaceInstanceResult::Success
+            self.store
+                .audit(AuditEntry {
+                    plot: Some(plot.plot_id),
+                    event: AuditEvent::PlotMoved,
+                    actor,
+                    ip: client_addr(req),
+                    detail: instance_key.0,
+                })
+                .await;
+            Ok(ReplaceInstanceResult::Success)
+        }
+    }
+
+    /// Take over the state of a plot that moves here from another instance
+    ///
+    /// Sent by the instance the plot was registered to, signed like relayed transfers
+    /// with `DFTOOLS HANDOFF {plot_id} {nonce}\n` followed by the export in canonical JSON
+    #[oai(path = "/plot/import", method = "post")]
+    async fn import_plot(
+        &self,
+        export: Json<serde_json::Value>,
+        /// Base64 encoded ed25519 signature of the export by the sending instance
+        #[oai(name = "X-Handoff-Signature")]
+        signature: Header<String>,
+        auth: ExternalServerAuth,
+        req: &Request,
+    ) -> ImportPlotResult {
+        let raw = export.0;
+        let export = match PlotExport::deserialize(&raw) {
+            Ok(export) => export,
+            Err(err) => return ImportPlotResult::Malformed(PlainText(err.to_string())),
+        };
+        let auth = auth.request();
+        let instance: Instance = auth
+            .sub
+            .parse()
+            .expect("Server should create good send instances");
+        let signature = match BASE64
+            .decode(&signature.0)
+            .ok()
+            .and_then(|sig| Signature::from_slice(&sig).ok())
+        {
+            Some(sig) => sig,
+            None => return ImportPlotResult::InvalidSignature,
+        };
+        let msg = handoff_message(export.plot_id, &auth.nonce, &raw);
+        if instance.key.verify_strict(&msg, &signature).is_err() {
+            return ImportPlotResult::InvalidSignature;
+        }
+        let origin = Origin {
+            domain: auth.sub.domain.clone(),
+            key: instance.key,
+            sent_at: unix_now(),
+        };
+        let plot_id = export.plot_id;
+        match self
+            .store
+            .import_plot(export, &origin)
+            .await
+            .expect("store ops shouldn't fail")
+        {
+            Ok(queued) => {
+                self.store
+                    .audit(AuditEntry {
+                        plot: Some(plot_id),
+                        event: AuditEvent::PlotImported,
+                        actor: format!("instance:{}", origin.domain),
+                        ip: client_addr(req),
+                        detail: None,
+                    })
+                    .await;
+                ImportPlotResult::Ok(Json(queued as u32))
+            }
+            Err(ImportError::PlotNotRegistered) => ImportPlotResult::PlotNotRegistered,
+            Err(ImportError::OwnerMismatch) => ImportPlotResult::OwnerMismatch,
         }
     }
 
     /// Create an api key
     #[oai(path = "/key", method = "post")]
-    async fn create_api_key(&self, auth: PlotAuth) -> Json<String> {
+    async fn create_api_key(
+        &self,
+        /// Name to tell the key apart in the key list
+        #[oai(validator(max_length = 64))]
+        label: Query<Option<String>>,
+        /// What the key may do, it can do everything without scopes
+        scopes: Query<Option<Vec<String>>>,
+        /// Addresses and CIDR ranges the key can be used from, anywhere without them
+        allowed_ips: Query<Option<Vec<String>>>,
+        auth: PlotAuth,
+        req: &Request,
+    ) -> poem::Result<CreateKeyResult> {
+        let actor = auth.0.actor();
+        let plot = auth.0.require(Ability::CreateKeys)?;
+        if let Some(invalid) = scopes.0.iter().flatten().find(|scope| !valid_scope(scope)) {
+            return Ok(CreateKeyResult::InvalidScope(PlainText(format!(
+                "Unknown scope {invalid}"
+            ))));
+        }
+        let allowed_ips = match allowed_ips
+            .0
+            .map(|ips| {
+                ips.iter()
+                    .map(|ip| ip.parse())
+                    .collect::<Result<Vec<IpRange>, _>>()
+            })
+            .transpose()
+        {
+            Ok(ips) => ips,
+            Err(err) => return Ok(CreateKeyResult::InvalidAddress(PlainText(err))),
+        };
         let key = self
             .store
-            .create_key(auth.0.plot_id)
+            .create_key(
+                plot.plot_id,
+                label.0.as_deref(),
+                scopes.0.as_deref(),
+                allowed_ips.as_deref(),
+            )
             .await
             .expect("store ops shouldn't fail");
-        Json(key)
+        self.store
+            .audit(AuditEntry {
+                plot: Some(plot.plot_id),
+                event: AuditEvent::KeyCreated,
+                actor,
+                ip: client_addr(req),
+                detail: key_prefix(&key).map(str::to_string),
+            })
+            .await;
+        Ok(CreateKeyResult::Ok(Json(key)))
+    }
+    /// Metadata of the plot's api keys, the keys themselves aren't stored
+    #[oai(path = "/key", method = "get")]
+    async fn list_api_keys(&self, auth: Auth) -> poem::Result<Json<Vec<ApiKeyInfo>>> {
+        let plot = auth.require(Ability::CreateKeys)?;
+        let keys = self
+            .store
+            .list_keys(plot.plot_id)
+            .await
+            .expect("store ops shouldn't fail");
+        Ok(Json(keys))
+    }
+    /// Disable one api key
+    #[oai(path = "/key/:id", method = "delete")]
+    async fn delete_api_key(
+        &self,
+        /// Id or prefix of the key
+        id: Path<String>,
+        auth: Auth,
+        req: &Request,
+    ) -> poem::Result<DeleteKeyResult> {
+        let actor = auth.actor();
+        let plot = auth.require(Ability::CreateKeys)?;
+        Ok(
+            match self
+                .store
+                .disable_key(plot.plot_id, &id.0)
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Ok(()) => {
+                    self.store
+                        .audit(AuditEntry {
+                            plot: Some(plot.plot_id),
+                            event: AuditEvent::KeyDisabled,
+                            actor,
+                            ip: client_addr(req),
+                            detail: Some(id.0),
+                        })
+                        .await;
+                    DeleteKeyResult::Disabled
+                }
+                Err(DisableKeyError::NotFound) => DeleteKeyResult::NotFound,
+                Err(DisableKeyError::Ambiguous) => DeleteKeyResult::Ambiguous,
+            },
+        )
+    }
+    /// Replace an api key with a new one that has the same label and scopes
+    ///
+    /// The old key keeps working for `grace` seconds so integrations can switch over.
+    /// API keys can only rotate themselves
+    #[oai(path = "/key/rotate", method = "post")]
+    async fn rotate_api_key(
+        &self,
+        /// Id or prefix of the key, not needed when authenticating with it
+        key: Query<Option<String>>,
+        /// Seconds the old key keeps working, at most 30 days
+        #[oai(validator(maximum(value = "2592000")))]
+        grace: Query<Option<u64>>,
+        auth: Auth,
+        req: &Request,
+    ) -> poem::Result<RotateKeyResult> {
+        let actor = auth.actor();
+        let (plot, key) = match auth {
+            // A key rotating another could get itself more scopes
+            Auth::KeyAuth(auth) => {
+                let id = auth.0.id.to_string();
+                (auth.require(Scope::KeysWrite)?, id)
+            }
+            Auth::DelegatedAuth(_) => return Err(ForbiddenError::Delegated.into()),
+            auth => {
+                let plot = auth.require(Ability::CreateKeys)?;
+                let Some(key) = key.0 else {
+                    return Ok(RotateKeyResult::MissingKey);
+                };
+                (plot, key)
+            }
+        };
+        Ok(
+            match self
+                .store
+                .rotate_key(
+                    plot.plot_id,
+                    &key,
+                    grace.0.unwrap_or(self.key_rotation_grace),
+                )
+                .await
+                .expect("store ops shouldn't fail")
+            {
+                Ok(new_key) => {
+                    self.store
+                        .audit(AuditEntry {
+                            plot: Some(plot.plot_id),
+                            event: AuditEvent::KeyRotated,
+                            actor,
+                            ip: client_addr(req),
+                            detail: Some(match key_prefix(&new_key) {
+                                Some(prefix) => format!("{key} to {prefix}"),
+                                None => key,
+                            }),
+                        })
+                        .await;
+                    RotateKeyResult::Ok(Json(new_key))
+                }
+                Err(DisableKeyError::NotFound) => RotateKeyResult::NotFound,
+                Err(DisableKeyError::Ambiguous) => RotateKeyResult::Ambiguous,
+            },
+        )
     }
     /// Purge all api keys
     #[oai(path = "/key", method = "delete")]
-    async fn delete_all_api_keys(&self, auth: Auth) {
+    async fn delete_all_api_keys(&self, auth: Auth, req: &Request) -> poem::Result<()> {
+        let actor = auth.actor();
+        let plot = auth.require(Ability::CreateKeys)?;
         self.store
-            .disable_all_keys(auth.plot().plot_id)
+            .disable_all_keys(plot.plot_id)
             .await
             .expect("store ops shouldn't fail");
+        self.store
+            .audit(AuditEntry {
+                plot: Some(plot.plot_id),
+                event: AuditEvent::AllKeysDisabled,
+                actor,
+                ip: client_addr(req),
+                detail: None,
+            })
+            .await;
+        Ok(())
+    }
+
+    /// Mint a short lived token for another service that can only do what its scopes allow
+    ///
+    /// Send it as a bearer token. API keys and tokens can't hand out scopes they lack
+    #[oai(path = "/delegate", method = "post")]
+    async fn delegate(
+        &self,
+        /// Like the scopes of an API key
+        scopes: Query<Vec<String>>,
+        /// Seconds the token is valid for, 10 minutes by default and at most an hour
+        #[oai(validator(maximum(value = "3600")))]
+        ttl: Query<Option<u64>>,
+        auth: Auth,
+    ) -> poem::Result<DelegateResult> {
+        if scopes.0.is_empty() {
+            return Ok(DelegateResult::InvalidScope(PlainText(
+                "At least one scope is needed".to_string(),
+            )));
+        }
+        if let Some(invalid) = scopes.0.iter().find(|scope| !valid_scope(scope)) {
+            return Ok(DelegateResult::InvalidScope(PlainText(format!(
+                "Unknown scope {invalid}"
+            ))));
+        }
+        let plot = match auth {
+            Auth::KeyAuth(auth) => {
+                if let Some(missing) = scopes
+                    .0
+                    .iter()
+                    .flat_map(|scope| granted_scopes(scope))
+                    .find(|scope| !auth.0.allows(*scope))
+                {
+                    return Err(ForbiddenError::MissingScope(missing).into());
+                }
+                auth.0.plot
+            }
+            Auth::DelegatedAuth(auth) => {
+                if let Some(missing) = scopes
+                    .0
+                    .iter()
+                    .flat_map(|scope| granted_scopes(scope))
+                    .find(|scope| !scopes_allow(&auth.0.scopes, *scope))
+                {
+                    return Err(ForbiddenError::MissingScope(missing).into());
+                }
+                auth.0.plot
+            }
+            auth => auth.require(Ability::CreateKeys)?,
+        };
+        let issued = unix_now();
+        let token = DelegatedToken {
+            plot: plot.plot_id,
+            scopes: scopes.0,
+            iat: issued,
+            exp: issued + ttl.0.unwrap_or(60 * 10).min(DELEGATED_MAX_EXPIRY),
+            jti: Uuid::new_v4(),
+        };
+        let signed = self.store.sign_jwt(&token).expect("signing failed");
+        Ok(DelegateResult::Ok(Json(SessionToken {
+            token: signed,
+            expires_at: token.exp,
+        })))
     }
+
+    /// Key, trust and registration changes of the plot, newest first
+    ///
+    /// Pass the id of the last record as `before` for the next page
+    #[oai(path = "/plot/audit", method = "get")]
+    async fn plot_audit_log(
+        &self,
+        before: Query<Option<i64>>,
+        #[oai(default = "default_plots_limit", validator(maximum(value = "200")))] limit: Query<
+            u32,
+        >,
+        auth: Auth,
+    ) -> poem::Result<Json<Vec<AuditRecord>>> {
+        let plot = auth.require_owner()?;
+        Ok(Json(
+            self.store
+                .audit_log(Some(plot.plot_id), before.0, limit.0)
+                .await
+                .expect("Store ops shouldn't fail"),
+        ))
+    }
+
+    /// Get a code to log in as the plot owner outside of DiamondFire
+    ///
+    /// Show it to the owner in game, it is exchanged for a session at `/login/complete`
+    #[oai(path = "/login", method = "post")]
+    async fn start_login(&self, auth: PlotAuth) -> poem::Result<Json<VerificationCode>> {
+        let plot = auth.0.require_owner()?;
+        let code = self
+            .store
+            .issue_login_code(&PendingLogin {
+                plot_id: plot.plot_id,
+                owner: plot.owner,
+            })
+            .await
+            .expect("Store ops shouldn't fail");
+        Ok(Json(VerificationCode {
+            code,
+            expires_in: LOGIN_CODE_TTL,
+        }))
+    }
+
+    /// Exchange a code from `/login` for a session token
+    ///
+    /// Send it as a bearer token, it acts as the plot owner until it expires
+    #[oai(path = "/login/complete", method = "post")]
+    async fn complete_login(&self, code: PlainText<String>) -> LoginResult {
+        let Some(pending) = self
+            .store
+            .take_login_code(&code.0)
+            .await
+            .expect("Store ops shouldn't fail")
+        else {
+            return LoginResult::InvalidCode;
+        };
+        let issued = unix_now();
+        let session = OwnerSession {
+            plot: pending.plot_id,
+            owner: pending.owner,
+            iat: issued,
+            exp: issued + SESSION_EXPIRY,
+            jti: Uuid::new_v4(),
+        };
+        let token = self.store.sign_jwt(&session).expect("signing failed");
+        LoginResult::Ok(Json(SessionToken {
+            token,
+            expires_at: session.exp,
+        }))
+    }
+
+    /// End the session before it expires
+    #[oai(path = "/logout", method = "post")]
+    async fn logout(&self, auth: SessionAuth) {
+        let claims = auth.0.claims;
+        self.store
+            .revoke_session(claims.jti, claims.exp)
+            .await
+            .expect("Store ops shouldn't fail");
+    }
+}
+
+impl InstanceApi {
+    fn plot_response(&self, plot: Plot) -> PlotResponse {
+        let domain = match &plot.instance.domain {
+            InstanceDomain::External(ext) => ext.inner().as_inner().to_string(),
+            InstanceDomain::Current => self.domain.as_inner().to_string(),
+        };
+        PlotResponse {
+            plot: plot.plot_id,
+            owner: plot.owner,
+            domain,
+            key: BASE64.encode(plot.instance.key),
+        }
+    }
+}
+
+fn default_plots_limit() -> u32 {
+    50
+}
+
+fn default_handoff() -> bool {
+    true
+}
+
+/// Optional features listed at `/capabilities`
+const FEATURES: &[&str] = &[
+    "webhooks",
+    "channels",
+    "streams",
+    "replies",
+    "broadcasts",
+    "patches",
+    "scheduled_transfers",
+    "transfer_history",
+    "plot_handoff",
+    "request_signatures",
+    "delegated_tokens",
+];
+
+/// Supported protocol versions of each API
+pub fn protocols() -> HashMap<String, Vec<String>> {
+    HashMap::from([
+        ("instance".to_string(), vec!["v0".to_string()]),
+        ("baton".to_string(), vec!["v0".to_string()]),
+    ])
+}
+
+fn valid_tag(tag: &str) -> bool {
+    (1..=32).contains(&tag.len())
+        && tag
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+#[derive(Object)]
+pub struct WhoamiResponse {
+    plot: PlotId,
+    /// Minecraft uuid of the plot owner
+    owner: Uuid,
+    /// Domain of the instance the plot is registered to
+    domain: String,
+    /// First 16 bytes of the SHA-256 of the instance key, hex encoded
+    key_fingerprint: String,
+    /// API keys that haven't been purged
+    active_keys: u32,
+    /// Plots this plot trusts
+    trusted_plots: u32,
+    /// Instances this plot trusts
+    trusted_instances: u32,
+    /// Unix timestamp in seconds, missing for plots registered before it was recorded
+    registered_at: Option<i64>,
+    /// Node the plot runs on, only known when DF sent the request
+    node: Option<String>,
+    /// Only known when DF sent the request
+    size: Option<PlotSize>,
+    /// Unix timestamp in seconds since the instance the plot is registered to stopped answering,
+    /// only when it has been down for hours. Transfers to the plot fail until it answers again
+    instance_down_since: Option<i64>,
+}
+
+#[derive(ApiResponse)]
+enum UnregisterResult {
+    /// Nothing was removed yet, send the request again with this token as `confirm`
+    #[oai(status = 202)]
+    ConfirmationRequired(PlainText<String>),
+    /// Confirmation token is wrong or expired, request a new one
+    #[oai(status = 409)]
+    InvalidConfirmation,
+    #[oai(status = 204)]
+    Unregistered,
+}
+
+#[derive(ApiResponse)]
+enum PlotMetaResult {
+    #[oai(status = 200)]
+    Ok(Json<PlotMeta>),
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+enum SetPlotMetaResult {
+    #[oai(status = 400)]
+    InvalidTag(PlainText<String>),
+    #[oai(status = 400)]
+    InvalidWebsite(PlainText<String>),
+    #[oai(status = 200)]
+    Success,
+}
+
+#[derive(ApiResponse)]
+enum SetMemberResult {
+    /// The owner can already do everything
+    #[oai(status = 400)]
+    IsOwner,
+    #[oai(status = 200)]
+    Success,
+}
+
+#[derive(ApiResponse)]
+enum CreateKeyResult {
+    /// The key, it can't be shown again
+    #[oai(status = 200)]
+    Ok(Json<String>),
+    #[oai(status = 400)]
+    InvalidScope(PlainText<String>),
+    #[oai(status = 400)]
+    InvalidAddress(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum DeleteKeyResult {
+    #[oai(status = 204)]
+    Disabled,
+    /// No key of the plot has this id or prefix
+    #[oai(status = 404)]
+    NotFound,
+    /// Several keys share the prefix, use the id instead
+    #[oai(status = 409)]
+    Ambiguous,
+}
+
+#[derive(ApiResponse)]
+enum RotateKeyResult {
+    /// The new key
+    #[oai(status = 200)]
+    Ok(Json<String>),
+    /// `key` is needed unless authenticating with an API key
+    #[oai(status = 400)]
+    MissingKey,
+    /// No key of the plot that wasn't rotated already has this id or prefix
+    #[oai(status = 404)]
+    NotFound,
+    /// Several keys share the prefix, use the id instead
+    #[oai(status = 409)]
+    Ambiguous,
+}
+
+#[derive(ApiResponse)]
+enum RemoveMemberResult {
+    #[oai(status = 204)]
+    Removed,
+    #[oai(status = 404)]
+    NotAMember,
 }
 
 #[derive(ApiResponse)]
@@ -286,11 +1643,32 @@ enum ReplaceInstanceResult {
     /// Invalid key format
     #[oai(status = 400)]
     InvalidKeyFormat(PlainText<String>),
+    /// The new instance couldn't be reached or didn't take the plot's state, nothing changed
+    #[oai(status = 502)]
+    HandoffFailed(PlainText<String>),
     /// Success
     #[oai(status = 200)]
     Success,
 }
 
+#[derive(ApiResponse)]
+enum ImportPlotResult {
+    #[oai(status = 400)]
+    Malformed(PlainText<String>),
+    /// Signature doesn't match the key of the sending instance
+    #[oai(status = 401)]
+    InvalidSignature,
+    /// The plot has to be registered to this instance before it can move here
+    #[oai(status = 404)]
+    PlotNotRegistered,
+    /// The plot is registered here under another owner
+    #[oai(status = 409)]
+    OwnerMismatch,
+    /// How many pending transfers were queued
+    #[oai(status = 200)]
+    Ok(Json<u32>),
+}
+
 #[derive(ApiResponse)]
 enum RegisterResult {
     /// Try again until mojang servers cooperate
@@ -305,16 +1683,121 @@ enum RegisterResult {
     /// Plot already registered
     #[oai(status = 409)]
     PlotAlreadyExists,
+    /// Verification code is wrong, expired or for another plot
+    #[oai(status = 404)]
+    InvalidCode,
     /// Ok
     #[oai(status = 200)]
     Ok,
 }
 
+#[derive(Object)]
+pub struct PlotLookupResponse {
+    /// Like `GET /plot`, lowest plot id first
+    plots: Vec<PlotResponse>,
+    /// Plots that aren't registered
+    missing: Vec<PlotId>,
+}
+
+#[derive(ApiResponse)]
+enum LookupPlotsResult {
+    #[oai(status = 400)]
+    TooManyPlots(PlainText<String>),
+    #[oai(status = 200)]
+    Ok(Json<PlotLookupResponse>),
+}
+
+#[derive(Object)]
+pub struct VerificationRequest {
+    plot_id: PlotId,
+    /// Base64 encoded key of the instance to register to, this instance if missing
+    instance_key: Option<String>,
+}
+
+#[derive(Object)]
+pub struct VerificationCode {
+    code: String,
+    /// Seconds until the code expires
+    expires_in: u64,
+}
+
+#[derive(ApiResponse)]
+enum StartVerificationResult {
+    /// Invalid key format
+    #[oai(status = 400)]
+    InvalidKeyFormat(PlainText<String>),
+    /// Plot already registered
+    #[oai(status = 409)]
+    PlotAlreadyExists,
+    #[oai(status = 200)]
+    Ok(Json<VerificationCode>),
+}
+
+#[derive(Object)]
+pub struct SessionToken {
+    token: String,
+    /// Unix timestamp in seconds
+    expires_at: u64,
+}
+
+#[derive(ApiResponse)]
+enum RegisterInstanceResult {
+    /// Registered, the body is its base64 encoded key
+    #[oai(status = 201)]
+    Registered(PlainText<String>),
+    /// Already registered with this key
+    #[oai(status = 200)]
+    AlreadyRegistered(PlainText<String>),
+    #[oai(status = 400)]
+    InvalidDomain(PlainText<String>),
+    /// The domain or key is this instance's
+    #[oai(status = 400)]
+    InternalDomainUsed,
+    /// The instance didn't answer `/sign` properly
+    #[oai(status = 502)]
+    CannotPingInstance(PlainText<String>),
+    /// The instance key was blocked by the instance operator
+    #[oai(status = 403)]
+    KeyBlocked,
+    /// The instance operator doesn't federate with this instance
+    #[oai(status = 403)]
+    NotAllowed,
+    /// This instance requires a `_dftools` TXT record with the key fingerprint, the body says which
+    #[oai(status = 403)]
+    DnsNotVerified(PlainText<String>),
+    /// The DNS resolver couldn't be asked
+    #[oai(status = 502)]
+    DnsLookupFailed,
+    /// Domain or key are registered already, but not with each other
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum DelegateResult {
+    #[oai(status = 200)]
+    Ok(Json<SessionToken>),
+    #[oai(status = 400)]
+    InvalidScope(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum LoginResult {
+    /// Login code is wrong or expired
+    #[oai(status = 404)]
+    InvalidCode,
+    #[oai(status = 200)]
+    Ok(Json<SessionToken>),
+}
+
 #[derive(ApiResponse)]
 enum PlotFetchResult {
     /// Ok
     #[oai(status = 200)]
-    Ok(PlainText<String>),
+    Ok(Json<PlotResponse>),
+    /// Ok, `domain;base64key` when asked for `text/plain`
+    #[oai(status = 200)]
+    Legacy(PlainText<String>),
     /// Plot not found
     #[oai(status = 404)]
     NotFound,
@@ -323,6 +1806,10 @@ enum PlotFetchResult {
 #[derive(Object)]
 pub struct PlotResponse {
     plot: PlotId,
-    owner: String,
-    instance: String,
+    /// Minecraft uuid of the plot owner
+    owner: Uuid,
+    /// Domain of the instance the plot is registered to
+    domain: String,
+    /// Base64 encoded key of the instance the plot is registered to
+    key: String,
 }
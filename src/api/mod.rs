@@ -1,6 +1,37 @@
+use base64::Engine;
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha256};
+
+use crate::BASE64;
+
+pub mod admin;
 pub mod auth;
 pub mod baton;
+pub mod discovery;
 pub mod instance;
+#[cfg(test)]
+mod tests;
 
 // They cannot be negative, it is just because postgres can return negatives
 pub type PlotId = i32;
+
+/// Decodes a base64 encoded instance key, the error is meant for the response body
+pub fn decode_instance_key(key: &str) -> Result<VerifyingKey, String> {
+    let key = BASE64
+        .decode(key)
+        .map_err(|err| format!("base64 decode: {}", err))?;
+    let key: [u8; 32] = key
+        .as_slice()
+        .try_into()
+        .map_err(|err| format!("{}", err))?;
+    VerifyingKey::from_bytes(&key)
+        .map_err(|err| format!("converting to verify key failed: {}", err))
+}
+
+/// First 16 bytes of the SHA-256 of an instance key, hex encoded
+pub fn key_fingerprint(key: &VerifyingKey) -> String {
+    Sha256::digest(key.as_bytes())[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -0,0 +1,560 @@
+use std::{net::Ipv4Addr, sync::Arc};
+
+use base64::Engine;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use poem::{
+    listener::{Acceptor, Listener, TcpListener},
+    EndpointExt, Route, Server,
+};
+use poem_openapi::OpenApiService;
+use reqwest::{Method, StatusCode};
+
+use crate::{
+    allowlist::DfIps,
+    dfjson::DfJsonLimits,
+    store::{
+        instance::InstanceTier,
+        member::Abilities,
+        mock::{MockStore, MAX_QUEUE_DEPTH, TRANSFER_RATE_LIMIT},
+        ops::AuthStore,
+    },
+    BASE64,
+};
+
+use super::{
+    auth::{AcceptedClients, TrustedProxies},
+    baton::BatonApi,
+    PlotId,
+};
+
+const KEY_A: &str = "dft_aaaaaaaa_secret";
+const KEY_B: &str = "dft_bbbbbbbb_secret";
+
+/// Who a request is sent as
+#[derive(Clone, Copy)]
+enum Caller<'a> {
+    Key(&'a str),
+    /// A player on the plot, sent from DF's address
+    Player(PlotId, &'a str),
+}
+
+struct TestApp {
+    store: Arc<MockStore>,
+    url: String,
+    client: reqwest::Client,
+}
+
+impl TestApp {
+    /// Serves the baton API on localhost, which is DF's address here.
+    /// Plot auth needs the peer address, which only a real connection has
+    async fn new() -> Self {
+        let store = MockStore::new();
+        store.add_plot(1, "alice");
+        store.add_plot(2, "bob");
+        store.add_key(1, KEY_A, None);
+        store.add_key(2, KEY_B, None);
+        let api = OpenApiService::new(
+            BatonApi {
+                store: store.clone(),
+                max_transfer_bytes: 1024,
+                dfjson_limits: DfJsonLimits {
+                    max_depth: 8,
+                    max_nodes: 64,
+                    max_string_bytes: 256,
+                    max_dict_keys: 16,
+                    max_list_entries: 16,
+                    max_string_chars: 256,
+                },
+            },
+            "Baton",
+            "0",
+        );
+        let app = Route::new()
+            .nest("/", api)
+            .data(store.clone() as Arc<dyn AuthStore>)
+            .data(DfIps::new(vec!["127.0.0.1/32"
+                .parse()
+                .expect("Range should parse")]))
+            .data(TrustedProxies::default())
+            .data(AcceptedClients(vec!["*".to_string()]));
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .expect("Should bind localhost");
+        let addr = *acceptor.local_addr()[0]
+            .as_socket_addr()
+            .expect("Should be a socket address");
+        tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+        Self {
+            store,
+            url: format!("http://{addr}"),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        caller: Caller<'_>,
+        body: Option<&str>,
+    ) -> (StatusCode, String) {
+        let req = self
+            .client
+            .request(method, format!("{}{}", self.url, path))
+            .header("Content-Type", "application/json");
+        let req = match caller {
+            Caller::Key(key) => req.header("X-API-Key", key),
+            Caller::Player(plot_id, name) => {
+                req.header("User-Agent", format!("Hypercube/7.2 ({plot_id}, {name})"))
+            }
+        };
+        let req = match body {
+            Some(body) => req.body(body.to_string()),
+            None => req,
+        };
+        let resp = req.send().await.expect("Request should be sent");
+        let status = resp.status();
+        (status, resp.text().await.unwrap_or_default())
+    }
+
+    /// Has plot 2 trust plot 1 so it can send to it
+    async fn trust_a(&self) {
+        let (status, _) = self
+            .call(Method::POST, "/trusted", Caller::Key(KEY_B), Some("[1]"))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Sends a text transfer from plot 1 to plot 2
+    async fn send_to_b(&self, text: &str) -> (StatusCode, String) {
+        let payload = format!(r#"{{"kind": "text", "data": "{text}"}}"#);
+        self.call(
+            Method::POST,
+            "/transfer?dest=2",
+            Caller::Key(KEY_A),
+            Some(&payload),
+        )
+        .await
+    }
+}
+
+/// Id of the transfer in a response body
+fn transfer_id(body: &str) -> String {
+    let value: serde_json::Value = serde_json::from_str(body).expect("Body should be JSON");
+    match value {
+        serde_json::Value::String(id) => id,
+        value => value["id"].as_str().expect("Should have an id").to_string(),
+    }
+}
+
+#[tokio::test]
+async fn unknown_key_is_rejected() {
+    let app = TestApp::new().await;
+    let (status, _) = app
+        .call(
+            Method::GET,
+            "/trusted",
+            Caller::Key("dft_cccccccc_secret"),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(app.store.audit_len(), 1);
+}
+
+#[tokio::test]
+async fn locked_out_address_is_rejected() {
+    let app = TestApp::new().await;
+    app.store.lock_out(Ipv4Addr::LOCALHOST.into(), 30);
+    let (status, body) = app
+        .call(Method::GET, "/trusted", Caller::Key(KEY_A), None)
+        .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(body.contains("30 seconds"));
+}
+
+#[tokio::test]
+async fn restricted_key_is_rejected_elsewhere() {
+    let app = TestApp::new().await;
+    app.store.restrict_key(
+        KEY_A,
+        vec!["10.0.0.0/8".parse().expect("Range should parse")],
+    );
+    let (status, _) = app
+        .call(Method::GET, "/trusted", Caller::Key(KEY_A), None)
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(app.store.audit_len(), 1);
+}
+
+#[tokio::test]
+async fn disabled_plot_is_rejected() {
+    let app = TestApp::new().await;
+    app.store.disable_plot(1);
+    let (status, _) = app
+        .call(Method::GET, "/trusted", Caller::Key(KEY_A), None)
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn disabled_plot_rejects_players() {
+    let app = TestApp::new().await;
+    app.store.disable_plot(1);
+    let (status, body) = app
+        .call(Method::GET, "/trusted", Caller::Player(1, "alice"), None)
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(body.contains("disabled"));
+}
+
+#[tokio::test]
+async fn key_needs_scope() {
+    let app = TestApp::new().await;
+    app.store
+        .add_key(1, "dft_readonly_secret", Some(&["baton:read"]));
+    let readonly = Caller::Key("dft_readonly_secret");
+    let (status, _) = app
+        .call(Method::POST, "/trusted", readonly, Some("[2]"))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = app.call(Method::GET, "/transfer", readonly, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn member_needs_ability() {
+    let app = TestApp::new().await;
+    app.store.add_member(1, "carol", Abilities::default());
+    app.store.add_member(
+        1,
+        "dave",
+        Abilities {
+            edit_trust: true,
+            ..Abilities::default()
+        },
+    );
+    let (status, _) = app
+        .call(Method::DELETE, "/webhook", Caller::Player(1, "carol"), None)
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = app
+        .call(Method::DELETE, "/webhook", Caller::Player(1, "dave"), None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = app
+        .call(Method::GET, "/trusted", Caller::Player(1, "carol"), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app
+        .call(Method::GET, "/trusted", Caller::Player(1, "erin"), None)
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn trust_needs_registered_plots() {
+    let app = TestApp::new().await;
+    let (status, body) = app
+        .call(Method::POST, "/trusted", Caller::Key(KEY_A), Some("[2, 3]"))
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body, "[3]");
+
+    let (status, _) = app
+        .call(Method::POST, "/trusted", Caller::Key(KEY_A), Some("[2]"))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = app
+        .call(Method::GET, "/trusted", Caller::Key(KEY_A), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "[2]");
+}
+
+#[tokio::test]
+async fn transfer_needs_trust() {
+    let app = TestApp::new().await;
+    let payload = r#"{"kind": "text", "data": "hello"}"#;
+    let (status, _) = app
+        .call(
+            Method::POST,
+            "/transfer?dest=2",
+            Caller::Key(KEY_A),
+            Some(payload),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _) = app
+        .call(Method::POST, "/trusted", Caller::Key(KEY_B), Some("[1]"))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app
+        .call(
+            Method::POST,
+            "/transfer?dest=2",
+            Caller::Key(KEY_A),
+            Some(payload),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("hello"));
+    let (status, _) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn peek_leaves_transfer_queued() {
+    let app = TestApp::new().await;
+    app.trust_a().await;
+    app.send_to_b("first").await;
+    app.send_to_b("second").await;
+    for _ in 0..2 {
+        let (status, body) = app
+            .call(Method::GET, "/transfer/peek", Caller::Key(KEY_B), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("first"));
+    }
+    let (_, body) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert!(body.contains("first"));
+    let (_, body) = app
+        .call(Method::GET, "/transfer/peek", Caller::Key(KEY_B), None)
+        .await;
+    assert!(body.contains("second"));
+}
+
+#[tokio::test]
+async fn taken_transfer_can_be_acked() {
+    let app = TestApp::new().await;
+    app.trust_a().await;
+    let (status, body) = app.send_to_b("hello").await;
+    assert_eq!(status, StatusCode::OK);
+    let id = transfer_id(&body);
+    let ack = format!("/transfer/{id}/ack");
+
+    let (status, _) = app.call(Method::POST, &ack, Caller::Key(KEY_B), None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (_, body) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert_eq!(transfer_id(&body), id);
+    // Only the receiving plot can acknowledge it
+    let (status, _) = app.call(Method::POST, &ack, Caller::Key(KEY_A), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = app.call(Method::POST, &ack, Caller::Key(KEY_B), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = app
+        .call(
+            Method::GET,
+            &format!("/transfer/status?id={id}"),
+            Caller::Key(KEY_A),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#""status":"acked""#));
+}
+
+/// Has plot 2 switch to at least once delivery and take a transfer from plot 1, returns its id
+async fn take_at_least_once(app: &TestApp) -> String {
+    app.trust_a().await;
+    let settings = r#"{"mutual_trust": false, "delivery": "at_least_once"}"#;
+    let (status, _) = app
+        .call(Method::PUT, "/settings", Caller::Key(KEY_B), Some(settings))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = app.send_to_b("hello").await;
+    assert_eq!(status, StatusCode::OK);
+    let id = transfer_id(&body);
+    let (_, body) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert_eq!(transfer_id(&body), id);
+    id
+}
+
+#[tokio::test]
+async fn unacked_transfer_is_queued_again() {
+    let app = TestApp::new().await;
+    let id = take_at_least_once(&app).await;
+    app.store.time_out_acks();
+
+    // Too late once it is queued again
+    let ack = format!("/transfer/{id}/ack");
+    let (status, _) = app.call(Method::POST, &ack, Caller::Key(KEY_B), None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, body) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(transfer_id(&body), id);
+    let (status, _) = app.call(Method::POST, &ack, Caller::Key(KEY_B), None).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn acked_transfer_is_not_queued_again() {
+    let app = TestApp::new().await;
+    let id = take_at_least_once(&app).await;
+    let (status, _) = app
+        .call(
+            Method::POST,
+            &format!("/transfer/{id}/ack"),
+            Caller::Key(KEY_B),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    app.store.time_out_acks();
+
+    let (status, _) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn full_queue_rejects_transfers() {
+    let app = TestApp::new().await;
+    app.trust_a().await;
+    for n in 0..MAX_QUEUE_DEPTH {
+        let (status, _) = app.send_to_b(&n.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, _) = app.send_to_b("one too many").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // Taking one makes room again
+    let (status, _) = app
+        .call(Method::GET, "/transfer", Caller::Key(KEY_B), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app.send_to_b("fits").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn styled_text_only_rejects_unmatched_closing_tags() {
+    let app = TestApp::new().await;
+    app.trust_a().await;
+    let app = &app;
+    let send = |text: &'static str| async move {
+        let payload = format!(r#"{{"kind": "dfjson", "data": {{"id": "comp", "val": "{text}"}}}}"#);
+        app.call(
+            Method::POST,
+            "/transfer?dest=2",
+            Caller::Key(KEY_A),
+            Some(&payload),
+        )
+        .await
+        .0
+    };
+    // Shown as plain text in game
+    assert_eq!(send("1 < 2 <sparkle>hi").await, StatusCode::OK);
+    assert_eq!(send("<red>red</red> <#00ff00>green").await, StatusCode::OK);
+    assert_eq!(send("red</red>").await, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn sent_transfers_count_against_quota() {
+    let app = TestApp::new().await;
+    app.trust_a().await;
+    app.send_to_b("hello").await;
+    let (status, body) = app
+        .call(Method::GET, "/quota", Caller::Key(KEY_A), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let quota: serde_json::Value = serde_json::from_str(&body).expect("Body should be JSON");
+    assert_eq!(quota["transfers_remaining"], TRANSFER_RATE_LIMIT - 1);
+}
+
+#[tokio::test]
+async fn reply_goes_back_to_sender() {
+    let app = TestApp::new().await;
+    app.trust_a().await;
+    let (_, body) = app.send_to_b("ping").await;
+    let id = transfer_id(&body);
+    let reply = format!("/transfer/reply?reply_to={id}");
+    let pong = Some(r#"{"kind": "text", "data": "pong"}"#);
+
+    // Plot 1 sent it, only plot 2 can reply
+    let (status, _) = app
+        .call(Method::POST, &reply, Caller::Key(KEY_A), pong)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = app
+        .call(Method::POST, &reply, Caller::Key(KEY_B), pong)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app
+        .call(Method::POST, &reply, Caller::Key(KEY_B), pong)
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, body) = app
+        .call(
+            Method::GET,
+            &format!("/reply?correlation={id}&timeout=1"),
+            Caller::Key(KEY_A),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("pong"));
+}
+
+#[tokio::test]
+async fn instance_trust_needs_trustable_instances() {
+    let app = TestApp::new().await;
+    let open = SigningKey::from_bytes(&[1; 32]).verifying_key();
+    let verified = SigningKey::from_bytes(&[2; 32]).verifying_key();
+    let body = |key: &VerifyingKey| format!(r#"["{}"]"#, BASE64.encode(key));
+
+    let (status, _) = app
+        .call(
+            Method::POST,
+            "/trusted/instances",
+            Caller::Key(KEY_A),
+            Some(&body(&verified)),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    app.store.add_instance(open, InstanceTier::Open);
+    app.store.add_instance(verified, InstanceTier::Verified);
+    let (status, _) = app
+        .call(
+            Method::POST,
+            "/trusted/instances",
+            Caller::Key(KEY_A),
+            Some(&body(&open)),
+        )
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = app
+        .call(
+            Method::POST,
+            "/trusted/instances",
+            Caller::Key(KEY_A),
+            Some(&body(&verified)),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, trusted) = app
+        .call(Method::GET, "/trusted/instances", Caller::Key(KEY_A), None)
+        .await;
+    assert_eq!(trusted, body(&verified));
+}
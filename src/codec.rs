@@ -0,0 +1,119 @@
+use poem::{
+    http::{
+        header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderValue, StatusCode,
+    },
+    Body, Endpoint, IntoResponse, Request, Response,
+};
+use serde_json::Value;
+
+/// Binary formats request and response bodies can use instead of JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Cbor,
+    MessagePack,
+}
+
+impl Format {
+    fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+        if mime.eq_ignore_ascii_case("application/cbor") {
+            Some(Format::Cbor)
+        } else if [
+            "application/msgpack",
+            "application/x-msgpack",
+            "application/vnd.msgpack",
+        ]
+        .iter()
+        .any(|it| mime.eq_ignore_ascii_case(it))
+        {
+            Some(Format::MessagePack)
+        } else {
+            None
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn decode(self, data: &[u8]) -> Result<Value, String> {
+        match self {
+            Format::Cbor => ciborium::from_reader(data).map_err(|err| err.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(data).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn encode(self, value: &Value) -> Vec<u8> {
+        match self {
+            Format::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(value, &mut out).expect("Encoding in memory shouldn't fail");
+                out
+            }
+            // Named so objects stay maps instead of becoming arrays
+            Format::MessagePack => {
+                rmp_serde::to_vec_named(value).expect("Encoding in memory shouldn't fail")
+            }
+        }
+    }
+}
+
+/// Lets clients send CBOR or MessagePack bodies with a matching `Content-Type`
+/// and get JSON responses back in either by asking for them with `Accept`.
+/// Bodies are converted to and from JSON so the API itself only deals in JSON
+pub async fn transcode<E: Endpoint>(ep: E, mut req: Request) -> poem::Result<Response> {
+    let request_format = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Format::from_mime);
+    let response_format = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|accept| accept.split(',').find_map(Format::from_mime));
+
+    if let Some(format) = request_format {
+        let body = req.take_body().into_vec().await?;
+        let value = format.decode(&body).map_err(|err| {
+            poem::Error::from_string(
+                format!("Malformed {} body: {}", format.mime(), err),
+                StatusCode::BAD_REQUEST,
+            )
+        })?;
+        let json = serde_json::to_vec(&value).expect("Value should serialize");
+        req.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        req.headers_mut().remove(CONTENT_LENGTH);
+        req.set_body(Body::from_vec(json));
+    }
+
+    let mut resp = ep.call(req).await?.into_response();
+    let Some(format) = response_format else {
+        return Ok(resp);
+    };
+    let is_json = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|mime| mime.starts_with("application/json"));
+    if !is_json {
+        return Ok(resp);
+    }
+    let body = resp.take_body().into_vec().await?;
+    let Ok(value) = serde_json::from_slice::<Value>(&body) else {
+        resp.set_body(body);
+        return Ok(resp);
+    };
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(format.mime()));
+    resp.headers_mut().remove(CONTENT_LENGTH);
+    resp.set_body(format.encode(&value));
+    Ok(resp)
+}
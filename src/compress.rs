@@ -0,0 +1,115 @@
+use std::io::Read;
+
+use base64::Engine;
+use flate2::read::GzDecoder;
+use poem::{
+    http::{header::CONTENT_ENCODING, StatusCode},
+    Body, Endpoint, IntoResponse, Request, Response,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{api::baton::TransferPayload, BASE64};
+
+/// Content encodings request bodies can use, in order of preference
+pub const ENCODINGS: [&str; 2] = ["zstd", "gzip"];
+
+/// Bodies and stored payloads smaller than this aren't worth compressing
+pub const COMPRESS_THRESHOLD: usize = 1024;
+
+pub fn zstd_compress(data: &[u8]) -> Vec<u8> {
+    zstd::encode_all(data, 0).expect("Compressing in memory shouldn't fail")
+}
+
+/// Decompresses data, failing if it ends up larger than `limit` bytes
+pub fn decompress(encoding: &str, data: &[u8], limit: usize) -> std::io::Result<Vec<u8>> {
+    let reader: Box<dyn Read + '_> = if encoding.eq_ignore_ascii_case("zstd") {
+        Box::new(zstd::Decoder::new(data)?)
+    } else if encoding.eq_ignore_ascii_case("gzip") {
+        Box::new(GzDecoder::new(data))
+    } else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Unsupported content encoding {}", encoding),
+        ));
+    };
+    let mut out = Vec::new();
+    // One byte past the limit tells apart hitting the limit and being over it
+    reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+    if out.len() > limit {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Decompressed body is over {} bytes", limit),
+        ));
+    }
+    Ok(out)
+}
+
+/// Decompresses request bodies with a `Content-Encoding` from [ENCODINGS].
+/// Unlike poem's `Compression`, the decompressed size is capped at `limit`
+/// so a tiny body can't expand into something huge
+pub async fn decompress_body<E: Endpoint>(
+    ep: E,
+    mut req: Request,
+    limit: usize,
+) -> poem::Result<Response> {
+    let encoding = req
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if let Some(encoding) = encoding {
+        let compressed = req.take_body().into_vec().await?;
+        let body = decompress(&encoding, &compressed, limit).map_err(|err| {
+            let status = if err.kind() == std::io::ErrorKind::Unsupported {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            poem::Error::from_string(err.to_string(), status)
+        })?;
+        req.headers_mut().remove(CONTENT_ENCODING);
+        req.set_body(Body::from_vec(body));
+    }
+    Ok(ep.call(req).await?.into_response())
+}
+
+/// Stores large payloads zstd compressed, reads both forms.
+/// Use with `#[serde(with = "crate::compress::stored_payload")]`
+pub mod stored_payload {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Compressed { zstd: String },
+        Plain(TransferPayload),
+    }
+
+    pub fn serialize<S: Serializer>(
+        payload: &TransferPayload,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let json = serde_json::to_vec(payload).map_err(serde::ser::Error::custom)?;
+        if json.len() < COMPRESS_THRESHOLD {
+            return payload.serialize(serializer);
+        }
+        Stored::Compressed {
+            zstd: BASE64.encode(zstd_compress(&json)),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<TransferPayload, D::Error> {
+        match Stored::deserialize(deserializer)? {
+            Stored::Plain(payload) => Ok(payload),
+            Stored::Compressed { zstd } => {
+                let compressed = BASE64.decode(zstd).map_err(serde::de::Error::custom)?;
+                let json =
+                    zstd::decode_all(compressed.as_slice()).map_err(serde::de::Error::custom)?;
+                serde_json::from_slice(&json).map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
@@ -1,17 +1,150 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap};
 
-use poem_openapi::{Object, Union};
+use poem_openapi::{
+    registry::MetaSchemaRef,
+    types::{ParseError, ParseFromJSON, ParseResult, ToJSON, Type},
+    Enum, Object, Union,
+};
 use redis_macros::{FromRedisValue, ToRedisArgs};
-use schemars::JsonSchema;
+use schemars::{
+    r#gen::SchemaGenerator,
+    schema::{RootSchema, Schema},
+    schema_for, JsonSchema,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use sha2::{Digest, Sha256};
 
-#[derive(Serialize, Deserialize, JsonSchema, Union, ToRedisArgs, FromRedisValue)]
+/// DF numbers are fixed point with 3 decimal places, stored as thousandths so they survive round trips.
+/// On the wire it is a plain JSON number, extra decimal places get rounded away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DfFixed(i64);
+
+impl DfFixed {
+    pub const SCALE: i64 = 1000;
+
+    pub fn from_f64(num: f64) -> Result<Self, FixedPointError> {
+        let scaled = (num * Self::SCALE as f64).round();
+        // i64::MAX as f64 rounds up to 2^63, which is already out of range
+        if !scaled.is_finite() || scaled >= i64::MAX as f64 || scaled < i64::MIN as f64 {
+            return Err(FixedPointError::OutOfRange(num));
+        }
+        Ok(Self(scaled as i64))
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / Self::SCALE as f64
+    }
+
+    pub fn thousandths(self) -> i64 {
+        self.0
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FixedPointError {
+    #[error("Number {0} is out of the range DF numbers can hold")]
+    OutOfRange(f64),
+}
+
+impl Serialize for DfFixed {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.to_f64())
+    }
+}
+
+impl<'de> Deserialize<'de> for DfFixed {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        DfFixed::from_f64(f64::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl JsonSchema for DfFixed {
+    fn schema_name() -> String {
+        f64::schema_name()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        f64::json_schema(generator)
+    }
+}
+
+impl Type for DfFixed {
+    const IS_REQUIRED: bool = true;
+    type RawValueType = f64;
+    type RawElementValueType = f64;
+
+    fn name() -> Cow<'static, str> {
+        f64::name()
+    }
+
+    fn schema_ref() -> MetaSchemaRef {
+        f64::schema_ref()
+    }
+
+    fn as_raw_value(&self) -> Option<&Self::RawValueType> {
+        None
+    }
+
+    fn raw_element_iter<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = &'a Self::RawElementValueType> + 'a> {
+        Box::new(std::iter::empty())
+    }
+}
+
+impl ParseFromJSON for DfFixed {
+    fn parse_from_json(value: Option<Value>) -> ParseResult<Self> {
+        DfFixed::from_f64(f64::parse_from_json(value).map_err(ParseError::propagate)?)
+            .map_err(ParseError::custom)
+    }
+}
+
+impl ToJSON for DfFixed {
+    fn to_json(&self) -> Option<Value> {
+        self.to_f64().to_json()
+    }
+}
+
+/// Bumped whenever the shape of [DfJson] changes
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Upgrades DfJson written with schema version `from` to the current version,
+/// so payloads from instances running older versions can still be read
+pub fn migrate(data: &mut Value, from: u32) -> Result<(), String> {
+    if from > SCHEMA_VERSION {
+        return Err(format!(
+            "Schema version {} is newer than the supported version {}",
+            from, SCHEMA_VERSION
+        ));
+    }
+    for migration in MIGRATIONS.iter().skip(from.saturating_sub(1) as usize) {
+        migration(data);
+    }
+    Ok(())
+}
+
+/// The migration at index `i` upgrades version `i + 1` to `i + 2`
+const MIGRATIONS: [fn(&mut Value); SCHEMA_VERSION as usize - 1] = [
+    // 2 added game values, version 1 values are still valid
+    |_| {},
+];
+
+/// JSON schema of [DfJson]
+pub fn schema() -> RootSchema {
+    schema_for!(DfJson)
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Union, ToRedisArgs, FromRedisValue, Clone)]
 #[oai(discriminator_name = "id", rename_all = "snake_case")]
 #[serde(tag = "id")]
 #[serde(rename_all = "snake_case")]
 pub enum DfJson {
     Dict(DfDict),
-    Comp(DfComp),
+    /// Styled text in MiniMessage format, `comp` like DF calls it
+    #[oai(mapping = "comp")]
+    #[serde(rename = "comp")]
+    StyledText(DfStyledText),
     Str(DfString),
     Num(DfNumber),
     Loc(DfLoc),
@@ -20,57 +153,78 @@ pub enum DfJson {
     Particle(DfParticle),
     Potion(DfPotion),
     List(DfList),
+    GameValue(DfGameValue),
     /*
      * TODO: Add item data type
      */
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfList {
     val: Vec<DfJson>,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfNumber {
-    val: f64,
+    val: DfFixed,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfString {
     val: String,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
-pub struct DfComp {
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
+pub struct DfStyledText {
     val: String,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfDict {
     val: HashMap<String, DfJson>,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+/// A reference to a game value, resolved by DF when the code runs
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
+pub struct DfGameValue {
+    /// Name like DF shows it, like `Location` or `Current Health`
+    name: String,
+    target: GameValueTarget,
+}
+#[derive(Serialize, Deserialize, JsonSchema, Enum, Clone, Copy)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum GameValueTarget {
+    Default,
+    Selection,
+    Killer,
+    Damager,
+    Victim,
+    Shooter,
+    Projectile,
+    LastEntity,
+}
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfPotion {
     potion: String,
     duration: f64,
     amplifier: f64,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfParticle {
     particle: String,
     cluster: ParticleCluster,
     data: ParticleData,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfSound {
     sound: String,
     variant: String,
     pitch: f64,
     volume: f64,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfVec {
     x: f64,
     y: f64,
     z: f64,
 }
-#[derive(Serialize, Deserialize, JsonSchema, Object)]
+#[derive(Serialize, Deserialize, JsonSchema, Object, Clone)]
 pub struct DfLoc {
     x: f64,
     y: f64,
@@ -79,7 +233,7 @@ pub struct DfLoc {
     yaw: f64,
 }
 
-#[derive(JsonSchema, Serialize, Deserialize, Object)]
+#[derive(JsonSchema, Serialize, Deserialize, Object, Clone)]
 pub struct ParticleData {
     pub x: Option<f64>,
     pub y: Option<f64>,
@@ -95,7 +249,7 @@ pub struct ParticleData {
     pub opacity: Option<f64>,
 }
 
-#[derive(JsonSchema, Serialize, Deserialize, Object)]
+#[derive(JsonSchema, Serialize, Deserialize, Object, Clone)]
 pub struct ParticleCluster {
     pub horizontal: f64,
     pub vertical: f64,
@@ -120,3 +274,533 @@ pub struct ParticleCluster {
     }
 }
 */
+
+/// Builders, `DfJson::dict().insert("name", "Notch").into()`
+impl DfJson {
+    pub fn dict() -> DfDict {
+        DfDict {
+            val: HashMap::new(),
+        }
+    }
+
+    pub fn list() -> DfList {
+        DfList { val: Vec::new() }
+    }
+
+    pub fn styled_text(text: impl Into<String>) -> DfJson {
+        DfJson::StyledText(DfStyledText { val: text.into() })
+    }
+
+    pub fn vec(x: f64, y: f64, z: f64) -> DfJson {
+        DfJson::Vec(DfVec { x, y, z })
+    }
+
+    pub fn loc(x: f64, y: f64, z: f64, pitch: f64, yaw: f64) -> DfJson {
+        DfJson::Loc(DfLoc {
+            x,
+            y,
+            z,
+            pitch,
+            yaw,
+        })
+    }
+
+    pub fn game_value(name: impl Into<String>, target: GameValueTarget) -> DfJson {
+        DfJson::GameValue(DfGameValue {
+            name: name.into(),
+            target,
+        })
+    }
+}
+
+impl DfDict {
+    pub fn insert(mut self, key: impl Into<String>, value: impl Into<DfJson>) -> Self {
+        self.val.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl DfList {
+    pub fn push(mut self, value: impl Into<DfJson>) -> Self {
+        self.val.push(value.into());
+        self
+    }
+}
+
+impl<T: Into<DfJson>> FromIterator<T> for DfList {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        DfList {
+            val: iter.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<DfDict> for DfJson {
+    fn from(value: DfDict) -> Self {
+        DfJson::Dict(value)
+    }
+}
+
+impl From<DfList> for DfJson {
+    fn from(value: DfList) -> Self {
+        DfJson::List(value)
+    }
+}
+
+impl From<String> for DfJson {
+    fn from(value: String) -> Self {
+        DfJson::Str(DfString { val: value })
+    }
+}
+
+impl From<&str> for DfJson {
+    fn from(value: &str) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl From<DfFixed> for DfJson {
+    fn from(value: DfFixed) -> Self {
+        DfJson::Num(DfNumber { val: value })
+    }
+}
+
+impl From<i32> for DfJson {
+    fn from(value: i32) -> Self {
+        DfFixed(value as i64 * DfFixed::SCALE).into()
+    }
+}
+
+impl TryFrom<f64> for DfJson {
+    type Error = FixedPointError;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        Ok(DfFixed::from_f64(value)?.into())
+    }
+}
+
+impl From<DfJson> for Value {
+    fn from(value: DfJson) -> Self {
+        serde_json::to_value(value).expect("DfJson should serialize")
+    }
+}
+
+impl TryFrom<Value> for DfJson {
+    type Error = serde_json::Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        serde_json::from_value(value)
+    }
+}
+
+impl DfJson {
+    /// Canonical bytes of the value, equal values always encode to the same bytes
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        canonical_json(&serde_json::to_value(self).expect("DfJson should serialize"))
+    }
+
+    /// SHA-256 of the canonical bytes, hex encoded
+    pub fn hash(&self) -> String {
+        hex_digest(&self.canonical_bytes())
+    }
+}
+
+/// Compact JSON with sorted object keys and normalized numbers:
+/// integral numbers drop their fraction (`1.0` is `1`) and `-0` is `0`
+pub fn canonical_json(value: &Value) -> Vec<u8> {
+    serde_json::to_vec(&normalize(value)).expect("Value should serialize")
+}
+
+fn normalize(value: &Value) -> Value {
+    // serde_json::Map is a BTreeMap so keys come out sorted,
+    // as long as the preserve_order feature stays off
+    match value {
+        Value::Number(num) => match num.as_f64() {
+            // Integers above 2^53 can't be told apart as floats, keep them as they are
+            Some(float) if num.is_f64() && float.fract() == 0.0 && float.abs() < 2f64.powi(53) => {
+                Value::Number(Number::from(float as i64))
+            }
+            _ => value.clone(),
+        },
+        Value::Array(values) => Value::Array(values.iter().map(normalize).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), normalize(value)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+pub fn hex_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Limits checked by [DfJson::validate]
+#[derive(Debug, Clone, Copy)]
+pub struct DfJsonLimits {
+    /// Deepest nesting of dicts and lists, a value that isn't nested has a depth of 1
+    pub max_depth: usize,
+    /// Most values in total, counting every dict and list
+    pub max_nodes: usize,
+    /// Longest string in bytes, including dict keys
+    pub max_string_bytes: usize,
+    /// Most keys in a single dict
+    pub max_dict_keys: usize,
+    /// Most entries in a single list
+    pub max_list_entries: usize,
+    /// Longest `str` value in characters
+    pub max_string_chars: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DfJsonViolation {
+    #[error("DfJson at {path} is nested deeper than the limit of {limit}")]
+    TooDeep { path: String, limit: usize },
+    #[error("DfJson has more than {limit} values")]
+    TooManyNodes { limit: usize },
+    #[error("Styled text at {path} is not valid MiniMessage: {reason}")]
+    InvalidStyledText { path: String, reason: String },
+    #[error("String at {path} is {len} bytes, the limit is {limit} bytes")]
+    StringTooLong {
+        path: String,
+        len: usize,
+        limit: usize,
+    },
+    #[error("Dict at {path} has {len} keys, DF dicts hold at most {limit}")]
+    TooManyKeys {
+        path: String,
+        len: usize,
+        limit: usize,
+    },
+    #[error("List at {path} has {len} entries, DF lists hold at most {limit}")]
+    TooManyEntries {
+        path: String,
+        len: usize,
+        limit: usize,
+    },
+    #[error("String at {path} is {len} characters, DF strings hold at most {limit}")]
+    TooManyChars {
+        path: String,
+        len: usize,
+        limit: usize,
+    },
+    #[error("DF can't represent NaN or infinity, found at {}", .paths.join(", "))]
+    NotFinite { paths: Vec<String> },
+}
+
+impl DfJsonViolation {
+    /// Paths of the values breaking the limit, like `$.players[3]`
+    pub fn paths(&self) -> Vec<String> {
+        match self {
+            DfJsonViolation::TooDeep { path, .. }
+            | DfJsonViolation::InvalidStyledText { path, .. }
+            | DfJsonViolation::StringTooLong { path, .. }
+            | DfJsonViolation::TooManyKeys { path, .. }
+            | DfJsonViolation::TooManyEntries { path, .. }
+            | DfJsonViolation::TooManyChars { path, .. } => vec![path.clone()],
+            DfJsonViolation::TooManyNodes { .. } => Vec::new(),
+            DfJsonViolation::NotFinite { paths } => paths.clone(),
+        }
+    }
+}
+
+impl DfJson {
+    /// Checks the value against the limits, without recursing so deep values can't overflow the stack
+    pub fn validate(&self, limits: &DfJsonLimits) -> Result<(), DfJsonViolation> {
+        let check_str = |path: &str, str: &str| {
+            if str.len() > limits.max_string_bytes {
+                Err(DfJsonViolation::StringTooLong {
+                    path: path.to_string(),
+                    len: str.len(),
+                    limit: limits.max_string_bytes,
+                })
+            } else {
+                Ok(())
+            }
+        };
+        let mut not_finite = Vec::new();
+        let mut check_finite = |path: &str, fields: &[(&str, Option<f64>)]| {
+            for (field, num) in fields {
+                if num.is_some_and(|num| !num.is_finite()) {
+                    not_finite.push(format!("{}.{}", path, field));
+                }
+            }
+        };
+        let mut nodes = 0;
+        let mut pending = vec![(self, "$".to_string(), 1)];
+        while let Some((value, path, depth)) = pending.pop() {
+            nodes += 1;
+            if nodes > limits.max_nodes {
+                return Err(DfJsonViolation::TooManyNodes {
+                    limit: limits.max_nodes,
+                });
+            }
+            if depth > limits.max_depth {
+                return Err(DfJsonViolation::TooDeep {
+                    path,
+                    limit: limits.max_depth,
+                });
+            }
+            match value {
+                DfJson::Dict(dict) => {
+                    if dict.val.len() > limits.max_dict_keys {
+                        return Err(DfJsonViolation::TooManyKeys {
+                            path,
+                            len: dict.val.len(),
+                            limit: limits.max_dict_keys,
+                        });
+                    }
+                    for (key, value) in &dict.val {
+                        let path = format!("{}.{}", path, key);
+                        check_str(&path, key)?;
+                        pending.push((value, path, depth + 1));
+                    }
+                }
+                DfJson::List(list) => {
+                    if list.val.len() > limits.max_list_entries {
+                        return Err(DfJsonViolation::TooManyEntries {
+                            path,
+                            len: list.val.len(),
+                            limit: limits.max_list_entries,
+                        });
+                    }
+                    for (i, value) in list.val.iter().enumerate() {
+                        pending.push((value, format!("{}[{}]", path, i), depth + 1));
+                    }
+                }
+                DfJson::Str(DfString { val }) => {
+                    check_str(&path, val)?;
+                    let len = val.chars().count();
+                    if len > limits.max_string_chars {
+                        return Err(DfJsonViolation::TooManyChars {
+                            path,
+                            len,
+                            limit: limits.max_string_chars,
+                        });
+                    }
+                }
+                DfJson::StyledText(DfStyledText { val }) => {
+                    check_str(&path, val)?;
+                    check_minimessage(val)
+                        .map_err(|reason| DfJsonViolation::InvalidStyledText { path, reason })?;
+                }
+                DfJson::Sound(sound) => {
+                    check_str(&path, &sound.sound)?;
+                    check_str(&path, &sound.variant)?;
+                    check_finite(
+                        &path,
+                        &[("pitch", Some(sound.pitch)), ("volume", Some(sound.volume))],
+                    );
+                }
+                DfJson::Particle(particle) => {
+                    check_str(&path, &particle.particle)?;
+                    let (cluster, data) = (&particle.cluster, &particle.data);
+                    check_finite(
+                        &path,
+                        &[
+                            ("cluster.horizontal", Some(cluster.horizontal)),
+                            ("cluster.vertical", Some(cluster.vertical)),
+                            ("cluster.amount", Some(cluster.amount)),
+                            ("data.x", data.x),
+                            ("data.y", data.y),
+                            ("data.z", data.z),
+                            ("data.motion_variation", data.motion_variation),
+                            ("data.size", data.size),
+                            ("data.size_variation", data.size_variation),
+                            ("data.color_variation", data.color_variation),
+                            ("data.roll", data.roll),
+                            ("data.opacity", data.opacity),
+                        ],
+                    );
+                    for str in [
+                        &particle.data.color,
+                        &particle.data.color_fade,
+                        &particle.data.material,
+                    ]
+                    .into_iter()
+                    .flatten()
+                    {
+                        check_str(&path, str)?;
+                    }
+                }
+                DfJson::GameValue(value) => check_str(&path, &value.name)?,
+                DfJson::Potion(potion) => {
+                    check_str(&path, &potion.potion)?;
+                    check_finite(
+                        &path,
+                        &[
+                            ("duration", Some(potion.duration)),
+                            ("amplifier", Some(potion.amplifier)),
+                        ],
+                    );
+                }
+                DfJson::Loc(loc) => check_finite(
+                    &path,
+                    &[
+                        ("x", Some(loc.x)),
+                        ("y", Some(loc.y)),
+                        ("z", Some(loc.z)),
+                        ("pitch", Some(loc.pitch)),
+                        ("yaw", Some(loc.yaw)),
+                    ],
+                ),
+                DfJson::Vec(vec) => check_finite(
+                    &path,
+                    &[("x", Some(vec.x)), ("y", Some(vec.y)), ("z", Some(vec.z))],
+                ),
+                // Fixed point numbers are always finite
+                DfJson::Num(_) => {}
+            }
+        }
+        if !not_finite.is_empty() {
+            return Err(DfJsonViolation::NotFinite { paths: not_finite });
+        }
+        Ok(())
+    }
+}
+
+/// Tags MiniMessage understands, besides `#rrggbb` colors
+const MINIMESSAGE_TAGS: &[&str] = &[
+    // Colors
+    "black",
+    "dark_blue",
+    "dark_green",
+    "dark_aqua",
+    "dark_red",
+    "dark_purple",
+    "gold",
+    "gray",
+    "grey",
+    "dark_gray",
+    "dark_grey",
+    "blue",
+    "green",
+    "aqua",
+    "red",
+    "light_purple",
+    "yellow",
+    "white",
+    "color",
+    "colour",
+    "c",
+    "shadow",
+    // Decorations
+    "bold",
+    "b",
+    "italic",
+    "i",
+    "em",
+    "underlined",
+    "u",
+    "strikethrough",
+    "st",
+    "obfuscated",
+    "obf",
+    // Everything else
+    "reset",
+    "click",
+    "hover",
+    "key",
+    "lang",
+    "tr",
+    "translate",
+    "lang_or",
+    "tr_or",
+    "translate_or",
+    "insert",
+    "insertion",
+    "rainbow",
+    "gradient",
+    "transition",
+    "font",
+    "newline",
+    "br",
+    "selector",
+    "sel",
+    "score",
+    "nbt",
+    "data",
+    "pride",
+    "sprite",
+    "head",
+];
+
+/// Checks that closing tags of known tags close an open tag.
+/// Unknown tags and `<` without a `>` are plain text, MiniMessage shows them as they are.
+/// `\` escapes the next character
+fn check_minimessage(text: &str) -> Result<(), String> {
+    let mut open: Vec<String> = Vec::new();
+    let mut chars = text.char_indices();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '<' => {
+                // Looked ahead on a copy, so text that isn't a tag is read on after the `<`
+                let mut tag_chars = chars.clone();
+                let mut quote = None;
+                let mut escaped = false;
+                let mut end = None;
+                for (i, c) in tag_chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                        continue;
+                    }
+                    match (quote, c) {
+                        (Some(_), '\\') => escaped = true,
+                        (Some(q), c) if c == q => quote = None,
+                        (Some(_), _) => {}
+                        (None, '\'' | '"') => quote = Some(c),
+                        (None, '>') => {
+                            end = Some(i);
+                            break;
+                        }
+                        (None, '<') => break,
+                        (None, _) => {}
+                    }
+                }
+                let Some(end) = end else {
+                    continue;
+                };
+                chars = tag_chars;
+                let tag = &text[start + 1..end];
+                let (closing, tag) = match tag.strip_prefix('/') {
+                    Some(tag) => (true, tag),
+                    None => (false, tag.strip_prefix('!').unwrap_or(tag)),
+                };
+                let self_closing = tag.ends_with('/');
+                let tag = tag.strip_suffix('/').unwrap_or(tag);
+                let name = tag
+                    .split(':')
+                    .next()
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                let is_hex = name.len() == 7
+                    && name.starts_with('#')
+                    && name[1..].bytes().all(|b| b.is_ascii_hexdigit());
+                if !is_hex && !MINIMESSAGE_TAGS.contains(&name.as_str()) {
+                    continue;
+                }
+                if closing {
+                    // Closing a tag also closes every tag opened after it
+                    let Some(pos) = open.iter().rposition(|it| *it == name) else {
+                        return Err(format!(
+                            "Closing tag `{}` at byte {} has no open tag",
+                            name, start
+                        ));
+                    };
+                    open.truncate(pos);
+                } else if !self_closing {
+                    open.push(name);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::str::FromStr;
+
+use base64::Engine;
+use ed25519_dalek::VerifyingKey;
+use serde::Deserialize;
+
+use crate::{instance::InstanceScheme, BASE64};
+
+/// A domain, `*.example.com` for every subdomain of it, or a base64 encoded instance key
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FederationRule {
+    Domain(String),
+    Subdomains(String),
+    Key([u8; 32]),
+}
+
+impl FederationRule {
+    pub fn matches(&self, domain: &str, key: &VerifyingKey) -> bool {
+        match self {
+            Self::Key(rule) => key.as_bytes() == rule,
+            _ => self.matches_domain(domain),
+        }
+    }
+
+    /// Key rules never match, the port of the domain is ignored
+    pub fn matches_domain(&self, domain: &str) -> bool {
+        let host = domain.split_once(':').map_or(domain, |(host, _)| host);
+        match self {
+            Self::Domain(rule) => host.eq_ignore_ascii_case(rule),
+            Self::Subdomains(rule) => host
+                .to_ascii_lowercase()
+                .strip_suffix(rule.as_str())
+                .is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+            Self::Key(_) => false,
+        }
+    }
+}
+
+impl FromStr for FederationRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // Padded base64 ends with `=`, which domains can't have
+        if let Ok(key) = BASE64.decode(s) {
+            return key
+                .as_slice()
+                .try_into()
+                .map(Self::Key)
+                .map_err(|_| format!("{}: instance keys are 32 bytes", s));
+        }
+        let domain = s.to_ascii_lowercase();
+        if domain.is_empty() || domain.contains(['/', ':', '=']) {
+            return Err(format!("{}: expected a domain or base64 instance key", s));
+        }
+        Ok(match domain.strip_prefix("*.") {
+            Some(parent) => Self::Subdomains(parent.to_string()),
+            None => Self::Domain(domain),
+        })
+    }
+}
+
+impl TryFrom<String> for FederationRule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Which instances this one talks to and how, set by the operator
+#[derive(Debug, Clone, Default)]
+pub struct FederationPolicy {
+    /// Only these instances are allowed unless it is empty
+    pub allow: Vec<FederationRule>,
+    /// Never allowed, even if they are in `allow`
+    pub deny: Vec<FederationRule>,
+    /// How instances are reached unless they are in `http`
+    pub scheme: InstanceScheme,
+    /// Reached over plain http, like instances on a local network
+    pub http: Vec<FederationRule>,
+}
+
+impl FederationPolicy {
+    pub fn allows(&self, domain: &str, key: &VerifyingKey) -> bool {
+        if self.deny.iter().any(|rule| rule.matches(domain, key)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(domain, key))
+    }
+
+    pub fn scheme(&self, domain: &str) -> InstanceScheme {
+        if self.http.iter().any(|rule| rule.matches_domain(domain)) {
+            InstanceScheme::Http
+        } else {
+            self.scheme
+        }
+    }
+}
@@ -10,6 +10,24 @@ use serde::{Deserialize, Serialize};
 
 use crate::BASE64;
 
+/// How other instances are reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceScheme {
+    #[default]
+    Https,
+    Http,
+}
+
+impl InstanceScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Https => "https",
+            Self::Http => "http",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Instance {
     pub key: VerifyingKey,
@@ -74,7 +92,7 @@ impl InstanceDomain {
     }
 }
 
-/// Represents an instance domain
+/// Represents an instance domain, optionally with a port like `example.com:8443`
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ExternalDomain(Domain<String>);
 
@@ -93,6 +111,11 @@ impl ExternalDomain {
     pub fn into_inner(self) -> Domain<String> {
         self.0
     }
+    /// The domain without the port
+    pub fn host(&self) -> &str {
+        let domain: &str = self.0.as_inner();
+        domain.split_once(':').map_or(domain, |(host, _)| host)
+    }
     fn convert(str: String) -> Result<Self, DomainErr> {
         let allowed: AllowedAscii<[u8; 38]> = AllowedAscii::try_from_unique_ascii([
             b'-', b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'a', b'b', b'c',
@@ -100,6 +123,15 @@ impl ExternalDomain {
             b'r', b's', b't', b'u', b'v', b'w', b'x', b'y', b'z', b':',
         ])
         .expect("fit all criteria");
+        // A `:` only separates the port, which is 1 to 65535
+        if let Some((_, port)) = str.split_once(':') {
+            if let Some(invalid) = port.bytes().find(|b| !b.is_ascii_digit()) {
+                return Err(DomainErr::InvalidByte(invalid));
+            }
+            if !port.parse::<u16>().is_ok_and(|port| port != 0) {
+                return Err(DomainErr::InvalidByte(b':'));
+            }
+        }
         let domain = Domain::try_from_bytes(str, &allowed)?;
         Ok(ExternalDomain(domain))
     }
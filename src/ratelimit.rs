@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use base64::Engine;
+use poem::{
+    http::{header::RETRY_AFTER, StatusCode},
+    Endpoint, IntoResponse, Request, Response,
+};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    api::auth::{claimed_plot, client_addr},
+    store::Store,
+    BASE64,
+};
+
+/// Requests allowed per minute, 0 turns the limit off
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub per_key: u32,
+    pub per_plot: u32,
+    /// For requests that are neither from an API key nor a plot
+    pub per_ip: u32,
+}
+
+/// Rejects requests over the rate limit with 429 before they reach the endpoint.
+/// Requests are let through if redis can't be reached
+pub async fn rate_limit<E: Endpoint>(
+    ep: E,
+    req: Request,
+    limits: RateLimits,
+) -> poem::Result<Response> {
+    let store: &Arc<Store> = req.data().expect("Store should be there");
+    let (bucket, limit) = bucket(&req, store, limits).await;
+    if limit > 0 {
+        match store.hit_rate_limit(&bucket, limit).await {
+            Ok(Some(retry_after)) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(RETRY_AFTER, retry_after)
+                    .body(format!(
+                        "Rate limit exceeded, retry after {retry_after} seconds"
+                    )));
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Rate limiting {} failed: {:?}", bucket, err),
+        }
+    }
+    Ok(ep.call(req).await?.into_response())
+}
+
+/// Who the request is counted against. API keys get their own bucket once they verify,
+/// so sending made up keys doesn't get around the address limit
+async fn bucket(req: &Request, store: &Store, limits: RateLimits) -> (String, u32) {
+    if let Some(key) = req.header("X-API-Key") {
+        match store.verify_key(key).await {
+            Ok(Some(_)) => {
+                // Raw keys are never stored
+                let key = BASE64.encode(Sha256::digest(key));
+                return (format!("key:{key}"), limits.per_key);
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Verifying key for rate limiting failed: {:?}", err),
+        }
+    }
+    if let Some(plot) = claimed_plot(req) {
+        return (format!("plot:{plot}"), limits.per_plot);
+    }
+    let ip = client_addr(req)
+        .map(|ip| ip.to_string())
+        .unwrap_or_default();
+    (format!("ip:{ip}"), limits.per_ip)
+}
@@ -0,0 +1,88 @@
+use poem::{http::Method, Body, Endpoint, IntoResponse, Request, Response};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::auth::{SIGNATURE_HEADER, SIGNATURE_MAX_SKEW},
+    instance::ExternalDomain,
+};
+
+/// Everything `/instance/v0/sign` signs starts with this, so it can't be made to sign other messages
+pub const CHALLENGE_PREFIX: &str = "DFTOOLS CHALLENGE ";
+/// Longer challenges aren't signed
+pub const MAX_CHALLENGE_LEN: usize = 256;
+/// Seconds a challenge is valid for after it is made
+pub const CHALLENGE_TTL: u64 = 60;
+
+/// SHA-256 of the request body as it was sent, only there for signed requests
+#[derive(Debug, Clone, Copy)]
+pub struct BodyHash(pub [u8; 32]);
+
+/// Hashes the body of requests signed by other instances so their signature can be checked,
+/// has to run before the body gets decompressed or transcoded
+pub async fn hash_body<E: Endpoint>(ep: E, mut req: Request) -> poem::Result<Response> {
+    if req.headers().contains_key(SIGNATURE_HEADER) {
+        let body = req.take_body().into_bytes().await?;
+        req.extensions_mut()
+            .insert(BodyHash(Sha256::digest(&body).into()));
+        req.set_body(Body::from(body));
+    }
+    Ok(ep.call(req).await?.into_response())
+}
+
+/// What an instance signs for `X-Signature`, the path includes the query
+pub fn request_message(
+    method: &Method,
+    path: &str,
+    timestamp: u64,
+    nonce: &str,
+    body_hash: &[u8; 32],
+) -> Vec<u8> {
+    let mut msg = format!(
+        "DFTOOLS REQUEST {} {} {} {}\n",
+        method, path, timestamp, nonce
+    );
+    msg.extend(body_hash.iter().map(|b| format!("{:02x}", b)));
+    msg.into_bytes()
+}
+
+/// What an instance has another one sign to prove it has its key
+pub fn challenge_message(requester: &str, nonce: &str, expires_at: u64) -> String {
+    format!("{}{} {} {}", CHALLENGE_PREFIX, requester, nonce, expires_at)
+}
+
+/// Checks a challenge is well formed and fresh before signing it
+pub fn check_challenge(challenge: &str, now: u64) -> Result<(), String> {
+    if challenge.len() > MAX_CHALLENGE_LEN {
+        return Err(format!(
+            "Challenge is longer than {} bytes",
+            MAX_CHALLENGE_LEN
+        ));
+    }
+    let rest = challenge
+        .strip_prefix(CHALLENGE_PREFIX)
+        .ok_or_else(|| format!("Challenge doesn't start with `{}`", CHALLENGE_PREFIX))?;
+    let [requester, nonce, expires_at] = rest.split(' ').collect::<Vec<_>>()[..] else {
+        return Err("Expected `{requester} {nonce} {expires_at}` after the prefix".to_string());
+    };
+    ExternalDomain::try_from(requester.to_string()).map_err(|err| format!("Requester: {}", err))?;
+    if !(16..=64).contains(&nonce.len())
+        || !nonce
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err("Nonce has to be 16 to 64 letters, digits, `-` or `_`".to_string());
+    }
+    let expires_at: u64 = expires_at
+        .parse()
+        .map_err(|_| "Expiry isn't a unix timestamp".to_string())?;
+    if expires_at.saturating_add(SIGNATURE_MAX_SKEW) < now {
+        return Err("Challenge expired".to_string());
+    }
+    if expires_at > now + CHALLENGE_TTL + SIGNATURE_MAX_SKEW {
+        return Err(format!(
+            "Challenge expires more than {} seconds ahead",
+            CHALLENGE_TTL
+        ));
+    }
+    Ok(())
+}
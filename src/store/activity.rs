@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use poem_openapi::Object;
+use sqlx::query;
+use tracing::warn;
+
+use crate::api::PlotId;
+
+use super::{baton::unix_now, Store};
+
+/// Sorted set of plots by when they were last seen
+const LAST_SEEN_KEY: &str = "activity:last_seen";
+/// Hash of the counters of every plot added up
+const TOTALS_KEY: &str = "activity:totals";
+
+/// Activity
+impl Store {
+    /// Counts an authenticated request by the plot and marks it as seen
+    pub async fn record_request(&self, plot_id: PlotId) -> color_eyre::Result<()> {
+        let now = unix_now();
+        let redis = &self.redis;
+        let key = activity_key(plot_id);
+        let _: () = redis.hincr(&key, "requests", 1).await?;
+        let _: () = redis.hset(&key, "last_seen", now).await?;
+        let _: () = redis.zadd(LAST_SEEN_KEY, plot_id, now).await?;
+        let _: () = redis.hincr(TOTALS_KEY, "requests", 1).await?;
+        Ok(())
+    }
+
+    /// Counts payload bytes the plot sent or received
+    pub async fn record_transfer_bytes(
+        &self,
+        plot_id: PlotId,
+        direction: ActivityDirection,
+        bytes: u64,
+    ) -> color_eyre::Result<()> {
+        let field = match direction {
+            ActivityDirection::Sent => "bytes_sent",
+            ActivityDirection::Received => "bytes_received",
+        };
+        let redis = &self.redis;
+        let _: () = redis
+            .hincr(activity_key(plot_id), field, bytes as i64)
+            .await?;
+        let _: () = redis.hincr(TOTALS_KEY, field, bytes as i64).await?;
+        Ok(())
+    }
+
+    /// Counts whether looking up the plot was served from the cache.
+    /// Done in the background and only logged when it fails, it mustn't get in the way of the lookup
+    pub(super) fn record_plot_cache(&self, plot_id: PlotId, hit: bool) {
+        let field = if hit { "cache_hits" } else { "cache_misses" };
+        let redis = self.redis.clone();
+        tokio::spawn(async move {
+            let res: redis::RedisResult<()> = async {
+                let _: () = redis.hincr(activity_key(plot_id), field, 1).await?;
+                redis.hincr(TOTALS_KEY, field, 1).await
+            }
+            .await;
+            if let Err(err) = res {
+                warn!("Recording plot cache {} failed: {:?}", field, err);
+            }
+        });
+    }
+
+    pub async fn plot_activity(&self, plot_id: PlotId) -> color_eyre::Result<PlotActivity> {
+        let redis = &self.redis;
+        let counters: ActivityCounters = redis.hgetall(activity_key(plot_id)).await?;
+        let last_seen = counters.get("last_seen").copied();
+        Ok(PlotActivity {
+            requests: counters.get("requests").copied().unwrap_or_default(),
+            last_seen,
+            bytes_sent: counters.get("bytes_sent").copied().unwrap_or_default(),
+            bytes_received: counters.get("bytes_received").copied().unwrap_or_default(),
+            cache_hits: counters.get("cache_hits").copied().unwrap_or_default(),
+            cache_misses: counters.get("cache_misses").copied().unwrap_or_default(),
+        })
+    }
+
+    /// Counters of every plot added up, `active_plots` are the ones seen in the last `active_secs`
+    pub async fn activity_summary(&self, active_secs: u64) -> color_eyre::Result<ActivitySummary> {
+        let redis = &self.redis;
+        let totals: ActivityCounters = redis.hgetall(TOTALS_KEY).await?;
+        let active_plots: u64 = redis
+            .zcount(LAST_SEEN_KEY, unix_now().saturating_sub(active_secs)..)
+            .await?;
+        let registered_plots = query!(r#"SELECT COUNT(*) as "count!" FROM plot"#)
+            .fetch_one(&self.pg)
+            .await?
+            .count;
+        Ok(ActivitySummary {
+            registered_plots: registered_plots as u64,
+            active_plots,
+            requests: totals.get("requests").copied().unwrap_or_default(),
+            bytes_sent: totals.get("bytes_sent").copied().unwrap_or_default(),
+            bytes_received: totals.get("bytes_received").copied().unwrap_or_default(),
+            cache_hits: totals.get("cache_hits").copied().unwrap_or_default(),
+            cache_misses: totals.get("cache_misses").copied().unwrap_or_default(),
+        })
+    }
+
+    /// Registered plots not seen in the last `idle_secs`, plots never seen count as idle
+    pub async fn idle_plots(&self, idle_secs: u64, limit: i64) -> color_eyre::Result<Vec<PlotId>> {
+        let redis = &self.redis;
+        let active: Vec<PlotId> = redis
+            .zrangebyscore(LAST_SEEN_KEY, unix_now().saturating_sub(idle_secs)..)
+            .await?;
+        Ok(query!(
+            "SELECT id FROM plot WHERE NOT (id = ANY($1)) ORDER BY id LIMIT $2",
+            &active,
+            limit
+        )
+        .fetch_all(&self.pg)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+    }
+
+    pub(super) async fn delete_activity(&self, plot_id: PlotId) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let _: () = redis.del(activity_key(plot_id)).await?;
+        let _: () = redis.zrem(LAST_SEEN_KEY, plot_id).await?;
+        Ok(())
+    }
+}
+
+fn activity_key(plot_id: PlotId) -> String {
+    format!("plot:{}:activity", plot_id)
+}
+
+type ActivityCounters = HashMap<String, u64>;
+
+pub enum ActivityDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Object)]
+pub struct PlotActivity {
+    /// Authenticated requests made by the plot
+    pub requests: u64,
+    /// Unix timestamp in seconds of the last authenticated request
+    pub last_seen: Option<u64>,
+    /// Payload bytes of transfers the plot sent
+    pub bytes_sent: u64,
+    /// Payload bytes of transfers queued for the plot
+    pub bytes_received: u64,
+    /// Lookups of the plot served from the cache
+    pub cache_hits: u64,
+    /// Lookups of the plot that had to go to the database
+    pub cache_misses: u64,
+}
+
+#[derive(Object)]
+pub struct ActivitySummary {
+    pub registered_plots: u64,
+    /// Plots seen within the asked for time
+    pub active_plots: u64,
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
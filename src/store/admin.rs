@@ -0,0 +1,391 @@
+use base64::Engine;
+use ed25519_dalek::VerifyingKey;
+use poem_openapi::{Enum, Object};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::{
+    api::{auth::JWT_EXPIRY, key_fingerprint, PlotId},
+    BASE64,
+};
+
+use super::{
+    baton::unix_now,
+    breaker::breaker_keys,
+    discovery_key,
+    history::from_text,
+    instance::{tier_key, InstanceTier},
+    invalidate::Invalidation,
+    relay::RelayJob,
+    Store,
+};
+
+/// Ids of disabled plots, checked on every authenticated request
+pub(super) const DISABLED_PLOTS_KEY: &str = "plots:disabled";
+/// Base64 instance keys that can't get or use server tokens
+const BLOCKED_KEYS_KEY: &str = "server:blocked_keys";
+
+/// Instance operation
+impl Store {
+    /// Disables a plot, returns false if it isn't registered
+    pub async fn disable_plot(
+        &self,
+        plot_id: PlotId,
+        reason: Option<&str>,
+    ) -> color_eyre::Result<bool> {
+        let updated = query!(
+            "UPDATE plot SET disabled_at = NOW(), disabled_reason = $2 WHERE id = $1",
+            plot_id,
+            reason
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(false);
+        }
+        let _: () = self.redis.sadd(DISABLED_PLOTS_KEY, plot_id).await?;
+        self.invalidate(Invalidation::DisabledPlots).await?;
+        Ok(true)
+    }
+
+    /// Lets a disabled plot authenticate again, returns false if it wasn't disabled
+    pub async fn enable_plot(&self, plot_id: PlotId) -> color_eyre::Result<bool> {
+        let updated = query!(
+            "UPDATE plot SET disabled_at = NULL, disabled_reason = NULL
+            WHERE id = $1 AND disabled_at IS NOT NULL",
+            plot_id
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        let _: () = self.redis.srem(DISABLED_PLOTS_KEY, plot_id).await?;
+        self.invalidate(Invalidation::DisabledPlots).await?;
+        Ok(updated > 0)
+    }
+
+    pub async fn is_plot_disabled(&self, plot_id: PlotId) -> color_eyre::Result<bool> {
+        Ok(self
+            .disabled_plots
+            .read()
+            .expect("Disabled plots shouldn't be poisoned")
+            .contains(&plot_id))
+    }
+
+    /// Copies the disabled plots from postgres to redis, meant to be called on startup
+    pub async fn sync_disabled_plots(&self) -> color_eyre::Result<()> {
+        let disabled: Vec<PlotId> = query!("SELECT id FROM plot WHERE disabled_at IS NOT NULL")
+            .fetch_all(&self.pg)
+            .await?
+            .into_iter()
+            .map(|row| row.id)
+            .collect();
+        let _: () = self.redis.sreplace(DISABLED_PLOTS_KEY, disabled).await?;
+        self.invalidate(Invalidation::DisabledPlots).await
+    }
+
+    /// Drops everything cached about a plot, it is looked up again on its next use
+    pub async fn invalidate_plot(&self, plot_id: PlotId) -> color_eyre::Result<()> {
+        self.invalidate_plot_cache(plot_id).await?;
+        let _: () = self.redis.del(format!("plot:{}:webhook", plot_id)).await?;
+        Ok(())
+    }
+
+    /// Rejects a server token before it expires
+    pub async fn revoke_server_token(&self, jti: Uuid) -> color_eyre::Result<()> {
+        let _: () = self
+            .redis
+            .set_ex(format!("server:{}:revoked", jti), true, JWT_EXPIRY)
+            .await?;
+        Ok(())
+    }
+
+    /// Rejects every server token issued to the domain so far
+    pub async fn revoke_instance_tokens(&self, domain: &str) -> color_eyre::Result<()> {
+        let _: () = self
+            .redis
+            .set_ex(
+                format!("server:{}:revoked_before", domain.to_ascii_lowercase()),
+                unix_now(),
+                JWT_EXPIRY,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Rejects every token of an instance key and refuses to issue new ones until unblocked,
+    /// returns false if it was already blocked
+    pub async fn block_instance_key(&self, key: &VerifyingKey) -> color_eyre::Result<bool> {
+        let added: u32 = self
+            .redis
+            .sadd(BLOCKED_KEYS_KEY, BASE64.encode(key.as_bytes()))
+            .await?;
+        Ok(added > 0)
+    }
+
+    /// Returns false if the key wasn't blocked
+    pub async fn unblock_instance_key(&self, key: &VerifyingKey) -> color_eyre::Result<bool> {
+        let removed: u32 = self
+            .redis
+            .srem(BLOCKED_KEYS_KEY, BASE64.encode(key.as_bytes()))
+            .await?;
+        Ok(removed > 0)
+    }
+
+    /// Base64 encoded
+    pub async fn blocked_instance_keys(&self) -> color_eyre::Result<Vec<String>> {
+        let mut keys: Vec<String> = self.redis.smembers(BLOCKED_KEYS_KEY).await?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// `key` is base64 encoded
+    pub async fn is_instance_key_blocked(&self, key: &str) -> color_eyre::Result<bool> {
+        Ok(self.redis.sismember(BLOCKED_KEYS_KEY, key).await?)
+    }
+
+    /// Whether the token was revoked by itself, along with every token of its domain
+    /// or its instance key is blocked
+    pub async fn is_server_token_revoked(
+        &self,
+        jti: Uuid,
+        domain: &str,
+        key: &str,
+        issued_at: u64,
+    ) -> color_eyre::Result<bool> {
+        let (revoked, revoked_before): (Option<bool>, Option<u64>) = self
+            .redis
+            .mget(&[
+                format!("server:{}:revoked", jti),
+                format!("server:{}:revoked_before", domain.to_ascii_lowercase()),
+            ])
+            .await?;
+        let blocked: bool = self.redis.sismember(BLOCKED_KEYS_KEY, key).await?;
+        Ok(revoked.unwrap_or(false)
+            || revoked_before.is_some_and(|before| issued_at <= before)
+            || blocked)
+    }
+
+    /// Forgets a known instance, returns None if it isn't known.
+    /// Its plots are moved to this instance or disabled, trust in it is dropped,
+    /// its server tokens are revoked and relays waiting on it fail.
+    /// Its key is blocked too if `block` is set
+    pub async fn remove_instance(
+        &self,
+        domain: &str,
+        plots: RemovedInstancePlots,
+        block: bool,
+    ) -> color_eyre::Result<Option<RemovedInstance>> {
+        let mut tx = self.pg.begin().await?;
+        let Some(instance) = query!(
+            "SELECT id, domain, public_key FROM known_instance WHERE LOWER(domain) = LOWER($1) FOR UPDATE",
+            domain
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let reason = format!("Instance {} was removed", instance.domain);
+        let moved: Vec<PlotId> = query!(
+            "UPDATE plot SET
+                instance = NULL,
+                disabled_at = CASE WHEN $2 THEN COALESCE(disabled_at, NOW()) ELSE disabled_at END,
+                disabled_reason = CASE WHEN $2 THEN COALESCE(disabled_reason, $3) ELSE disabled_reason END
+            WHERE instance = $1
+            RETURNING id",
+            instance.id,
+            plots == RemovedInstancePlots::Orphan,
+            reason
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        let trusting: Vec<PlotId> = query!(
+            "DELETE FROM baton_instance_trust WHERE instance = $1 RETURNING plot",
+            instance.id
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| row.plot)
+        .collect();
+        query!("DELETE FROM known_instance WHERE id = $1", instance.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        if plots == RemovedInstancePlots::Orphan && !moved.is_empty() {
+            let _: () = self.redis.sadd(DISABLED_PLOTS_KEY, &moved).await?;
+            self.invalidate(Invalidation::DisabledPlots).await?;
+        }
+        for plot_id in moved.iter().chain(&trusting) {
+            self.invalidate_plot_cache(*plot_id).await?;
+        }
+        self.revoke_instance_tokens(&instance.domain).await?;
+        let _: () = self
+            .redis
+            .del_many(&[
+                format!("instance:{}:encodings", instance.domain),
+                format!("instance:{}:token", instance.domain),
+                discovery_key(&instance.domain),
+                tier_key(&instance.domain),
+            ])
+            .await?;
+        let _: () = self.redis.del_many(&breaker_keys(&instance.domain)).await?;
+        self.invalidate_instance_key(&instance.domain).await?;
+        let dropped_relays = self.drop_relays(&instance.domain, &reason).await?;
+        let key = VerifyingKey::from_bytes(instance.public_key.as_slice().try_into()?)?;
+        if block {
+            self.block_instance_key(&key).await?;
+        }
+        Ok(Some(RemovedInstance {
+            domain: instance.domain,
+            key_fingerprint: key_fingerprint(&key),
+            plots: moved,
+            dropped_relays,
+        }))
+    }
+
+    /// Known instances with how many plots are registered to them and how many relays wait on them
+    pub async fn federation_status(&self) -> color_eyre::Result<Vec<FederatedInstance>> {
+        let rows = query!(
+            r#"SELECT
+                known_instance.domain,
+                known_instance.public_key,
+                known_instance.tier,
+                (SELECT COUNT(*) FROM plot WHERE plot.instance = known_instance.id) as "plots!"
+            FROM known_instance
+            ORDER BY known_instance.domain"#
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        let relays = self.pending_relays().await?;
+        rows.into_iter()
+            .map(|row| {
+                let key =
+                    ed25519_dalek::VerifyingKey::from_bytes(row.public_key.as_slice().try_into()?)?;
+                let waiting: Vec<&RelayJob> = relays
+                    .iter()
+                    .filter(|job| {
+                        job.domain
+                            .inner()
+                            .as_inner()
+                            .eq_ignore_ascii_case(&row.domain)
+                    })
+                    .collect();
+                Ok(FederatedInstance {
+                    key_fingerprint: key_fingerprint(&key),
+                    tier: from_text(&row.tier)?,
+                    domain: row.domain,
+                    plots: row.plots as u32,
+                    pending_relays: waiting.len() as u32,
+                    last_error: waiting
+                        .iter()
+                        .filter(|job| job.last_error.is_some())
+                        .max_by_key(|job| job.attempts)
+                        .and_then(|job| job.last_error.clone()),
+                })
+            })
+            .collect()
+    }
+
+    /// Sizes of the background queues
+    pub async fn queue_stats(&self) -> color_eyre::Result<QueueStats> {
+        let now = unix_now();
+        let redis = &self.redis;
+        Ok(QueueStats {
+            relays: redis.zcard("relay:pending").await?,
+            due_relays: redis.zcount("relay:pending", ..=now).await?,
+            webhooks: redis.zcard("webhook:pending").await?,
+            scheduled: redis.zcard("transfer:scheduled").await?,
+        })
+    }
+
+    /// Transfers waiting in the plot's queue, oldest first, without their payload
+    pub async fn inspect_queue(&self, plot_id: PlotId) -> color_eyre::Result<Vec<QueuedSummary>> {
+        let queued: Vec<super::baton::QueuedTransfer> = self
+            .redis
+            .lrange(format!("plot:{}:transfer", plot_id), 0, -1)
+            .await?;
+        Ok(queued
+            .into_iter()
+            .map(|transfer| QueuedSummary {
+                id: transfer.id,
+                from: transfer.from,
+                expires_at: transfer.expires_at,
+                received_at: transfer.received_at,
+            })
+            .collect())
+    }
+
+    async fn pending_relays(&self) -> color_eyre::Result<Vec<RelayJob>> {
+        let ids: Vec<String> = self.redis.zrange("relay:pending", 0, -1).await?;
+        let mut jobs = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(job) = self.get_relay(id.parse()?).await? {
+                jobs.push(job);
+            }
+        }
+        Ok(jobs)
+    }
+}
+
+#[derive(Debug, Object)]
+pub struct FederatedInstance {
+    pub domain: String,
+    /// First 16 bytes of the SHA-256 of the instance key, hex encoded
+    pub key_fingerprint: String,
+    pub tier: InstanceTier,
+    /// Plots registered to the instance
+    pub plots: u32,
+    /// Transfers waiting to be relayed to the instance
+    pub pending_relays: u32,
+    /// Error of the relay that failed the most times, if any failed
+    pub last_error: Option<String>,
+}
+
+/// What happens to the plots of a removed instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[oai(rename_all = "snake_case")]
+pub enum RemovedInstancePlots {
+    /// Registered to this instance instead
+    Current,
+    /// Registered to this instance but disabled until an admin enables them
+    Orphan,
+}
+
+#[derive(Debug, Object)]
+pub struct RemovedInstance {
+    pub domain: String,
+    /// First 16 bytes of the SHA-256 of the instance key, hex encoded
+    pub key_fingerprint: String,
+    /// Plots that were registered to the instance
+    pub plots: Vec<PlotId>,
+    /// Relays to the instance that were waiting and now failed
+    pub dropped_relays: u32,
+}
+
+#[derive(Debug, Object)]
+pub struct QueueStats {
+    /// Transfers waiting to be relayed to other instances
+    pub relays: u64,
+    /// Relays that are due, a growing number means the worker can't keep up
+    pub due_relays: u64,
+    /// Webhook deliveries waiting
+    pub webhooks: u64,
+    /// Transfers waiting for their `deliver_at`
+    pub scheduled: u64,
+}
+
+#[derive(Debug, Object)]
+pub struct QueuedSummary {
+    pub id: Uuid,
+    pub from: PlotId,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+    /// Unix timestamp in seconds
+    pub received_at: u64,
+}
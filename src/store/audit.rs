@@ -0,0 +1,126 @@
+use std::net::IpAddr;
+
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use tracing::error;
+
+use crate::api::PlotId;
+
+use super::{
+    history::{from_text, to_text},
+    Store,
+};
+
+/// Something security relevant that happened to a plot or the instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    KeyCreated,
+    KeyRotated,
+    KeyDisabled,
+    AllKeysDisabled,
+    /// An invalid key, or a key used from an address it isn't allowed from
+    KeyRejected,
+    TrustChanged,
+    InstanceTrustChanged,
+    PlotRegistered,
+    PlotUnregistered,
+    PlotMoved,
+    PlotImported,
+    ServerTokenIssued,
+    InstanceRegistered,
+    InstanceRemoved,
+    InstanceTierChanged,
+}
+
+pub struct AuditEntry {
+    /// None for events that aren't about a single plot
+    pub plot: Option<PlotId>,
+    pub event: AuditEvent,
+    /// Who did it, like `player:{uuid}`, `key:{id}`, `instance:{domain}` or `admin`
+    pub actor: String,
+    pub ip: Option<IpAddr>,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Object)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub plot: Option<PlotId>,
+    pub event: AuditEvent,
+    pub actor: String,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+/// Audit log
+impl Store {
+    /// Records the event, failing to is only logged so it never fails the request
+    pub async fn audit(&self, entry: AuditEntry) {
+        let event = entry.event;
+        if let Err(err) = self.insert_audit(entry).await {
+            error!("Recording {:?} failed: {:?}", event, err);
+        }
+    }
+
+    async fn insert_audit(&self, entry: AuditEntry) -> color_eyre::Result<()> {
+        query!(
+            "INSERT INTO audit_log (plot, event, actor, ip, detail) VALUES ($1, $2, $3, $4, $5)",
+            entry.plot,
+            to_text(entry.event)?,
+            entry.actor,
+            entry.ip.map(|ip| ip.to_string()),
+            entry.detail
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+
+    /// Newest first, `before` is the id of the last record of the previous page
+    pub async fn audit_log(
+        &self,
+        plot: Option<PlotId>,
+        before: Option<i64>,
+        limit: u32,
+    ) -> color_eyre::Result<Vec<AuditRecord>> {
+        let rows = query!(
+            r#"SELECT
+                id,
+                plot,
+                event,
+                actor,
+                ip,
+                detail,
+                EXTRACT(EPOCH FROM created_at)::BIGINT as "created_at!"
+            FROM audit_log
+            WHERE
+                ($1::INTEGER IS NULL OR plot = $1)
+                AND ($2::BIGINT IS NULL OR id < $2)
+            ORDER BY id DESC
+            LIMIT $3"#,
+            plot,
+            before,
+            limit as i64
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(AuditRecord {
+                    id: row.id,
+                    plot: row.plot,
+                    event: from_text(&row.event)?,
+                    actor: row.actor,
+                    ip: row.ip,
+                    detail: row.detail,
+                    created_at: row.created_at,
+                })
+            })
+            .collect()
+    }
+}
@@ -1,45 +1,120 @@
-use redis::AsyncCommands;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_dalek::VerifyingKey;
+use futures::{stream, stream::BoxStream, StreamExt};
+use poem_openapi::{Enum, Object};
 use redis_macros::{FromRedisValue, ToRedisArgs};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as};
+use sqlx::query;
+use tracing::error;
+use uuid::Uuid;
 
-use crate::{api::PlotId, dfjson::DfJson};
+use crate::api::{
+    baton::{PayloadKind, TransferPayload, TransferStatus},
+    PlotId,
+};
 
-use super::Store;
+use super::{
+    activity::ActivityDirection,
+    cache::BoundedPush,
+    history::{from_text, to_text},
+    instance::InstanceTier,
+    invalidate::Invalidation,
+    Store,
+};
 
 #[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
 pub struct TrustVec(Vec<PlotId>);
 
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+pub struct InstanceTrustVec(Vec<VerifyingKey>);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToRedisArgs, FromRedisValue, Object)]
+pub struct BatonSettings {
+    /// Only accept transfers from plots that this plot trusts back
+    pub mutual_trust: bool,
+    /// Missing in settings cached or exported before it existed
+    #[serde(default)]
+    #[oai(default)]
+    pub delivery: DeliveryGuarantee,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryGuarantee {
+    /// Taking a transfer removes it for good, it is lost if the plot never handles it
+    #[default]
+    AtMostOnce,
+    /// Taken transfers are queued again unless they are acked within `ACK_TIMEOUT` seconds
+    AtLeastOnce,
+}
+
+/// Sequence counters of sender and receiver pairs restart after being unused this long
+const SEQ_TTL: u64 = 60 * 60 * 24 * 7;
+/// Queued transfers carry this until the push hands out their sequence number.
+/// `seq` is the first field that can hold it, `id` and `from` come before it and are too short
+const SEQ_PLACEHOLDER: u64 = u64::MAX;
+/// Status records outlive the transfer so senders can find out what happened
+const TRANSFER_RECORD_TTL: u64 = 60 * 60 * 24;
+
+fn transfer_record_key(id: Uuid) -> String {
+    format!("transfer:{}", id)
+}
+
 /// Baton
 impl Store {
+    /// Origin of transfers sent by plots registered to this instance
+    pub fn local_origin(&self, sent_at: u64) -> Origin {
+        Origin {
+            domain: self.domain.as_inner().clone(),
+            key: self.public_key,
+            sent_at,
+        }
+    }
+
+    /// Plots trusted by the plot, lapsed trust is left out
     pub async fn fetch_plot_trust(&self, plot: PlotId) -> color_eyre::Result<Vec<PlotId>> {
-        let mut redis = self.redis.clone();
+        if let Some(trusts) = self.local_plot_trust.get(&plot) {
+            return Ok(trusts);
+        }
+        let redis = &self.redis;
         let attempt: Option<TrustVec> = redis.get(format!("plot:{}:baton_trust", plot)).await?;
-        Ok(if let Some(trusts) = attempt {
+        let trusts = if let Some(trusts) = attempt {
             trusts.0
         } else {
-            struct TrustRow {
-                trusted: PlotId,
-            }
-            let trusts: Vec<PlotId> = query_as!(
-                TrustRow,
-                "SELECT trusted FROM baton_trust WHERE plot = $1;",
+            let rows = query!(
+                r#"SELECT trusted, EXTRACT(EPOCH FROM expires_at)::BIGINT as expires_at
+                FROM baton_trust
+                WHERE plot = $1 AND (expires_at IS NULL OR expires_at > NOW() AT TIME ZONE 'UTC');"#,
                 plot
             )
             .fetch_all(&self.pg)
-            .await?
-            .into_iter()
-            .map(|it| it.trusted)
-            .collect();
-
-            let trusts = TrustVec(trusts);
+            .await?;
+            // The cache can't outlive the first trust to lapse
+            let lapses = rows.iter().filter_map(|it| it.expires_at).min();
+            let trusts = TrustVec(rows.into_iter().map(|it| it.trusted).collect());
 
-            let _: () = redis
-                .set(format!("plot:{}:baton_trust", plot), &trusts)
-                .await?;
+            let key = format!("plot:{}:baton_trust", plot);
+            match lapses {
+                Some(at) => {
+                    let ttl = (at as u64).saturating_sub(unix_now()).max(1);
+                    let _: () = redis.set_ex(key, &trusts, ttl).await?;
+                }
+                None => {
+                    let _: () = redis.set(key, &trusts).await?;
+                }
+            }
             trusts.0
-        })
+        };
+        self.local_plot_trust.insert(plot, trusts.clone());
+        Ok(trusts)
     }
+
     pub async fn set_plot_trust(
         &self,
         plot_id: PlotId,
@@ -53,7 +128,7 @@ impl Store {
             return Ok(Err(PlotTrustSetError::PlotNotFound));
         }
 
-        query!("DELETE FROM baton_trust WHERE id = $1", plot_id)
+        query!("DELETE FROM baton_trust WHERE plot = $1", plot_id)
             .execute(&mut *tx)
             .await?;
 
@@ -73,23 +148,613 @@ impl Store {
         Ok(Ok(()))
     }
 
-    async fn invalidate_trust_cache(&self, plot_id: PlotId) -> color_eyre::Result<()> {
-        let mut redis = self.redis.clone();
+    /// Trusts a single plot until `expires_at` or forever, returns whether it wasn't trusted already.
+    /// Trusting an already trusted plot replaces its expiry
+    pub async fn add_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+        expires_at: Option<u64>,
+    ) -> color_eyre::Result<Result<bool, PlotTrustSetError>> {
+        if !self.plot_exists(plot_id).await? {
+            return Ok(Err(PlotTrustSetError::PlotNotFound));
+        }
+        // Kept in UTC whatever the session time zone is, `EXTRACT(EPOCH FROM ...)` reads it as UTC
+        let added = query!(
+            r#"INSERT INTO baton_trust (plot, trusted, expires_at)
+            VALUES ($1, $2, to_timestamp($3::BIGINT) AT TIME ZONE 'UTC')
+            ON CONFLICT (plot, trusted) DO UPDATE SET expires_at = EXCLUDED.expires_at
+            RETURNING (xmax = 0) as "inserted!""#,
+            plot_id,
+            trusted,
+            expires_at.map(|it| it as i64)
+        )
+        .fetch_one(&self.pg)
+        .await?
+        .inserted;
+        self.invalidate_trust_cache(plot_id).await?;
+        Ok(Ok(added))
+    }
+
+    /// Deletes lapsed trust forever, meant to be spawned once
+    pub async fn trust_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.prune_trust().await {
+                error!("Pruning lapsed trust failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    async fn prune_trust(&self) -> color_eyre::Result<()> {
+        let plots = query!(
+            "DELETE FROM baton_trust WHERE expires_at <= NOW() AT TIME ZONE 'UTC' RETURNING plot"
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        for plot in plots {
+            self.invalidate_trust_cache(plot.plot).await?;
+        }
+        Ok(())
+    }
+
+    /// Stops trusting a single plot, returns whether it was trusted
+    pub async fn remove_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+    ) -> color_eyre::Result<bool> {
+        let removed = query!(
+            "DELETE FROM baton_trust WHERE plot = $1 AND trusted = $2",
+            plot_id,
+            trusted
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        self.invalidate_trust_cache(plot_id).await?;
+        Ok(removed != 0)
+    }
+
+    pub async fn get_baton_settings(&self, plot_id: PlotId) -> color_eyre::Result<BatonSettings> {
+        let redis = &self.redis;
+        let key = format!("plot:{}:baton_settings", plot_id);
+        if let Some(settings) = redis.get(&key).await? {
+            return Ok(settings);
+        }
+        let settings = match query!(
+            "SELECT mutual_trust, delivery FROM baton_settings WHERE plot = $1",
+            plot_id
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        {
+            Some(row) => BatonSettings {
+                mutual_trust: row.mutual_trust,
+                delivery: from_text(&row.delivery)?,
+            },
+            None => BatonSettings::default(),
+        };
+        let _: () = redis.set(key, &settings).await?;
+        Ok(settings)
+    }
+
+    pub async fn set_baton_settings(
+        &self,
+        plot_id: PlotId,
+        settings: &BatonSettings,
+    ) -> color_eyre::Result<Result<(), PlotTrustSetError>> {
+        if !self.plot_exists(plot_id).await? {
+            return Ok(Err(PlotTrustSetError::PlotNotFound));
+        }
+        query!(
+            "INSERT INTO baton_settings (plot, mutual_trust, delivery) VALUES ($1, $2, $3)
+            ON CONFLICT (plot) DO UPDATE
+            SET mutual_trust = EXCLUDED.mutual_trust, delivery = EXCLUDED.delivery",
+            plot_id,
+            settings.mutual_trust,
+            to_text(settings.delivery)?
+        )
+        .execute(&self.pg)
+        .await?;
+        let redis = &self.redis;
+        let _: () = redis
+            .del(format!("plot:{}:baton_settings", plot_id))
+            .await?;
+        Ok(Ok(()))
+    }
+
+    /// Instances, by their key, whose plots are all trusted
+    pub async fn fetch_instance_trust(
+        &self,
+        plot: PlotId,
+    ) -> color_eyre::Result<Vec<VerifyingKey>> {
+        if let Some(trusts) = self.local_instance_trust.get(&plot) {
+            return Ok(trusts);
+        }
+        let redis = &self.redis;
+        let attempt: Option<InstanceTrustVec> = redis
+            .get(format!("plot:{}:baton_instance_trust", plot))
+            .await?;
+        if let Some(trusts) = attempt {
+            self.local_instance_trust.insert(plot, trusts.0.clone());
+            return Ok(trusts.0);
+        }
+        let trusts = query!(
+            "SELECT known_instance.public_key FROM baton_instance_trust
+            JOIN known_instance ON known_instance.id = baton_instance_trust.instance
+            WHERE baton_instance_trust.plot = $1;",
+            plot
+        )
+        .fetch_all(&self.pg)
+        .await?
+        .into_iter()
+        .map(|it| -> color_eyre::Result<VerifyingKey> {
+            Ok(VerifyingKey::from_bytes(
+                it.public_key.as_slice().try_into()?,
+            )?)
+        })
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+
+        let trusts = InstanceTrustVec(trusts);
+        let _: () = redis
+            .set(format!("plot:{}:baton_instance_trust", plot), &trusts)
+            .await?;
+        self.local_instance_trust.insert(plot, trusts.0.clone());
+        Ok(trusts.0)
+    }
+
+    /// Replaces the trusted instances of a plot
+    pub async fn set_instance_trust(
+        &self,
+        plot_id: PlotId,
+        trusts: Vec<VerifyingKey>,
+    ) -> color_eyre::Result<Result<(), InstanceTrustSetError>> {
+        let mut tx = self.pg.begin().await?;
+        let affected = query!("SELECT id FROM plot WHERE id = $1", plot_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if affected.is_none() {
+            return Ok(Err(InstanceTrustSetError::PlotNotFound));
+        }
+
+        let keys: Vec<Vec<u8>> = trusts.iter().map(|it| it.as_bytes().to_vec()).collect();
+        let found = query!(
+            "SELECT id, public_key, tier FROM known_instance WHERE public_key = ANY($1)",
+            &keys
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let missing: Vec<VerifyingKey> = trusts
+            .into_iter()
+            .filter(|key| !found.iter().any(|it| it.public_key == key.as_bytes()))
+            .collect();
+        if !missing.is_empty() {
+            return Ok(Err(InstanceTrustSetError::InstanceNotFound(missing)));
+        }
+        let mut untrustable = Vec::new();
+        for row in &found {
+            if !from_text::<InstanceTier>(&row.tier)?.allows_instance_trust() {
+                untrustable.push(VerifyingKey::from_bytes(
+                    row.public_key.as_slice().try_into()?,
+                )?);
+            }
+        }
+        if !untrustable.is_empty() {
+            return Ok(Err(InstanceTrustSetError::NotTrustable(untrustable)));
+        }
+
+        query!("DELETE FROM baton_instance_trust WHERE plot = $1", plot_id)
+            .execute(&mut *tx)
+            .await?;
+        for instance in found {
+            query!(
+                "INSERT INTO baton_instance_trust (plot, instance) VALUES ($1, $2)
+                ON CONFLICT (plot, instance) DO NOTHING",
+                plot_id,
+                instance.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        self.invalidate_trust_cache(plot_id).await?;
+        Ok(Ok(()))
+    }
+
+    pub(super) async fn invalidate_trust_cache(&self, plot_id: PlotId) -> color_eyre::Result<()> {
+        let redis = &self.redis;
         let _: () = redis.del(format!("plot:{}:baton_trust", plot_id)).await?;
+        let _: () = redis
+            .del(format!("plot:{}:baton_instance_trust", plot_id))
+            .await?;
+        self.invalidate(Invalidation::Plot(plot_id)).await
+    }
+
+    /// Appends a transfer to the end of the plot's queue, returns the id of the transfer
+    pub async fn enqueue_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        origin: Origin,
+    ) -> color_eyre::Result<Result<Uuid, TransferQueueError>> {
+        self.push_transfer(Uuid::new_v4(), from, plot_id, payload, origin)
+            .await
+    }
+
+    /// Appends a transfer with a known id to the end of the plot's queue
+    pub(super) async fn push_transfer(
+        &self,
+        id: Uuid,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        origin: Origin,
+    ) -> color_eyre::Result<Result<Uuid, TransferQueueError>> {
+        let redis = &self.redis;
+        let key = format!("plot:{}:transfer", plot_id);
+        let now = unix_now();
+        let expires_at = now + self.baton.transfer_ttl;
+        let size = serde_json::to_vec(&payload)?.len() as u64;
+        // Logged first so a quick take can't update the log before it exists
+        self.log_transfer(
+            id,
+            from,
+            plot_id,
+            &payload,
+            TransferStatus::Queued,
+            expires_at,
+        )
+        .await?;
+        let transfer = QueuedTransfer {
+            id,
+            from,
+            seq: SEQ_PLACEHOLDER,
+            payload,
+            expires_at,
+            origin: Some(origin),
+            received_at: now,
+        };
+        // The record is written with the push, a take right after it can't be overwritten
+        let record = TransferRecord {
+            from,
+            to: plot_id,
+            status: TransferStatus::Queued,
+            expires_at,
+        };
+        let pushed = redis
+            .push_bounded(BoundedPush {
+                list: &key,
+                max: self.baton.max_queue_depth,
+                now,
+                value: &serde_json::to_string(&transfer)?,
+                placeholder: &SEQ_PLACEHOLDER.to_string(),
+                seq_key: &format!("plot:{}:seq:{}", plot_id, from),
+                seq_ttl: SEQ_TTL,
+                record_key: &transfer_record_key(id),
+                record: &serde_json::to_string(&record)?,
+                record_ttl: TRANSFER_RECORD_TTL,
+            })
+            .await?;
+        if pushed.is_none() {
+            self.log_transfer_status(id, TransferStatus::Failed).await?;
+            return Ok(Err(TransferQueueError::QueueFull));
+        }
+        let _: () = redis
+            .publish(format!("plot:{}:transfer:notify", plot_id), id.to_string())
+            .await?;
+        self.record_transfer_bytes(plot_id, ActivityDirection::Received, size)
+            .await?;
+        if self.get_webhook(plot_id).await?.is_some() {
+            self.queue_webhook_delivery(plot_id, id).await?;
+        }
+        Ok(Ok(id))
+    }
+
+    /// Returns the oldest unexpired transfer in the plot's queue without removing it
+    pub async fn peek_transfer(
+        &self,
+        plot_id: PlotId,
+    ) -> color_eyre::Result<Option<QueuedTransfer>> {
+        let redis = &self.redis;
+        let queued: Vec<QueuedTransfer> = redis
+            .lrange(format!("plot:{}:transfer", plot_id), 0, -1)
+            .await?;
+        let now = unix_now();
+        Ok(queued.into_iter().find(|it| it.expires_at > now))
+    }
+
+    /// Removes and returns the oldest unexpired transfer in the plot's queue.
+    /// If `kind` is set, the oldest transfer of that kind gets taken.
+    /// If `in_order` is set, transfers are skipped while one with a lower sequence number
+    /// from the same sender is still queued.
+    /// Expired transfers it comes across get dropped.
+    /// With at-least-once delivery it is queued again if it isn't acked in time
+    pub async fn take_transfer(
+        &self,
+        plot_id: PlotId,
+        kind: Option<PayloadKind>,
+        in_order: bool,
+    ) -> color_eyre::Result<Option<QueuedTransfer>> {
+        let redis = &self.redis;
+        let key = format!("plot:{}:transfer", plot_id);
+        let queued: Vec<String> = redis.lrange(&key, 0, -1).await?;
+        let now = unix_now();
+        let mut live = Vec::with_capacity(queued.len());
+        for raw in queued {
+            let transfer: QueuedTransfer = serde_json::from_str(&raw)?;
+            if transfer.expires_at <= now {
+                let _: () = redis.lrem(&key, 1, &raw).await?;
+                continue;
+            }
+            live.push((raw, transfer));
+        }
+        // Lowest queued sequence number of each sender
+        let mut next_seq = HashMap::new();
+        if in_order {
+            for (_, transfer) in &live {
+                next_seq
+                    .entry(transfer.from)
+                    .and_modify(|seq: &mut u64| *seq = (*seq).min(transfer.seq))
+                    .or_insert(transfer.seq);
+            }
+        }
+        for (raw, transfer) in live {
+            if in_order && next_seq.get(&transfer.from) != Some(&transfer.seq) {
+                continue;
+            }
+            if kind.is_some_and(|kind| transfer.payload.kind() != kind) {
+                continue;
+            }
+            // Someone else could have taken it in the meantime
+            let removed: usize = redis.lrem(&key, 1, &raw).await?;
+            if removed == 1 {
+                self.deliver_transfer(plot_id, &transfer).await?;
+                return Ok(Some(transfer));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns a queued transfer by its id without removing it
+    pub async fn find_transfer(
+        &self,
+        plot_id: PlotId,
+        id: Uuid,
+    ) -> color_eyre::Result<Option<QueuedTransfer>> {
+        let redis = &self.redis;
+        let queued: Vec<QueuedTransfer> = redis
+            .lrange(format!("plot:{}:transfer", plot_id), 0, -1)
+            .await?;
+        let now = unix_now();
+        Ok(queued
+            .into_iter()
+            .find(|it| it.id == id && it.expires_at > now))
+    }
+
+    /// Removes a queued transfer by its id, marking it as delivered
+    pub async fn remove_transfer(&self, plot_id: PlotId, id: Uuid) -> color_eyre::Result<bool> {
+        let redis = &self.redis;
+        let key = format!("plot:{}:transfer", plot_id);
+        let queued: Vec<String> = redis.lrange(&key, 0, -1).await?;
+        for raw in queued {
+            let transfer: QueuedTransfer = serde_json::from_str(&raw)?;
+            if transfer.id != id {
+                continue;
+            }
+            let removed: usize = redis.lrem(&key, 1, &raw).await?;
+            if removed == 1 {
+                self.update_transfer_status(id, TransferStatus::Delivered)
+                    .await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Streams transfers of a plot as they arrive, starting with the ones already queued.
+    /// Streamed transfers are taken from the queue
+    pub async fn stream_transfers(
+        self: Arc<Self>,
+        plot_id: PlotId,
+        in_order: bool,
+    ) -> color_eyre::Result<BoxStream<'static, QueuedTransfer>> {
+        let notified = self
+            .redis
+            .subscribe(format!("plot:{}:transfer:notify", plot_id))
+            .await?;
+        let notifications = stream::once(async {}).chain(notified.map(|_| ()));
+        Ok(notifications
+            .then(move |()| {
+                let store = self.clone();
+                async move { store.drain_transfers(plot_id, in_order).await }
+            })
+            .flat_map(stream::iter)
+            .boxed())
+    }
+
+    async fn drain_transfers(&self, plot_id: PlotId, in_order: bool) -> Vec<QueuedTransfer> {
+        let mut taken = Vec::new();
+        loop {
+            match self.take_transfer(plot_id, None, in_order).await {
+                Ok(Some(transfer)) => taken.push(transfer),
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Taking transfer for stream failed: {:?}", err);
+                    break;
+                }
+            }
+        }
+        taken
+    }
+
+    /// Fetches the status record of a transfer
+    pub async fn get_transfer_record(
+        &self,
+        id: Uuid,
+    ) -> color_eyre::Result<Option<TransferRecord>> {
+        let redis = &self.redis;
+        let record: Option<TransferRecord> = redis.get(transfer_record_key(id)).await?;
+        Ok(record.map(|mut it| {
+            if it.status == TransferStatus::Queued && it.expires_at <= unix_now() {
+                it.status = TransferStatus::Expired;
+            }
+            it
+        }))
+    }
+
+    /// Acknowledges a delivered transfer on behalf of the plot that received it
+    pub async fn ack_transfer(
+        &self,
+        plot_id: PlotId,
+        id: Uuid,
+    ) -> color_eyre::Result<Result<(), TransferAckError>> {
+        let record = match self.get_transfer_record(id).await? {
+            Some(it) if it.to == plot_id => it,
+            _ => return Ok(Err(TransferAckError::NotFound)),
+        };
+        match record.status {
+            TransferStatus::Delivered => {}
+            TransferStatus::Acked => return Ok(Ok(())),
+            _ => return Ok(Err(TransferAckError::NotDelivered)),
+        }
+        // Deleted first, if it was already queued again this ack came too late
+        if !self.forget_unacked(id).await?
+            && self.get_baton_settings(plot_id).await?.delivery == DeliveryGuarantee::AtLeastOnce
+        {
+            return Ok(Err(TransferAckError::NotDelivered));
+        }
+        self.update_transfer_status(id, TransferStatus::Acked)
+            .await?;
+        Ok(Ok(()))
+    }
+
+    pub(super) async fn update_transfer_status(
+        &self,
+        id: Uuid,
+        status: TransferStatus,
+    ) -> color_eyre::Result<()> {
+        if let Some(mut record) = self.get_transfer_record(id).await? {
+            record.status = status;
+            self.set_transfer_record(id, &record).await?;
+        }
+        self.log_transfer_status(id, status).await?;
         Ok(())
     }
 
-    pub async fn set_transfer(&self, plot_id: PlotId, payload: DfJson) -> color_eyre::Result<()> {
-        let mut redis = self.redis.clone();
+    pub(super) async fn set_transfer_record(
+        &self,
+        id: Uuid,
+        record: &TransferRecord,
+    ) -> color_eyre::Result<()> {
+        let redis = &self.redis;
         let _: () = redis
-            .set_ex(format!("plot:{}:transfer", plot_id), payload, 10)
+            .set_ex(transfer_record_key(id), record, TRANSFER_RECORD_TTL)
             .await?;
         Ok(())
     }
 }
 
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+pub struct TransferRecord {
+    pub from: PlotId,
+    pub to: PlotId,
+    pub status: TransferStatus,
+    /// When the transfer expires if it is never taken, unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+pub struct QueuedTransfer {
+    pub id: Uuid,
+    /// Transfers queued before senders were tracked have 0
+    #[serde(default)]
+    pub from: PlotId,
+    /// Counts up per sender and receiver pair, starting at 1.
+    /// 0 for replies and transfers queued before sequencing
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(with = "crate::compress::stored_payload")]
+    pub payload: TransferPayload,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+    /// Missing for transfers queued before origins were tracked
+    #[serde(default)]
+    pub origin: Option<Origin>,
+    /// When this instance queued it, unix timestamp in seconds
+    #[serde(default)]
+    pub received_at: u64,
+}
+
+/// Where a transfer comes from
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Origin {
+    /// Domain of the instance the sending plot is registered to
+    pub domain: String,
+    /// Key of the instance the sending plot is registered to
+    pub key: VerifyingKey,
+    /// When the sending plot sent it, unix timestamp in seconds
+    pub sent_at: u64,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+pub struct BatonConfig {
+    /// Maximum amount of transfers queued for a single plot
+    pub max_queue_depth: usize,
+    /// Seconds a transfer stays queued before it expires
+    pub transfer_ttl: u64,
+    /// Seconds a plot with at-least-once delivery has to ack a taken transfer before it is queued again
+    pub ack_timeout: u64,
+    /// Attempts at relaying a transfer to another instance before giving up
+    pub relay_max_attempts: u32,
+    /// Seconds to wait after the first failed relay attempt, doubles every attempt
+    pub relay_backoff: u64,
+    /// Transfers a plot can send per minute
+    pub transfer_rate_limit: u32,
+    /// Payload bytes a plot can send per hour
+    pub transfer_byte_quota: u64,
+    /// Days transfers are kept in the history
+    pub history_days: u32,
+    /// Whether the history keeps payloads
+    pub history_payloads: bool,
+    /// Seconds an instance fails every ping before transfers to its plots fail right away
+    pub instance_down_after: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransferQueueError {
+    #[error("Transfer queue of the plot is full")]
+    QueueFull,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransferAckError {
+    #[error("Transfer not found")]
+    NotFound,
+    #[error("Transfer has not been delivered")]
+    NotDelivered,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PlotTrustSetError {
     #[error("Plot not found")]
     PlotNotFound,
 }
+
+#[derive(Debug, thiserror::Error)]
+pub enum InstanceTrustSetError {
+    #[error("Plot not found")]
+    PlotNotFound,
+    #[error("Instances not registered")]
+    InstanceNotFound(Vec<VerifyingKey>),
+    #[error("Instances can't be trusted as a whole")]
+    NotTrustable(Vec<VerifyingKey>),
+}
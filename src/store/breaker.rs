@@ -0,0 +1,94 @@
+use poem_openapi::Enum;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::Store;
+
+/// Failed calls in a row that open the breaker of an instance
+const BREAKER_THRESHOLD: u32 = 5;
+/// Failures older than this are forgotten
+const BREAKER_WINDOW: u64 = 60 * 5;
+/// Seconds an open breaker fails calls right away before letting one through
+const BREAKER_COOLDOWN: u64 = 60;
+
+/// Circuit breaker of calls to other instances, so a dead instance fails fast
+/// instead of every request waiting for it to time out
+impl Store {
+    /// Whether a call to the instance should be attempted.
+    /// Once the cooldown of an open breaker is over a single call is let through to try it
+    pub(super) async fn breaker_allows(&self, domain: &str) -> color_eyre::Result<bool> {
+        let [open_key, failures_key, probe_key] = breaker_keys(domain);
+        let open: bool = self.redis.exists(open_key).await?;
+        let failures: Option<u32> = self.redis.get(failures_key).await?;
+        if open {
+            return Ok(false);
+        }
+        if failures.unwrap_or(0) < BREAKER_THRESHOLD {
+            return Ok(true);
+        }
+        Ok(self
+            .redis
+            .set_nx_ex(probe_key, true, BREAKER_COOLDOWN)
+            .await?)
+    }
+
+    /// Closes the breaker after a call that reached the instance, or counts a failed one
+    pub(super) async fn breaker_record(&self, domain: &str, reached: bool) {
+        if let Err(err) = self.update_breaker(domain, reached).await {
+            error!("Updating the breaker of {} failed: {:?}", domain, err);
+        }
+    }
+
+    async fn update_breaker(&self, domain: &str, reached: bool) -> color_eyre::Result<()> {
+        let [open_key, failures_key, probe_key] = breaker_keys(domain);
+        let redis = &self.redis;
+        if reached {
+            let _: () = redis.del_many(&[open_key, failures_key, probe_key]).await?;
+            return Ok(());
+        }
+        let failures: u32 = redis.incr_ex(&failures_key, 1, BREAKER_WINDOW).await?;
+        if failures >= BREAKER_THRESHOLD {
+            if failures == BREAKER_THRESHOLD {
+                warn!("Breaker of {} opened after {} failures", domain, failures);
+            }
+            let _: () = redis.set_ex(open_key, true, BREAKER_COOLDOWN).await?;
+            let _: () = redis.del(probe_key).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn breaker_state(&self, domain: &str) -> color_eyre::Result<BreakerState> {
+        let [open_key, failures_key, _] = breaker_keys(domain);
+        let open: bool = self.redis.exists(open_key).await?;
+        let failures: Option<u32> = self.redis.get(failures_key).await?;
+        Ok(if open {
+            BreakerState::Open
+        } else if failures.unwrap_or(0) >= BREAKER_THRESHOLD {
+            BreakerState::HalfOpen
+        } else {
+            BreakerState::Closed
+        })
+    }
+}
+
+/// Keys of the breaker of an instance: whether it is open, recent failures and the trial call
+pub(super) fn breaker_keys(domain: &str) -> [String; 3] {
+    let domain = domain.to_ascii_lowercase();
+    [
+        format!("instance:{}:breaker:open", domain),
+        format!("instance:{}:breaker:failures", domain),
+        format!("instance:{}:breaker:probe", domain),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Calls go through
+    Closed,
+    /// The instance failed too often, calls fail right away
+    Open,
+    /// The cooldown is over, the next call decides whether it closes or opens again
+    HalfOpen,
+}
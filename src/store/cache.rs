@@ -0,0 +1,1969 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ops::{Bound, Range, RangeBounds},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, BoxFuture},
+    stream,
+    stream::BoxStream,
+    StreamExt,
+};
+use redis::{
+    aio::MultiplexedConnection, AsyncCommands, ErrorKind, ExistenceCheck, FromRedisValue,
+    RedisError, RedisResult, Script, SetExpiry, SetOptions, ToRedisArgs, Value,
+};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// How often expired keys of the in-process cache are swept out
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages a subscriber of the in-process cache can fall behind by before missing some
+const PUBLISH_BUFFER: usize = 1024;
+
+/// Drops the entries of the list at KEYS[1] whose `expires_at` is ARGV[2] or earlier,
+/// leaves the latest `expires_at` left in `newest` and returns false if ARGV[1] are left
+const DROP_EXPIRED: &str = r"
+        local now = tonumber(ARGV[2])
+        local newest = now
+        for _, entry in ipairs(redis.call('LRANGE', KEYS[1], 0, -1)) do
+            local expires_at = cjson.decode(entry).expires_at
+            if expires_at <= now then
+                redis.call('LREM', KEYS[1], 1, entry)
+            else
+                newest = math.max(newest, expires_at)
+            end
+        end
+        if redis.call('LLEN', KEYS[1]) >= tonumber(ARGV[1]) then
+            return false
+        end
+";
+
+/// See [CacheBackend::push_bounded]
+static PUSH_BOUNDED: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(&format!(
+        r"{DROP_EXPIRED}
+        local seq = redis.call('INCR', KEYS[3])
+        redis.call('EXPIRE', KEYS[3], ARGV[5])
+        local at = string.find(ARGV[3], ARGV[4], 1, true)
+        local value = string.sub(ARGV[3], 1, at - 1) .. seq .. string.sub(ARGV[3], at + #ARGV[4])
+        redis.call('SET', KEYS[2], ARGV[6], 'EX', ARGV[7])
+        local len = redis.call('RPUSH', KEYS[1], value)
+        newest = math.max(newest, cjson.decode(value).expires_at)
+        redis.call('EXPIRE', KEYS[1], newest - now)
+        return len
+        ",
+    ))
+});
+
+/// See [CacheBackend::requeue_bounded]
+static REQUEUE_BOUNDED: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(&format!(
+        r"{DROP_EXPIRED}
+        local len = redis.call('LPUSH', KEYS[1], ARGV[3])
+        newest = math.max(newest, cjson.decode(ARGV[3]).expires_at)
+        redis.call('EXPIRE', KEYS[1], newest - now)
+        return len
+        ",
+    ))
+});
+
+/// See [CacheBackend::move_member]
+static MOVE_MEMBER: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+            return 0
+        end
+        redis.call('ZADD', KEYS[2], ARGV[2], ARGV[1])
+        return 1
+        ",
+    )
+});
+
+/// Where short lived state like queues, rate limits and cached lookups is kept, postgres stays the source of truth.
+/// Redis lets several processes share it, the in-process cache spares single process instances from running redis.
+/// Values go in and come out with the same conversions as redis commands, so stored types work with both
+#[derive(Clone)]
+pub struct Cache(Arc<dyn CacheBackend>);
+
+impl Cache {
+    pub async fn redis(client: redis::Client) -> RedisResult<Self> {
+        Ok(Self(Arc::new(RedisCache {
+            connection: client.get_multiplexed_async_connection().await?,
+            client,
+        })))
+    }
+
+    pub fn memory() -> Self {
+        Self(Arc::new(MemoryCache::new()))
+    }
+
+    pub async fn get<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.get(key.as_ref()).await?)
+    }
+
+    pub async fn mget<K: AsRef<str>, RV: FromRedisValue>(&self, keys: &[K]) -> RedisResult<RV> {
+        convert(self.0.mget(&owned(keys)).await?)
+    }
+
+    pub async fn set<K: AsRef<str>, V: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        value: V,
+    ) -> RedisResult<RV> {
+        convert(self.0.set(key.as_ref(), arg(value)?, None).await?)
+    }
+
+    pub async fn set_ex<K: AsRef<str>, V: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        value: V,
+        seconds: u64,
+    ) -> RedisResult<RV> {
+        convert(self.0.set(key.as_ref(), arg(value)?, Some(seconds)).await?)
+    }
+
+    /// Whether it was set, only missing keys are
+    pub async fn set_nx_ex<K: AsRef<str>, V: ToRedisArgs>(
+        &self,
+        key: K,
+        value: V,
+        seconds: u64,
+    ) -> RedisResult<bool> {
+        self.0.set_nx(key.as_ref(), arg(value)?, seconds).await
+    }
+
+    pub async fn set_multiple<K: AsRef<str>, V: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        entries: &[(K, V)],
+    ) -> RedisResult<RV> {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| Ok((key.as_ref().to_string(), arg(value)?)))
+            .collect::<RedisResult<_>>()?;
+        convert(self.0.set_multiple(entries).await?)
+    }
+
+    pub async fn get_del<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.get_del(key.as_ref()).await?)
+    }
+
+    pub async fn del<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.del(&[key.as_ref().to_string()]).await?)
+    }
+
+    pub async fn del_many<K: AsRef<str>, RV: FromRedisValue>(&self, keys: &[K]) -> RedisResult<RV> {
+        convert(self.0.del(&owned(keys)).await?)
+    }
+
+    pub async fn exists<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.exists(key.as_ref()).await?)
+    }
+
+    pub async fn expire<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        seconds: i64,
+    ) -> RedisResult<RV> {
+        convert(self.0.expire(key.as_ref(), seconds).await?)
+    }
+
+    pub async fn ttl<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.ttl(key.as_ref()).await?)
+    }
+
+    pub async fn incr<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        by: i64,
+    ) -> RedisResult<RV> {
+        convert(self.0.incr(key.as_ref(), by).await?)
+    }
+
+    /// Increments the counter and has it expire in `seconds` in one step, returns the new count
+    pub async fn incr_ex<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        by: i64,
+        seconds: u64,
+    ) -> RedisResult<RV> {
+        convert(self.0.incr_ex(key.as_ref(), by, seconds).await?)
+    }
+
+    pub async fn hget<K: AsRef<str>, F: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        field: F,
+    ) -> RedisResult<RV> {
+        convert(self.0.hget(key.as_ref(), arg(field)?).await?)
+    }
+
+    pub async fn hgetall<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.hgetall(key.as_ref()).await?)
+    }
+
+    pub async fn hset<K: AsRef<str>, F: ToRedisArgs, V: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        field: F,
+        value: V,
+    ) -> RedisResult<RV> {
+        convert(self.0.hset(key.as_ref(), arg(field)?, arg(value)?).await?)
+    }
+
+    pub async fn hincr<K: AsRef<str>, F: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        field: F,
+        by: i64,
+    ) -> RedisResult<RV> {
+        convert(self.0.hincr(key.as_ref(), arg(field)?, by).await?)
+    }
+
+    /// Replaces every field of the hash in one step, readers never see it half written
+    pub async fn hreplace<K: AsRef<str>, F: ToRedisArgs, V: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        fields: &[(F, V)],
+    ) -> RedisResult<RV> {
+        let fields = fields
+            .iter()
+            .map(|(field, value)| Ok((arg(field)?, arg(value)?)))
+            .collect::<RedisResult<_>>()?;
+        convert(self.0.hreplace(key.as_ref(), fields).await?)
+    }
+
+    /// Returns the whole hash and deletes it in one step, so no field set in between is lost
+    pub async fn htake<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.htake(key.as_ref()).await?)
+    }
+
+    pub async fn lpush<K: AsRef<str>, V: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        value: V,
+    ) -> RedisResult<RV> {
+        convert(self.0.lpush(key.as_ref(), arg(value)?).await?)
+    }
+
+    pub async fn lrange<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        start: isize,
+        stop: isize,
+    ) -> RedisResult<RV> {
+        convert(self.0.lrange(key.as_ref(), start, stop).await?)
+    }
+
+    pub async fn lrem<K: AsRef<str>, V: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        count: isize,
+        value: V,
+    ) -> RedisResult<RV> {
+        convert(self.0.lrem(key.as_ref(), count, arg(value)?).await?)
+    }
+
+    pub async fn ltrim<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        start: isize,
+        stop: isize,
+    ) -> RedisResult<RV> {
+        convert(self.0.ltrim(key.as_ref(), start, stop).await?)
+    }
+
+    /// Appends the entry to its list and sets its record in one step, see [BoundedPush].
+    /// Returns the new length, None if the list was full
+    pub async fn push_bounded(&self, push: BoundedPush<'_>) -> RedisResult<Option<usize>> {
+        self.0.push_bounded(push).await
+    }
+
+    /// Puts an entry taken from a list pushed with [Cache::push_bounded] back at its front,
+    /// unless `max` unexpired entries are left. Returns the new length, None if the list was full
+    pub async fn requeue_bounded(
+        &self,
+        list: &str,
+        max: usize,
+        now: u64,
+        value: &str,
+    ) -> RedisResult<Option<usize>> {
+        self.0.requeue_bounded(list, max, now, value).await
+    }
+
+    /// Adds every one of `members`, a list adds each of its items
+    pub async fn sadd<K: AsRef<str>, M: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        members: M,
+    ) -> RedisResult<RV> {
+        convert(self.0.sadd(key.as_ref(), members.to_redis_args()).await?)
+    }
+
+    pub async fn srem<K: AsRef<str>, M: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        members: M,
+    ) -> RedisResult<RV> {
+        convert(self.0.srem(key.as_ref(), members.to_redis_args()).await?)
+    }
+
+    pub async fn smembers<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.smembers(key.as_ref()).await?)
+    }
+
+    pub async fn sismember<K: AsRef<str>, M: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        member: M,
+    ) -> RedisResult<RV> {
+        convert(self.0.sismember(key.as_ref(), arg(member)?).await?)
+    }
+
+    /// Replaces every member of the set in one step, readers never see it half written
+    pub async fn sreplace<K: AsRef<str>, M: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        members: M,
+    ) -> RedisResult<RV> {
+        convert(
+            self.0
+                .sreplace(key.as_ref(), members.to_redis_args())
+                .await?,
+        )
+    }
+
+    pub async fn zadd<K: AsRef<str>, M: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        member: M,
+        score: u64,
+    ) -> RedisResult<RV> {
+        convert(self.0.zadd(key.as_ref(), arg(member)?, score).await?)
+    }
+
+    pub async fn zrem<K: AsRef<str>, M: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        member: M,
+    ) -> RedisResult<RV> {
+        convert(self.0.zrem(key.as_ref(), arg(member)?).await?)
+    }
+
+    pub async fn zscore<K: AsRef<str>, M: ToRedisArgs, RV: FromRedisValue>(
+        &self,
+        key: K,
+        member: M,
+    ) -> RedisResult<RV> {
+        convert(self.0.zscore(key.as_ref(), arg(member)?).await?)
+    }
+
+    /// Members from rank `start` to `stop` inclusive, negative ones count from the end
+    pub async fn zrange<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        start: isize,
+        stop: isize,
+    ) -> RedisResult<RV> {
+        convert(self.0.zrange(key.as_ref(), start, stop).await?)
+    }
+
+    /// Members with a score in `scores`, lowest first
+    pub async fn zrangebyscore<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        scores: impl RangeBounds<u64>,
+    ) -> RedisResult<RV> {
+        let (min, max) = inclusive(scores);
+        convert(self.0.zrangebyscore(key.as_ref(), min, max).await?)
+    }
+
+    pub async fn zcard<K: AsRef<str>, RV: FromRedisValue>(&self, key: K) -> RedisResult<RV> {
+        convert(self.0.zcard(key.as_ref()).await?)
+    }
+
+    pub async fn zcount<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        scores: impl RangeBounds<u64>,
+    ) -> RedisResult<RV> {
+        let (min, max) = inclusive(scores);
+        convert(self.0.zcount(key.as_ref(), min, max).await?)
+    }
+
+    pub async fn zrembyscore<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        scores: impl RangeBounds<u64>,
+    ) -> RedisResult<RV> {
+        let (min, max) = inclusive(scores);
+        convert(self.0.zrembyscore(key.as_ref(), min, max).await?)
+    }
+
+    pub async fn zremrangebyrank<K: AsRef<str>, RV: FromRedisValue>(
+        &self,
+        key: K,
+        start: isize,
+        stop: isize,
+    ) -> RedisResult<RV> {
+        convert(self.0.zremrangebyrank(key.as_ref(), start, stop).await?)
+    }
+
+    /// Moves the member from one sorted set to another with a new score in one step.
+    /// False if it wasn't in `from`, so only one caller gets to move it
+    pub async fn move_member(
+        &self,
+        from: &str,
+        to: &str,
+        member: &str,
+        score: u64,
+    ) -> RedisResult<bool> {
+        self.0.move_member(from, to, member, score).await
+    }
+
+    pub async fn publish<K: AsRef<str>, E: ToString, RV: FromRedisValue>(
+        &self,
+        channel: K,
+        message: E,
+    ) -> RedisResult<RV> {
+        convert(
+            self.0
+                .publish(channel.as_ref(), message.to_string())
+                .await?,
+        )
+    }
+
+    /// Payloads published to the channel from the moment this returns
+    pub async fn subscribe(&self, channel: String) -> RedisResult<BoxStream<'static, String>> {
+        self.0.subscribe(channel).await
+    }
+}
+
+type CacheFuture<'a, T> = BoxFuture<'a, RedisResult<T>>;
+
+/// Everything the store does with its cache. Each method is one step, other callers never see it half done
+pub trait CacheBackend: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<u8>>>;
+    fn mget<'a>(&'a self, keys: &'a [String]) -> CacheFuture<'a, Vec<Option<Vec<u8>>>>;
+    /// Without `seconds` it never expires
+    fn set<'a>(&'a self, key: &'a str, value: Vec<u8>, seconds: Option<u64>)
+        -> CacheFuture<'a, ()>;
+    /// Only sets a missing key, returns whether it did
+    fn set_nx<'a>(&'a self, key: &'a str, value: Vec<u8>, seconds: u64) -> CacheFuture<'a, bool>;
+    fn set_multiple(&self, entries: Vec<(String, Vec<u8>)>) -> CacheFuture<'_, ()>;
+    fn get_del<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<u8>>>;
+    /// How many of them existed
+    fn del<'a>(&'a self, keys: &'a [String]) -> CacheFuture<'a, usize>;
+    fn exists<'a>(&'a self, key: &'a str) -> CacheFuture<'a, bool>;
+    /// False if the key doesn't exist, it is deleted if `seconds` isn't positive
+    fn expire<'a>(&'a self, key: &'a str, seconds: i64) -> CacheFuture<'a, bool>;
+    /// Seconds left, -1 if it never expires and -2 if it doesn't exist
+    fn ttl<'a>(&'a self, key: &'a str) -> CacheFuture<'a, i64>;
+    fn incr<'a>(&'a self, key: &'a str, by: i64) -> CacheFuture<'a, i64>;
+    fn incr_ex<'a>(&'a self, key: &'a str, by: i64, seconds: u64) -> CacheFuture<'a, i64>;
+    fn hget<'a>(&'a self, key: &'a str, field: Vec<u8>) -> CacheFuture<'a, Option<Vec<u8>>>;
+    fn hgetall<'a>(&'a self, key: &'a str) -> CacheFuture<'a, HashMap<Vec<u8>, Vec<u8>>>;
+    fn hset<'a>(&'a self, key: &'a str, field: Vec<u8>, value: Vec<u8>) -> CacheFuture<'a, ()>;
+    fn hincr<'a>(&'a self, key: &'a str, field: Vec<u8>, by: i64) -> CacheFuture<'a, i64>;
+    fn hreplace<'a>(&'a self, key: &'a str, fields: Vec<(Vec<u8>, Vec<u8>)>)
+        -> CacheFuture<'a, ()>;
+    fn htake<'a>(&'a self, key: &'a str) -> CacheFuture<'a, HashMap<Vec<u8>, Vec<u8>>>;
+    /// Returns the new length
+    fn lpush<'a>(&'a self, key: &'a str, value: Vec<u8>) -> CacheFuture<'a, usize>;
+    /// Items from `start` to `stop` inclusive, negative ones count from the end
+    fn lrange<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>>;
+    /// Removes up to `count` items equal to `value`, from the end if it is negative and all of them if it is 0
+    fn lrem<'a>(&'a self, key: &'a str, count: isize, value: Vec<u8>) -> CacheFuture<'a, usize>;
+    /// Keeps items from `start` to `stop` inclusive, negative ones count from the end
+    fn ltrim<'a>(&'a self, key: &'a str, start: isize, stop: isize) -> CacheFuture<'a, ()>;
+    /// See [BoundedPush]
+    fn push_bounded<'a>(&'a self, push: BoundedPush<'a>) -> CacheFuture<'a, Option<usize>>;
+    /// Drops expired entries like [BoundedPush] and pushes `value` to the front as it is
+    fn requeue_bounded<'a>(
+        &'a self,
+        list: &'a str,
+        max: usize,
+        now: u64,
+        value: &'a str,
+    ) -> CacheFuture<'a, Option<usize>>;
+    /// How many were new
+    fn sadd<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, usize>;
+    fn srem<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, usize>;
+    fn smembers<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Vec<Vec<u8>>>;
+    fn sismember<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, bool>;
+    fn sreplace<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, ()>;
+    /// Whether the member is new, the score of an existing one is replaced
+    fn zadd<'a>(&'a self, key: &'a str, member: Vec<u8>, score: u64) -> CacheFuture<'a, bool>;
+    fn zrem<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, bool>;
+    fn zscore<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, Option<u64>>;
+    fn zrange<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>>;
+    /// Scores from `min` to `max` inclusive
+    fn zrangebyscore<'a>(
+        &'a self,
+        key: &'a str,
+        min: u64,
+        max: u64,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>>;
+    fn zcard<'a>(&'a self, key: &'a str) -> CacheFuture<'a, usize>;
+    fn zcount<'a>(&'a self, key: &'a str, min: u64, max: u64) -> CacheFuture<'a, usize>;
+    fn zrembyscore<'a>(&'a self, key: &'a str, min: u64, max: u64) -> CacheFuture<'a, usize>;
+    fn zremrangebyrank<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, usize>;
+    fn move_member<'a>(
+        &'a self,
+        from: &'a str,
+        to: &'a str,
+        member: &'a str,
+        score: u64,
+    ) -> CacheFuture<'a, bool>;
+    /// How many subscribers got it
+    fn publish<'a>(&'a self, channel: &'a str, message: String) -> CacheFuture<'a, usize>;
+    fn subscribe(&self, channel: String) -> CacheFuture<'_, BoxStream<'static, String>>;
+}
+
+/// An entry for [Cache::push_bounded]. Entries of the list whose `expires_at` passed are dropped,
+/// then the entry is pushed unless `max` are left. The sequence number is only handed out
+/// once the entry fits, so a full list doesn't leave gaps in it.
+/// The list expires along with the last of its entries
+pub struct BoundedPush<'a> {
+    pub list: &'a str,
+    pub max: usize,
+    /// Unix timestamp in seconds
+    pub now: u64,
+    /// JSON with an `expires_at` like every entry of the list
+    pub value: &'a str,
+    /// Its first occurrence in `value` is replaced by the next number of `seq_key`
+    pub placeholder: &'a str,
+    pub seq_key: &'a str,
+    pub seq_ttl: u64,
+    /// Set to `record` for `record_ttl` seconds along with the push
+    pub record_key: &'a str,
+    pub record: &'a str,
+    pub record_ttl: u64,
+}
+
+/// Entries of lists pushed with [Cache::push_bounded]
+#[derive(Deserialize)]
+struct Expiring {
+    expires_at: u64,
+}
+
+/// What the backends return, turned into what redis would have replied so stored types convert the same way
+trait Reply {
+    fn into_value(self) -> Value;
+}
+
+impl Reply for () {
+    fn into_value(self) -> Value {
+        Value::Okay
+    }
+}
+
+impl Reply for bool {
+    fn into_value(self) -> Value {
+        Value::Int(self as i64)
+    }
+}
+
+impl Reply for i64 {
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl Reply for usize {
+    fn into_value(self) -> Value {
+        Value::Int(self as i64)
+    }
+}
+
+impl Reply for Vec<u8> {
+    fn into_value(self) -> Value {
+        Value::BulkString(self)
+    }
+}
+
+impl Reply for u64 {
+    fn into_value(self) -> Value {
+        Value::BulkString(self.to_string().into_bytes())
+    }
+}
+
+impl<T: Reply> Reply for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Nil, Reply::into_value)
+    }
+}
+
+impl Reply for Vec<Vec<u8>> {
+    fn into_value(self) -> Value {
+        Value::Array(self.into_iter().map(Reply::into_value).collect())
+    }
+}
+
+impl Reply for Vec<Option<Vec<u8>>> {
+    fn into_value(self) -> Value {
+        Value::Array(self.into_iter().map(Reply::into_value).collect())
+    }
+}
+
+impl Reply for HashMap<Vec<u8>, Vec<u8>> {
+    fn into_value(self) -> Value {
+        Value::Array(
+            self.into_iter()
+                .flat_map(|(field, value)| [Value::BulkString(field), Value::BulkString(value)])
+                .collect(),
+        )
+    }
+}
+
+fn convert<RV: FromRedisValue>(reply: impl Reply) -> RedisResult<RV> {
+    redis::from_redis_value(&reply.into_value())
+}
+
+/// The value as the single argument redis would send it as
+fn arg(value: impl ToRedisArgs) -> RedisResult<Vec<u8>> {
+    let mut args = value.to_redis_args();
+    match args.pop() {
+        Some(arg) if args.is_empty() => Ok(arg),
+        _ => Err(invalid("expected a single value")),
+    }
+}
+
+fn owned<K: AsRef<str>>(keys: &[K]) -> Vec<String> {
+    keys.iter().map(|key| key.as_ref().to_string()).collect()
+}
+
+/// Scores are whole numbers, so exclusive bounds are one further in
+fn inclusive(scores: impl RangeBounds<u64>) -> (u64, u64) {
+    let min = match scores.start_bound() {
+        Bound::Included(min) => *min,
+        Bound::Excluded(min) => min.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let max = match scores.end_bound() {
+        Bound::Included(max) => *max,
+        Bound::Excluded(max) => max.saturating_sub(1),
+        Bound::Unbounded => u64::MAX,
+    };
+    (min, max)
+}
+
+pub struct RedisCache {
+    connection: MultiplexedConnection,
+    /// For connections that can't be multiplexed, like pub/sub
+    client: redis::Client,
+}
+
+impl RedisCache {
+    fn connection(&self) -> MultiplexedConnection {
+        self.connection.clone()
+    }
+}
+
+impl CacheBackend for RedisCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move { self.connection().get(key).await })
+    }
+
+    fn mget<'a>(&'a self, keys: &'a [String]) -> CacheFuture<'a, Vec<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            if keys.is_empty() {
+                return Ok(vec![]);
+            }
+            redis::cmd("MGET")
+                .arg(keys)
+                .query_async(&mut self.connection())
+                .await
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        seconds: Option<u64>,
+    ) -> CacheFuture<'a, ()> {
+        Box::pin(async move {
+            match seconds {
+                Some(seconds) => self.connection().set_ex(key, value, seconds).await,
+                None => self.connection().set(key, value).await,
+            }
+        })
+    }
+
+    fn set_nx<'a>(&'a self, key: &'a str, value: Vec<u8>, seconds: u64) -> CacheFuture<'a, bool> {
+        Box::pin(async move {
+            let set: Option<()> = self
+                .connection()
+                .set_options(
+                    key,
+                    value,
+                    SetOptions::default()
+                        .conditional_set(ExistenceCheck::NX)
+                        .with_expiration(SetExpiry::EX(seconds)),
+                )
+                .await?;
+            Ok(set.is_some())
+        })
+    }
+
+    fn set_multiple(&self, entries: Vec<(String, Vec<u8>)>) -> CacheFuture<'_, ()> {
+        Box::pin(async move {
+            if entries.is_empty() {
+                return Ok(());
+            }
+            self.connection().mset(&entries).await
+        })
+    }
+
+    fn get_del<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move { self.connection().get_del(key).await })
+    }
+
+    fn del<'a>(&'a self, keys: &'a [String]) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().del(keys).await })
+    }
+
+    fn exists<'a>(&'a self, key: &'a str) -> CacheFuture<'a, bool> {
+        Box::pin(async move { self.connection().exists(key).await })
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, seconds: i64) -> CacheFuture<'a, bool> {
+        Box::pin(async move { self.connection().expire(key, seconds).await })
+    }
+
+    fn ttl<'a>(&'a self, key: &'a str) -> CacheFuture<'a, i64> {
+        Box::pin(async move { self.connection().ttl(key).await })
+    }
+
+    fn incr<'a>(&'a self, key: &'a str, by: i64) -> CacheFuture<'a, i64> {
+        Box::pin(async move { self.connection().incr(key, by).await })
+    }
+
+    fn incr_ex<'a>(&'a self, key: &'a str, by: i64, seconds: u64) -> CacheFuture<'a, i64> {
+        Box::pin(async move {
+            let (count,): (i64,) = redis::pipe()
+                .atomic()
+                .incr(key, by)
+                .expire(key, seconds as i64)
+                .ignore()
+                .query_async(&mut self.connection())
+                .await?;
+            Ok(count)
+        })
+    }
+
+    fn hget<'a>(&'a self, key: &'a str, field: Vec<u8>) -> CacheFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move { self.connection().hget(key, field).await })
+    }
+
+    fn hgetall<'a>(&'a self, key: &'a str) -> CacheFuture<'a, HashMap<Vec<u8>, Vec<u8>>> {
+        Box::pin(async move { self.connection().hgetall(key).await })
+    }
+
+    fn hset<'a>(&'a self, key: &'a str, field: Vec<u8>, value: Vec<u8>) -> CacheFuture<'a, ()> {
+        Box::pin(async move { self.connection().hset(key, field, value).await })
+    }
+
+    fn hincr<'a>(&'a self, key: &'a str, field: Vec<u8>, by: i64) -> CacheFuture<'a, i64> {
+        Box::pin(async move { self.connection().hincr(key, field, by).await })
+    }
+
+    fn hreplace<'a>(
+        &'a self,
+        key: &'a str,
+        fields: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> CacheFuture<'a, ()> {
+        Box::pin(async move {
+            let mut pipe = redis::pipe();
+            pipe.atomic().del(key).ignore();
+            if !fields.is_empty() {
+                pipe.hset_multiple(key, &fields).ignore();
+            }
+            pipe.query_async(&mut self.connection()).await
+        })
+    }
+
+    fn htake<'a>(&'a self, key: &'a str) -> CacheFuture<'a, HashMap<Vec<u8>, Vec<u8>>> {
+        Box::pin(async move {
+            let (hash,): (HashMap<Vec<u8>, Vec<u8>>,) = redis::pipe()
+                .atomic()
+                .hgetall(key)
+                .del(key)
+                .ignore()
+                .query_async(&mut self.connection())
+                .await?;
+            Ok(hash)
+        })
+    }
+
+    fn lpush<'a>(&'a self, key: &'a str, value: Vec<u8>) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().lpush(key, value).await })
+    }
+
+    fn lrange<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        Box::pin(async move { self.connection().lrange(key, start, stop).await })
+    }
+
+    fn lrem<'a>(&'a self, key: &'a str, count: isize, value: Vec<u8>) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().lrem(key, count, value).await })
+    }
+
+    fn ltrim<'a>(&'a self, key: &'a str, start: isize, stop: isize) -> CacheFuture<'a, ()> {
+        Box::pin(async move { self.connection().ltrim(key, start, stop).await })
+    }
+
+    fn push_bounded<'a>(&'a self, push: BoundedPush<'a>) -> CacheFuture<'a, Option<usize>> {
+        Box::pin(async move {
+            PUSH_BOUNDED
+                .key(push.list)
+                .key(push.record_key)
+                .key(push.seq_key)
+                .arg(push.max)
+                .arg(push.now)
+                .arg(push.value)
+                .arg(push.placeholder)
+                .arg(push.seq_ttl)
+                .arg(push.record)
+                .arg(push.record_ttl)
+                .invoke_async(&mut self.connection())
+                .await
+        })
+    }
+
+    fn requeue_bounded<'a>(
+        &'a self,
+        list: &'a str,
+        max: usize,
+        now: u64,
+        value: &'a str,
+    ) -> CacheFuture<'a, Option<usize>> {
+        Box::pin(async move {
+            REQUEUE_BOUNDED
+                .key(list)
+                .arg(max)
+                .arg(now)
+                .arg(value)
+                .invoke_async(&mut self.connection())
+                .await
+        })
+    }
+
+    fn sadd<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, usize> {
+        Box::pin(async move {
+            if members.is_empty() {
+                return Ok(0);
+            }
+            self.connection().sadd(key, members).await
+        })
+    }
+
+    fn srem<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, usize> {
+        Box::pin(async move {
+            if members.is_empty() {
+                return Ok(0);
+            }
+            self.connection().srem(key, members).await
+        })
+    }
+
+    fn smembers<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        Box::pin(async move { self.connection().smembers(key).await })
+    }
+
+    fn sismember<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, bool> {
+        Box::pin(async move { self.connection().sismember(key, member).await })
+    }
+
+    fn sreplace<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, ()> {
+        Box::pin(async move {
+            let mut pipe = redis::pipe();
+            pipe.atomic().del(key).ignore();
+            if !members.is_empty() {
+                pipe.sadd(key, members).ignore();
+            }
+            pipe.query_async(&mut self.connection()).await
+        })
+    }
+
+    fn zadd<'a>(&'a self, key: &'a str, member: Vec<u8>, score: u64) -> CacheFuture<'a, bool> {
+        Box::pin(async move { self.connection().zadd(key, member, score).await })
+    }
+
+    fn zrem<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, bool> {
+        Box::pin(async move { self.connection().zrem(key, member).await })
+    }
+
+    fn zscore<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, Option<u64>> {
+        Box::pin(async move { self.connection().zscore(key, member).await })
+    }
+
+    fn zrange<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        Box::pin(async move { self.connection().zrange(key, start, stop).await })
+    }
+
+    fn zrangebyscore<'a>(
+        &'a self,
+        key: &'a str,
+        min: u64,
+        max: u64,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        Box::pin(async move { self.connection().zrangebyscore(key, min, max).await })
+    }
+
+    fn zcard<'a>(&'a self, key: &'a str) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().zcard(key).await })
+    }
+
+    fn zcount<'a>(&'a self, key: &'a str, min: u64, max: u64) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().zcount(key, min, max).await })
+    }
+
+    fn zrembyscore<'a>(&'a self, key: &'a str, min: u64, max: u64) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().zrembyscore(key, min, max).await })
+    }
+
+    fn zremrangebyrank<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().zremrangebyrank(key, start, stop).await })
+    }
+
+    fn move_member<'a>(
+        &'a self,
+        from: &'a str,
+        to: &'a str,
+        member: &'a str,
+        score: u64,
+    ) -> CacheFuture<'a, bool> {
+        Box::pin(async move {
+            MOVE_MEMBER
+                .key(from)
+                .key(to)
+                .arg(member)
+                .arg(score)
+                .invoke_async(&mut self.connection())
+                .await
+        })
+    }
+
+    fn publish<'a>(&'a self, channel: &'a str, message: String) -> CacheFuture<'a, usize> {
+        Box::pin(async move { self.connection().publish(channel, message).await })
+    }
+
+    fn subscribe(&self, channel: String) -> CacheFuture<'_, BoxStream<'static, String>> {
+        Box::pin(async move {
+            let mut pubsub = self.client.get_async_pubsub().await?;
+            pubsub.subscribe(channel).await?;
+            Ok(pubsub
+                .into_on_message()
+                .filter_map(|msg| future::ready(msg.get_payload().ok()))
+                .boxed())
+        })
+    }
+}
+
+/// Keeps everything in maps in the process, it is lost when the process stops
+pub struct MemoryCache {
+    state: Mutex<MemoryState>,
+    published: broadcast::Sender<(String, String)>,
+}
+
+struct MemoryState {
+    entries: HashMap<String, Entry>,
+    swept_at: Instant,
+}
+
+struct Entry {
+    data: Data,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+enum Data {
+    String(Vec<u8>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    SortedSet(SortedSet),
+}
+
+impl Data {
+    /// Redis drops collections once they are empty
+    fn is_empty_collection(&self) -> bool {
+        match self {
+            Data::String(_) => false,
+            Data::Hash(hash) => hash.is_empty(),
+            Data::List(list) => list.is_empty(),
+            Data::Set(set) => set.is_empty(),
+            Data::SortedSet(set) => set.0.is_empty(),
+        }
+    }
+}
+
+/// Members ordered by score, then by member like redis does
+#[derive(Default)]
+struct SortedSet(Vec<(u64, Vec<u8>)>);
+
+impl SortedSet {
+    /// Whether the member is new
+    fn insert(&mut self, score: u64, member: Vec<u8>) -> bool {
+        let existed = self.remove(&member);
+        let at = self
+            .0
+            .partition_point(|(s, m)| (*s, m.as_slice()) < (score, member.as_slice()));
+        self.0.insert(at, (score, member));
+        !existed
+    }
+
+    fn remove(&mut self, member: &[u8]) -> bool {
+        if let Some(at) = self.0.iter().position(|(_, m)| m == member) {
+            self.0.remove(at);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn score(&self, member: &[u8]) -> Option<u64> {
+        self.0.iter().find(|(_, m)| m == member).map(|(s, _)| *s)
+    }
+
+    fn scored(&self, min: u64, max: u64) -> impl Iterator<Item = &(u64, Vec<u8>)> {
+        self.0
+            .iter()
+            .filter(move |(score, _)| (min..=max).contains(score))
+    }
+}
+
+/// A string or collection type of an entry
+trait Typed: Default {
+    fn wrap(self) -> Data;
+    fn unwrap(data: &mut Data) -> Option<&mut Self>;
+}
+
+macro_rules! typed {
+    ($variant:ident, $ty:ty) => {
+        impl Typed for $ty {
+            fn wrap(self) -> Data {
+                Data::$variant(self)
+            }
+
+            fn unwrap(data: &mut Data) -> Option<&mut Self> {
+                match data {
+                    Data::$variant(it) => Some(it),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+typed!(String, Vec<u8>);
+typed!(Hash, HashMap<Vec<u8>, Vec<u8>>);
+typed!(List, VecDeque<Vec<u8>>);
+typed!(Set, HashSet<Vec<u8>>);
+typed!(SortedSet, SortedSet);
+
+impl MemoryState {
+    fn entry(&mut self, key: &str, now: Instant) -> Option<&mut Entry> {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.expired(now))
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn get<T: Typed>(&mut self, key: &str, now: Instant) -> RedisResult<Option<&mut T>> {
+        match self.entry(key, now) {
+            Some(entry) => T::unwrap(&mut entry.data).map(Some).ok_or_else(wrong_type),
+            None => Ok(None),
+        }
+    }
+
+    fn get_or_default<T: Typed>(&mut self, key: &str, now: Instant) -> RedisResult<&mut T> {
+        if self.entry(key, now).is_none() {
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    data: T::default().wrap(),
+                    expires_at: None,
+                },
+            );
+        }
+        let entry = self.entries.get_mut(key).expect("Entry was just inserted");
+        T::unwrap(&mut entry.data).ok_or_else(wrong_type)
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>, seconds: Option<u64>, now: Instant) {
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                data: Data::String(value),
+                expires_at: seconds.map(|seconds| now + Duration::from_secs(seconds)),
+            },
+        );
+    }
+
+    fn expire(&mut self, key: &str, seconds: i64, now: Instant) -> bool {
+        match self.entry(key, now) {
+            Some(_) if seconds <= 0 => {
+                self.entries.remove(key);
+                true
+            }
+            Some(entry) => {
+                entry.expires_at = Some(now + Duration::from_secs(seconds as u64));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn incr(&mut self, key: &str, by: i64, now: Instant) -> RedisResult<i64> {
+        let value = self.get_or_default::<Vec<u8>>(key, now)?;
+        let current = if value.is_empty() { 0 } else { int(value)? };
+        let next = current
+            .checked_add(by)
+            .ok_or_else(|| invalid("increment or decrement would overflow"))?;
+        *value = next.to_string().into_bytes();
+        Ok(next)
+    }
+
+    /// Drops the entries of a list pushed with [Cache::push_bounded] that expired by `push_now`,
+    /// returns how many are left and the latest `expires_at` of them, at least `push_now`
+    fn drop_expired(
+        &mut self,
+        list: &str,
+        push_now: u64,
+        now: Instant,
+    ) -> RedisResult<(usize, u64)> {
+        let mut newest = push_now;
+        let Some(entries) = self.get::<VecDeque<Vec<u8>>>(list, now)? else {
+            return Ok((0, newest));
+        };
+        let mut live = VecDeque::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let expiring: Expiring =
+                serde_json::from_slice(entry).map_err(|_| invalid("entry has no expires_at"))?;
+            if expiring.expires_at > push_now {
+                newest = newest.max(expiring.expires_at);
+                live.push_back(entry.clone());
+            }
+        }
+        *entries = live;
+        Ok((entries.len(), newest))
+    }
+
+    fn hash(&mut self, key: &str, now: Instant) -> RedisResult<HashMap<Vec<u8>, Vec<u8>>> {
+        Ok(self
+            .get::<HashMap<Vec<u8>, Vec<u8>>>(key, now)?
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Redis drops collections once they are empty
+    fn drop_if_empty(&mut self, key: &str) {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.data.is_empty_collection())
+        {
+            self.entries.remove(key);
+        }
+    }
+}
+
+impl MemoryCache {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(MemoryState {
+                entries: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+            published: broadcast::channel(PUBLISH_BUFFER).0,
+        }
+    }
+
+    /// Runs the step under the lock, then drops the collection at `key` if the step emptied it
+    fn with<'a, T: Send + 'a>(
+        &self,
+        key: &str,
+        step: impl FnOnce(&mut MemoryState, Instant) -> RedisResult<T>,
+    ) -> CacheFuture<'a, T> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Memory cache shouldn't be poisoned");
+        let now = Instant::now();
+        if now.duration_since(state.swept_at) >= SWEEP_INTERVAL {
+            state.entries.retain(|_, entry| !entry.expired(now));
+            state.swept_at = now;
+        }
+        let result = step(&mut state, now);
+        state.drop_if_empty(key);
+        Box::pin(future::ready(result))
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<u8>>> {
+        self.with(key, |state, now| {
+            Ok(state.get::<Vec<u8>>(key, now)?.cloned())
+        })
+    }
+
+    fn mget<'a>(&'a self, keys: &'a [String]) -> CacheFuture<'a, Vec<Option<Vec<u8>>>> {
+        self.with("", |state, now| {
+            // Keys holding something else come back as missing like in redis
+            Ok(keys
+                .iter()
+                .map(|key| state.get::<Vec<u8>>(key, now).ok().flatten().cloned())
+                .collect())
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        seconds: Option<u64>,
+    ) -> CacheFuture<'a, ()> {
+        self.with(key, |state, now| {
+            state.set(key, value, seconds, now);
+            Ok(())
+        })
+    }
+
+    fn set_nx<'a>(&'a self, key: &'a str, value: Vec<u8>, seconds: u64) -> CacheFuture<'a, bool> {
+        self.with(key, |state, now| {
+            if state.entry(key, now).is_some() {
+                return Ok(false);
+            }
+            state.set(key, value, Some(seconds), now);
+            Ok(true)
+        })
+    }
+
+    fn set_multiple(&self, entries: Vec<(String, Vec<u8>)>) -> CacheFuture<'_, ()> {
+        self.with("", |state, now| {
+            for (key, value) in entries {
+                state.set(&key, value, None, now);
+            }
+            Ok(())
+        })
+    }
+
+    fn get_del<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<Vec<u8>>> {
+        self.with(key, |state, now| {
+            let value = state.get::<Vec<u8>>(key, now)?.cloned();
+            state.entries.remove(key);
+            Ok(value)
+        })
+    }
+
+    fn del<'a>(&'a self, keys: &'a [String]) -> CacheFuture<'a, usize> {
+        self.with("", |state, now| {
+            Ok(keys
+                .iter()
+                .filter(|key| {
+                    state.entry(key, now).is_some() && state.entries.remove(key.as_str()).is_some()
+                })
+                .count())
+        })
+    }
+
+    fn exists<'a>(&'a self, key: &'a str) -> CacheFuture<'a, bool> {
+        self.with(key, |state, now| Ok(state.entry(key, now).is_some()))
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, seconds: i64) -> CacheFuture<'a, bool> {
+        self.with(key, |state, now| Ok(state.expire(key, seconds, now)))
+    }
+
+    fn ttl<'a>(&'a self, key: &'a str) -> CacheFuture<'a, i64> {
+        self.with(key, |state, now| {
+            Ok(match state.entry(key, now) {
+                Some(Entry {
+                    expires_at: Some(at),
+                    ..
+                }) => (at.duration_since(now).as_millis() as i64 + 500) / 1000,
+                Some(_) => -1,
+                None => -2,
+            })
+        })
+    }
+
+    fn incr<'a>(&'a self, key: &'a str, by: i64) -> CacheFuture<'a, i64> {
+        self.with(key, |state, now| state.incr(key, by, now))
+    }
+
+    fn incr_ex<'a>(&'a self, key: &'a str, by: i64, seconds: u64) -> CacheFuture<'a, i64> {
+        self.with(key, |state, now| {
+            let count = state.incr(key, by, now)?;
+            state.expire(key, seconds as i64, now);
+            Ok(count)
+        })
+    }
+
+    fn hget<'a>(&'a self, key: &'a str, field: Vec<u8>) -> CacheFuture<'a, Option<Vec<u8>>> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get::<HashMap<Vec<u8>, Vec<u8>>>(key, now)?
+                .and_then(|hash| hash.get(&field).cloned()))
+        })
+    }
+
+    fn hgetall<'a>(&'a self, key: &'a str) -> CacheFuture<'a, HashMap<Vec<u8>, Vec<u8>>> {
+        self.with(key, |state, now| state.hash(key, now))
+    }
+
+    fn hset<'a>(&'a self, key: &'a str, field: Vec<u8>, value: Vec<u8>) -> CacheFuture<'a, ()> {
+        self.with(key, |state, now| {
+            state
+                .get_or_default::<HashMap<Vec<u8>, Vec<u8>>>(key, now)?
+                .insert(field, value);
+            Ok(())
+        })
+    }
+
+    fn hincr<'a>(&'a self, key: &'a str, field: Vec<u8>, by: i64) -> CacheFuture<'a, i64> {
+        self.with(key, |state, now| {
+            let hash = state.get_or_default::<HashMap<Vec<u8>, Vec<u8>>>(key, now)?;
+            let current = hash.get(&field).map(|value| int(value)).transpose()?;
+            let next = current
+                .unwrap_or(0)
+                .checked_add(by)
+                .ok_or_else(|| invalid("increment or decrement would overflow"))?;
+            hash.insert(field, next.to_string().into_bytes());
+            Ok(next)
+        })
+    }
+
+    fn hreplace<'a>(
+        &'a self,
+        key: &'a str,
+        fields: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> CacheFuture<'a, ()> {
+        self.with(key, |state, _| {
+            state.entries.insert(
+                key.to_string(),
+                Entry {
+                    data: Data::Hash(fields.into_iter().collect()),
+                    expires_at: None,
+                },
+            );
+            Ok(())
+        })
+    }
+
+    fn htake<'a>(&'a self, key: &'a str) -> CacheFuture<'a, HashMap<Vec<u8>, Vec<u8>>> {
+        self.with(key, |state, now| {
+            let hash = state.hash(key, now)?;
+            state.entries.remove(key);
+            Ok(hash)
+        })
+    }
+
+    fn lpush<'a>(&'a self, key: &'a str, value: Vec<u8>) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            let list = state.get_or_default::<VecDeque<Vec<u8>>>(key, now)?;
+            list.push_front(value);
+            Ok(list.len())
+        })
+    }
+
+    fn lrange<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        self.with(key, |state, now| {
+            Ok(match state.get::<VecDeque<Vec<u8>>>(key, now)? {
+                Some(list) => list
+                    .range(rank_range(list.len(), start, stop))
+                    .cloned()
+                    .collect(),
+                None => vec![],
+            })
+        })
+    }
+
+    fn lrem<'a>(&'a self, key: &'a str, count: isize, value: Vec<u8>) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            let Some(list) = state.get::<VecDeque<Vec<u8>>>(key, now)? else {
+                return Ok(0);
+            };
+            let limit = if count == 0 {
+                usize::MAX
+            } else {
+                count.unsigned_abs()
+            };
+            let mut matching: Vec<usize> = list
+                .iter()
+                .enumerate()
+                .filter(|(_, it)| **it == value)
+                .map(|(at, _)| at)
+                .collect();
+            if count < 0 {
+                matching.reverse();
+            }
+            matching.truncate(limit);
+            matching.sort_unstable();
+            for at in matching.iter().rev() {
+                list.remove(*at);
+            }
+            Ok(matching.len())
+        })
+    }
+
+    fn ltrim<'a>(&'a self, key: &'a str, start: isize, stop: isize) -> CacheFuture<'a, ()> {
+        self.with(key, |state, now| {
+            if let Some(list) = state.get::<VecDeque<Vec<u8>>>(key, now)? {
+                let keep = rank_range(list.len(), start, stop);
+                list.truncate(keep.end);
+                list.drain(..keep.start.min(list.len()));
+            }
+            Ok(())
+        })
+    }
+
+    fn push_bounded<'a>(&'a self, push: BoundedPush<'a>) -> CacheFuture<'a, Option<usize>> {
+        self.with(push.list, |state, now| {
+            let (len, newest) = state.drop_expired(push.list, push.now, now)?;
+            if len >= push.max {
+                return Ok(None);
+            }
+            let seq = state.incr(push.seq_key, 1, now)?;
+            state.expire(push.seq_key, push.seq_ttl as i64, now);
+            let value = push.value.replacen(push.placeholder, &seq.to_string(), 1);
+            let expiring: Expiring =
+                serde_json::from_str(&value).map_err(|_| invalid("entry has no expires_at"))?;
+            state.set(
+                push.record_key,
+                push.record.as_bytes().to_vec(),
+                Some(push.record_ttl),
+                now,
+            );
+            let list = state.get_or_default::<VecDeque<Vec<u8>>>(push.list, now)?;
+            list.push_back(value.into_bytes());
+            let len = list.len();
+            let newest = newest.max(expiring.expires_at);
+            state.expire(push.list, (newest - push.now) as i64, now);
+            Ok(Some(len))
+        })
+    }
+
+    fn requeue_bounded<'a>(
+        &'a self,
+        list: &'a str,
+        max: usize,
+        push_now: u64,
+        value: &'a str,
+    ) -> CacheFuture<'a, Option<usize>> {
+        self.with(list, |state, now| {
+            let (len, newest) = state.drop_expired(list, push_now, now)?;
+            if len >= max {
+                return Ok(None);
+            }
+            let expiring: Expiring =
+                serde_json::from_str(value).map_err(|_| invalid("entry has no expires_at"))?;
+            let entries = state.get_or_default::<VecDeque<Vec<u8>>>(list, now)?;
+            entries.push_front(value.as_bytes().to_vec());
+            let len = entries.len();
+            let newest = newest.max(expiring.expires_at);
+            state.expire(list, (newest - push_now) as i64, now);
+            Ok(Some(len))
+        })
+    }
+
+    fn sadd<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            let set = state.get_or_default::<HashSet<Vec<u8>>>(key, now)?;
+            Ok(members
+                .into_iter()
+                .filter(|member| set.insert(member.clone()))
+                .count())
+        })
+    }
+
+    fn srem<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            Ok(match state.get::<HashSet<Vec<u8>>>(key, now)? {
+                Some(set) => members.iter().filter(|member| set.remove(*member)).count(),
+                None => 0,
+            })
+        })
+    }
+
+    fn smembers<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get::<HashSet<Vec<u8>>>(key, now)?
+                .map(|set| set.iter().cloned().collect())
+                .unwrap_or_default())
+        })
+    }
+
+    fn sismember<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, bool> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get::<HashSet<Vec<u8>>>(key, now)?
+                .is_some_and(|set| set.contains(&member)))
+        })
+    }
+
+    fn sreplace<'a>(&'a self, key: &'a str, members: Vec<Vec<u8>>) -> CacheFuture<'a, ()> {
+        self.with(key, |state, _| {
+            state.entries.insert(
+                key.to_string(),
+                Entry {
+                    data: Data::Set(members.into_iter().collect()),
+                    expires_at: None,
+                },
+            );
+            Ok(())
+        })
+    }
+
+    fn zadd<'a>(&'a self, key: &'a str, member: Vec<u8>, score: u64) -> CacheFuture<'a, bool> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get_or_default::<SortedSet>(key, now)?
+                .insert(score, member))
+        })
+    }
+
+    fn zrem<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, bool> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get::<SortedSet>(key, now)?
+                .is_some_and(|set| set.remove(&member)))
+        })
+    }
+
+    fn zscore<'a>(&'a self, key: &'a str, member: Vec<u8>) -> CacheFuture<'a, Option<u64>> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get::<SortedSet>(key, now)?
+                .and_then(|set| set.score(&member)))
+        })
+    }
+
+    fn zrange<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        self.with(key, |state, now| {
+            Ok(match state.get::<SortedSet>(key, now)? {
+                Some(set) => set.0[rank_range(set.0.len(), start, stop)]
+                    .iter()
+                    .map(|(_, member)| member.clone())
+                    .collect(),
+                None => vec![],
+            })
+        })
+    }
+
+    fn zrangebyscore<'a>(
+        &'a self,
+        key: &'a str,
+        min: u64,
+        max: u64,
+    ) -> CacheFuture<'a, Vec<Vec<u8>>> {
+        self.with(key, |state, now| {
+            Ok(match state.get::<SortedSet>(key, now)? {
+                Some(set) => set
+                    .scored(min, max)
+                    .map(|(_, member)| member.clone())
+                    .collect(),
+                None => vec![],
+            })
+        })
+    }
+
+    fn zcard<'a>(&'a self, key: &'a str) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get::<SortedSet>(key, now)?
+                .map_or(0, |set| set.0.len()))
+        })
+    }
+
+    fn zcount<'a>(&'a self, key: &'a str, min: u64, max: u64) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            Ok(state
+                .get::<SortedSet>(key, now)?
+                .map_or(0, |set| set.scored(min, max).count()))
+        })
+    }
+
+    fn zrembyscore<'a>(&'a self, key: &'a str, min: u64, max: u64) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            Ok(match state.get::<SortedSet>(key, now)? {
+                Some(set) => {
+                    let before = set.0.len();
+                    set.0.retain(|(score, _)| !(min..=max).contains(score));
+                    before - set.0.len()
+                }
+                None => 0,
+            })
+        })
+    }
+
+    fn zremrangebyrank<'a>(
+        &'a self,
+        key: &'a str,
+        start: isize,
+        stop: isize,
+    ) -> CacheFuture<'a, usize> {
+        self.with(key, |state, now| {
+            Ok(match state.get::<SortedSet>(key, now)? {
+                Some(set) => {
+                    let range = rank_range(set.0.len(), start, stop);
+                    set.0.drain(range).count()
+                }
+                None => 0,
+            })
+        })
+    }
+
+    fn move_member<'a>(
+        &'a self,
+        from: &'a str,
+        to: &'a str,
+        member: &'a str,
+        score: u64,
+    ) -> CacheFuture<'a, bool> {
+        self.with(from, |state, now| {
+            let removed = state
+                .get::<SortedSet>(from, now)?
+                .is_some_and(|set| set.remove(member.as_bytes()));
+            if removed {
+                state
+                    .get_or_default::<SortedSet>(to, now)?
+                    .insert(score, member.as_bytes().to_vec());
+            }
+            Ok(removed)
+        })
+    }
+
+    fn publish<'a>(&'a self, channel: &'a str, message: String) -> CacheFuture<'a, usize> {
+        // Sending only fails without subscribers
+        let sent = self
+            .published
+            .send((channel.to_string(), message))
+            .unwrap_or(0);
+        Box::pin(future::ready(Ok(sent)))
+    }
+
+    fn subscribe(&self, channel: String) -> CacheFuture<'_, BoxStream<'static, String>> {
+        let receiver = self.published.subscribe();
+        Box::pin(future::ready(Ok(stream::unfold(
+            (receiver, channel),
+            |(mut receiver, channel)| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok((published, payload)) if published == channel => {
+                            return Some((payload, (receiver, channel)));
+                        }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!("Subscriber of {} missed {} messages", channel, missed);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )
+        .boxed())))
+    }
+}
+
+/// Indices from `start` to `stop` inclusive, negative ones counting from the end
+fn rank_range(len: usize, start: isize, stop: isize) -> Range<usize> {
+    let len = len as isize;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        0..0
+    } else {
+        start as usize..stop as usize + 1
+    }
+}
+
+fn int(value: &[u8]) -> RedisResult<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid("value is not an integer"))
+}
+
+fn wrong_type() -> RedisError {
+    (
+        ErrorKind::TypeError,
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    )
+        .into()
+}
+
+fn invalid(detail: &str) -> RedisError {
+    (
+        ErrorKind::ResponseError,
+        "Invalid arguments",
+        detail.to_string(),
+    )
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keys_expire() {
+        let cache = Cache::memory();
+        let _: () = cache.set_ex("a", "1", 1).await.unwrap();
+        let _: () = cache.set("b", "2").await.unwrap();
+        let ttl: i64 = cache.ttl("b").await.unwrap();
+        assert_eq!(ttl, -1);
+        let found: Option<String> = cache.get("a").await.unwrap();
+        assert_eq!(found.as_deref(), Some("1"));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let found: Option<String> = cache.get("a").await.unwrap();
+        assert_eq!(found, None);
+        let exists: bool = cache.exists("a").await.unwrap();
+        assert!(!exists);
+        let found: Option<String> = cache.get("b").await.unwrap();
+        assert_eq!(found.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn set_nx_only_sets_missing() {
+        let cache = Cache::memory();
+        assert!(cache.set_nx_ex("a", "1", 60).await.unwrap());
+        assert!(!cache.set_nx_ex("a", "2", 60).await.unwrap());
+        let found: String = cache.get("a").await.unwrap();
+        assert_eq!(found, "1");
+        let ttl: i64 = cache.ttl("a").await.unwrap();
+        assert_eq!(ttl, 60);
+    }
+
+    #[tokio::test]
+    async fn list_bounds() {
+        let cache = Cache::memory();
+        for item in ["d", "c", "b", "a"] {
+            let _: () = cache.lpush("l", item).await.unwrap();
+        }
+        let all: Vec<String> = cache.lrange("l", 0, -1).await.unwrap();
+        assert_eq!(all, ["a", "b", "c", "d"]);
+        let tail: Vec<String> = cache.lrange("l", -2, 100).await.unwrap();
+        assert_eq!(tail, ["c", "d"]);
+        let none: Vec<String> = cache.lrange("l", 3, 1).await.unwrap();
+        assert!(none.is_empty());
+        let none: Vec<String> = cache.lrange("l", 10, 20).await.unwrap();
+        assert!(none.is_empty());
+
+        let removed: usize = cache.lrem("l", 1, "b").await.unwrap();
+        assert_eq!(removed, 1);
+        let _: () = cache.ltrim("l", 1, -1).await.unwrap();
+        let left: Vec<String> = cache.lrange("l", 0, -1).await.unwrap();
+        assert_eq!(left, ["c", "d"]);
+        let _: () = cache.ltrim("l", 5, -1).await.unwrap();
+        // Emptied lists are removed like in redis
+        let exists: bool = cache.exists("l").await.unwrap();
+        assert!(!exists);
+    }
+
+    #[tokio::test]
+    async fn zrangebyscore_bounds() {
+        let cache = Cache::memory();
+        for (score, member) in [(1, "a"), (2, "b"), (3, "c")] {
+            let _: () = cache.zadd("z", member, score).await.unwrap();
+        }
+        let all: Vec<String> = cache.zrangebyscore("z", ..).await.unwrap();
+        assert_eq!(all, ["a", "b", "c"]);
+        let upto: Vec<String> = cache.zrangebyscore("z", ..=2).await.unwrap();
+        assert_eq!(upto, ["a", "b"]);
+        let between: Vec<String> = cache.zrangebyscore("z", 2..3).await.unwrap();
+        assert_eq!(between, ["b"]);
+        let from: Vec<String> = cache.zrangebyscore("z", 3..).await.unwrap();
+        assert_eq!(from, ["c"]);
+        let count: u64 = cache.zcount("z", 2..).await.unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn stored_types_convert_like_redis() {
+        let cache = Cache::memory();
+        let count: u32 = cache.incr_ex("n", 5, 60).await.unwrap();
+        assert_eq!(count, 5);
+        let (count, missing): (Option<u32>, Option<u64>) =
+            cache.mget(&["n", "missing"]).await.unwrap();
+        assert_eq!((count, missing), (Some(5), None));
+        let _: () = cache.hincr("h", "requests", 2).await.unwrap();
+        let _: () = cache.hset("h", "last_seen", 100).await.unwrap();
+        let hash: HashMap<String, u64> = cache.htake("h").await.unwrap();
+        assert_eq!(
+            hash,
+            HashMap::from([("requests".to_string(), 2), ("last_seen".to_string(), 100)])
+        );
+        let exists: bool = cache.exists("h").await.unwrap();
+        assert!(!exists);
+        let _: () = cache.sreplace("s", vec![1, 2]).await.unwrap();
+        let _: () = cache.sreplace("s", vec![3]).await.unwrap();
+        let members: Vec<i32> = cache.smembers("s").await.unwrap();
+        assert_eq!(members, [3]);
+    }
+
+    #[tokio::test]
+    async fn publish_reaches_subscribers() {
+        let cache = Cache::memory();
+        let mut first = cache.subscribe("a".to_string()).await.unwrap();
+        let mut other = cache.subscribe("b".to_string()).await.unwrap();
+        let _: () = cache.publish("b", "skipped").await.unwrap();
+        let _: () = cache.publish("a", "hello").await.unwrap();
+        assert_eq!(first.next().await.as_deref(), Some("hello"));
+        assert_eq!(other.next().await.as_deref(), Some("skipped"));
+    }
+
+    /// Pushes an entry to `q` holding at most 2, it expires 50 seconds after `now`
+    async fn push(cache: &Cache, n: u64, now: u64) -> Option<usize> {
+        cache
+            .push_bounded(BoundedPush {
+                list: "q",
+                max: 2,
+                now,
+                value: &format!(r#"{{"seq":"SEQ","expires_at":{}}}"#, now + 50),
+                placeholder: "\"SEQ\"",
+                seq_key: "seq",
+                seq_ttl: 60,
+                record_key: &format!("r{n}"),
+                record: "queued",
+                record_ttl: 60,
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn push_bounded_stops_at_max() {
+        let cache = Cache::memory();
+        for n in 1..=2 {
+            assert_eq!(push(&cache, n, 100).await, Some(n as usize));
+        }
+        assert_eq!(push(&cache, 3, 100).await, None);
+        let records: Vec<Option<String>> = cache.mget(&["r1", "r2", "r3"]).await.unwrap();
+        assert_eq!(
+            records,
+            [Some("queued".to_string()), Some("queued".to_string()), None]
+        );
+        let ttl: i64 = cache.ttl("r1").await.unwrap();
+        assert!(ttl > 0);
+    }
+
+    #[tokio::test]
+    async fn push_bounded_drops_expired_entries() {
+        let cache = Cache::memory();
+        for n in 1..=2 {
+            push(&cache, n, 100).await;
+        }
+        assert_eq!(push(&cache, 3, 100).await, None);
+        assert_eq!(push(&cache, 4, 150).await, Some(1));
+        // The full push didn't use up a sequence number
+        let entries: Vec<String> = cache.lrange("q", 0, -1).await.unwrap();
+        assert_eq!(entries, [r#"{"seq":3,"expires_at":200}"#]);
+    }
+
+    #[tokio::test]
+    async fn push_bounded_expires_list_with_newest_entry() {
+        let cache = Cache::memory();
+        push(&cache, 1, 100).await;
+        push(&cache, 2, 120).await;
+        let ttl: i64 = cache.ttl("q").await.unwrap();
+        assert_eq!(ttl, 50);
+    }
+
+    #[tokio::test]
+    async fn requeue_bounded_goes_to_the_front_unless_full() {
+        let cache = Cache::memory();
+        push(&cache, 1, 100).await;
+        let requeued = r#"{"seq":7,"expires_at":130}"#;
+        assert_eq!(
+            cache.requeue_bounded("q", 2, 110, requeued).await.unwrap(),
+            Some(2)
+        );
+        let entries: Vec<String> = cache.lrange("q", 0, -1).await.unwrap();
+        assert_eq!(entries, [requeued, r#"{"seq":1,"expires_at":150}"#]);
+        assert_eq!(
+            cache.requeue_bounded("q", 2, 110, requeued).await.unwrap(),
+            None
+        );
+        // Both expired by then
+        let later = r#"{"seq":8,"expires_at":200}"#;
+        assert_eq!(
+            cache.requeue_bounded("q", 2, 150, later).await.unwrap(),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn move_member_only_moves_once() {
+        let cache = Cache::memory();
+        let _: () = cache.zadd("from", "job", 1).await.unwrap();
+        assert!(cache.move_member("from", "to", "job", 5).await.unwrap());
+        assert!(!cache.move_member("from", "to", "job", 5).await.unwrap());
+        let score: Option<u64> = cache.zscore("to", "job").await.unwrap();
+        assert_eq!(score, Some(5));
+    }
+}
@@ -0,0 +1,118 @@
+use futures::{stream::BoxStream, StreamExt};
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use tracing::warn;
+
+use crate::api::{baton::ChannelMessage, PlotId};
+
+use super::Store;
+
+/// Channel owners are cached for this long
+const CHANNEL_CACHE_TTL: u64 = 60 * 10;
+
+/// Channel
+impl Store {
+    /// Creates a channel owned by the plot, returns whether the name was free
+    pub async fn create_channel(&self, owner: PlotId, name: &str) -> color_eyre::Result<bool> {
+        let created = query!(
+            "INSERT INTO baton_channel (name, owner) VALUES ($1, $2)
+            ON CONFLICT (name) DO NOTHING",
+            name,
+            owner
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        self.invalidate_channel_cache(name).await?;
+        Ok(created != 0)
+    }
+
+    /// Deletes a channel if the plot owns it, returns whether it did
+    pub async fn delete_channel(&self, owner: PlotId, name: &str) -> color_eyre::Result<bool> {
+        let deleted = query!(
+            "DELETE FROM baton_channel WHERE name = $1 AND owner = $2",
+            name,
+            owner
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        self.invalidate_channel_cache(name).await?;
+        Ok(deleted != 0)
+    }
+
+    /// Returns the owner of a channel
+    pub async fn get_channel_owner(&self, name: &str) -> color_eyre::Result<Option<PlotId>> {
+        let redis = &self.redis;
+        let key = format!("channel:{}:owner", name);
+        let cached: Option<CachedOwner> = redis.get(&key).await?;
+        if let Some(cached) = cached {
+            return Ok(cached.0);
+        }
+        let owner = query!("SELECT owner FROM baton_channel WHERE name = $1", name)
+            .fetch_optional(&self.pg)
+            .await?
+            .map(|row| row.owner);
+        let cached = CachedOwner(owner);
+        let _: () = redis.set_ex(key, &cached, CHANNEL_CACHE_TTL).await?;
+        Ok(cached.0)
+    }
+
+    pub(super) async fn invalidate_channel_cache(&self, name: &str) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let _: () = redis.del(format!("channel:{}:owner", name)).await?;
+        Ok(())
+    }
+
+    /// Whether the owner trusts the plot, directly or through its instance
+    pub async fn is_trusted_by(&self, owner: PlotId, plot_id: PlotId) -> color_eyre::Result<bool> {
+        if owner == plot_id || self.fetch_plot_trust(owner).await?.contains(&plot_id) {
+            return Ok(true);
+        }
+        let Some(plot) = self.get_plot(plot_id).await? else {
+            return Ok(false);
+        };
+        Ok(self
+            .fetch_instance_trust(owner)
+            .await?
+            .contains(&plot.instance.key))
+    }
+
+    /// Publishes a message to everyone subscribed to the channel, returns how many got it
+    pub async fn publish_channel(
+        &self,
+        name: &str,
+        message: &ChannelMessage,
+    ) -> color_eyre::Result<usize> {
+        let redis = &self.redis;
+        let received: usize = redis
+            .publish(
+                format!("channel:{}:notify", name),
+                serde_json::to_string(message)?,
+            )
+            .await?;
+        Ok(received)
+    }
+
+    /// Messages published to the channel from now on
+    pub async fn subscribe_channel(
+        &self,
+        name: &str,
+    ) -> color_eyre::Result<BoxStream<'static, ChannelMessage>> {
+        Ok(self
+            .redis
+            .subscribe(format!("channel:{}:notify", name))
+            .await?
+            .filter_map(|payload| async move {
+                serde_json::from_str(&payload)
+                    .inspect_err(|err| warn!("Bad channel message: {}", err))
+                    .ok()
+            })
+            .boxed())
+    }
+}
+
+/// A channel not existing gets cached too
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct CachedOwner(Option<PlotId>);
@@ -0,0 +1,154 @@
+use std::{sync::Arc, time::Duration};
+
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::api::{baton::TransferStatus, PlotId};
+
+use super::{
+    baton::{unix_now, DeliveryGuarantee, QueuedTransfer},
+    Store,
+};
+
+/// Ids of taken at-least-once transfers scored by when they are queued again
+const UNACKED: &str = "transfer:unacked";
+
+/// Delivery
+impl Store {
+    /// Marks a transfer the plot took as delivered. With at-least-once delivery it is held
+    /// until the plot acks it
+    pub(super) async fn deliver_transfer(
+        &self,
+        plot_id: PlotId,
+        transfer: &QueuedTransfer,
+    ) -> color_eyre::Result<()> {
+        self.update_transfer_status(transfer.id, TransferStatus::Delivered)
+            .await?;
+        if self.get_baton_settings(plot_id).await?.delivery != DeliveryGuarantee::AtLeastOnce {
+            return Ok(());
+        }
+        let unacked = UnackedTransfer {
+            plot_id,
+            transfer: serde_json::to_string(transfer)?,
+        };
+        self.hold_unacked(&unacked, transfer).await
+    }
+
+    /// Holds the transfer until it is acked or the ack timeout passes
+    async fn hold_unacked(
+        &self,
+        unacked: &UnackedTransfer,
+        transfer: &QueuedTransfer,
+    ) -> color_eyre::Result<()> {
+        let now = unix_now();
+        let redis = &self.redis;
+        // Taking it again after it expired is no use
+        let _: () = redis
+            .set_ex(
+                unacked_key(transfer.id),
+                unacked,
+                transfer.expires_at.saturating_sub(now).max(1),
+            )
+            .await?;
+        let _: () = redis
+            .zadd(
+                UNACKED,
+                transfer.id.to_string(),
+                now + self.baton.ack_timeout,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Stops holding an acked transfer, false if it wasn't held.
+    /// Whoever deletes the held transfer first decides between the ack and queueing it again
+    pub(super) async fn forget_unacked(&self, id: Uuid) -> color_eyre::Result<bool> {
+        let redis = &self.redis;
+        let deleted: usize = redis.del(unacked_key(id)).await?;
+        let _: () = redis.zrem(UNACKED, id.to_string()).await?;
+        Ok(deleted == 1)
+    }
+
+    /// Queues transfers that weren't acked in time again forever, meant to be spawned once
+    pub async fn redelivery_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.process_unacked().await {
+                error!("Redelivering unacked transfers failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn process_unacked(&self) -> color_eyre::Result<()> {
+        self.recover_jobs(UNACKED).await?;
+        let redis = &self.redis;
+        let due: Vec<String> = redis.zrangebyscore(UNACKED, ..=unix_now()).await?;
+        for id in due {
+            if !self.claim_job(UNACKED, &id).await? {
+                continue;
+            }
+            let key = unacked_key(id.parse()?);
+            // Acked or expired in the meantime, an ack after this finds it gone and is refused
+            let Some(unacked) = redis.get_del::<_, Option<UnackedTransfer>>(&key).await? else {
+                self.release_job(UNACKED, &id).await?;
+                continue;
+            };
+            let transfer: QueuedTransfer = serde_json::from_str(&unacked.transfer)?;
+            let queue = format!("plot:{}:transfer", unacked.plot_id);
+            // Marked first so a quick take can't be overwritten
+            self.update_transfer_status(transfer.id, TransferStatus::Queued)
+                .await?;
+            // It was taken first, so it goes back to the front
+            let requeued = redis
+                .requeue_bounded(
+                    &queue,
+                    self.baton.max_queue_depth,
+                    unix_now(),
+                    &unacked.transfer,
+                )
+                .await?;
+            if requeued.is_none() {
+                // Still held by the plot, it is tried again once the ack timeout passes again
+                self.update_transfer_status(transfer.id, TransferStatus::Delivered)
+                    .await?;
+                self.hold_unacked(&unacked, &transfer).await?;
+                warn!(
+                    "Queue of plot {} is full, transfer {} stays unacked",
+                    unacked.plot_id, transfer.id
+                );
+                self.release_job(UNACKED, &id).await?;
+                continue;
+            }
+            info!(
+                "Transfer {} wasn't acked by plot {} in time, queued it again",
+                transfer.id, unacked.plot_id
+            );
+            let _: () = redis
+                .publish(
+                    format!("plot:{}:transfer:notify", unacked.plot_id),
+                    transfer.id.to_string(),
+                )
+                .await?;
+            if self.get_webhook(unacked.plot_id).await?.is_some() {
+                self.queue_webhook_delivery(unacked.plot_id, transfer.id)
+                    .await?;
+            }
+            self.release_job(UNACKED, &id).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A taken transfer waiting for its ack
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct UnackedTransfer {
+    plot_id: PlotId,
+    /// As it was queued, so it goes back unchanged
+    transfer: String,
+}
+
+fn unacked_key(id: Uuid) -> String {
+    format!("transfer:{}:unacked", id)
+}
@@ -0,0 +1,213 @@
+use std::{sync::Arc, time::Duration};
+
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use tracing::{error, warn};
+
+use super::{
+    baton::unix_now,
+    history::{from_text, to_text},
+    Store,
+};
+
+const FEED_WEBHOOK_KEY: &str = "federation:webhook:pending";
+
+/// Something that happened to another instance, for operators auditing their peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum FederationEvent {
+    InstanceRegistered,
+    InstanceRemoved,
+    TierChanged,
+    /// Its pings started failing, or answering again
+    HealthChanged,
+    /// It answered a ping with a key other than the registered one
+    KeyChanged,
+    TokenIssued,
+    TokensRevoked,
+    KeyBlocked,
+    KeyUnblocked,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct FederationEventRecord {
+    pub id: i64,
+    pub event: FederationEvent,
+    /// None for events about a key or token that isn't tied to a known domain
+    pub domain: Option<String>,
+    pub detail: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+/// Federation event feed
+impl Store {
+    /// Records the event and queues it for the federation webhook,
+    /// failing to is only logged so it never fails the request
+    pub async fn federation_event(
+        &self,
+        event: FederationEvent,
+        domain: Option<&str>,
+        detail: Option<String>,
+    ) {
+        if let Err(err) = self.insert_federation_event(event, domain, detail).await {
+            error!("Recording {:?} failed: {:?}", event, err);
+        }
+    }
+
+    async fn insert_federation_event(
+        &self,
+        event: FederationEvent,
+        domain: Option<&str>,
+        detail: Option<String>,
+    ) -> color_eyre::Result<()> {
+        let id = query!(
+            "INSERT INTO federation_event (event, domain, detail) VALUES ($1, $2, $3) RETURNING id",
+            to_text(event)?,
+            domain.map(str::to_ascii_lowercase),
+            detail
+        )
+        .fetch_one(&self.pg)
+        .await?
+        .id;
+        if self.federation_webhook.is_some() {
+            let job = FeedWebhookJob { id, attempts: 0 };
+            let _: () = self
+                .redis
+                .zadd(FEED_WEBHOOK_KEY, serde_json::to_string(&job)?, unix_now())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Newest first, `before` is the id of the last record of the previous page
+    pub async fn federation_events(
+        &self,
+        domain: Option<&str>,
+        before: Option<i64>,
+        limit: u32,
+    ) -> color_eyre::Result<Vec<FederationEventRecord>> {
+        let rows = query!(
+            r#"SELECT
+                id,
+                event,
+                domain,
+                detail,
+                EXTRACT(EPOCH FROM created_at)::BIGINT as "created_at!"
+            FROM federation_event
+            WHERE
+                ($1::TEXT IS NULL OR LOWER(domain) = LOWER($1))
+                AND ($2::BIGINT IS NULL OR id < $2)
+            ORDER BY id DESC
+            LIMIT $3"#,
+            domain,
+            before,
+            limit as i64
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(FederationEventRecord {
+                    id: row.id,
+                    event: from_text(&row.event)?,
+                    domain: row.domain,
+                    detail: row.detail,
+                    created_at: row.created_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Posts recorded events to the federation webhook forever, meant to be spawned once
+    pub async fn federation_webhook_worker(self: Arc<Self>) {
+        if self.federation_webhook.is_none() {
+            return;
+        }
+        loop {
+            if let Err(err) = self.process_feed_webhook().await {
+                error!("Processing federation webhooks failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn process_feed_webhook(&self) -> color_eyre::Result<()> {
+        let Some(webhook) = &self.federation_webhook else {
+            return Ok(());
+        };
+        self.recover_jobs(FEED_WEBHOOK_KEY).await?;
+        let redis = &self.redis;
+        let due: Vec<String> = redis.zrangebyscore(FEED_WEBHOOK_KEY, ..=unix_now()).await?;
+        for raw in due {
+            if !self.claim_job(FEED_WEBHOOK_KEY, &raw).await? {
+                continue;
+            }
+            let mut job: FeedWebhookJob = serde_json::from_str(&raw)?;
+            job.attempts += 1;
+            let Some(record) = self.federation_event_by_id(job.id).await? else {
+                self.release_job(FEED_WEBHOOK_KEY, &raw).await?;
+                continue;
+            };
+            if let Err(err) = self
+                .post_signed(webhook, serde_json::to_vec(&record)?)
+                .await
+            {
+                warn!("Federation webhook of event {} failed: {}", job.id, err);
+                // Out of attempts, it can still be read from the feed
+                if job.attempts < self.baton.relay_max_attempts {
+                    let backoff = self
+                        .baton
+                        .relay_backoff
+                        .saturating_mul(1 << (job.attempts - 1).min(16));
+                    let _: () = redis
+                        .zadd(
+                            FEED_WEBHOOK_KEY,
+                            serde_json::to_string(&job)?,
+                            unix_now() + backoff,
+                        )
+                        .await?;
+                }
+            }
+            self.release_job(FEED_WEBHOOK_KEY, &raw).await?;
+        }
+        Ok(())
+    }
+
+    async fn federation_event_by_id(
+        &self,
+        id: i64,
+    ) -> color_eyre::Result<Option<FederationEventRecord>> {
+        let Some(row) = query!(
+            r#"SELECT
+                id,
+                event,
+                domain,
+                detail,
+                EXTRACT(EPOCH FROM created_at)::BIGINT as "created_at!"
+            FROM federation_event
+            WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(FederationEventRecord {
+            id: row.id,
+            event: from_text(&row.event)?,
+            domain: row.domain,
+            detail: row.detail,
+            created_at: row.created_at,
+        }))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FeedWebhookJob {
+    id: i64,
+    attempts: u32,
+}
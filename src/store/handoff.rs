@@ -0,0 +1,275 @@
+use base64::Engine;
+use ed25519_dalek::VerifyingKey;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::{
+    api::{auth::NONCE_HEADER, PlotId},
+    dfjson,
+    instance::{ExternalDomain, InstanceDomain},
+    BASE64,
+};
+
+use super::{
+    baton::{unix_now, BatonSettings, InstanceTrustSetError, Origin, QueuedTransfer},
+    member::Member,
+    meta::PlotMeta,
+    Store,
+};
+
+/// Everything stored about a plot that moves with it to another instance
+#[derive(Serialize, Deserialize)]
+pub struct PlotExport {
+    pub plot_id: PlotId,
+    pub owner: Uuid,
+    pub trusted: Vec<ExportedTrust>,
+    /// Base64 encoded keys
+    pub trusted_instances: Vec<String>,
+    pub settings: BatonSettings,
+    pub meta: PlotMeta,
+    pub members: Vec<Member>,
+    /// Pending transfers, oldest first
+    pub transfers: Vec<QueuedTransfer>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportedTrust {
+    pub plot: PlotId,
+    /// Unix timestamp in seconds
+    pub expires_at: Option<u64>,
+}
+
+/// Bytes the old instance signs when handing a plot off, like [crate::api::baton::signing_message]
+pub fn handoff_message(plot_id: PlotId, nonce: &str, export: &serde_json::Value) -> Vec<u8> {
+    let mut msg = format!("DFTOOLS HANDOFF {} {}\n", plot_id, nonce).into_bytes();
+    msg.extend(dfjson::canonical_json(export));
+    msg
+}
+
+/// Handoff
+impl Store {
+    /// Domain of a known instance, None if it is this instance or unknown
+    pub async fn instance_domain(
+        &self,
+        key: &VerifyingKey,
+    ) -> color_eyre::Result<Option<ExternalDomain>> {
+        if *key == self.public_key {
+            return Ok(None);
+        }
+        let row = query!(
+            "SELECT domain FROM known_instance WHERE public_key = $1",
+            key.as_bytes().as_slice()
+        )
+        .fetch_optional(&self.pg)
+        .await?;
+        Ok(match row {
+            Some(row) => match InstanceDomain::from_option(Some(row.domain))? {
+                InstanceDomain::External(domain) => Some(domain),
+                InstanceDomain::Current => None,
+            },
+            None => None,
+        })
+    }
+
+    pub async fn export_plot(&self, plot_id: PlotId) -> color_eyre::Result<Option<PlotExport>> {
+        let Some(plot) = self.get_plot(plot_id).await? else {
+            return Ok(None);
+        };
+        let trusted = query!(
+            r#"SELECT trusted, EXTRACT(EPOCH FROM expires_at)::BIGINT as expires_at
+            FROM baton_trust
+            WHERE plot = $1 AND (expires_at IS NULL OR expires_at > NOW() AT TIME ZONE 'UTC')
+            ORDER BY trusted"#,
+            plot_id
+        )
+        .fetch_all(&self.pg)
+        .await?
+        .into_iter()
+        .map(|row| ExportedTrust {
+            plot: row.trusted,
+            expires_at: row.expires_at.map(|at| at as u64),
+        })
+        .collect();
+        let trusted_instances = self
+            .fetch_instance_trust(plot_id)
+            .await?
+            .into_iter()
+            .map(|key| BASE64.encode(key))
+            .collect();
+        let redis = &self.redis;
+        let transfers: Vec<QueuedTransfer> = redis
+            .lrange(format!("plot:{}:transfer", plot_id), 0, -1)
+            .await?;
+        let now = unix_now();
+        Ok(Some(PlotExport {
+            plot_id,
+            owner: plot.owner,
+            trusted,
+            trusted_instances,
+            settings: self.get_baton_settings(plot_id).await?,
+            meta: self.get_plot_meta(plot_id).await?,
+            members: self.list_members(plot_id).await?,
+            transfers: transfers
+                .into_iter()
+                .filter(|it| it.expires_at > now)
+                .collect(),
+        }))
+    }
+
+    /// Sends everything stored about the plot to the instance it moves to.
+    /// Handed off transfers are taken out of the queue here
+    pub async fn hand_off_plot(
+        &self,
+        plot_id: PlotId,
+        domain: &ExternalDomain,
+    ) -> color_eyre::Result<Result<(), HandoffError>> {
+        let Some(export) = self.export_plot(plot_id).await? else {
+            return Ok(Err(HandoffError::Rejected(
+                "Plot not registered".to_string(),
+            )));
+        };
+        let queued = export.transfers.len();
+        let token = match self.server_token(domain).await {
+            Ok(token) => token,
+            Err(err) => return Ok(Err(HandoffError::Unreachable(err.to_string()))),
+        };
+        let body = serde_json::to_value(&export)?;
+        let nonce = Uuid::new_v4().to_string();
+        let signature = self.sign(&handoff_message(plot_id, &nonce, &body)).await;
+        let res = match self
+            .client
+            .post(self.api_url(domain, "instance", "/plot/import").await)
+            .header("X-Server-Key", token)
+            .header(NONCE_HEADER, &nonce)
+            .header("X-Handoff-Signature", BASE64.encode(signature.to_bytes()))
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&body)?)
+            .send()
+            .await
+        {
+            Ok(res) => res,
+            Err(err) => return Ok(Err(HandoffError::Unreachable(err.to_string()))),
+        };
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            return Ok(Err(HandoffError::Rejected(format!("{}: {}", status, body))));
+        }
+        // Transfers queued since the export stay, the new instance has the rest
+        let redis = &self.redis;
+        let _: () = redis
+            .ltrim(format!("plot:{}:transfer", plot_id), queued as isize, -1)
+            .await?;
+        Ok(Ok(()))
+    }
+
+    /// Takes over the state of a plot handed off by the instance it was registered to.
+    /// Returns how many transfers were queued
+    pub async fn import_plot(
+        &self,
+        export: PlotExport,
+        origin: &Origin,
+    ) -> color_eyre::Result<Result<usize, ImportError>> {
+        let plot_id = export.plot_id;
+        let Some(plot) = self.get_plot(plot_id).await? else {
+            return Ok(Err(ImportError::PlotNotRegistered));
+        };
+        if plot.instance.domain != InstanceDomain::Current {
+            return Ok(Err(ImportError::PlotNotRegistered));
+        }
+        if plot.owner != export.owner {
+            return Ok(Err(ImportError::OwnerMismatch));
+        }
+
+        let now = unix_now();
+        for trust in export.trusted {
+            if trust.expires_at.is_some_and(|at| at <= now) {
+                continue;
+            }
+            // Plots that aren't registered here can't be trusted here either
+            if self.plot_exists(trust.plot).await? {
+                let _ = self
+                    .add_plot_trust(plot_id, trust.plot, trust.expires_at)
+                    .await?;
+            }
+        }
+
+        let mut instances = self.fetch_instance_trust(plot_id).await?;
+        for key in &export.trusted_instances {
+            let key = BASE64
+                .decode(key)
+                .ok()
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .and_then(|key| VerifyingKey::from_bytes(&key).ok());
+            if let Some(key) = key.filter(|key| !instances.contains(key)) {
+                instances.push(key);
+            }
+        }
+        // Instances unknown here or that can't be trusted as a whole are left out
+        if let Err(
+            InstanceTrustSetError::InstanceNotFound(dropped)
+            | InstanceTrustSetError::NotTrustable(dropped),
+        ) = self.set_instance_trust(plot_id, instances.clone()).await?
+        {
+            instances.retain(|key| !dropped.contains(key));
+            if let Err(InstanceTrustSetError::NotTrustable(dropped)) =
+                self.set_instance_trust(plot_id, instances.clone()).await?
+            {
+                instances.retain(|key| !dropped.contains(key));
+                let _ = self.set_instance_trust(plot_id, instances).await?;
+            }
+        }
+
+        let _ = self.set_baton_settings(plot_id, &export.settings).await?;
+        let meta = self.get_plot_meta(plot_id).await?;
+        if meta.name.is_none() && meta.description.is_none() && meta.tags.is_empty() {
+            self.set_plot_meta(plot_id, &export.meta).await?;
+        }
+        for member in export.members {
+            if member.uuid != plot.owner {
+                self.set_member(plot_id, member.uuid, &member.abilities)
+                    .await?;
+            }
+        }
+
+        let mut queued = 0;
+        for transfer in export.transfers {
+            if transfer.expires_at <= now {
+                continue;
+            }
+            let origin = transfer.origin.unwrap_or_else(|| origin.clone());
+            if self
+                .push_transfer(
+                    transfer.id,
+                    transfer.from,
+                    plot_id,
+                    transfer.payload,
+                    origin,
+                )
+                .await?
+                .is_ok()
+            {
+                queued += 1;
+            }
+        }
+        Ok(Ok(queued))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandoffError {
+    #[error("New instance unreachable: {0}")]
+    Unreachable(String),
+    #[error("New instance rejected the handoff: {0}")]
+    Rejected(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("Plot isn't registered to this instance, register it here first")]
+    PlotNotRegistered,
+    #[error("Plot is registered here under another owner")]
+    OwnerMismatch,
+}
@@ -0,0 +1,190 @@
+use std::{sync::Arc, time::Duration};
+
+use poem_openapi::Enum;
+use sqlx::query;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::api::{
+    baton::{PayloadKind, TransferPayload, TransferStatus},
+    PlotId,
+};
+
+use super::{baton::unix_now, Store};
+
+/// History
+impl Store {
+    /// Logs a new transfer, or the new status of one logged before
+    pub async fn log_transfer(
+        &self,
+        id: Uuid,
+        from: PlotId,
+        to: PlotId,
+        payload: &TransferPayload,
+        status: TransferStatus,
+        expires_at: u64,
+    ) -> color_eyre::Result<()> {
+        let size = serde_json::to_vec(payload)?.len();
+        let stored = if self.baton.history_payloads {
+            Some(serde_json::to_string(payload)?)
+        } else {
+            None
+        };
+        query!(
+            "INSERT INTO baton_transfer_log (id, sender, receiver, kind, size, outcome, payload, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (id) DO UPDATE SET
+                outcome = EXCLUDED.outcome,
+                expires_at = EXCLUDED.expires_at,
+                updated_at = NOW()",
+            id,
+            from,
+            to,
+            to_text(payload.kind())?,
+            size as i32,
+            to_text(status)?,
+            stored,
+            expires_at as i64
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+
+    /// Updates the outcome of a logged transfer, nothing happens if it isn't logged
+    pub async fn log_transfer_status(
+        &self,
+        id: Uuid,
+        status: TransferStatus,
+    ) -> color_eyre::Result<()> {
+        query!(
+            "UPDATE baton_transfer_log SET outcome = $2, updated_at = NOW() WHERE id = $1",
+            id,
+            to_text(status)?
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+
+    /// Transfers sent or received by the plot, newest first
+    pub async fn transfer_history(
+        &self,
+        plot_id: PlotId,
+        filter: HistoryFilter,
+    ) -> color_eyre::Result<Vec<HistoryEntry>> {
+        let outcome = filter.status.map(to_text).transpose()?;
+        let (sent, received) = match filter.direction {
+            None => (true, true),
+            Some(HistoryDirection::Sent) => (true, false),
+            Some(HistoryDirection::Received) => (false, true),
+        };
+        let rows = query!(
+            r#"SELECT id, sender, receiver, kind, size, outcome, payload, expires_at,
+                EXTRACT(EPOCH FROM created_at)::BIGINT as "created_at!",
+                EXTRACT(EPOCH FROM updated_at)::BIGINT as "updated_at!"
+            FROM baton_transfer_log
+            WHERE (($2 AND sender = $1) OR ($3 AND receiver = $1))
+                AND ($4::INTEGER IS NULL OR sender = $4 OR receiver = $4)
+                AND ($5::TEXT IS NULL OR outcome = $5)
+            ORDER BY created_at DESC, id
+            LIMIT $6 OFFSET $7"#,
+            plot_id,
+            sent,
+            received,
+            filter.peer,
+            outcome,
+            filter.limit,
+            filter.offset
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        let now = unix_now() as i64;
+        rows.into_iter()
+            .map(|row| {
+                let mut status: TransferStatus = from_text(&row.outcome)?;
+                if status == TransferStatus::Queued && row.expires_at <= now {
+                    status = TransferStatus::Expired;
+                }
+                Ok(HistoryEntry {
+                    id: row.id,
+                    from: row.sender,
+                    to: row.receiver,
+                    kind: from_text(&row.kind)?,
+                    size: row.size as u32,
+                    status,
+                    payload: row
+                        .payload
+                        .map(|it| serde_json::from_str(&it))
+                        .transpose()?,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Drops history past its retention forever, meant to be spawned once
+    pub async fn history_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.prune_history().await {
+                error!("Pruning transfer history failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    }
+
+    async fn prune_history(&self) -> color_eyre::Result<()> {
+        query!(
+            "DELETE FROM baton_transfer_log WHERE created_at < NOW() - make_interval(days => $1)",
+            self.baton.history_days as i32
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Enums are stored by their serde name
+pub(super) fn to_text<T: serde::Serialize>(value: T) -> color_eyre::Result<String> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(it) => Ok(it),
+        other => Err(color_eyre::eyre::eyre!("Expected a string, got {}", other)),
+    }
+}
+
+pub(super) fn from_text<T: serde::de::DeserializeOwned>(value: &str) -> color_eyre::Result<T> {
+    Ok(serde_json::from_value(serde_json::Value::String(
+        value.to_string(),
+    ))?)
+}
+
+pub struct HistoryFilter {
+    pub direction: Option<HistoryDirection>,
+    /// Only transfers to or from this plot
+    pub peer: Option<PlotId>,
+    pub status: Option<TransferStatus>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[oai(rename_all = "snake_case")]
+pub enum HistoryDirection {
+    Sent,
+    Received,
+}
+
+pub struct HistoryEntry {
+    pub id: Uuid,
+    pub from: PlotId,
+    pub to: PlotId,
+    pub kind: PayloadKind,
+    pub size: u32,
+    pub status: TransferStatus,
+    pub payload: Option<TransferPayload>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds
+    pub updated_at: i64,
+}
@@ -0,0 +1,81 @@
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::api::PlotId;
+
+use super::Store;
+
+/// Results of requests are remembered this long
+const IDEMPOTENCY_TTL: u64 = 60 * 60 * 24;
+/// A claim is given up after this long in case the request never finishes
+const CLAIM_TTL: u64 = 60;
+
+/// Idempotency
+impl Store {
+    /// Claims an idempotency key, unless a request with the same key was seen before
+    pub async fn claim_idempotency_key<T: DeserializeOwned>(
+        &self,
+        key: &IdempotencyKey,
+    ) -> color_eyre::Result<IdempotencyClaim<T>> {
+        let redis = &self.redis;
+        let claimed = redis
+            .set_nx_ex(&key.0, IdempotentResult::Pending, CLAIM_TTL)
+            .await?;
+        if claimed {
+            return Ok(IdempotencyClaim::Claimed);
+        }
+        let existing: Option<IdempotentResult> = redis.get(&key.0).await?;
+        Ok(match existing {
+            Some(IdempotentResult::Done(result)) => {
+                IdempotencyClaim::Done(serde_json::from_str(&result)?)
+            }
+            Some(IdempotentResult::Pending) => IdempotencyClaim::InProgress,
+            // Expired in between, let the retry decide
+            None => IdempotencyClaim::InProgress,
+        })
+    }
+
+    /// Remembers the result of a claimed key, `None` releases the claim so the request can be retried
+    pub async fn finish_idempotency_key<T: Serialize>(
+        &self,
+        key: &IdempotencyKey,
+        result: Option<&T>,
+    ) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        match result {
+            Some(result) => {
+                let result = IdempotentResult::Done(serde_json::to_string(result)?);
+                let _: () = redis.set_ex(&key.0, result, IDEMPOTENCY_TTL).await?;
+            }
+            None => {
+                let _: () = redis.del(&key.0).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Redis key of an idempotency key, scoped to an endpoint and the plot sending
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    pub fn new(scope: &str, plot_id: PlotId, key: &str) -> Self {
+        Self(format!("idempotency:{}:{}:{}", scope, plot_id, key))
+    }
+}
+
+pub enum IdempotencyClaim<T> {
+    /// First time this key is seen, the result should be finished
+    Claimed,
+    /// Another request with this key hasn't finished yet
+    InProgress,
+    /// Result of the earlier request
+    Done(T),
+}
+
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+enum IdempotentResult {
+    Pending,
+    /// JSON encoded result
+    Done(String),
+}
@@ -1,41 +1,92 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ascii_domain::dom::Domain;
+use base64::Engine;
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use hmac::Hmac;
-use redis::{aio::MultiplexedConnection, AsyncCommands};
+use poem_openapi::{Enum, Object};
 use redis_macros::{FromRedisValue, ToRedisArgs};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use sqlx::{query, query_as, Pool, Postgres};
+use tracing::{error, warn};
 use uuid::Uuid;
 
 use crate::{
-    api::{auth::Plot, PlotId},
-    instance::{ExternalDomain, Instance},
+    api::{auth::Plot, instance::InstanceInfo, key_fingerprint, PlotId},
+    federation::FederationPolicy,
+    instance::{ExternalDomain, Instance, SendInstance},
+    BASE64,
+};
+
+use super::{
+    baton::BatonConfig,
+    breaker::BreakerState,
+    cache::Cache,
+    feed::FederationEvent,
+    history::{from_text, to_text},
+    invalidate::Invalidation,
+    local::{LocalCache, LOCAL_CACHE_CAPACITY, LOCAL_CACHE_TTL},
+    webhook::Webhook,
+    Store,
 };
 
-use super::Store;
+/// Seconds between pings of every registered instance
+const INSTANCE_HEALTH_INTERVAL: u64 = 60 * 10;
+/// TXT records at `_dftools.{domain}` proving the domain belongs to an instance look like `dftools-key={fingerprint}`
+pub const DNS_RECORD_PREFIX: &str = "dftools-key=";
+/// Domains of instances that have been down for `instance_down_after`, with when they went down
+const DOWN_INSTANCES_KEY: &str = "instances:down";
+/// Longest name of another instance that is kept
+const MAX_INSTANCE_NAME_CHARS: usize = 64;
+/// Longest contact of another instance that is kept
+const MAX_INSTANCE_CONTACT_CHARS: usize = 256;
 
 impl Store {
-    pub fn new(
-        redis: MultiplexedConnection,
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        cache: Cache,
         pg: Pool<Postgres>,
         client: Client,
         jwt_key: Hmac<Sha256>,
         secret_key: SigningKey,
-    ) -> Self {
-        Self {
-            redis,
+        domain: Domain<String>,
+        baton: BatonConfig,
+        federation: FederationPolicy,
+        federation_webhook: Option<Webhook>,
+        outbound_retries: u32,
+    ) -> color_eyre::Result<Self> {
+        Ok(Self {
+            redis: cache,
             pg,
             client,
             jwt_key,
             public_key: secret_key.verifying_key(),
             secret_key: secret_key.into(),
-        }
+            domain,
+            baton,
+            federation,
+            federation_webhook,
+            outbound_retries,
+            disabled_plots: Default::default(),
+            local_plots: LocalCache::new(LOCAL_CACHE_TTL, LOCAL_CACHE_CAPACITY),
+            local_plot_trust: LocalCache::new(LOCAL_CACHE_TTL, LOCAL_CACHE_CAPACITY),
+            local_instance_trust: LocalCache::new(LOCAL_CACHE_TTL, LOCAL_CACHE_CAPACITY),
+        })
     }
 
     pub async fn plot_exists(&self, plot_id: PlotId) -> color_eyre::Result<bool> {
-        let mut redis = self.redis.clone();
+        if self.local_plots.get(&plot_id).is_some() {
+            self.record_plot_cache(plot_id, true);
+            return Ok(true);
+        }
+        let redis = &self.redis;
         let found: Option<()> = redis.get(format!("plot:{}", plot_id)).await?;
+        self.record_plot_cache(plot_id, found.is_some());
         if let Some(_val) = found {
             Ok(true)
         } else {
@@ -44,11 +95,407 @@ impl Store {
         }
     }
 
+    /// Adds an instance after it proved it has the key, returns false if it was known already
+    pub async fn register_instance(
+        &self,
+        domain: &ExternalDomain,
+        key: &VerifyingKey,
+    ) -> color_eyre::Result<Result<bool, RegisterInstanceError>> {
+        let domain = domain.inner().as_inner();
+        let existing = query!(
+            "SELECT domain, public_key FROM known_instance
+            WHERE LOWER(domain) = LOWER($1) OR public_key = $2",
+            domain,
+            key.as_bytes()
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        if let Some(row) = existing.first() {
+            return Ok(if !row.domain.eq_ignore_ascii_case(domain) {
+                Err(RegisterInstanceError::KeyTaken)
+            } else if row.public_key != key.as_bytes() || existing.len() > 1 {
+                Err(RegisterInstanceError::DomainTaken)
+            } else {
+                Ok(false)
+            });
+        }
+        let inserted = query!(
+            "INSERT INTO known_instance (public_key, domain) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            key.as_bytes(),
+            domain
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        // Lost a race against another registration of the domain or key
+        if inserted == 0 {
+            return Ok(Err(RegisterInstanceError::DomainTaken));
+        }
+        Ok(Ok(true))
+    }
+
+    /// Whether `_dftools.{domain}` has a TXT record with the fingerprint of the key,
+    /// looked up with a DNS over HTTPS resolver that answers in JSON
+    pub async fn verify_instance_dns(
+        &self,
+        resolver: &str,
+        domain: &ExternalDomain,
+        key: &VerifyingKey,
+    ) -> color_eyre::Result<bool> {
+        #[derive(Deserialize)]
+        struct DnsResponse {
+            #[serde(rename = "Answer", default)]
+            answer: Vec<DnsAnswer>,
+        }
+        #[derive(Deserialize)]
+        struct DnsAnswer {
+            #[serde(rename = "type")]
+            kind: u16,
+            data: String,
+        }
+        /// Type of TXT records
+        const TXT: u16 = 16;
+
+        let fingerprint = key_fingerprint(key);
+        let body = self
+            .send_retrying(
+                self.client
+                    .get(resolver)
+                    .query(&[
+                        ("name", format!("_dftools.{}", domain.host())),
+                        ("type", "TXT".to_string()),
+                    ])
+                    .header(reqwest::header::ACCEPT, "application/dns-json"),
+            )
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let response: DnsResponse = serde_json::from_str(&body)?;
+        Ok(response
+            .answer
+            .iter()
+            .filter(|answer| answer.kind == TXT)
+            .any(|answer| {
+                // Long records come split into quoted strings
+                let value: String = answer.data.split('"').skip(1).step_by(2).collect();
+                let value = if value.is_empty() {
+                    &answer.data
+                } else {
+                    &value
+                };
+                value.trim().strip_prefix(DNS_RECORD_PREFIX) == Some(fingerprint.as_str())
+            }))
+    }
+
+    /// Tier the operator gave a known instance, None if it isn't known
+    pub async fn instance_tier(&self, domain: &str) -> color_eyre::Result<Option<InstanceTier>> {
+        let key = tier_key(domain);
+        let redis = &self.redis;
+        if let Some(tier) = redis.get::<_, Option<String>>(&key).await? {
+            return Ok(Some(from_text(&tier)?));
+        }
+        let Some(row) = query!(
+            "SELECT tier FROM known_instance WHERE LOWER(domain) = LOWER($1)",
+            domain
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let _: () = redis.set(key, &row.tier).await?;
+        Ok(Some(from_text(&row.tier)?))
+    }
+
+    /// Returns false if the instance isn't known
+    pub async fn set_instance_tier(
+        &self,
+        domain: &str,
+        tier: InstanceTier,
+    ) -> color_eyre::Result<bool> {
+        let updated = query!(
+            "UPDATE known_instance SET tier = $2 WHERE LOWER(domain) = LOWER($1)",
+            domain,
+            to_text(tier)?
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        let _: () = self.redis.del(tier_key(domain)).await?;
+        Ok(updated > 0)
+    }
+
+    /// Registered instances ordered by domain
+    pub async fn list_instances(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> color_eyre::Result<Vec<KnownInstanceInfo>> {
+        let rows = query!(
+            r#"SELECT
+                domain,
+                public_key,
+                health,
+                tier,
+                name,
+                contact,
+                version,
+                apis,
+                EXTRACT(EPOCH FROM registered_at)::BIGINT as "registered_at!",
+                EXTRACT(EPOCH FROM health_checked_at)::BIGINT as health_checked_at
+            FROM known_instance
+            ORDER BY domain
+            LIMIT $1 OFFSET $2"#,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let key = VerifyingKey::from_bytes(row.public_key.as_slice().try_into()?)?;
+                Ok(KnownInstanceInfo {
+                    domain: row.domain,
+                    key: BASE64.encode(key),
+                    key_fingerprint: key_fingerprint(&key),
+                    registered_at: row.registered_at,
+                    health: row
+                        .health
+                        .map(|health| from_text(&health))
+                        .transpose()?
+                        .unwrap_or(InstanceHealth::Unknown),
+                    health_checked_at: row.health_checked_at,
+                    tier: from_text(&row.tier)?,
+                    name: row.name,
+                    contact: row.contact,
+                    version: row.version,
+                    apis: row.apis,
+                })
+            })
+            .collect()
+    }
+
+    /// Pings every registered instance forever, meant to be spawned once
+    pub async fn instance_health_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.check_instance_health().await {
+                error!("Checking instance health failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(INSTANCE_HEALTH_INTERVAL)).await;
+        }
+    }
+
+    async fn check_instance_health(&self) -> color_eyre::Result<()> {
+        let rows = query!("SELECT id, domain, public_key, health FROM known_instance")
+            .fetch_all(&self.pg)
+            .await?;
+        for row in rows {
+            let started = Instant::now();
+            let mut answered_key = None;
+            let health = match ExternalDomain::try_from(row.domain.clone()) {
+                Ok(domain) => match self.ping_instance(&domain).await {
+                    Ok(key) if key.as_bytes() == row.public_key.as_slice() => {
+                        self.cache_instance_key(&row.domain, &key).await?;
+                        if let Err(err) = self.refresh_instance_info(&domain).await {
+                            warn!("Fetching info of {} failed: {:?}", row.domain, err);
+                        }
+                        InstanceHealth::Healthy
+                    }
+                    Ok(key) => {
+                        self.cache_instance_key(&row.domain, &key).await?;
+                        answered_key = Some(key);
+                        InstanceHealth::KeyMismatch
+                    }
+                    Err(err) => {
+                        warn!("Pinging {} failed: {:?}", row.domain, err);
+                        InstanceHealth::Unreachable
+                    }
+                },
+                Err(_) => InstanceHealth::Unreachable,
+            };
+            let latency_ms = started.elapsed().as_millis() as i32;
+            let previous = row.health.as_deref().unwrap_or("unknown");
+            let current = to_text(health)?;
+            if previous != current {
+                let (event, detail) = match answered_key {
+                    Some(key) => (
+                        FederationEvent::KeyChanged,
+                        format!("Answered with key {}", key_fingerprint(&key)),
+                    ),
+                    None => (
+                        FederationEvent::HealthChanged,
+                        format!("{} -> {}", previous, current),
+                    ),
+                };
+                self.federation_event(event, Some(&row.domain), Some(detail))
+                    .await;
+            }
+            if health == InstanceHealth::Healthy {
+                query!(
+                    "UPDATE known_instance SET
+                        health = $2,
+                        health_checked_at = NOW(),
+                        latency_ms = $3,
+                        failures = 0,
+                        down_since = NULL
+                    WHERE id = $1",
+                    row.id,
+                    to_text(health)?,
+                    latency_ms
+                )
+                .execute(&self.pg)
+                .await?;
+            } else {
+                query!(
+                    "UPDATE known_instance SET
+                        health = $2,
+                        health_checked_at = NOW(),
+                        failures = failures + 1,
+                        down_since = COALESCE(down_since, NOW())
+                    WHERE id = $1",
+                    row.id,
+                    to_text(health)?
+                )
+                .execute(&self.pg)
+                .await?;
+            }
+        }
+        self.sync_down_instances().await
+    }
+
+    /// Copies the instances that failed every ping for `instance_down_after` to redis
+    async fn sync_down_instances(&self) -> color_eyre::Result<()> {
+        let down: Vec<(String, i64)> = query!(
+            r#"SELECT
+                LOWER(domain) as "domain!",
+                EXTRACT(EPOCH FROM down_since)::BIGINT as "down_since!"
+            FROM known_instance
+            WHERE down_since <= NOW() - make_interval(secs => $1)"#,
+            self.baton.instance_down_after as f64
+        )
+        .fetch_all(&self.pg)
+        .await?
+        .into_iter()
+        .map(|row| (row.domain, row.down_since))
+        .collect();
+        let _: () = self.redis.hreplace(DOWN_INSTANCES_KEY, &down).await?;
+        Ok(())
+    }
+
+    /// Unix timestamp in seconds of the first failed ping of an instance that has been down
+    /// for `instance_down_after`, None if it answers
+    pub async fn instance_down_since(&self, domain: &str) -> color_eyre::Result<Option<i64>> {
+        Ok(self
+            .redis
+            .hget(DOWN_INSTANCES_KEY, domain.to_ascii_lowercase())
+            .await?)
+    }
+
+    /// How every registered instance answered its pings, ordered by domain
+    pub async fn instance_health(&self) -> color_eyre::Result<Vec<InstanceHealthInfo>> {
+        let rows = query!(
+            r#"SELECT
+                domain,
+                health,
+                latency_ms,
+                failures,
+                EXTRACT(EPOCH FROM down_since)::BIGINT as down_since,
+                EXTRACT(EPOCH FROM health_checked_at)::BIGINT as health_checked_at
+            FROM known_instance
+            ORDER BY domain"#
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        let mut instances = Vec::with_capacity(rows.len());
+        for row in rows {
+            instances.push(InstanceHealthInfo {
+                breaker: self.breaker_state(&row.domain).await?,
+                domain: row.domain,
+                health: row
+                    .health
+                    .map(|health| from_text(&health))
+                    .transpose()?
+                    .unwrap_or(InstanceHealth::Unknown),
+                latency_ms: row.latency_ms.map(|it| it as u32),
+                failures: row.failures as u32,
+                down_since: row.down_since,
+                health_checked_at: row.health_checked_at,
+            });
+        }
+        Ok(instances)
+    }
+
+    /// Asks a known instance about itself and keeps what it says
+    pub async fn refresh_instance_info(&self, domain: &ExternalDomain) -> color_eyre::Result<()> {
+        let body = self
+            .send_retrying(
+                self.client
+                    .get(self.api_url(domain, "instance", "/info").await),
+            )
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let info: InstanceInfo = serde_json::from_str(&body)?;
+        let mut apis: Vec<String> = info
+            .apis
+            .iter()
+            .flat_map(|(api, versions)| {
+                versions
+                    .iter()
+                    .map(move |version| format!("{}/{}", api, version))
+            })
+            .collect();
+        apis.sort();
+        query!(
+            "UPDATE known_instance SET name = $2, contact = $3, version = $4, apis = $5
+            WHERE LOWER(domain) = LOWER($1)",
+            domain.inner().as_inner(),
+            info.name.map(|name| name
+                .chars()
+                .take(MAX_INSTANCE_NAME_CHARS)
+                .collect::<String>()),
+            info.contact.map(|contact| contact
+                .chars()
+                .take(MAX_INSTANCE_CONTACT_CHARS)
+                .collect::<String>()),
+            info.version,
+            &apis
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+
+    /// Key and domain of an instance plots are registered to, None if none are
+    pub async fn get_known_instance(
+        &self,
+        domain: &str,
+    ) -> color_eyre::Result<Option<SendInstance>> {
+        let row = query!(
+            "SELECT domain, public_key FROM known_instance WHERE LOWER(domain) = LOWER($1)",
+            domain
+        )
+        .fetch_optional(&self.pg)
+        .await?;
+        Ok(row.map(|row| SendInstance {
+            key: BASE64.encode(row.public_key),
+            domain: row.domain,
+        }))
+    }
+
     pub async fn get_plot(&self, plot_id: PlotId) -> color_eyre::Result<Option<Plot>> {
-        let mut redis = self.redis.clone();
+        if let Some(plot) = self.local_plots.get(&plot_id) {
+            self.record_plot_cache(plot_id, true);
+            return Ok(Some(plot));
+        }
+        let redis = &self.redis;
         let found: Option<Plot> = redis.get(format!("plot:{}", plot_id)).await?;
+        self.record_plot_cache(plot_id, found.is_some());
 
         if let Some(val) = found {
+            self.local_plots.insert(plot_id, val.clone());
             Ok(Some(val))
         } else {
             Ok(self.cache_plot(plot_id).await?)
@@ -72,7 +519,7 @@ impl Store {
         .fetch_optional(&self.pg)
         .await?;
 
-        let mut redis = self.redis.clone();
+        let redis = &self.redis;
         if let Some(plot) = plot {
             let plot = if let Some(key) = plot.public_key {
                 let instance = Instance::from_row(key, plot.domain)?;
@@ -89,12 +536,198 @@ impl Store {
                 }
             };
             let _: () = redis.set(format!("plot:{}", plot_id), &plot).await?;
+            self.local_plots.insert(plot_id, plot.clone());
             Ok(Some(plot))
         } else {
             Ok(None)
         }
     }
 
+    /// Every registered plot out of `plot_ids`, lowest plot id first.
+    /// Whatever isn't cached is looked up with a single MGET and a single query
+    pub async fn get_plots(&self, plot_ids: &[PlotId]) -> color_eyre::Result<Vec<Plot>> {
+        let mut plot_ids = plot_ids.to_vec();
+        plot_ids.sort_unstable();
+        plot_ids.dedup();
+        let mut plots = Vec::with_capacity(plot_ids.len());
+        let mut uncached = Vec::new();
+        for plot_id in plot_ids {
+            if let Some(plot) = self.local_plots.get(&plot_id) {
+                self.record_plot_cache(plot_id, true);
+                plots.push(plot);
+            } else {
+                uncached.push(plot_id);
+            }
+        }
+        if uncached.is_empty() {
+            return Ok(plots);
+        }
+
+        let redis = &self.redis;
+        let keys: Vec<String> = uncached.iter().map(|id| format!("plot:{}", id)).collect();
+        let found: Vec<Option<Plot>> = redis.mget(&keys).await?;
+        let mut missing = Vec::new();
+        for (plot_id, found) in uncached.into_iter().zip(found) {
+            self.record_plot_cache(plot_id, found.is_some());
+            if let Some(plot) = found {
+                self.local_plots.insert(plot_id, plot.clone());
+                plots.push(plot);
+            } else {
+                missing.push(plot_id);
+            }
+        }
+        if !missing.is_empty() {
+            let fetched = self.fetch_plots(&missing).await?;
+            let mut entries = Vec::with_capacity(fetched.len());
+            for plot in &fetched {
+                entries.push((format!("plot:{}", plot.plot_id), plot));
+                self.local_plots.insert(plot.plot_id, plot.clone());
+            }
+            let _: () = redis.set_multiple(&entries).await?;
+            plots.extend(fetched);
+        }
+        plots.sort_unstable_by_key(|plot| plot.plot_id);
+        Ok(plots)
+    }
+
+    async fn fetch_plots(&self, plot_ids: &[PlotId]) -> color_eyre::Result<Vec<Plot>> {
+        struct Row {
+            id: PlotId,
+            owner_uuid: Uuid,
+            public_key: Option<Vec<u8>>,
+            domain: Option<String>,
+        }
+        let rows = query_as!(
+            Row,
+            r#"SELECT plot.id, owner_uuid, known_instance.public_key as "public_key?", known_instance.domain as "domain?" FROM plot
+            LEFT JOIN known_instance ON plot.instance = known_instance.id
+            WHERE plot.id = ANY($1)
+            ORDER BY plot.id;"#,
+            plot_ids
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let instance = if let Some(key) = row.public_key {
+                    Instance::from_row(key, row.domain)?
+                } else {
+                    self.construct_current_instance()
+                };
+                Ok(Plot {
+                    plot_id: row.id,
+                    owner: row.owner_uuid,
+                    instance,
+                })
+            })
+            .collect()
+    }
+
+    /// Plots registered under an owner, lowest plot id first
+    pub async fn plots_by_owner(
+        &self,
+        owner: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> color_eyre::Result<Vec<Plot>> {
+        struct Row {
+            id: PlotId,
+            owner_uuid: Uuid,
+            public_key: Option<Vec<u8>>,
+            domain: Option<String>,
+        }
+        let rows = query_as!(
+            Row,
+            r#"SELECT plot.id, owner_uuid, known_instance.public_key as "public_key?", known_instance.domain as "domain?" FROM plot
+            LEFT JOIN known_instance ON plot.instance = known_instance.id
+            WHERE owner_uuid = $1
+            ORDER BY plot.id
+            LIMIT $2 OFFSET $3;"#,
+            owner,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let instance = if let Some(key) = row.public_key {
+                    Instance::from_row(key, row.domain)?
+                } else {
+                    self.construct_current_instance()
+                };
+                Ok(Plot {
+                    plot_id: row.id,
+                    owner: row.owner_uuid,
+                    instance,
+                })
+            })
+            .collect()
+    }
+
+    /// Every registered plot matching the filter with how many keys and trusts it has,
+    /// lowest plot id first
+    pub async fn admin_plots(
+        &self,
+        filter: &AdminPlotFilter,
+        limit: i64,
+        offset: i64,
+    ) -> color_eyre::Result<Vec<AdminPlot>> {
+        let (current_instance, domain) = match &filter.instance {
+            Some(InstanceFilter::Current) => (true, None),
+            Some(InstanceFilter::Domain(domain)) => (false, Some(domain.as_str())),
+            None => (false, None),
+        };
+        let rows = query!(
+            r#"SELECT
+                plot.id,
+                plot.owner_uuid,
+                known_instance.public_key as "public_key?",
+                known_instance.domain as "domain?",
+                EXTRACT(EPOCH FROM plot.registered_at)::BIGINT as registered_at,
+                (SELECT COUNT(*) FROM api_key WHERE plot = plot.id AND disabled = false) as "active_keys!",
+                (SELECT COUNT(*) FROM baton_trust WHERE plot = plot.id
+                    AND (expires_at IS NULL OR expires_at > NOW() AT TIME ZONE 'UTC')) as "trusted_plots!",
+                (SELECT COUNT(*) FROM baton_instance_trust WHERE plot = plot.id) as "trusted_instances!",
+                plot.disabled_at IS NOT NULL as "disabled!"
+            FROM plot
+            LEFT JOIN known_instance ON plot.instance = known_instance.id
+            WHERE ($1::UUID IS NULL OR plot.owner_uuid = $1)
+                AND (NOT $2 OR plot.instance IS NULL)
+                AND ($3::TEXT IS NULL OR known_instance.domain = $3)
+            ORDER BY plot.id
+            LIMIT $4 OFFSET $5;"#,
+            filter.owner,
+            current_instance,
+            domain,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let instance = if let Some(key) = row.public_key {
+                    Instance::from_row(key, row.domain)?
+                } else {
+                    self.construct_current_instance()
+                };
+                Ok(AdminPlot {
+                    plot: Plot {
+                        plot_id: row.id,
+                        owner: row.owner_uuid,
+                        instance,
+                    },
+                    active_keys: row.active_keys as u32,
+                    trusted_plots: row.trusted_plots as u32,
+                    trusted_instances: row.trusted_instances as u32,
+                    registered_at: row.registered_at,
+                    disabled: row.disabled,
+                })
+            })
+            .collect()
+    }
+
     /// You are supposed to unwrap the eyre result, which is almost always ok,
     /// and handle the inner Result
     pub async fn register_plot(
@@ -186,11 +819,115 @@ impl Store {
     }
     /// Do not `tokio::task` this
     /// Invalidating caches should be a part of the update operation
-    async fn invalidate_plot_cache(&self, plot_id: PlotId) -> color_eyre::Result<()> {
-        let mut redis = self.redis.clone();
+    /// Hands out a token that has to be passed to [Store::unregister_plot] within 5 minutes
+    pub async fn unregister_token(&self, plot_id: PlotId) -> color_eyre::Result<String> {
+        let token = Uuid::new_v4().to_string();
+        let redis = &self.redis;
+        let _: () = redis
+            .set_ex(format!("plot:{}:unregister", plot_id), &token, 60 * 5)
+            .await?;
+        Ok(token)
+    }
+
+    /// Removes the plot along with its keys, trust in both directions, settings, members, metadata and channels.
+    /// Returns false if the token doesn't match the one from [Store::unregister_token]
+    pub async fn unregister_plot(&self, plot_id: PlotId, token: &str) -> color_eyre::Result<bool> {
+        let redis = &self.redis;
+        let expected: Option<String> = redis
+            .get_del(format!("plot:{}:unregister", plot_id))
+            .await?;
+        if expected.as_deref() != Some(token) {
+            return Ok(false);
+        }
+        self.disable_all_keys(plot_id).await?;
+
+        let mut ta = self.pg.begin().await?;
+        let trusting = query!(
+            "DELETE FROM baton_trust WHERE plot = $1 OR trusted = $1 RETURNING plot",
+            plot_id
+        )
+        .fetch_all(&mut *ta)
+        .await?;
+        let channels = query!(
+            "DELETE FROM baton_channel WHERE owner = $1 RETURNING name",
+            plot_id
+        )
+        .fetch_all(&mut *ta)
+        .await?;
+        query!("DELETE FROM baton_instance_trust WHERE plot = $1", plot_id)
+            .execute(&mut *ta)
+            .await?;
+        query!("DELETE FROM baton_webhook WHERE plot = $1", plot_id)
+            .execute(&mut *ta)
+            .await?;
+        query!("DELETE FROM baton_settings WHERE plot = $1", plot_id)
+            .execute(&mut *ta)
+            .await?;
+        query!("DELETE FROM api_key WHERE plot = $1", plot_id)
+            .execute(&mut *ta)
+            .await?;
+        query!("DELETE FROM plot_meta WHERE plot = $1", plot_id)
+            .execute(&mut *ta)
+            .await?;
+        let members = query!(
+            "DELETE FROM plot_member WHERE plot = $1 RETURNING member",
+            plot_id
+        )
+        .fetch_all(&mut *ta)
+        .await?;
+        query!("DELETE FROM plot WHERE id = $1", plot_id)
+            .execute(&mut *ta)
+            .await?;
+        ta.commit().await?;
+
+        self.invalidate_plot_cache(plot_id).await?;
+        let _: () = redis.del(format!("plot:{}:webhook", plot_id)).await?;
+        for row in trusting {
+            self.invalidate_trust_cache(row.plot).await?;
+        }
+        for row in channels {
+            self.invalidate_channel_cache(&row.name).await?;
+        }
+        for row in members {
+            self.invalidate_member_cache(plot_id, row.member).await?;
+        }
+        self.delete_activity(plot_id).await?;
+        Ok(true)
+    }
+
+    pub async fn plot_stats(&self, plot_id: PlotId) -> color_eyre::Result<PlotStats> {
+        let row = query!(
+            r#"SELECT
+                EXTRACT(EPOCH FROM plot.registered_at)::BIGINT as registered_at,
+                (SELECT COUNT(*) FROM api_key WHERE plot = plot.id AND disabled = false) as "active_keys!"
+            FROM plot WHERE plot.id = $1"#,
+            plot_id
+        )
+        .fetch_optional(&self.pg)
+        .await?;
+        Ok(match row {
+            Some(row) => PlotStats {
+                active_keys: row.active_keys as u32,
+                registered_at: row.registered_at,
+            },
+            None => PlotStats {
+                active_keys: 0,
+                registered_at: None,
+            },
+        })
+    }
+
+    pub(super) async fn invalidate_plot_cache(&self, plot_id: PlotId) -> color_eyre::Result<()> {
+        let redis = &self.redis;
         let _: () = redis.del(format!("plot:{}", plot_id)).await?;
         let _: () = redis.del(format!("plot:{}:baton_trust", plot_id)).await?;
-        Ok(())
+        let _: () = redis
+            .del(format!("plot:{}:baton_instance_trust", plot_id))
+            .await?;
+        let _: () = redis
+            .del(format!("plot:{}:baton_settings", plot_id))
+            .await?;
+        self.invalidate(Invalidation::Plot(plot_id)).await
     }
 }
 
@@ -202,6 +939,103 @@ pub enum RegisterError {
     PlotTaken,
 }
 
+#[derive(Debug, Object)]
+pub struct KnownInstanceInfo {
+    pub domain: String,
+    /// Base64 encoded, what `POST /plot` and `PUT /plot` take
+    pub key: String,
+    /// First 16 bytes of the SHA-256 of the key, hex encoded
+    pub key_fingerprint: String,
+    /// Unix timestamp in seconds
+    pub registered_at: i64,
+    pub health: InstanceHealth,
+    /// Unix timestamp in seconds of the last ping, missing if it wasn't pinged yet
+    pub health_checked_at: Option<i64>,
+    pub tier: InstanceTier,
+    /// What the instance says about itself, missing until it is asked
+    pub name: Option<String>,
+    pub contact: Option<String>,
+    pub version: Option<String>,
+    /// Like `instance/v0`
+    pub apis: Option<Vec<String>>,
+}
+
+#[derive(Debug, Object)]
+pub struct InstanceHealthInfo {
+    pub domain: String,
+    pub health: InstanceHealth,
+    /// Round trip of the last successful ping in milliseconds
+    pub latency_ms: Option<u32>,
+    /// Pings that failed in a row
+    pub failures: u32,
+    /// Unix timestamp in seconds of the first failed ping in a row, missing while it answers
+    pub down_since: Option<i64>,
+    /// Unix timestamp in seconds of the last ping, missing if it wasn't pinged yet
+    pub health_checked_at: Option<i64>,
+    /// Whether calls to the instance fail right away because it failed too often
+    pub breaker: BreakerState,
+}
+
+/// How much the operator trusts another instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceTier {
+    /// Registered itself. Plots can't trust all of its plots and its transfers always need mutual trust
+    Open,
+    /// Checked by the operator
+    Verified,
+    /// Run by someone the operator works with, its plots get larger quotas
+    Partner,
+}
+
+impl InstanceTier {
+    /// Whether plots can trust every plot of the instance at once
+    pub fn allows_instance_trust(self) -> bool {
+        self != Self::Open
+    }
+
+    /// Whether its plots have to be trusted back by the plots they send to
+    pub fn requires_mutual_trust(self) -> bool {
+        self == Self::Open
+    }
+
+    /// What the transfer quotas of its plots are multiplied with
+    pub fn quota_factor(self) -> u32 {
+        match self {
+            Self::Open | Self::Verified => 1,
+            Self::Partner => 4,
+        }
+    }
+}
+
+/// Redis key of the cached tier of an instance
+pub(super) fn tier_key(domain: &str) -> String {
+    format!("instance:{}:tier", domain.to_ascii_lowercase())
+}
+
+/// Result of the last time the instance was pinged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceHealth {
+    Healthy,
+    /// It didn't answer or didn't sign the challenge properly
+    Unreachable,
+    /// It answered with another key than the one it was registered with
+    KeyMismatch,
+    /// Not pinged yet
+    Unknown,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegisterInstanceError {
+    #[error("Domain is registered with another key")]
+    DomainTaken,
+    #[error("Key is registered with another domain")]
+    KeyTaken,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PlotEditError {
     #[error("Instance not found, perhaps register it?")]
@@ -215,3 +1049,32 @@ pub struct PlotValue {
     pub owner: Uuid,
     pub instance: ExternalDomain,
 }
+
+pub struct PlotStats {
+    /// API keys that haven't been purged
+    pub active_keys: u32,
+    /// Unix timestamp in seconds
+    pub registered_at: Option<i64>,
+}
+
+#[derive(Default)]
+pub struct AdminPlotFilter {
+    pub owner: Option<Uuid>,
+    pub instance: Option<InstanceFilter>,
+}
+
+pub enum InstanceFilter {
+    /// Plots registered to this instance
+    Current,
+    Domain(String),
+}
+
+pub struct AdminPlot {
+    pub plot: Plot,
+    pub active_keys: u32,
+    pub trusted_plots: u32,
+    pub trusted_instances: u32,
+    /// Unix timestamp in seconds
+    pub registered_at: Option<i64>,
+    pub disabled: bool,
+}
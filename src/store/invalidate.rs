@@ -0,0 +1,83 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::api::PlotId;
+
+use super::{admin::DISABLED_PLOTS_KEY, Store};
+
+/// Every process sharing the redis listens here for state it keeps in memory changing
+const INVALIDATION_CHANNEL: &str = "cache:invalidate";
+
+/// In memory state another process changed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Invalidation {
+    /// A plot was disabled or enabled
+    DisabledPlots,
+    /// What is cached about a plot, like its trust, changed
+    Plot(PlotId),
+}
+
+/// Cross process invalidation of in memory state
+impl Store {
+    /// Applies the change here and tells the other processes to do the same
+    pub(super) async fn invalidate(&self, invalidation: Invalidation) -> color_eyre::Result<()> {
+        self.apply_invalidation(invalidation).await?;
+        let _: () = self
+            .redis
+            .publish(INVALIDATION_CHANNEL, serde_json::to_string(&invalidation)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Applies invalidations of other processes forever, meant to be spawned once
+    pub async fn invalidation_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.listen_invalidations().await {
+                error!("Listening for invalidations failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn listen_invalidations(&self) -> color_eyre::Result<()> {
+        let mut messages = self
+            .redis
+            .subscribe(INVALIDATION_CHANNEL.to_string())
+            .await?;
+        // Whatever was published while not subscribed is lost, so start over from redis
+        self.apply_invalidation(Invalidation::DisabledPlots).await?;
+        self.local_plots.clear();
+        self.local_plot_trust.clear();
+        self.local_instance_trust.clear();
+        while let Some(payload) = messages.next().await {
+            match serde_json::from_str(&payload) {
+                Ok(invalidation) => self.apply_invalidation(invalidation).await?,
+                Err(err) => warn!("Unknown invalidation {}: {}", payload, err),
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply_invalidation(&self, invalidation: Invalidation) -> color_eyre::Result<()> {
+        match invalidation {
+            Invalidation::DisabledPlots => {
+                let disabled: Vec<PlotId> = self.redis.smembers(DISABLED_PLOTS_KEY).await?;
+                *self
+                    .disabled_plots
+                    .write()
+                    .expect("Disabled plots shouldn't be poisoned") =
+                    disabled.into_iter().collect();
+            }
+            Invalidation::Plot(plot_id) => {
+                self.local_plots.remove(&plot_id);
+                self.local_plot_trust.remove(&plot_id);
+                self.local_instance_trust.remove(&plot_id);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,524 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+
+use base64::Engine;
+use color_eyre::eyre::eyre;
+use poem_openapi::Object;
+use rand::distr::{Alphanumeric, SampleString};
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{prelude::FromRow, query, query_as};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    allowlist::IpRange,
+    api::{auth::Plot, PlotId},
+    instance::Instance,
+    BASE64,
+};
+
+use super::{baton::unix_now, Store};
+
+/// Start of every key, lets secret scanners find leaked keys
+pub const KEY_PREFIX: &str = "dft_";
+/// Length of the random id after [KEY_PREFIX], the two are kept in plain text to recognize the key
+const KEY_ID_LEN: usize = 8;
+/// Seconds a key lookup is cached
+pub const KEY_CACHE_TTL: u64 = 60 * 5;
+/// Seconds a key that doesn't exist is remembered
+const INVALID_KEY_CACHE_TTL: u64 = 30;
+/// Hashes of keys that don't exist with when they are forgotten,
+/// a sorted set so guesses can't pile up in redis
+const INVALID_KEYS_KEY: &str = "key:invalid";
+/// Invalid keys remembered at once, the ones forgotten soonest make room for new ones
+const MAX_INVALID_KEYS: isize = 10_000;
+/// Invalid keys an address can send before it is locked out
+const MAX_KEY_FAILURES: u32 = 20;
+/// Seconds failures are counted for, the lockout lasts until none were made for this long
+const KEY_FAILURE_WINDOW: u64 = 60 * 15;
+/// Last uses waiting to be written to postgres, key id to unix timestamp
+const PENDING_LAST_USED_KEY: &str = "apikey:last_used";
+/// Usage is counted in buckets of an hour, kept for a week
+const USAGE_BUCKET_SECS: u64 = 60 * 60;
+const USAGE_BUCKETS: u64 = 24 * 7;
+
+/// API keys
+impl Store {
+    /// The plot a key belongs to and what it may do, None if it is invalid or disabled
+    pub async fn verify_key(&self, key: &str) -> color_eyre::Result<Option<KeyGrant>> {
+        let redis = &self.redis;
+        let hashed = BASE64.encode(Sha256::digest(key));
+        let cached: Option<CachedKey> = redis.get(grant_key(&hashed)).await?;
+        let invalid_until: Option<u64> = redis.zscore(INVALID_KEYS_KEY, &hashed).await?;
+        if let Some(CachedKey(grant)) = cached {
+            return Ok(Some(grant));
+        }
+        if invalid_until.is_some_and(|until| until > unix_now()) {
+            return Ok(None);
+        }
+
+        #[derive(FromRow)]
+        struct Row {
+            id: i32,
+            plot: PlotId,
+            scopes: Option<Vec<String>>,
+            allowed_ips: Option<Vec<String>>,
+            owner_uuid: Uuid,
+            domain: Option<String>,
+            public_key: Option<Vec<u8>>,
+            /// Seconds until a rotated key stops working
+            disables_in: Option<i64>,
+        }
+
+        let row = query_as!(
+            Row,
+            r#"
+            SELECT
+                key.id,
+                key.plot,
+                key.scopes,
+                key.allowed_ips,
+                p.owner_uuid,
+                instance.domain,
+                instance.public_key,
+                EXTRACT(EPOCH FROM key.disable_at - NOW()::TIMESTAMP)::BIGINT as disables_in
+            FROM api_key key
+            JOIN plot p ON key.plot = p.id
+            LEFT JOIN known_instance instance ON instance.id = p.instance
+            WHERE
+                key.hashed_key = sha256($1) AND
+                key.disabled = false AND
+                (key.disable_at IS NULL OR key.disable_at > NOW());
+            "#,
+            key.as_bytes()
+        )
+        .fetch_optional(&self.pg)
+        .await?;
+
+        let Some(row) = row else {
+            let now = unix_now();
+            let _: () = redis.zrembyscore(INVALID_KEYS_KEY, ..=now).await?;
+            let _: () = redis
+                .zadd(INVALID_KEYS_KEY, &hashed, now + INVALID_KEY_CACHE_TTL)
+                .await?;
+            // Only the latest are kept, the oldest go first
+            let _: () = redis
+                .zremrangebyrank(INVALID_KEYS_KEY, 0, -(MAX_INVALID_KEYS + 1))
+                .await?;
+            return Ok(None);
+        };
+        // A rotated key can't stay cached past when it stops working
+        let ttl = row.disables_in.map_or(KEY_CACHE_TTL, |secs| {
+            (secs.max(1) as u64).min(KEY_CACHE_TTL)
+        });
+        let instance = match row.public_key {
+            Some(key) => Instance::from_row(key, row.domain)?,
+            None => self.construct_current_instance(),
+        };
+        let grant = KeyGrant {
+            id: row.id,
+            plot: Plot {
+                plot_id: row.plot,
+                owner: row.owner_uuid,
+                instance,
+            },
+            scopes: row.scopes,
+            allowed_ips: row
+                .allowed_ips
+                .map(|ips| ips.iter().map(|ip| ip.parse()).collect())
+                .transpose()
+                .map_err(|err: String| eyre!(err))?,
+        };
+        let _: () = redis
+            .set_ex(grant_key(&hashed), CachedKey(grant.clone()), ttl)
+            .await?;
+        Ok(Some(grant))
+    }
+    /// Creates a key, without scopes it can do everything
+    pub async fn create_key(
+        &self,
+        plot_id: PlotId,
+        label: Option<&str>,
+        scopes: Option<&[String]>,
+        allowed_ips: Option<&[IpRange]>,
+    ) -> color_eyre::Result<String> {
+        let key = generate_key();
+        let allowed_ips: Option<Vec<String>> =
+            allowed_ips.map(|ips| ips.iter().map(IpRange::to_string).collect());
+        query!(
+            "INSERT INTO api_key (plot, hashed_key, label, prefix, scopes, allowed_ips)
+            VALUES ($1, sha256($2), $3, $4, $5, $6)",
+            plot_id,
+            key.as_bytes(),
+            label,
+            key_prefix(&key),
+            scopes,
+            allowed_ips.as_deref()
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(key)
+    }
+    pub async fn disable_all_keys(&self, plot_id: PlotId) -> color_eyre::Result<()> {
+        let deleted = query!(
+            "WITH disabled_keys AS (
+                UPDATE api_key SET
+                    disabled = true
+                WHERE 
+                    plot = $1 
+                    AND disabled = false
+                RETURNING hashed_key, prefix
+            ) SELECT hashed_key, prefix FROM disabled_keys;",
+            plot_id
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        for row in deleted {
+            info!(
+                "Disabled key {} of {}",
+                row.prefix.as_deref().unwrap_or("without prefix"),
+                plot_id
+            );
+            let key = BASE64.encode(row.hashed_key);
+            let _: () = self.redis.del(grant_key(&key)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Keys of a plot that haven't been disabled, oldest first
+    pub async fn list_keys(&self, plot_id: PlotId) -> color_eyre::Result<Vec<ApiKeyInfo>> {
+        let rows = query!(
+            r#"SELECT
+                id,
+                label,
+                prefix,
+                scopes,
+                allowed_ips,
+                EXTRACT(EPOCH FROM created_at)::BIGINT as "created_at!",
+                EXTRACT(EPOCH FROM last_used)::BIGINT as last_used,
+                EXTRACT(EPOCH FROM disable_at)::BIGINT as disable_at
+            FROM api_key
+            WHERE plot = $1 AND disabled = false AND (disable_at IS NULL OR disable_at > NOW())
+            ORDER BY id"#,
+            plot_id
+        )
+        .fetch_all(&self.pg)
+        .await?;
+
+        let redis = &self.redis;
+        let current = unix_now() / USAGE_BUCKET_SECS;
+        let mut keys = Vec::with_capacity(rows.len());
+        for row in rows {
+            // Uses since the last flush are only in redis
+            let pending: Option<i64> = redis.hget(PENDING_LAST_USED_KEY, row.id).await?;
+            let buckets: Vec<String> = (0..USAGE_BUCKETS)
+                .map(|ago| usage_key(row.id, current - ago))
+                .collect();
+            let counts: Vec<Option<u64>> = redis.mget(&buckets).await?;
+            let counts: Vec<u64> = counts.into_iter().map(Option::unwrap_or_default).collect();
+            keys.push(ApiKeyInfo {
+                id: row.id,
+                label: row.label,
+                prefix: row.prefix,
+                scopes: row.scopes,
+                allowed_ips: row.allowed_ips,
+                created_at: row.created_at,
+                last_used: row.last_used.max(pending),
+                disable_at: row.disable_at,
+                requests_day: counts[..24].iter().sum(),
+                requests_week: counts.iter().sum(),
+            });
+        }
+        Ok(keys)
+    }
+
+    /// Counts a request made with the key, postgres is only written by [Store::key_usage_worker]
+    pub async fn record_key_use(&self, key_id: i32) -> color_eyre::Result<()> {
+        let now = unix_now();
+        let bucket = usage_key(key_id, now / USAGE_BUCKET_SECS);
+        let redis = &self.redis;
+        let _: () = redis
+            .incr_ex(&bucket, 1, USAGE_BUCKETS * USAGE_BUCKET_SECS)
+            .await?;
+        let _: () = redis.hset(PENDING_LAST_USED_KEY, key_id, now).await?;
+        Ok(())
+    }
+
+    /// Writes when keys were last used to postgres forever, meant to be spawned once
+    pub async fn key_usage_worker(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            if let Err(err) = self.flush_key_usage().await {
+                error!("Writing API key usage failed: {:?}", err);
+            }
+        }
+    }
+
+    async fn flush_key_usage(&self) -> color_eyre::Result<()> {
+        let pending: HashMap<i32, i64> = self.redis.htake(PENDING_LAST_USED_KEY).await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let (ids, used): (Vec<i32>, Vec<i64>) = pending.into_iter().unzip();
+        query!(
+            "UPDATE api_key SET
+                last_used = to_timestamp(used.at)::TIMESTAMP
+            FROM UNNEST($1::INTEGER[], $2::BIGINT[]) AS used(id, at)
+            WHERE api_key.id = used.id",
+            &ids,
+            &used
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+
+    /// Seconds until the address may try keys again, None if it isn't locked out
+    pub async fn key_lockout(&self, ip: IpAddr) -> color_eyre::Result<Option<u64>> {
+        let key = format!("apikey:failures:{ip}");
+        let failures: Option<u32> = self.redis.get(&key).await?;
+        let ttl: i64 = self.redis.ttl(&key).await?;
+        Ok(failures
+            .filter(|failures| *failures >= MAX_KEY_FAILURES)
+            .map(|_| ttl.max(1) as u64))
+    }
+
+    /// Counts an invalid key sent from the address
+    pub async fn record_key_failure(&self, ip: IpAddr) -> color_eyre::Result<()> {
+        let key = format!("apikey:failures:{ip}");
+        let _: () = self.redis.incr_ex(&key, 1, KEY_FAILURE_WINDOW).await?;
+        Ok(())
+    }
+
+    /// Replaces a key with a new one that has the same label, scopes and addresses,
+    /// the old key keeps working for `grace` seconds. `key` is either its id or its prefix
+    pub async fn rotate_key(
+        &self,
+        plot_id: PlotId,
+        key: &str,
+        grace: u64,
+    ) -> color_eyre::Result<Result<String, DisableKeyError>> {
+        let mut tx = self.pg.begin().await?;
+        let id: Option<i32> = key.parse().ok();
+        let matching = query!(
+            "SELECT id, label, scopes, allowed_ips FROM api_key
+            WHERE plot = $1 AND disabled = false AND disable_at IS NULL AND (id = $2 OR prefix = $3)
+            FOR UPDATE",
+            plot_id,
+            id,
+            key
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let old = match matching.as_slice() {
+            [] => return Ok(Err(DisableKeyError::NotFound)),
+            [row] => row,
+            _ => return Ok(Err(DisableKeyError::Ambiguous)),
+        };
+        let key = generate_key();
+        query!(
+            "INSERT INTO api_key (plot, hashed_key, label, prefix, scopes, allowed_ips)
+            VALUES ($1, sha256($2), $3, $4, $5, $6)",
+            plot_id,
+            key.as_bytes(),
+            old.label,
+            key_prefix(&key),
+            old.scopes.as_deref(),
+            old.allowed_ips.as_deref()
+        )
+        .execute(&mut *tx)
+        .await?;
+        let rotated = query!(
+            "UPDATE api_key SET disable_at = NOW() + make_interval(secs => $2)
+            WHERE id = $1 RETURNING hashed_key",
+            old.id,
+            grace as f64
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        // The cached lookup doesn't know when the key stops working
+        let hashed = BASE64.encode(rotated.hashed_key);
+        let _: () = self.redis.del(grant_key(&hashed)).await?;
+        Ok(Ok(key))
+    }
+
+    /// Disables one key of a plot, `key` is either its id or its prefix
+    pub async fn disable_key(
+        &self,
+        plot_id: PlotId,
+        key: &str,
+    ) -> color_eyre::Result<Result<(), DisableKeyError>> {
+        let id: Option<i32> = key.parse().ok();
+        let matching = query!(
+            "SELECT id FROM api_key
+            WHERE plot = $1 AND disabled = false AND (id = $2 OR prefix = $3)",
+            plot_id,
+            id,
+            key
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        let id = match matching.as_slice() {
+            [] => return Ok(Err(DisableKeyError::NotFound)),
+            [row] => row.id,
+            _ => return Ok(Err(DisableKeyError::Ambiguous)),
+        };
+        let disabled = query!(
+            "UPDATE api_key SET disabled = true WHERE id = $1 RETURNING hashed_key",
+            id
+        )
+        .fetch_one(&self.pg)
+        .await?;
+        let key = BASE64.encode(disabled.hashed_key);
+        let _: () = self.redis.del(grant_key(&key)).await?;
+        Ok(Ok(()))
+    }
+}
+
+/// Plot an API key authenticates as
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyGrant {
+    pub id: i32,
+    pub plot: Plot,
+    /// None for keys created without scopes
+    pub scopes: Option<Vec<String>>,
+    /// None for keys that can be used from anywhere
+    pub allowed_ips: Option<Vec<IpRange>>,
+}
+
+impl KeyGrant {
+    pub fn allows(&self, scope: Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes_allow(scopes, scope),
+            None => true,
+        }
+    }
+
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        match &self.allowed_ips {
+            Some(ranges) => ranges.iter().any(|range| range.contains(ip)),
+            None => true,
+        }
+    }
+}
+
+/// Redis key of the cached grant of a key, by the base64 of its hash
+fn grant_key(hashed: &str) -> String {
+    format!("key:grant:{hashed}")
+}
+
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct CachedKey(KeyGrant);
+
+/// What an API key with scopes is allowed to do
+#[derive(Debug, Clone, Copy)]
+pub enum Scope {
+    /// Send transfers, patches, broadcasts, replies and channel messages
+    BatonSend,
+    /// Take, stream and acknowledge transfers, wait for replies and read statuses and history
+    BatonRead,
+    /// Edit trust, baton settings, the webhook and channels
+    TrustWrite,
+    /// List and disable API keys
+    KeysWrite,
+    /// What only the plot owner can do
+    PlotWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::BatonSend,
+        Scope::BatonRead,
+        Scope::TrustWrite,
+        Scope::KeysWrite,
+        Scope::PlotWrite,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::BatonSend => "baton:send",
+            Scope::BatonRead => "baton:read",
+            Scope::TrustWrite => "trust:write",
+            Scope::KeysWrite => "keys:write",
+            Scope::PlotWrite => "plot:write",
+        }
+    }
+}
+
+/// `*` grants every scope, `{group}:*` every scope of the group
+fn scope_grants(granted: &str, scope: Scope) -> bool {
+    let name = scope.name();
+    granted == "*"
+        || granted == name
+        || granted
+            .strip_suffix(":*")
+            .is_some_and(|group| name.split(':').next() == Some(group))
+}
+
+/// Keys look like `dft_{id}_{secret}`
+fn generate_key() -> String {
+    format!(
+        "{KEY_PREFIX}{}_{}",
+        Alphanumeric.sample_string(&mut rand::rng(), KEY_ID_LEN),
+        Alphanumeric.sample_string(&mut rand::rng(), 32)
+    )
+}
+
+/// `dft_` and the id of a key, safe to show and log. None for keys from before prefixes
+pub fn key_prefix(key: &str) -> Option<&str> {
+    let len = KEY_PREFIX.len() + KEY_ID_LEN;
+    (key.starts_with(KEY_PREFIX) && key.len() > len && key.as_bytes()[len] == b'_')
+        .then(|| &key[..len])
+}
+
+/// Whether any of the granted scopes covers the scope
+pub fn scopes_allow(granted: &[String], scope: Scope) -> bool {
+    granted.iter().any(|granted| scope_grants(granted, scope))
+}
+
+/// Scopes the granted scope covers
+pub fn granted_scopes(granted: &str) -> impl Iterator<Item = Scope> + '_ {
+    Scope::ALL
+        .into_iter()
+        .filter(move |scope| scope_grants(granted, *scope))
+}
+
+/// Whether the scope grants anything
+pub fn valid_scope(scope: &str) -> bool {
+    Scope::ALL.iter().any(|known| scope_grants(scope, *known))
+}
+
+pub enum DisableKeyError {
+    NotFound,
+    /// More than one key starts with the prefix
+    Ambiguous,
+}
+
+#[derive(Debug, Object)]
+pub struct ApiKeyInfo {
+    pub id: i32,
+    pub label: Option<String>,
+    /// `dft_` and the id of the key, older keys have their first 6 characters or nothing
+    pub prefix: Option<String>,
+    /// Missing for keys that can do everything
+    pub scopes: Option<Vec<String>>,
+    /// Addresses and CIDR ranges the key can be used from, missing for anywhere
+    pub allowed_ips: Option<Vec<String>>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds, missing if the key was never used
+    pub last_used: Option<i64>,
+    /// Unix timestamp in seconds the key stops working at, only set for rotated keys
+    pub disable_at: Option<i64>,
+    /// Requests made with the key in the last 24 hours
+    pub requests_day: u64,
+    /// Requests made with the key in the last 7 days
+    pub requests_week: u64,
+}
+
+fn usage_key(key_id: i32, bucket: u64) -> String {
+    format!("apikey:{key_id}:usage:{bucket}")
+}
@@ -0,0 +1,44 @@
+use tracing::warn;
+
+use super::{baton::unix_now, Store};
+
+/// Seconds a worker has to finish a claimed job before it is handed out again
+const LEASE: u64 = 60 * 5;
+
+/// Jobs wait in a sorted set scored by when they are due. Claiming one moves it into a
+/// processing set scored by when the lease runs out, so a job whose worker failed halfway
+/// or stopped isn't lost but attempted again
+impl Store {
+    /// Whether this worker got the job, another one may have claimed it first
+    pub(super) async fn claim_job(&self, pending: &str, job: &str) -> color_eyre::Result<bool> {
+        let redis = &self.redis;
+        Ok(redis
+            .move_member(pending, &processing_key(pending), job, unix_now() + LEASE)
+            .await?)
+    }
+
+    /// Done with the claimed job, retries have to be put back into the pending set before
+    pub(super) async fn release_job(&self, pending: &str, job: &str) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let _: () = redis.zrem(processing_key(pending), job).await?;
+        Ok(())
+    }
+
+    /// Puts jobs whose lease ran out back into the pending set, due right away
+    pub(super) async fn recover_jobs(&self, pending: &str) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let processing = processing_key(pending);
+        let now = unix_now();
+        let expired: Vec<String> = redis.zrangebyscore(&processing, ..=now).await?;
+        for job in expired {
+            if redis.move_member(&processing, pending, &job, now).await? {
+                warn!("Lease of a job in {} ran out, attempting it again", pending);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn processing_key(pending: &str) -> String {
+    format!("{}:processing", pending)
+}
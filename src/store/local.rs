@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Seconds entries of the local caches are kept at most
+pub const LOCAL_CACHE_TTL: Duration = Duration::from_secs(10);
+/// Entries each local cache holds at most
+pub const LOCAL_CACHE_CAPACITY: usize = 10_000;
+
+/// Small cache kept in the process in front of redis, for lookups made on every request.
+/// Entries are dropped after a short while even if no invalidation reaches the process
+pub struct LocalCache<K, V> {
+    entries: Mutex<HashMap<K, (Instant, V)>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> LocalCache<K, V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            capacity,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self
+            .entries
+            .lock()
+            .expect("Local cache shouldn't be poisoned");
+        match entries.get(key) {
+            Some((added, value)) if added.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self
+            .entries
+            .lock()
+            .expect("Local cache shouldn't be poisoned");
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, (added, _)| added.elapsed() < self.ttl);
+            // Still full, the oldest entry makes room
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (added, _))| *added)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.entries
+            .lock()
+            .expect("Local cache shouldn't be poisoned")
+            .remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .expect("Local cache shouldn't be poisoned")
+            .clear();
+    }
+}
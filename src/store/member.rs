@@ -0,0 +1,197 @@
+use poem_openapi::Object;
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::PlotId;
+
+use super::{key::Scope, Store};
+
+/// Seconds a membership lookup is cached
+const MEMBER_CACHE_TTL: u64 = 60 * 5;
+
+/// Members
+impl Store {
+    /// What a player can do for a plot, None if they aren't a member
+    pub async fn get_member(
+        &self,
+        plot_id: PlotId,
+        member: Uuid,
+    ) -> color_eyre::Result<Option<Abilities>> {
+        let redis = &self.redis;
+        let key = format!("plot:{}:member:{}", plot_id, member);
+        let cached: Option<CachedMember> = redis.get(&key).await?;
+        if let Some(cached) = cached {
+            return Ok(cached.0);
+        }
+        let abilities = query!(
+            "SELECT create_keys, edit_trust, send_transfers FROM plot_member
+            WHERE plot = $1 AND member = $2",
+            plot_id,
+            member
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .map(|row| Abilities {
+            create_keys: row.create_keys,
+            edit_trust: row.edit_trust,
+            send_transfers: row.send_transfers,
+        });
+        let cached = CachedMember(abilities);
+        let _: () = redis.set_ex(key, &cached, MEMBER_CACHE_TTL).await?;
+        Ok(cached.0)
+    }
+
+    pub async fn list_members(&self, plot_id: PlotId) -> color_eyre::Result<Vec<Member>> {
+        Ok(query!(
+            "SELECT member, create_keys, edit_trust, send_transfers FROM plot_member
+            WHERE plot = $1 ORDER BY member",
+            plot_id
+        )
+        .fetch_all(&self.pg)
+        .await?
+        .into_iter()
+        .map(|row| Member {
+            uuid: row.member,
+            abilities: Abilities {
+                create_keys: row.create_keys,
+                edit_trust: row.edit_trust,
+                send_transfers: row.send_transfers,
+            },
+        })
+        .collect())
+    }
+
+    /// Adds the member or replaces their abilities
+    pub async fn set_member(
+        &self,
+        plot_id: PlotId,
+        member: Uuid,
+        abilities: &Abilities,
+    ) -> color_eyre::Result<()> {
+        query!(
+            "INSERT INTO plot_member (plot, member, create_keys, edit_trust, send_transfers)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (plot, member) DO UPDATE SET
+                create_keys = EXCLUDED.create_keys,
+                edit_trust = EXCLUDED.edit_trust,
+                send_transfers = EXCLUDED.send_transfers",
+            plot_id,
+            member,
+            abilities.create_keys,
+            abilities.edit_trust,
+            abilities.send_transfers
+        )
+        .execute(&self.pg)
+        .await?;
+        self.invalidate_member_cache(plot_id, member).await
+    }
+
+    /// Returns false if they weren't a member
+    pub async fn remove_member(&self, plot_id: PlotId, member: Uuid) -> color_eyre::Result<bool> {
+        let deleted = query!(
+            "DELETE FROM plot_member WHERE plot = $1 AND member = $2",
+            plot_id,
+            member
+        )
+        .execute(&self.pg)
+        .await?
+        .rows_affected();
+        self.invalidate_member_cache(plot_id, member).await?;
+        Ok(deleted != 0)
+    }
+
+    pub(super) async fn invalidate_member_cache(
+        &self,
+        plot_id: PlotId,
+        member: Uuid,
+    ) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let _: () = redis
+            .del(format!("plot:{}:member:{}", plot_id, member))
+            .await?;
+        Ok(())
+    }
+}
+
+/// What a member can do for a plot, the owner can do everything
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Object)]
+pub struct Abilities {
+    /// Create and purge API keys
+    #[oai(default)]
+    #[serde(default)]
+    pub create_keys: bool,
+    /// Edit which plots and instances are trusted
+    #[oai(default)]
+    #[serde(default)]
+    pub edit_trust: bool,
+    /// Send transfers, broadcasts and replies
+    #[oai(default)]
+    #[serde(default)]
+    pub send_transfers: bool,
+}
+
+impl Abilities {
+    pub fn all() -> Self {
+        Self {
+            create_keys: true,
+            edit_trust: true,
+            send_transfers: true,
+        }
+    }
+
+    pub fn has(&self, ability: Ability) -> bool {
+        match ability {
+            Ability::CreateKeys => self.create_keys,
+            Ability::EditTrust => self.edit_trust,
+            Ability::SendTransfers => self.send_transfers,
+        }
+    }
+}
+
+impl Ability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Ability::CreateKeys => "create_keys",
+            Ability::EditTrust => "edit_trust",
+            Ability::SendTransfers => "send_transfers",
+        }
+    }
+
+    /// Scope an API key needs for the same
+    pub fn scope(self) -> Scope {
+        match self {
+            Ability::CreateKeys => Scope::KeysWrite,
+            Ability::EditTrust => Scope::TrustWrite,
+            Ability::SendTransfers => Scope::BatonSend,
+        }
+    }
+
+    /// Ability a member needs for what the scope covers, None if any member may do it
+    pub fn for_scope(scope: Scope) -> Option<Ability> {
+        match scope {
+            Scope::KeysWrite => Some(Ability::CreateKeys),
+            Scope::TrustWrite => Some(Ability::EditTrust),
+            Scope::BatonSend => Some(Ability::SendTransfers),
+            Scope::BatonRead | Scope::PlotWrite => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Ability {
+    CreateKeys,
+    EditTrust,
+    SendTransfers,
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct Member {
+    /// Minecraft uuid
+    pub uuid: Uuid,
+    pub abilities: Abilities,
+}
+
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct CachedMember(Option<Abilities>);
@@ -0,0 +1,66 @@
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+
+use crate::api::PlotId;
+
+use super::Store;
+
+/// Plot metadata
+impl Store {
+    /// Metadata of a plot, empty if it never set any
+    pub async fn get_plot_meta(&self, plot_id: PlotId) -> color_eyre::Result<PlotMeta> {
+        Ok(query!(
+            "SELECT name, description, tags, website FROM plot_meta WHERE plot = $1",
+            plot_id
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .map(|row| PlotMeta {
+            name: row.name,
+            description: row.description,
+            tags: row.tags,
+            website: row.website,
+        })
+        .unwrap_or_default())
+    }
+
+    /// Replaces the metadata of a plot
+    pub async fn set_plot_meta(&self, plot_id: PlotId, meta: &PlotMeta) -> color_eyre::Result<()> {
+        query!(
+            "INSERT INTO plot_meta (plot, name, description, tags, website)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (plot) DO UPDATE SET
+                name = EXCLUDED.name,
+                description = EXCLUDED.description,
+                tags = EXCLUDED.tags,
+                website = EXCLUDED.website,
+                updated_at = NOW()",
+            plot_id,
+            meta.name,
+            meta.description,
+            &meta.tags,
+            meta.website
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Optional details about a plot for directories
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Object)]
+pub struct PlotMeta {
+    /// Display name
+    #[oai(validator(max_length = 64))]
+    pub name: Option<String>,
+    #[oai(validator(max_length = 1024))]
+    pub description: Option<String>,
+    /// Lowercase letters, digits and dashes
+    #[oai(default, validator(max_items = 16))]
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// https url
+    #[oai(validator(max_length = 256))]
+    pub website: Option<String>,
+}
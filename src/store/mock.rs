@@ -0,0 +1,968 @@
+//! In memory store for handler tests
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::Engine;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use tokio::sync::{broadcast, Notify};
+use uuid::Uuid;
+
+use crate::{
+    allowlist::IpRange,
+    api::{
+        auth::Plot,
+        baton::{ChannelMessage, PayloadKind, TransferPayload, TransferStatus},
+        PlotId,
+    },
+    instance::{ExternalDomain, Instance, InstanceDomain, SendInstance},
+    BASE64,
+};
+
+use super::{
+    audit::AuditEntry,
+    baton::{
+        unix_now, BatonSettings, DeliveryGuarantee, InstanceTrustSetError, Origin,
+        PlotTrustSetError, QueuedTransfer, TransferAckError, TransferQueueError, TransferRecord,
+    },
+    history::{HistoryEntry, HistoryFilter},
+    idempotency::{IdempotencyClaim, IdempotencyKey},
+    instance::InstanceTier,
+    key::KeyGrant,
+    member::Abilities,
+    ops::{AuthStore, BatonStore},
+    patch::PatchError,
+    quota::{Quota, QuotaExceeded},
+    relay::{RelayJob, RelayState},
+    reply::ReplyError,
+    webhook::Webhook,
+};
+
+/// Transfers a plot can have queued
+pub const MAX_QUEUE_DEPTH: usize = 4;
+/// Seconds a transfer stays queued
+const TRANSFER_TTL: u64 = 60;
+/// Seconds a plot with at least once delivery has to ack a taken transfer
+const ACK_TIMEOUT: u64 = 30;
+/// Transfers a plot can send, the mock never resets it
+pub const TRANSFER_RATE_LIMIT: u32 = 16;
+/// Payload bytes a plot can send, the mock never resets it
+const TRANSFER_BYTE_QUOTA: u64 = 64 * 1024;
+
+pub struct MockStore {
+    jwt_key: Hmac<Sha256>,
+    key: VerifyingKey,
+    state: Mutex<MockState>,
+    /// Woken when a transfer or reply gets queued
+    arrived: Notify,
+}
+
+#[derive(Default)]
+struct MockState {
+    plots: HashMap<PlotId, Plot>,
+    /// Player names and their uuids
+    players: HashMap<String, Uuid>,
+    members: HashMap<(PlotId, Uuid), Abilities>,
+    /// Raw API keys
+    keys: HashMap<String, KeyGrant>,
+    disabled: HashSet<PlotId>,
+    /// Addresses locked out for invalid keys and the seconds until they can retry
+    lockouts: HashMap<IpAddr, u64>,
+    /// Registered instances
+    instances: Vec<(VerifyingKey, InstanceTier)>,
+    trust: HashMap<PlotId, Vec<PlotId>>,
+    instance_trust: HashMap<PlotId, Vec<VerifyingKey>>,
+    settings: HashMap<PlotId, BatonSettings>,
+    /// Transfers and bytes each plot sent
+    quota_used: HashMap<PlotId, (u32, u64)>,
+    queues: HashMap<PlotId, VecDeque<QueuedTransfer>>,
+    /// Taken transfers waiting for their ack, with their receiver and when they are queued again
+    unacked: HashMap<Uuid, (PlotId, QueuedTransfer, u64)>,
+    /// Last sequence number of each receiver and sender pair
+    seqs: HashMap<(PlotId, PlotId), u64>,
+    scheduled: Vec<ScheduledTransfer>,
+    records: HashMap<Uuid, TransferRecord>,
+    relays: HashMap<Uuid, RelayJob>,
+    /// Transfers that were replied to
+    replied: HashSet<Uuid>,
+    /// Replies waiting to be taken, by the transfer they reply to
+    replies: HashMap<Uuid, QueuedTransfer>,
+    webhooks: HashMap<PlotId, Webhook>,
+    /// Channels and their owners
+    channels: HashMap<String, PlotId>,
+    subscribers: HashMap<String, broadcast::Sender<String>>,
+    audit: Vec<AuditEntry>,
+}
+
+struct ScheduledTransfer {
+    id: Uuid,
+    from: PlotId,
+    to: PlotId,
+    payload: TransferPayload,
+    origin: Origin,
+    deliver_at: u64,
+}
+
+impl MockState {
+    /// Queues the transfer unless the queue is full
+    fn push(
+        &mut self,
+        id: Uuid,
+        from: PlotId,
+        to: PlotId,
+        payload: TransferPayload,
+        origin: Origin,
+    ) -> Result<(), TransferQueueError> {
+        let now = unix_now();
+        let queue = self.queues.entry(to).or_default();
+        queue.retain(|it| it.expires_at > now);
+        if queue.len() >= MAX_QUEUE_DEPTH {
+            return Err(TransferQueueError::QueueFull);
+        }
+        let seq = self.seqs.entry((to, from)).or_default();
+        *seq += 1;
+        let expires_at = now + TRANSFER_TTL;
+        queue.push_back(QueuedTransfer {
+            id,
+            from,
+            seq: *seq,
+            payload,
+            expires_at,
+            origin: Some(origin),
+            received_at: now,
+        });
+        self.records.insert(
+            id,
+            TransferRecord {
+                from,
+                to,
+                status: TransferStatus::Queued,
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    /// Queues scheduled transfers that are due like the schedule worker does,
+    /// queues unacked transfers again like the delivery worker does
+    /// and drops expired transfers of the plot's queue
+    fn live_queue(&mut self, plot_id: PlotId) -> &mut VecDeque<QueuedTransfer> {
+        let now = unix_now();
+        let (due, later) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition(|it| it.deliver_at <= now);
+        self.scheduled = later;
+        for job in due {
+            if self
+                .push(job.id, job.from, job.to, job.payload, job.origin)
+                .is_err()
+            {
+                self.set_status(job.id, TransferStatus::Failed);
+            }
+        }
+        let timed_out: Vec<Uuid> = self
+            .unacked
+            .iter()
+            .filter(|(_, (_, _, due_at))| *due_at <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in timed_out {
+            let (to, transfer, _) = self.unacked.remove(&id).expect("collected above");
+            let queue = self.queues.entry(to).or_default();
+            queue.retain(|it| it.expires_at > now);
+            if queue.len() >= MAX_QUEUE_DEPTH {
+                // Held for another timeout until there is room
+                self.unacked.insert(id, (to, transfer, now + ACK_TIMEOUT));
+                continue;
+            }
+            queue.push_front(transfer);
+            self.set_status(id, TransferStatus::Queued);
+        }
+        let queue = self.queues.entry(plot_id).or_default();
+        queue.retain(|it| it.expires_at > now);
+        queue
+    }
+
+    fn set_status(&mut self, id: Uuid, status: TransferStatus) {
+        if let Some(record) = self.records.get_mut(&id) {
+            record.status = status;
+        }
+    }
+
+    fn record(&self, id: Uuid) -> Option<TransferRecord> {
+        self.records.get(&id).map(|it| TransferRecord {
+            from: it.from,
+            to: it.to,
+            status: if it.status == TransferStatus::Queued && it.expires_at <= unix_now() {
+                TransferStatus::Expired
+            } else {
+                it.status
+            },
+            expires_at: it.expires_at,
+        })
+    }
+
+    /// Takes the reply to the transfer if it arrived
+    fn take_reply(&mut self, correlation: Uuid) -> Option<QueuedTransfer> {
+        let reply = self
+            .replies
+            .remove(&correlation)
+            .filter(|it| it.expires_at > unix_now())?;
+        self.set_status(reply.id, TransferStatus::Delivered);
+        Some(reply)
+    }
+}
+
+impl MockStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            jwt_key: Hmac::new_from_slice(b"mock").expect("Any key length works"),
+            key: SigningKey::from_bytes(&[7; 32]).verifying_key(),
+            state: Mutex::default(),
+            arrived: Notify::new(),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("Mock store shouldn't be poisoned")
+    }
+
+    /// Registers a plot owned by the player to this instance
+    pub fn add_plot(&self, plot_id: PlotId, owner: &str) -> Plot {
+        let plot = Plot {
+            plot_id,
+            owner: self.add_player(owner),
+            instance: Instance {
+                key: self.key,
+                domain: InstanceDomain::Current,
+            },
+        };
+        self.state().plots.insert(plot_id, plot.clone());
+        plot
+    }
+
+    /// Uuid of the player, made up the first time the name is seen
+    pub fn add_player(&self, name: &str) -> Uuid {
+        *self
+            .state()
+            .players
+            .entry(name.to_string())
+            .or_insert_with(Uuid::new_v4)
+    }
+
+    pub fn add_member(&self, plot_id: PlotId, name: &str, abilities: Abilities) {
+        let uuid = self.add_player(name);
+        self.state().members.insert((plot_id, uuid), abilities);
+    }
+
+    /// Creates an API key for a registered plot, None scopes grant everything
+    pub fn add_key(&self, plot_id: PlotId, key: &str, scopes: Option<&[&str]>) {
+        let mut state = self.state();
+        let plot = state.plots[&plot_id].clone();
+        let id = state.keys.len() as i32 + 1;
+        state.keys.insert(
+            key.to_string(),
+            KeyGrant {
+                id,
+                plot,
+                scopes: scopes.map(|scopes| scopes.iter().map(|it| it.to_string()).collect()),
+                allowed_ips: None,
+            },
+        );
+    }
+
+    /// Only lets the key be used from addresses in the ranges
+    pub fn restrict_key(&self, key: &str, allowed_ips: Vec<IpRange>) {
+        if let Some(grant) = self.state().keys.get_mut(key) {
+            grant.allowed_ips = Some(allowed_ips);
+        }
+    }
+
+    pub fn disable_plot(&self, plot_id: PlotId) {
+        self.state().disabled.insert(plot_id);
+    }
+
+    /// Locks the address out like too many invalid keys would
+    pub fn lock_out(&self, ip: IpAddr, retry_after: u64) {
+        self.state().lockouts.insert(ip, retry_after);
+    }
+
+    /// Registers an instance with the tier
+    pub fn add_instance(&self, key: VerifyingKey, tier: InstanceTier) {
+        self.state().instances.push((key, tier));
+    }
+
+    pub fn audit_len(&self) -> usize {
+        self.state().audit.len()
+    }
+
+    /// Lets the ack timeout of every taken transfer run out
+    pub fn time_out_acks(&self) {
+        for (_, _, due_at) in self.state().unacked.values_mut() {
+            *due_at = 0;
+        }
+    }
+}
+
+impl AuthStore for MockStore {
+    fn jwt_key(&self) -> &Hmac<Sha256> {
+        &self.jwt_key
+    }
+
+    fn is_server_token_revoked<'a>(
+        &'a self,
+        _jti: Uuid,
+        _domain: &'a str,
+        _key: &'a str,
+        _issued_at: u64,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn claim_server_nonce<'a>(
+        &'a self,
+        _issuer: &'a str,
+        _nonce: &'a str,
+        _ttl: u64,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>> {
+        Box::pin(async { Ok(true) })
+    }
+
+    fn get_known_instance<'a>(
+        &'a self,
+        _domain: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<SendInstance>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn is_instance_key_blocked<'a>(
+        &'a self,
+        _key: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn is_session_revoked(&self, _jti: Uuid) -> BoxFuture<'_, color_eyre::Result<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn get_plot(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<Option<Plot>>> {
+        Box::pin(async move { Ok(self.state().plots.get(&plot_id).cloned()) })
+    }
+
+    fn is_plot_disabled(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<bool>> {
+        Box::pin(async move { Ok(self.state().disabled.contains(&plot_id)) })
+    }
+
+    fn record_request(&self, _plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn key_lockout(&self, ip: IpAddr) -> BoxFuture<'_, color_eyre::Result<Option<u64>>> {
+        Box::pin(async move { Ok(self.state().lockouts.get(&ip).copied()) })
+    }
+
+    fn verify_key<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<KeyGrant>>> {
+        Box::pin(async move { Ok(self.state().keys.get(key).cloned()) })
+    }
+
+    fn record_key_failure(&self, _ip: IpAddr) -> BoxFuture<'_, color_eyre::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn record_key_use(&self, _key_id: i32) -> BoxFuture<'_, color_eyre::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn audit(&self, entry: AuditEntry) -> BoxFuture<'_, ()> {
+        Box::pin(async move { self.state().audit.push(entry) })
+    }
+
+    fn get_uuid<'a>(&'a self, name: &'a str) -> BoxFuture<'a, color_eyre::Result<Option<Uuid>>> {
+        Box::pin(async move { Ok(self.state().players.get(name).copied()) })
+    }
+
+    fn get_member(
+        &self,
+        plot_id: PlotId,
+        member: Uuid,
+    ) -> BoxFuture<'_, color_eyre::Result<Option<Abilities>>> {
+        Box::pin(async move { Ok(self.state().members.get(&(plot_id, member)).cloned()) })
+    }
+}
+
+impl BatonStore for MockStore {
+    async fn fetch_plot_trust(&self, plot: PlotId) -> color_eyre::Result<Vec<PlotId>> {
+        Ok(self.state().trust.get(&plot).cloned().unwrap_or_default())
+    }
+
+    async fn set_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusts: Vec<PlotId>,
+    ) -> color_eyre::Result<Result<(), PlotTrustSetError>> {
+        let mut state = self.state();
+        if !state.plots.contains_key(&plot_id) {
+            return Ok(Err(PlotTrustSetError::PlotNotFound));
+        }
+        state.trust.insert(plot_id, trusts);
+        Ok(Ok(()))
+    }
+
+    async fn add_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+        _expires_at: Option<u64>,
+    ) -> color_eyre::Result<Result<bool, PlotTrustSetError>> {
+        let mut state = self.state();
+        if !state.plots.contains_key(&plot_id) {
+            return Ok(Err(PlotTrustSetError::PlotNotFound));
+        }
+        let trust = state.trust.entry(plot_id).or_default();
+        if trust.contains(&trusted) {
+            return Ok(Ok(false));
+        }
+        trust.push(trusted);
+        Ok(Ok(true))
+    }
+
+    async fn remove_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+    ) -> color_eyre::Result<bool> {
+        let mut state = self.state();
+        let Some(trust) = state.trust.get_mut(&plot_id) else {
+            return Ok(false);
+        };
+        let len = trust.len();
+        trust.retain(|it| *it != trusted);
+        Ok(trust.len() != len)
+    }
+
+    async fn fetch_instance_trust(&self, plot: PlotId) -> color_eyre::Result<Vec<VerifyingKey>> {
+        Ok(self
+            .state()
+            .instance_trust
+            .get(&plot)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_instance_trust(
+        &self,
+        plot_id: PlotId,
+        trusts: Vec<VerifyingKey>,
+    ) -> color_eyre::Result<Result<(), InstanceTrustSetError>> {
+        let mut state = self.state();
+        if !state.plots.contains_key(&plot_id) {
+            return Ok(Err(InstanceTrustSetError::PlotNotFound));
+        }
+        let tier = |key: &VerifyingKey| {
+            state
+                .instances
+                .iter()
+                .find(|(it, _)| it == key)
+                .map(|(_, tier)| *tier)
+        };
+        let missing: Vec<VerifyingKey> = trusts
+            .iter()
+            .filter(|key| tier(key).is_none())
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Ok(Err(InstanceTrustSetError::InstanceNotFound(missing)));
+        }
+        let untrustable: Vec<VerifyingKey> = trusts
+            .iter()
+            .filter(|key| tier(key).is_some_and(|tier| !tier.allows_instance_trust()))
+            .copied()
+            .collect();
+        if !untrustable.is_empty() {
+            return Ok(Err(InstanceTrustSetError::NotTrustable(untrustable)));
+        }
+        state.instance_trust.insert(plot_id, trusts);
+        Ok(Ok(()))
+    }
+
+    async fn get_baton_settings(&self, plot_id: PlotId) -> color_eyre::Result<BatonSettings> {
+        Ok(self
+            .state()
+            .settings
+            .get(&plot_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_baton_settings(
+        &self,
+        plot_id: PlotId,
+        settings: &BatonSettings,
+    ) -> color_eyre::Result<Result<(), PlotTrustSetError>> {
+        let mut state = self.state();
+        if !state.plots.contains_key(&plot_id) {
+            return Ok(Err(PlotTrustSetError::PlotNotFound));
+        }
+        state.settings.insert(plot_id, settings.clone());
+        Ok(Ok(()))
+    }
+
+    async fn plot_exists(&self, plot_id: PlotId) -> color_eyre::Result<bool> {
+        Ok(self.state().plots.contains_key(&plot_id))
+    }
+
+    async fn get_plots(&self, plot_ids: &[PlotId]) -> color_eyre::Result<Vec<Plot>> {
+        let state = self.state();
+        let mut plots: Vec<Plot> = plot_ids
+            .iter()
+            .filter_map(|id| state.plots.get(id).cloned())
+            .collect();
+        plots.sort_by_key(|plot| plot.plot_id);
+        plots.dedup_by_key(|plot| plot.plot_id);
+        Ok(plots)
+    }
+
+    fn local_origin(&self, sent_at: u64) -> Origin {
+        Origin {
+            domain: "localhost".to_string(),
+            key: self.key,
+            sent_at,
+        }
+    }
+
+    fn federation_allows(&self, _domain: &str, _key: &VerifyingKey) -> bool {
+        true
+    }
+
+    async fn instance_tier(&self, _domain: &str) -> color_eyre::Result<Option<InstanceTier>> {
+        Ok(None)
+    }
+
+    async fn instance_down_since(&self, _domain: &str) -> color_eyre::Result<Option<i64>> {
+        Ok(None)
+    }
+
+    async fn consume_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+    ) -> color_eyre::Result<Result<(), QuotaExceeded>> {
+        self.consume_scaled_transfer_quota(plot_id, transfers, bytes, 1)
+            .await
+    }
+
+    async fn consume_scaled_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+        factor: u32,
+    ) -> color_eyre::Result<Result<(), QuotaExceeded>> {
+        let mut state = self.state();
+        let used = state.quota_used.entry(plot_id).or_default();
+        let retry_after = if used.0 + transfers > TRANSFER_RATE_LIMIT * factor {
+            60
+        } else if used.1 + bytes > TRANSFER_BYTE_QUOTA * factor as u64 {
+            60 * 60
+        } else {
+            used.0 += transfers;
+            used.1 += bytes;
+            return Ok(Ok(()));
+        };
+        Ok(Err(QuotaExceeded { retry_after }))
+    }
+
+    async fn get_transfer_quota(&self, plot_id: PlotId) -> color_eyre::Result<Quota> {
+        let (transfers, bytes) = self
+            .state()
+            .quota_used
+            .get(&plot_id)
+            .copied()
+            .unwrap_or_default();
+        Ok(Quota {
+            transfers_remaining: TRANSFER_RATE_LIMIT - transfers,
+            transfers_reset: 60,
+            bytes_remaining: TRANSFER_BYTE_QUOTA - bytes,
+            bytes_reset: 60 * 60,
+        })
+    }
+
+    async fn claim_idempotency_key<T: DeserializeOwned + Send>(
+        &self,
+        _key: &IdempotencyKey,
+    ) -> color_eyre::Result<IdempotencyClaim<T>> {
+        Ok(IdempotencyClaim::Claimed)
+    }
+
+    async fn finish_idempotency_key<T: Serialize + Sync>(
+        &self,
+        _key: &IdempotencyKey,
+        _result: Option<&T>,
+    ) -> color_eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn enqueue_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        origin: Origin,
+    ) -> color_eyre::Result<Result<Uuid, TransferQueueError>> {
+        let id = Uuid::new_v4();
+        let pushed = self.state().push(id, from, plot_id, payload, origin);
+        if pushed.is_ok() {
+            self.arrived.notify_waiters();
+        }
+        Ok(pushed.map(|()| id))
+    }
+
+    async fn schedule_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        deliver_at: u64,
+    ) -> color_eyre::Result<Uuid> {
+        let id = Uuid::new_v4();
+        let mut state = self.state();
+        state.records.insert(
+            id,
+            TransferRecord {
+                from,
+                to: plot_id,
+                status: TransferStatus::Scheduled,
+                expires_at: deliver_at + TRANSFER_TTL,
+            },
+        );
+        state.scheduled.push(ScheduledTransfer {
+            id,
+            from,
+            to: plot_id,
+            payload,
+            origin: self.local_origin(unix_now()),
+            deliver_at,
+        });
+        Ok(id)
+    }
+
+    async fn queue_relay(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        domain: ExternalDomain,
+        payload: TransferPayload,
+        not_before: Option<u64>,
+        trusted_back: bool,
+    ) -> color_eyre::Result<Uuid> {
+        let id = Uuid::new_v4();
+        self.state().relays.insert(
+            id,
+            RelayJob {
+                id,
+                from,
+                to,
+                domain,
+                payload,
+                state: RelayState::Pending,
+                attempts: 0,
+                last_error: None,
+                next_attempt: not_before.unwrap_or_else(unix_now),
+                remote_id: None,
+                sent_at: unix_now(),
+                trusted_back,
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get_relay(&self, id: Uuid) -> color_eyre::Result<Option<RelayJob>> {
+        Ok(self.state().relays.get(&id).cloned())
+    }
+
+    async fn take_transfer(
+        &self,
+        plot_id: PlotId,
+        kind: Option<PayloadKind>,
+        in_order: bool,
+    ) -> color_eyre::Result<Option<QueuedTransfer>> {
+        let mut state = self.state();
+        let queue = state.live_queue(plot_id);
+        // Lowest queued sequence number of each sender
+        let mut next_seq = HashMap::new();
+        for transfer in queue.iter() {
+            next_seq
+                .entry(transfer.from)
+                .and_modify(|seq: &mut u64| *seq = (*seq).min(transfer.seq))
+                .or_insert(transfer.seq);
+        }
+        let position = queue.iter().position(|it| {
+            (!in_order || next_seq.get(&it.from) == Some(&it.seq))
+                && kind.is_none_or(|kind| it.payload.kind() == kind)
+        });
+        let Some(transfer) = position.and_then(|position| queue.remove(position)) else {
+            return Ok(None);
+        };
+        state.set_status(transfer.id, TransferStatus::Delivered);
+        let delivery = state.settings.get(&plot_id).map(|it| it.delivery);
+        if delivery == Some(DeliveryGuarantee::AtLeastOnce) {
+            let due_at = unix_now() + ACK_TIMEOUT;
+            state
+                .unacked
+                .insert(transfer.id, (plot_id, transfer.clone(), due_at));
+        }
+        Ok(Some(transfer))
+    }
+
+    async fn peek_transfer(&self, plot_id: PlotId) -> color_eyre::Result<Option<QueuedTransfer>> {
+        Ok(self.state().live_queue(plot_id).front().cloned())
+    }
+
+    async fn ack_transfer(
+        &self,
+        plot_id: PlotId,
+        id: Uuid,
+    ) -> color_eyre::Result<Result<(), TransferAckError>> {
+        let mut state = self.state();
+        // Queues an unacked transfer whose timeout ran out first, the ack is too late then
+        state.live_queue(plot_id);
+        let record = match state.record(id) {
+            Some(it) if it.to == plot_id => it,
+            _ => return Ok(Err(TransferAckError::NotFound)),
+        };
+        match record.status {
+            TransferStatus::Delivered | TransferStatus::Acked => {}
+            _ => return Ok(Err(TransferAckError::NotDelivered)),
+        }
+        state.unacked.remove(&id);
+        state.set_status(id, TransferStatus::Acked);
+        Ok(Ok(()))
+    }
+
+    async fn stream_transfers(
+        self: Arc<Self>,
+        plot_id: PlotId,
+        in_order: bool,
+    ) -> color_eyre::Result<BoxStream<'static, QueuedTransfer>> {
+        Ok(stream::unfold(self, move |store| async move {
+            loop {
+                // Created before looking so a transfer queued in between wakes it
+                let arrived = store.arrived.notified();
+                let taken = store
+                    .take_transfer(plot_id, None, in_order)
+                    .await
+                    .expect("Mock store ops don't fail");
+                if let Some(transfer) = taken {
+                    drop(arrived);
+                    return Some((transfer, store));
+                }
+                arrived.await;
+            }
+        })
+        .boxed())
+    }
+
+    async fn get_transfer_record(&self, id: Uuid) -> color_eyre::Result<Option<TransferRecord>> {
+        Ok(self.state().record(id))
+    }
+
+    async fn transfer_history(
+        &self,
+        _plot_id: PlotId,
+        _filter: HistoryFilter,
+    ) -> color_eyre::Result<Vec<HistoryEntry>> {
+        Ok(Vec::new())
+    }
+
+    async fn remember_sent_payload(
+        &self,
+        _from: PlotId,
+        _to: PlotId,
+        _payload: &TransferPayload,
+    ) -> color_eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn patch_sent_payload(
+        &self,
+        _from: PlotId,
+        _to: PlotId,
+        _patch: &[json_patch::PatchOperation],
+    ) -> color_eyre::Result<Result<TransferPayload, PatchError>> {
+        Ok(Err(PatchError::NoBase))
+    }
+
+    async fn send_reply(
+        &self,
+        from: PlotId,
+        correlation: Uuid,
+        payload: TransferPayload,
+    ) -> color_eyre::Result<Result<Uuid, ReplyError>> {
+        let mut state = self.state();
+        let request = match state.record(correlation) {
+            Some(it) if it.to == from => it,
+            _ => return Ok(Err(ReplyError::RequestNotFound)),
+        };
+        if !state.replied.insert(correlation) {
+            return Ok(Err(ReplyError::AlreadyReplied));
+        }
+        let id = Uuid::new_v4();
+        let expires_at = unix_now() + TRANSFER_TTL;
+        state.replies.insert(
+            correlation,
+            QueuedTransfer {
+                id,
+                from,
+                seq: 0,
+                payload,
+                expires_at,
+                origin: Some(self.local_origin(unix_now())),
+                received_at: unix_now(),
+            },
+        );
+        state.records.insert(
+            id,
+            TransferRecord {
+                from,
+                to: request.from,
+                status: TransferStatus::Queued,
+                expires_at,
+            },
+        );
+        drop(state);
+        self.arrived.notify_waiters();
+        Ok(Ok(id))
+    }
+
+    async fn wait_reply(
+        &self,
+        plot_id: PlotId,
+        correlation: Uuid,
+        timeout: Duration,
+    ) -> color_eyre::Result<Result<Option<QueuedTransfer>, ReplyError>> {
+        match self.state().record(correlation) {
+            Some(it) if it.from == plot_id => {}
+            _ => return Ok(Err(ReplyError::RequestNotFound)),
+        }
+        let reply = tokio::time::timeout(timeout, async {
+            loop {
+                let arrived = self.arrived.notified();
+                let reply = self.state().take_reply(correlation);
+                if let Some(reply) = reply {
+                    return reply;
+                }
+                arrived.await;
+            }
+        })
+        .await;
+        Ok(Ok(reply.ok()))
+    }
+
+    async fn get_webhook(&self, plot_id: PlotId) -> color_eyre::Result<Option<Webhook>> {
+        Ok(self.state().webhooks.get(&plot_id).cloned())
+    }
+
+    async fn set_webhook(&self, plot_id: PlotId, url: &str) -> color_eyre::Result<String> {
+        let secret = Uuid::new_v4().as_bytes().to_vec();
+        let encoded = BASE64.encode(&secret);
+        self.state().webhooks.insert(
+            plot_id,
+            Webhook {
+                url: url.to_string(),
+                secret,
+            },
+        );
+        Ok(encoded)
+    }
+
+    async fn delete_webhook(&self, plot_id: PlotId) -> color_eyre::Result<bool> {
+        Ok(self.state().webhooks.remove(&plot_id).is_some())
+    }
+
+    async fn create_channel(&self, owner: PlotId, name: &str) -> color_eyre::Result<bool> {
+        let mut state = self.state();
+        if state.channels.contains_key(name) {
+            return Ok(false);
+        }
+        state.channels.insert(name.to_string(), owner);
+        Ok(true)
+    }
+
+    async fn delete_channel(&self, owner: PlotId, name: &str) -> color_eyre::Result<bool> {
+        let mut state = self.state();
+        if state.channels.get(name) != Some(&owner) {
+            return Ok(false);
+        }
+        state.channels.remove(name);
+        Ok(true)
+    }
+
+    async fn get_channel_owner(&self, name: &str) -> color_eyre::Result<Option<PlotId>> {
+        Ok(self.state().channels.get(name).copied())
+    }
+
+    async fn is_trusted_by(&self, owner: PlotId, plot_id: PlotId) -> color_eyre::Result<bool> {
+        Ok(self
+            .state()
+            .trust
+            .get(&owner)
+            .is_some_and(|trust| trust.contains(&plot_id)))
+    }
+
+    async fn publish_channel(
+        &self,
+        name: &str,
+        message: &ChannelMessage,
+    ) -> color_eyre::Result<usize> {
+        let message = serde_json::to_string(message)?;
+        // Sending only fails without subscribers
+        Ok(self
+            .state()
+            .subscribers
+            .get(name)
+            .map_or(0, |sender| sender.send(message).unwrap_or(0)))
+    }
+
+    async fn subscribe_channel(
+        &self,
+        name: &str,
+    ) -> color_eyre::Result<BoxStream<'static, ChannelMessage>> {
+        let receiver = self
+            .state()
+            .subscribers
+            .entry(name.to_string())
+            .or_insert_with(|| broadcast::channel(16).0)
+            .subscribe();
+        Ok(stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => {
+                        let message: ChannelMessage = serde_json::from_str(&message)
+                            .expect("Published messages should deserialize");
+                        return Some((message, receiver));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed())
+    }
+}
@@ -1,155 +1,125 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use ascii_domain::dom::Domain;
 use base64::Engine;
-use chrono::Local;
-use color_eyre::eyre::Context;
+use color_eyre::eyre::{eyre, Context};
 use ed25519_dalek::{ed25519::signature::SignerMut, Signature, SigningKey, VerifyingKey};
 use hmac::Hmac;
 use jwt::{FromBase64, SignWithKey, VerifyWithKey};
 use rand::distr::{Alphanumeric, SampleString};
-use redis::{aio::MultiplexedConnection, AsyncCommands};
-use reqwest::Client;
-use serde::Deserialize;
-use sha2::{Digest, Sha256};
-use sqlx::{prelude::FromRow, query, query_as, Pool, Postgres};
+use reqwest::{Client, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Pool, Postgres};
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::{
     api::{
-        auth::{ExternalServer, Plot},
-        instance::VerificationResponse,
-        PlotId,
+        auth::Plot, decode_instance_key, discovery::DiscoveryDocument,
+        instance::VerificationResponse, PlotId,
     },
+    federation::FederationPolicy,
     instance::{ExternalDomain, Instance, InstanceDomain},
+    signature::{challenge_message, CHALLENGE_TTL},
     BASE64,
 };
 
+pub mod activity;
+pub mod admin;
+pub mod audit;
 pub mod baton;
+pub mod breaker;
+pub mod cache;
+pub mod channel;
+pub mod delivery;
+pub mod feed;
+pub mod handoff;
+pub mod history;
+pub mod idempotency;
 pub mod instance;
+pub mod invalidate;
+pub mod key;
+pub mod lease;
+pub mod local;
+pub mod member;
+pub mod meta;
+#[cfg(test)]
+pub mod mock;
+pub mod ops;
+pub mod patch;
+pub mod quota;
+pub mod relay;
+pub mod reply;
+pub mod schedule;
+pub mod session;
+pub mod verify;
+pub mod webhook;
+
+use baton::{unix_now, BatonConfig};
+use cache::Cache;
+use local::LocalCache;
 
 pub struct Store {
-    redis: MultiplexedConnection,
+    /// Redis, or the in-process stand-in when no redis is configured
+    redis: Cache,
     pg: Pool<Postgres>,
     client: Client,
     jwt_key: Hmac<Sha256>,
     secret_key: RwLock<SigningKey>,
     public_key: VerifyingKey,
+    /// Domain of this instance
+    domain: Domain<String>,
+    baton: BatonConfig,
+    federation: FederationPolicy,
+    /// Where federation events are posted, if anywhere
+    federation_webhook: Option<webhook::Webhook>,
+    /// Times idempotent requests to other servers are retried
+    outbound_retries: u32,
+    /// Copy of the disabled plots in redis, checked on every authenticated request
+    disabled_plots: std::sync::RwLock<HashSet<PlotId>>,
+    /// Plot and trust lookups of every request, kept shortly in front of redis
+    local_plots: LocalCache<PlotId, Plot>,
+    local_plot_trust: LocalCache<PlotId, Vec<PlotId>>,
+    local_instance_trust: LocalCache<PlotId, Vec<VerifyingKey>>,
 }
 
-/// Misc
-impl Store {
-    pub async fn verify_key(&self, key: &str) -> color_eyre::Result<Option<Plot>> {
-        let mut redis = self.redis.clone();
-        let res: Option<Plot> = redis.get(format!("key:{key}")).await?;
-        if let Some(plot) = res {
-            return Ok(if plot.plot_id == -1 { None } else { Some(plot) });
-        }
+/// Verified keys of other instances are forgotten after this long
+const INSTANCE_KEY_TTL: u64 = 60 * 60;
+/// And verified again in the background once they are this old
+const INSTANCE_KEY_REFRESH: u64 = 60 * 10;
+/// Player names are looked up again after this long. Names that were changed can be taken by
+/// someone else once Mojang releases them, it can't be cached forever
+const PLAYER_UUID_TTL: u64 = 60 * 60 * 24;
 
-        #[derive(FromRow)]
-        struct Row {
-            plot: PlotId,
-            owner_uuid: Uuid,
-            domain: Option<String>,
-            public_key: Option<Vec<u8>>,
-        }
+/// Redis key of the cached discovery document of an instance
+pub(super) fn discovery_key(domain: &str) -> String {
+    format!("instance:{}:discovery", domain.to_ascii_lowercase())
+}
 
-        let plot = query_as!(
-            Row,
-            "
-            SELECT
-                key.plot,
-                p.owner_uuid,
-                instance.domain,
-                instance.public_key
-            FROM api_key key
-            JOIN plot p ON key.plot = p.id
-            LEFT JOIN known_instance instance ON instance.id = p.instance
-            WHERE
-                key.hashed_key = sha256($1) AND
-                key.disabled = false;
-            ",
-            key.as_bytes()
-        )
-        .fetch_optional(&self.pg)
-        .await?;
-
-        let key = BASE64.encode(Sha256::digest(key));
-        if let Some(plot) = plot {
-            let plot = if let Some(key) = plot.public_key {
-                let instance = Instance::from_row(key, plot.domain)?;
-                Plot {
-                    plot_id: plot.plot,
-                    owner: plot.owner_uuid,
-                    instance,
-                }
-            } else {
-                Plot {
-                    plot_id: plot.plot,
-                    owner: plot.owner_uuid,
-                    instance: self.construct_current_instance(),
-                }
-            };
-            let _: () = redis.set(format!("key:{}", key), &plot).await?;
-            Ok(Some(plot))
-        } else {
-            let _: () = redis
-                .set(
-                    format!("key:{}", key),
-                    // Yes... magic values due to redis
-                    Plot {
-                        plot_id: -1,
-                        owner: Uuid::from_u128(0),
-                        instance: Instance::new(self.public_key, InstanceDomain::Current),
-                    },
-                )
-                .await?;
-            Ok(None)
-        }
-    }
+/// Redis keys of the verified key of an instance and whether it is still fresh
+pub(super) fn instance_key_keys(domain: &str) -> [String; 2] {
+    let domain = domain.to_ascii_lowercase();
+    [
+        format!("instance:{}:key", domain),
+        format!("instance:{}:key:fresh", domain),
+    ]
+}
+
+/// Misc
+impl Store {
     pub fn construct_current_instance(&self) -> Instance {
         Instance {
             key: self.public_key,
             domain: InstanceDomain::Current,
         }
     }
-    pub async fn create_key(&self, plot_id: PlotId) -> color_eyre::Result<String> {
-        let key = Alphanumeric.sample_string(&mut rand::rng(), 32);
-        query!(
-            "INSERT INTO api_key (plot, hashed_key) VALUES ($1, sha256($2))",
-            plot_id,
-            key.as_bytes()
-        )
-        .execute(&self.pg)
-        .await?;
-        Ok(key)
-    }
-    pub async fn disable_all_keys(&self, plot_id: PlotId) -> color_eyre::Result<()> {
-        let deleted = query!(
-            "WITH disabled_keys AS (
-                UPDATE api_key SET
-                    disabled = true
-                WHERE 
-                    plot = $1 
-                    AND disabled = false
-                RETURNING hashed_key
-            ) SELECT hashed_key FROM disabled_keys;",
-            plot_id
-        )
-        .fetch_all(&self.pg)
-        .await?;
-        for row in deleted {
-            let key = BASE64.encode(row.hashed_key);
-            info!("{key}");
-            let _: () = self.redis.clone().del(format!("key:{key}")).await?;
-        }
-
-        Ok(())
-    }
+    /// Uuid of the player currently going by the name
     pub async fn get_uuid(&self, name: &str) -> color_eyre::Result<Option<Uuid>> {
         let found: Option<String> = self
             .redis
-            .clone()
-            .get(format!("player:{}:uuid", name))
+            .get(format!("player:{}:current_uuid", name))
             .await?;
 
         Ok(if let Some(uuid) = found {
@@ -157,15 +127,18 @@ impl Store {
         } else {
             let call = format!("https://api.mojang.com/users/profiles/minecraft/{}", name);
 
-            let uuid_fetch = reqwest::get(call).await?;
+            let uuid_fetch = self.send_retrying(self.client.get(call)).await?;
             let text = uuid_fetch.text().await?;
 
             let json: MojangResponse = serde_json::from_str(&text)?;
 
             let _: () = self
                 .redis
-                .clone()
-                .set(format!("player:{}:uuid", name), json.id.to_string())
+                .set_ex(
+                    format!("player:{}:current_uuid", name),
+                    json.id.to_string(),
+                    PLAYER_UUID_TTL,
+                )
                 .await?;
             Some(json.id)
         })
@@ -173,32 +146,219 @@ impl Store {
     pub fn verify_jwt<T: FromBase64>(&self, jwt: &str) -> Option<T> {
         VerifyWithKey::<T>::verify_with_key(jwt, &self.jwt_key).ok()
     }
-    pub fn sign_jwt(&self, jwt: &ExternalServer) -> Result<String, jwt::Error> {
+    pub fn sign_jwt<T: Serialize>(&self, jwt: &T) -> Result<String, jwt::Error> {
         jwt.sign_with_key(&self.jwt_key)
     }
+    /// Marks the nonce as used for the server token or signing instance,
+    /// returns false if it already was
+    pub async fn claim_server_nonce(
+        &self,
+        issuer: &str,
+        nonce: &str,
+        ttl: u64,
+    ) -> color_eyre::Result<bool> {
+        Ok(self
+            .redis
+            .set_nx_ex(
+                format!("server:{}:nonce:{}", issuer, nonce),
+                true,
+                ttl.max(1),
+            )
+            .await?)
+    }
     pub async fn sign(&self, msg: &[u8]) -> Signature {
         self.secret_key.write().await.sign(msg)
     }
+    /// Sends a request that is safe to repeat, retrying connection failures, timeouts
+    /// and 502 to 504 answers up to `outbound_retries` times
+    pub(super) async fn send_retrying(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let retry = match request.try_clone() {
+                Some(retry) if attempt < self.outbound_retries => retry,
+                _ => return request.send().await,
+            };
+            match retry.send().await {
+                Ok(res) if !matches!(res.status().as_u16(), 502..=504) => return Ok(res),
+                Err(err) if !err.is_connect() && !err.is_timeout() => return Err(err),
+                _ => {}
+            }
+            tokio::time::sleep(Duration::from_millis(200 << attempt.min(8))).await;
+            attempt += 1;
+        }
+    }
+
+    /// Whether the operator lets this instance talk to another one
+    pub fn federation_allows(&self, domain: &str, key: &VerifyingKey) -> bool {
+        self.federation.allows(domain, key)
+    }
+
+    /// Url of a path on another instance, over the scheme the operator set for it
+    pub fn instance_url(&self, instance: &ExternalDomain, path: &str) -> String {
+        let domain = instance.inner().as_inner();
+        format!(
+            "{}://{}{}",
+            self.federation.scheme(domain).as_str(),
+            domain,
+            path
+        )
+    }
+
+    /// The discovery document of another instance, None if it doesn't serve one
+    pub async fn discover_instance(
+        &self,
+        instance: &ExternalDomain,
+    ) -> color_eyre::Result<Option<DiscoveryDocument>> {
+        /// Instances are asked again after this long
+        const DISCOVERY_TTL: u64 = 60 * 60;
+        /// Or this long if they didn't have one
+        const MISSING_DISCOVERY_TTL: u64 = 60 * 5;
+        let redis = &self.redis;
+        let key = discovery_key(instance.inner().as_inner());
+        if let Some(cached) = redis.get::<_, Option<String>>(&key).await? {
+            // Empty when it had none
+            return Ok(serde_json::from_str(&cached).ok());
+        }
+        let fetched = async {
+            let body = self
+                .send_retrying(
+                    self.client
+                        .get(self.instance_url(instance, "/.well-known/dftools")),
+                )
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            color_eyre::Result::<_>::Ok(serde_json::from_str::<DiscoveryDocument>(&body)?)
+        }
+        .await;
+        let document = match fetched {
+            Ok(mut document) => {
+                document.apis.retain(|_, api| api.valid_base());
+                document
+            }
+            Err(_) => {
+                let _: () = redis.set_ex(key, "", MISSING_DISCOVERY_TTL).await?;
+                return Ok(None);
+            }
+        };
+        let _: () = redis
+            .set_ex(key, serde_json::to_string(&document)?, DISCOVERY_TTL)
+            .await?;
+        Ok(Some(document))
+    }
+
+    /// Url of a path of one of the APIs of another instance, like `api_url(domain, "baton", "/send/transfer")`.
+    /// The instance's discovery document decides where the API is, `/{api}` without one
+    pub async fn api_url(&self, instance: &ExternalDomain, api: &str, path: &str) -> String {
+        let base = match self.discover_instance(instance).await {
+            Ok(document) => document.and_then(|mut document| document.apis.remove(api)),
+            Err(err) => {
+                warn!(
+                    "Discovering {} failed: {:?}",
+                    instance.inner().as_inner(),
+                    err
+                );
+                None
+            }
+        }
+        .map(|api| api.base.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| format!("/{}", api));
+        self.instance_url(instance, &format!("{}/v0{}", base, path))
+    }
+
+    /// The key of another instance, verified by [Store::ping_instance] within the last hour.
+    /// Keys that aren't fresh anymore are still returned but verified again in the background
+    pub async fn instance_key(
+        self: &Arc<Self>,
+        instance: &ExternalDomain,
+    ) -> color_eyre::Result<VerifyingKey> {
+        let [key_key, fresh_key] = instance_key_keys(instance.inner().as_inner());
+        let redis = &self.redis;
+        let cached: Option<String> = redis.get(&key_key).await?;
+        let Some(key) = cached.and_then(|key| decode_instance_key(&key).ok()) else {
+            return self.verify_instance_key(instance).await;
+        };
+        // Whoever sets the marker again does the refresh
+        let stale = redis
+            .set_nx_ex(fresh_key, true, INSTANCE_KEY_REFRESH)
+            .await?;
+        if stale {
+            let store = self.clone();
+            let instance = instance.clone();
+            tokio::spawn(async move {
+                if let Err(err) = store.verify_instance_key(&instance).await {
+                    warn!(
+                        "Refreshing the key of {} failed: {:?}",
+                        instance.inner().as_inner(),
+                        err
+                    );
+                }
+            });
+        }
+        Ok(key)
+    }
+
+    /// Pings the instance and caches the key it answered with
+    pub async fn verify_instance_key(
+        &self,
+        instance: &ExternalDomain,
+    ) -> color_eyre::Result<VerifyingKey> {
+        let key = self.ping_instance(instance).await?;
+        self.cache_instance_key(instance.inner().as_inner(), &key)
+            .await?;
+        Ok(key)
+    }
+
+    pub(super) async fn cache_instance_key(
+        &self,
+        domain: &str,
+        key: &VerifyingKey,
+    ) -> color_eyre::Result<()> {
+        let [key_key, fresh_key] = instance_key_keys(domain);
+        let redis = &self.redis;
+        let _: () = redis
+            .set_ex(key_key, BASE64.encode(key), INSTANCE_KEY_TTL)
+            .await?;
+        let _: () = redis.set_ex(fresh_key, true, INSTANCE_KEY_REFRESH).await?;
+        Ok(())
+    }
+
+    /// Forgets the cached key, for when the instance was seen with another one
+    pub async fn invalidate_instance_key(&self, domain: &str) -> color_eyre::Result<()> {
+        let _: () = self.redis.del_many(&instance_key_keys(domain)).await?;
+        Ok(())
+    }
+
+    /// Has the instance sign a challenge and returns its key,
+    /// fails right away while its circuit breaker is open
     pub async fn ping_instance(
         &self,
         instance: &ExternalDomain,
     ) -> color_eyre::Result<VerifyingKey> {
         let domain = instance.inner().as_inner();
+        if !self.breaker_allows(domain).await? {
+            return Err(eyre!("Circuit breaker of {} is open", domain));
+        }
+        let res = self.request_signature(instance).await;
+        self.breaker_record(domain, res.is_ok()).await;
+        res
+    }
 
-        let verify_body = Local::now()
-            .format("DFTOOLS VERIFY %Y-%m-%d %H:%M:%S%.3f")
-            .to_string();
+    async fn request_signature(
+        &self,
+        instance: &ExternalDomain,
+    ) -> color_eyre::Result<VerifyingKey> {
+        let verify_body = challenge_message(
+            self.domain.as_inner(),
+            &Alphanumeric.sample_string(&mut rand::rng(), 32),
+            unix_now() + CHALLENGE_TTL,
+        );
 
-        #[cfg(debug_assertions)]
-        let url = format!("http://{}/instance/v0/sign", domain);
-        #[cfg(not(debug_assertions))]
-        let url = format!("https://{}/instance/v0/sign", domain);
+        let url = self.api_url(instance, "instance", "/sign").await;
         info!("{}", url);
         let req = self
-            .client
-            .get(url)
-            .query(&[("tosign", &verify_body)])
-            .send()
+            .send_retrying(self.client.get(url).query(&[("tosign", &verify_body)]))
             .await?;
         let body = req.text().await?;
         let json: VerificationResponse =
@@ -223,6 +383,11 @@ impl Store {
         let _: () = key
             .verify_strict(verify_body.as_bytes(), &sig)
             .wrap_err("Invalid signature")?;
+        // Whoever can serve the document can answer the challenge too, but they shouldn't disagree
+        let document = self.discover_instance(instance).await?;
+        if document.is_some_and(|document| document.key != BASE64.encode(key)) {
+            return Err(eyre!("Discovery document has another key"));
+        }
         Ok(key)
     }
 
@@ -0,0 +1,716 @@
+use std::{future::Future, net::IpAddr, sync::Arc, time::Duration};
+
+use ed25519_dalek::VerifyingKey;
+use futures::{future::BoxFuture, stream::BoxStream};
+use hmac::Hmac;
+use jwt::{FromBase64, VerifyWithKey};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        auth::Plot,
+        baton::{ChannelMessage, PayloadKind, TransferPayload},
+        PlotId,
+    },
+    instance::{ExternalDomain, SendInstance},
+};
+
+use super::{
+    audit::AuditEntry,
+    baton::{
+        BatonSettings, InstanceTrustSetError, Origin, PlotTrustSetError, QueuedTransfer,
+        TransferAckError, TransferQueueError, TransferRecord,
+    },
+    history::{HistoryEntry, HistoryFilter},
+    idempotency::{IdempotencyClaim, IdempotencyKey},
+    instance::InstanceTier,
+    key::KeyGrant,
+    member::Abilities,
+    patch::PatchError,
+    quota::{Quota, QuotaExceeded},
+    relay::RelayJob,
+    reply::ReplyError,
+    webhook::Webhook,
+    Store,
+};
+
+/// What the security scheme checkers need, they find it in the request data as `Arc<dyn AuthStore>`
+pub trait AuthStore: Send + Sync {
+    fn jwt_key(&self) -> &Hmac<Sha256>;
+    fn is_server_token_revoked<'a>(
+        &'a self,
+        jti: Uuid,
+        domain: &'a str,
+        key: &'a str,
+        issued_at: u64,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>>;
+    fn claim_server_nonce<'a>(
+        &'a self,
+        issuer: &'a str,
+        nonce: &'a str,
+        ttl: u64,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>>;
+    fn get_known_instance<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<SendInstance>>>;
+    fn is_instance_key_blocked<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>>;
+    fn is_session_revoked(&self, jti: Uuid) -> BoxFuture<'_, color_eyre::Result<bool>>;
+    fn get_plot(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<Option<Plot>>>;
+    fn is_plot_disabled(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<bool>>;
+    fn record_request(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<()>>;
+    fn key_lockout(&self, ip: IpAddr) -> BoxFuture<'_, color_eyre::Result<Option<u64>>>;
+    fn verify_key<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<KeyGrant>>>;
+    fn record_key_failure(&self, ip: IpAddr) -> BoxFuture<'_, color_eyre::Result<()>>;
+    fn record_key_use(&self, key_id: i32) -> BoxFuture<'_, color_eyre::Result<()>>;
+    fn audit(&self, entry: AuditEntry) -> BoxFuture<'_, ()>;
+    fn get_uuid<'a>(&'a self, name: &'a str) -> BoxFuture<'a, color_eyre::Result<Option<Uuid>>>;
+    fn get_member(
+        &self,
+        plot_id: PlotId,
+        member: Uuid,
+    ) -> BoxFuture<'_, color_eyre::Result<Option<Abilities>>>;
+}
+
+impl dyn AuthStore {
+    pub fn verify_jwt<T: FromBase64>(&self, jwt: &str) -> Option<T> {
+        VerifyWithKey::<T>::verify_with_key(jwt, self.jwt_key()).ok()
+    }
+}
+
+/// What the baton API needs, see [Store] for what each one does
+pub trait BatonStore: AuthStore + Sized + 'static {
+    fn fetch_plot_trust(
+        &self,
+        plot: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Vec<PlotId>>> + Send;
+    fn set_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusts: Vec<PlotId>,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), PlotTrustSetError>>> + Send;
+    fn add_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+        expires_at: Option<u64>,
+    ) -> impl Future<Output = color_eyre::Result<Result<bool, PlotTrustSetError>>> + Send;
+    fn remove_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send;
+    fn fetch_instance_trust(
+        &self,
+        plot: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Vec<VerifyingKey>>> + Send;
+    fn set_instance_trust(
+        &self,
+        plot_id: PlotId,
+        trusts: Vec<VerifyingKey>,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), InstanceTrustSetError>>> + Send;
+    fn get_baton_settings(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<BatonSettings>> + Send;
+    fn set_baton_settings(
+        &self,
+        plot_id: PlotId,
+        settings: &BatonSettings,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), PlotTrustSetError>>> + Send;
+    fn plot_exists(&self, plot_id: PlotId)
+        -> impl Future<Output = color_eyre::Result<bool>> + Send;
+    fn get_plots(
+        &self,
+        plot_ids: &[PlotId],
+    ) -> impl Future<Output = color_eyre::Result<Vec<Plot>>> + Send;
+    fn local_origin(&self, sent_at: u64) -> Origin;
+    fn federation_allows(&self, domain: &str, key: &VerifyingKey) -> bool;
+    fn instance_tier(
+        &self,
+        domain: &str,
+    ) -> impl Future<Output = color_eyre::Result<Option<InstanceTier>>> + Send;
+    fn instance_down_since(
+        &self,
+        domain: &str,
+    ) -> impl Future<Output = color_eyre::Result<Option<i64>>> + Send;
+    fn consume_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), QuotaExceeded>>> + Send;
+    fn consume_scaled_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+        factor: u32,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), QuotaExceeded>>> + Send;
+    fn get_transfer_quota(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Quota>> + Send;
+    fn claim_idempotency_key<T: DeserializeOwned + Send>(
+        &self,
+        key: &IdempotencyKey,
+    ) -> impl Future<Output = color_eyre::Result<IdempotencyClaim<T>>> + Send;
+    fn finish_idempotency_key<T: Serialize + Sync>(
+        &self,
+        key: &IdempotencyKey,
+        result: Option<&T>,
+    ) -> impl Future<Output = color_eyre::Result<()>> + Send;
+    fn enqueue_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        origin: Origin,
+    ) -> impl Future<Output = color_eyre::Result<Result<Uuid, TransferQueueError>>> + Send;
+    fn schedule_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        deliver_at: u64,
+    ) -> impl Future<Output = color_eyre::Result<Uuid>> + Send;
+    fn queue_relay(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        domain: ExternalDomain,
+        payload: TransferPayload,
+        not_before: Option<u64>,
+        trusted_back: bool,
+    ) -> impl Future<Output = color_eyre::Result<Uuid>> + Send;
+    fn get_relay(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = color_eyre::Result<Option<RelayJob>>> + Send;
+    fn take_transfer(
+        &self,
+        plot_id: PlotId,
+        kind: Option<PayloadKind>,
+        in_order: bool,
+    ) -> impl Future<Output = color_eyre::Result<Option<QueuedTransfer>>> + Send;
+    fn peek_transfer(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Option<QueuedTransfer>>> + Send;
+    fn ack_transfer(
+        &self,
+        plot_id: PlotId,
+        id: Uuid,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), TransferAckError>>> + Send;
+    fn stream_transfers(
+        self: Arc<Self>,
+        plot_id: PlotId,
+        in_order: bool,
+    ) -> impl Future<Output = color_eyre::Result<BoxStream<'static, QueuedTransfer>>> + Send;
+    fn get_transfer_record(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = color_eyre::Result<Option<TransferRecord>>> + Send;
+    fn transfer_history(
+        &self,
+        plot_id: PlotId,
+        filter: HistoryFilter,
+    ) -> impl Future<Output = color_eyre::Result<Vec<HistoryEntry>>> + Send;
+    fn remember_sent_payload(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        payload: &TransferPayload,
+    ) -> impl Future<Output = color_eyre::Result<()>> + Send;
+    fn patch_sent_payload(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        patch: &[json_patch::PatchOperation],
+    ) -> impl Future<Output = color_eyre::Result<Result<TransferPayload, PatchError>>> + Send;
+    fn send_reply(
+        &self,
+        from: PlotId,
+        correlation: Uuid,
+        payload: TransferPayload,
+    ) -> impl Future<Output = color_eyre::Result<Result<Uuid, ReplyError>>> + Send;
+    fn wait_reply(
+        &self,
+        plot_id: PlotId,
+        correlation: Uuid,
+        timeout: Duration,
+    ) -> impl Future<Output = color_eyre::Result<Result<Option<QueuedTransfer>, ReplyError>>> + Send;
+    fn get_webhook(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Option<Webhook>>> + Send;
+    fn set_webhook(
+        &self,
+        plot_id: PlotId,
+        url: &str,
+    ) -> impl Future<Output = color_eyre::Result<String>> + Send;
+    fn delete_webhook(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send;
+    fn create_channel(
+        &self,
+        owner: PlotId,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send;
+    fn delete_channel(
+        &self,
+        owner: PlotId,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send;
+    fn get_channel_owner(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<Option<PlotId>>> + Send;
+    fn is_trusted_by(
+        &self,
+        owner: PlotId,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send;
+    fn publish_channel(
+        &self,
+        name: &str,
+        message: &ChannelMessage,
+    ) -> impl Future<Output = color_eyre::Result<usize>> + Send;
+    fn subscribe_channel(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<BoxStream<'static, ChannelMessage>>> + Send;
+}
+
+impl AuthStore for Store {
+    fn jwt_key(&self) -> &Hmac<Sha256> {
+        &self.jwt_key
+    }
+
+    fn is_server_token_revoked<'a>(
+        &'a self,
+        jti: Uuid,
+        domain: &'a str,
+        key: &'a str,
+        issued_at: u64,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>> {
+        Box::pin(Store::is_server_token_revoked(
+            self, jti, domain, key, issued_at,
+        ))
+    }
+
+    fn claim_server_nonce<'a>(
+        &'a self,
+        issuer: &'a str,
+        nonce: &'a str,
+        ttl: u64,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>> {
+        Box::pin(Store::claim_server_nonce(self, issuer, nonce, ttl))
+    }
+
+    fn get_known_instance<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<SendInstance>>> {
+        Box::pin(Store::get_known_instance(self, domain))
+    }
+
+    fn is_instance_key_blocked<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<bool>> {
+        Box::pin(Store::is_instance_key_blocked(self, key))
+    }
+
+    fn is_session_revoked(&self, jti: Uuid) -> BoxFuture<'_, color_eyre::Result<bool>> {
+        Box::pin(Store::is_session_revoked(self, jti))
+    }
+
+    fn get_plot(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<Option<Plot>>> {
+        Box::pin(Store::get_plot(self, plot_id))
+    }
+
+    fn is_plot_disabled(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<bool>> {
+        Box::pin(Store::is_plot_disabled(self, plot_id))
+    }
+
+    fn record_request(&self, plot_id: PlotId) -> BoxFuture<'_, color_eyre::Result<()>> {
+        Box::pin(Store::record_request(self, plot_id))
+    }
+
+    fn key_lockout(&self, ip: IpAddr) -> BoxFuture<'_, color_eyre::Result<Option<u64>>> {
+        Box::pin(Store::key_lockout(self, ip))
+    }
+
+    fn verify_key<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<KeyGrant>>> {
+        Box::pin(Store::verify_key(self, key))
+    }
+
+    fn record_key_failure(&self, ip: IpAddr) -> BoxFuture<'_, color_eyre::Result<()>> {
+        Box::pin(Store::record_key_failure(self, ip))
+    }
+
+    fn record_key_use(&self, key_id: i32) -> BoxFuture<'_, color_eyre::Result<()>> {
+        Box::pin(Store::record_key_use(self, key_id))
+    }
+
+    fn audit(&self, entry: AuditEntry) -> BoxFuture<'_, ()> {
+        Box::pin(Store::audit(self, entry))
+    }
+
+    fn get_uuid<'a>(&'a self, name: &'a str) -> BoxFuture<'a, color_eyre::Result<Option<Uuid>>> {
+        Box::pin(Store::get_uuid(self, name))
+    }
+
+    fn get_member(
+        &self,
+        plot_id: PlotId,
+        member: Uuid,
+    ) -> BoxFuture<'_, color_eyre::Result<Option<Abilities>>> {
+        Box::pin(Store::get_member(self, plot_id, member))
+    }
+}
+
+impl BatonStore for Store {
+    fn fetch_plot_trust(
+        &self,
+        plot: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Vec<PlotId>>> + Send {
+        Store::fetch_plot_trust(self, plot)
+    }
+
+    fn set_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusts: Vec<PlotId>,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), PlotTrustSetError>>> + Send {
+        Store::set_plot_trust(self, plot_id, trusts)
+    }
+
+    fn add_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+        expires_at: Option<u64>,
+    ) -> impl Future<Output = color_eyre::Result<Result<bool, PlotTrustSetError>>> + Send {
+        Store::add_plot_trust(self, plot_id, trusted, expires_at)
+    }
+
+    fn remove_plot_trust(
+        &self,
+        plot_id: PlotId,
+        trusted: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send {
+        Store::remove_plot_trust(self, plot_id, trusted)
+    }
+
+    fn fetch_instance_trust(
+        &self,
+        plot: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Vec<VerifyingKey>>> + Send {
+        Store::fetch_instance_trust(self, plot)
+    }
+
+    fn set_instance_trust(
+        &self,
+        plot_id: PlotId,
+        trusts: Vec<VerifyingKey>,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), InstanceTrustSetError>>> + Send {
+        Store::set_instance_trust(self, plot_id, trusts)
+    }
+
+    fn get_baton_settings(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<BatonSettings>> + Send {
+        Store::get_baton_settings(self, plot_id)
+    }
+
+    fn set_baton_settings(
+        &self,
+        plot_id: PlotId,
+        settings: &BatonSettings,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), PlotTrustSetError>>> + Send {
+        Store::set_baton_settings(self, plot_id, settings)
+    }
+
+    fn plot_exists(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send {
+        Store::plot_exists(self, plot_id)
+    }
+
+    fn get_plots(
+        &self,
+        plot_ids: &[PlotId],
+    ) -> impl Future<Output = color_eyre::Result<Vec<Plot>>> + Send {
+        Store::get_plots(self, plot_ids)
+    }
+
+    fn local_origin(&self, sent_at: u64) -> Origin {
+        Store::local_origin(self, sent_at)
+    }
+
+    fn federation_allows(&self, domain: &str, key: &VerifyingKey) -> bool {
+        Store::federation_allows(self, domain, key)
+    }
+
+    fn instance_tier(
+        &self,
+        domain: &str,
+    ) -> impl Future<Output = color_eyre::Result<Option<InstanceTier>>> + Send {
+        Store::instance_tier(self, domain)
+    }
+
+    fn instance_down_since(
+        &self,
+        domain: &str,
+    ) -> impl Future<Output = color_eyre::Result<Option<i64>>> + Send {
+        Store::instance_down_since(self, domain)
+    }
+
+    fn consume_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), QuotaExceeded>>> + Send {
+        Store::consume_transfer_quota(self, plot_id, transfers, bytes)
+    }
+
+    fn consume_scaled_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+        factor: u32,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), QuotaExceeded>>> + Send {
+        Store::consume_scaled_transfer_quota(self, plot_id, transfers, bytes, factor)
+    }
+
+    fn get_transfer_quota(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Quota>> + Send {
+        Store::get_transfer_quota(self, plot_id)
+    }
+
+    fn claim_idempotency_key<T: DeserializeOwned + Send>(
+        &self,
+        key: &IdempotencyKey,
+    ) -> impl Future<Output = color_eyre::Result<IdempotencyClaim<T>>> + Send {
+        Store::claim_idempotency_key(self, key)
+    }
+
+    fn finish_idempotency_key<T: Serialize + Sync>(
+        &self,
+        key: &IdempotencyKey,
+        result: Option<&T>,
+    ) -> impl Future<Output = color_eyre::Result<()>> + Send {
+        Store::finish_idempotency_key(self, key, result)
+    }
+
+    fn enqueue_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        origin: Origin,
+    ) -> impl Future<Output = color_eyre::Result<Result<Uuid, TransferQueueError>>> + Send {
+        Store::enqueue_transfer(self, from, plot_id, payload, origin)
+    }
+
+    fn schedule_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        deliver_at: u64,
+    ) -> impl Future<Output = color_eyre::Result<Uuid>> + Send {
+        Store::schedule_transfer(self, from, plot_id, payload, deliver_at)
+    }
+
+    fn queue_relay(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        domain: ExternalDomain,
+        payload: TransferPayload,
+        not_before: Option<u64>,
+        trusted_back: bool,
+    ) -> impl Future<Output = color_eyre::Result<Uuid>> + Send {
+        Store::queue_relay(self, from, to, domain, payload, not_before, trusted_back)
+    }
+
+    fn get_relay(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = color_eyre::Result<Option<RelayJob>>> + Send {
+        Store::get_relay(self, id)
+    }
+
+    fn take_transfer(
+        &self,
+        plot_id: PlotId,
+        kind: Option<PayloadKind>,
+        in_order: bool,
+    ) -> impl Future<Output = color_eyre::Result<Option<QueuedTransfer>>> + Send {
+        Store::take_transfer(self, plot_id, kind, in_order)
+    }
+
+    fn peek_transfer(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Option<QueuedTransfer>>> + Send {
+        Store::peek_transfer(self, plot_id)
+    }
+
+    fn ack_transfer(
+        &self,
+        plot_id: PlotId,
+        id: Uuid,
+    ) -> impl Future<Output = color_eyre::Result<Result<(), TransferAckError>>> + Send {
+        Store::ack_transfer(self, plot_id, id)
+    }
+
+    fn stream_transfers(
+        self: Arc<Self>,
+        plot_id: PlotId,
+        in_order: bool,
+    ) -> impl Future<Output = color_eyre::Result<BoxStream<'static, QueuedTransfer>>> + Send {
+        Store::stream_transfers(self, plot_id, in_order)
+    }
+
+    fn get_transfer_record(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = color_eyre::Result<Option<TransferRecord>>> + Send {
+        Store::get_transfer_record(self, id)
+    }
+
+    fn transfer_history(
+        &self,
+        plot_id: PlotId,
+        filter: HistoryFilter,
+    ) -> impl Future<Output = color_eyre::Result<Vec<HistoryEntry>>> + Send {
+        Store::transfer_history(self, plot_id, filter)
+    }
+
+    fn remember_sent_payload(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        payload: &TransferPayload,
+    ) -> impl Future<Output = color_eyre::Result<()>> + Send {
+        Store::remember_sent_payload(self, from, to, payload)
+    }
+
+    fn patch_sent_payload(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        patch: &[json_patch::PatchOperation],
+    ) -> impl Future<Output = color_eyre::Result<Result<TransferPayload, PatchError>>> + Send {
+        Store::patch_sent_payload(self, from, to, patch)
+    }
+
+    fn send_reply(
+        &self,
+        from: PlotId,
+        correlation: Uuid,
+        payload: TransferPayload,
+    ) -> impl Future<Output = color_eyre::Result<Result<Uuid, ReplyError>>> + Send {
+        Store::send_reply(self, from, correlation, payload)
+    }
+
+    fn wait_reply(
+        &self,
+        plot_id: PlotId,
+        correlation: Uuid,
+        timeout: Duration,
+    ) -> impl Future<Output = color_eyre::Result<Result<Option<QueuedTransfer>, ReplyError>>> + Send
+    {
+        Store::wait_reply(self, plot_id, correlation, timeout)
+    }
+
+    fn get_webhook(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<Option<Webhook>>> + Send {
+        Store::get_webhook(self, plot_id)
+    }
+
+    fn set_webhook(
+        &self,
+        plot_id: PlotId,
+        url: &str,
+    ) -> impl Future<Output = color_eyre::Result<String>> + Send {
+        Store::set_webhook(self, plot_id, url)
+    }
+
+    fn delete_webhook(
+        &self,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send {
+        Store::delete_webhook(self, plot_id)
+    }
+
+    fn create_channel(
+        &self,
+        owner: PlotId,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send {
+        Store::create_channel(self, owner, name)
+    }
+
+    fn delete_channel(
+        &self,
+        owner: PlotId,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send {
+        Store::delete_channel(self, owner, name)
+    }
+
+    fn get_channel_owner(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<Option<PlotId>>> + Send {
+        Store::get_channel_owner(self, name)
+    }
+
+    fn is_trusted_by(
+        &self,
+        owner: PlotId,
+        plot_id: PlotId,
+    ) -> impl Future<Output = color_eyre::Result<bool>> + Send {
+        Store::is_trusted_by(self, owner, plot_id)
+    }
+
+    fn publish_channel(
+        &self,
+        name: &str,
+        message: &ChannelMessage,
+    ) -> impl Future<Output = color_eyre::Result<usize>> + Send {
+        Store::publish_channel(self, name, message)
+    }
+
+    fn subscribe_channel(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = color_eyre::Result<BoxStream<'static, ChannelMessage>>> + Send {
+        Store::subscribe_channel(self, name)
+    }
+}
@@ -0,0 +1,62 @@
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{baton::TransferPayload, PlotId};
+
+use super::Store;
+
+/// How long the last payload sent to a plot can be patched
+const PATCH_BASE_TTL: u64 = 60 * 60 * 24 * 7;
+
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct SentPayload(#[serde(with = "crate::compress::stored_payload")] TransferPayload);
+
+/// Patching
+impl Store {
+    /// Remembers the payload as the last one `from` sent to `to`, so the next one can be a patch of it
+    pub async fn remember_sent_payload(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        payload: &TransferPayload,
+    ) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let _: () = redis
+            .set_ex(
+                format!("plot:{}:sent:{}", from, to),
+                SentPayload(payload.clone()),
+                PATCH_BASE_TTL,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Applies an RFC 6902 patch to the last payload `from` sent to `to`
+    pub async fn patch_sent_payload(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        patch: &[json_patch::PatchOperation],
+    ) -> color_eyre::Result<Result<TransferPayload, PatchError>> {
+        let redis = &self.redis;
+        let base: Option<SentPayload> = redis.get(format!("plot:{}:sent:{}", from, to)).await?;
+        let Some(SentPayload(base)) = base else {
+            return Ok(Err(PatchError::NoBase));
+        };
+        let mut doc = serde_json::to_value(&base)?;
+        if let Err(err) = json_patch::patch(&mut doc, patch) {
+            return Ok(Err(PatchError::Failed(err.to_string())));
+        }
+        Ok(serde_json::from_value(doc).map_err(|err| PatchError::NotAPayload(err.to_string())))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    #[error("No earlier payload sent to this plot to patch")]
+    NoBase,
+    #[error("Patch failed: {0}")]
+    Failed(String),
+    #[error("Patched payload is malformed: {0}")]
+    NotAPayload(String),
+}
@@ -0,0 +1,112 @@
+use crate::api::PlotId;
+
+use super::{activity::ActivityDirection, baton::unix_now, Store};
+
+/// Window of the transfer rate limit in seconds
+const RATE_WINDOW: u64 = 60;
+/// Window of the byte quota in seconds
+const QUOTA_WINDOW: u64 = 60 * 60;
+/// Window of the request rate limits in seconds
+const REQUEST_WINDOW: u64 = 60;
+
+/// Quota
+impl Store {
+    /// Counts transfers against the plot's rate limit and byte quota, nothing is counted if either runs out
+    pub async fn consume_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+    ) -> color_eyre::Result<Result<(), QuotaExceeded>> {
+        self.consume_scaled_transfer_quota(plot_id, transfers, bytes, 1)
+            .await
+    }
+
+    /// Like [Store::consume_transfer_quota] with the limits multiplied by `factor`
+    pub async fn consume_scaled_transfer_quota(
+        &self,
+        plot_id: PlotId,
+        transfers: u32,
+        bytes: u64,
+        factor: u32,
+    ) -> color_eyre::Result<Result<(), QuotaExceeded>> {
+        let now = unix_now();
+        let (transfers_key, bytes_key) = quota_keys(plot_id, now);
+        let redis = &self.redis;
+        let used_transfers: u32 = redis
+            .incr_ex(&transfers_key, transfers as i64, RATE_WINDOW)
+            .await?;
+        let used_bytes: u64 = redis
+            .incr_ex(&bytes_key, bytes as i64, QUOTA_WINDOW)
+            .await?;
+
+        let retry_after = if used_transfers > self.baton.transfer_rate_limit.saturating_mul(factor)
+        {
+            RATE_WINDOW - now % RATE_WINDOW
+        } else if used_bytes > self.baton.transfer_byte_quota.saturating_mul(factor as u64) {
+            QUOTA_WINDOW - now % QUOTA_WINDOW
+        } else {
+            self.record_transfer_bytes(plot_id, ActivityDirection::Sent, bytes)
+                .await?;
+            return Ok(Ok(()));
+        };
+        let _: () = redis.incr(&transfers_key, -(transfers as i64)).await?;
+        let _: () = redis.incr(&bytes_key, -(bytes as i64)).await?;
+        Ok(Err(QuotaExceeded { retry_after }))
+    }
+
+    pub async fn get_transfer_quota(&self, plot_id: PlotId) -> color_eyre::Result<Quota> {
+        let now = unix_now();
+        let (transfers_key, bytes_key) = quota_keys(plot_id, now);
+        let redis = &self.redis;
+        let (used_transfers, used_bytes): (Option<u32>, Option<u64>) =
+            redis.mget(&[transfers_key, bytes_key]).await?;
+        Ok(Quota {
+            transfers_remaining: self
+                .baton
+                .transfer_rate_limit
+                .saturating_sub(used_transfers.unwrap_or(0)),
+            transfers_reset: RATE_WINDOW - now % RATE_WINDOW,
+            bytes_remaining: self
+                .baton
+                .transfer_byte_quota
+                .saturating_sub(used_bytes.unwrap_or(0)),
+            bytes_reset: QUOTA_WINDOW - now % QUOTA_WINDOW,
+        })
+    }
+
+    /// Counts a request against the rate limit of `bucket`,
+    /// the seconds until it resets if the limit is exceeded
+    pub async fn hit_rate_limit(
+        &self,
+        bucket: &str,
+        limit: u32,
+    ) -> color_eyre::Result<Option<u64>> {
+        let now = unix_now();
+        let key = format!("ratelimit:{}:{}", bucket, now / REQUEST_WINDOW);
+        let used: u32 = self.redis.incr_ex(&key, 1, REQUEST_WINDOW).await?;
+        Ok((used > limit).then(|| REQUEST_WINDOW - now % REQUEST_WINDOW))
+    }
+}
+
+fn quota_keys(plot_id: PlotId, now: u64) -> (String, String) {
+    (
+        format!("plot:{}:quota:transfers:{}", plot_id, now / RATE_WINDOW),
+        format!("plot:{}:quota:bytes:{}", plot_id, now / QUOTA_WINDOW),
+    )
+}
+
+pub struct Quota {
+    pub transfers_remaining: u32,
+    /// Seconds until the transfer count resets
+    pub transfers_reset: u64,
+    pub bytes_remaining: u64,
+    /// Seconds until the byte count resets
+    pub bytes_reset: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Transfer quota exceeded, retry after {retry_after} seconds")]
+pub struct QuotaExceeded {
+    pub retry_after: u64,
+}
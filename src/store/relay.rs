@@ -0,0 +1,380 @@
+use std::{sync::Arc, time::Duration};
+
+use base64::Engine;
+use poem_openapi::Enum;
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use reqwest::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        auth::NONCE_HEADER,
+        baton::{TransferPayload, TransferStatus},
+        instance::VersionResponse,
+        PlotId,
+    },
+    compress::{zstd_compress, COMPRESS_THRESHOLD},
+    instance::ExternalDomain,
+    BASE64,
+};
+
+use super::{baton::unix_now, Store};
+
+/// Relay jobs are kept around this long so senders can find out what happened
+const RELAY_RECORD_TTL: u64 = 60 * 60 * 24;
+/// Backoff never grows past this
+const MAX_BACKOFF: u64 = 60 * 60;
+
+/// Relay
+impl Store {
+    /// Queues a transfer to a plot on another instance, returns the id of the relay.
+    /// The first attempt happens at `not_before` if it is set
+    pub async fn queue_relay(
+        &self,
+        from: PlotId,
+        to: PlotId,
+        domain: ExternalDomain,
+        payload: TransferPayload,
+        not_before: Option<u64>,
+        trusted_back: bool,
+    ) -> color_eyre::Result<Uuid> {
+        let job = RelayJob {
+            id: Uuid::new_v4(),
+            from,
+            to,
+            domain,
+            payload,
+            state: RelayState::Pending,
+            attempts: 0,
+            last_error: None,
+            next_attempt: not_before.unwrap_or_else(unix_now),
+            remote_id: None,
+            sent_at: unix_now(),
+            trusted_back,
+        };
+        self.save_relay(&job).await?;
+        self.log_transfer(
+            job.id,
+            from,
+            to,
+            &job.payload,
+            TransferStatus::Queued,
+            unix_now() + RELAY_RECORD_TTL,
+        )
+        .await?;
+        let redis = &self.redis;
+        let _: () = redis
+            .zadd("relay:pending", job.id.to_string(), job.next_attempt)
+            .await?;
+        Ok(job.id)
+    }
+
+    pub async fn get_relay(&self, id: Uuid) -> color_eyre::Result<Option<RelayJob>> {
+        let redis = &self.redis;
+        Ok(redis.get(format!("relay:{}", id)).await?)
+    }
+
+    async fn save_relay(&self, job: &RelayJob) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let _: () = redis
+            .set_ex(format!("relay:{}", job.id), job, RELAY_RECORD_TTL)
+            .await?;
+        Ok(())
+    }
+
+    /// Attempts due relays forever, meant to be spawned once
+    pub async fn relay_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.process_relays().await {
+                error!("Processing relays failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn process_relays(&self) -> color_eyre::Result<()> {
+        self.recover_jobs("relay:pending").await?;
+        let redis = &self.redis;
+        let due: Vec<String> = redis.zrangebyscore("relay:pending", ..=unix_now()).await?;
+        for id in due {
+            if !self.claim_job("relay:pending", &id).await? {
+                continue;
+            }
+            let Some(mut job) = self.get_relay(id.parse()?).await? else {
+                self.release_job("relay:pending", &id).await?;
+                continue;
+            };
+            job.attempts += 1;
+            match self.deliver_relay(&job).await {
+                Ok(remote_id) => {
+                    job.state = RelayState::Relayed;
+                    job.remote_id = Some(remote_id);
+                }
+                Err(RelayError::Rejected(err)) => {
+                    job.state = RelayState::Failed;
+                    job.last_error = Some(err);
+                }
+                Err(RelayError::Unreachable(err)) => {
+                    warn!(
+                        "Relay {} to {} failed: {}",
+                        job.id,
+                        job.domain.inner().as_inner(),
+                        err
+                    );
+                    job.last_error = Some(err);
+                    if job.attempts >= self.baton.relay_max_attempts {
+                        job.state = RelayState::Failed;
+                    } else {
+                        let backoff = self
+                            .baton
+                            .relay_backoff
+                            .saturating_mul(1 << (job.attempts - 1).min(16))
+                            .min(MAX_BACKOFF);
+                        job.next_attempt = unix_now() + backoff;
+                        let _: () = redis.zadd("relay:pending", &id, job.next_attempt).await?;
+                    }
+                }
+            }
+            self.save_relay(&job).await?;
+            if job.state != RelayState::Pending {
+                self.log_transfer_status(job.id, job.state.into()).await?;
+            }
+            self.release_job("relay:pending", &id).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the transfer to the remote instance, returns the remote transfer id
+    async fn deliver_relay(&self, job: &RelayJob) -> Result<Uuid, RelayError> {
+        let domain = job.domain.inner().as_inner();
+        let instance = self
+            .get_known_instance(domain)
+            .await
+            .map_err(|err| RelayError::Unreachable(format!("known instance: {}", err)))?
+            .ok_or_else(|| RelayError::Rejected("Instance isn't known anymore".to_string()))?
+            .parse()
+            .map_err(|err| RelayError::Rejected(format!("known instance: {}", err)))?;
+        if !self.federation_allows(domain, &instance.key) {
+            return Err(RelayError::Rejected(
+                "Instance isn't allowed by the federation policy".to_string(),
+            ));
+        }
+        if !self
+            .breaker_allows(domain)
+            .await
+            .map_err(|err| RelayError::Unreachable(format!("breaker: {}", err)))?
+        {
+            return Err(RelayError::Unreachable(
+                "Circuit breaker is open, the instance failed too often".to_string(),
+            ));
+        }
+        let res = self.send_relay(job).await;
+        // Rejections still mean it is up
+        self.breaker_record(domain, !matches!(res, Err(RelayError::Unreachable(_))))
+            .await;
+        res
+    }
+
+    async fn send_relay(&self, job: &RelayJob) -> Result<Uuid, RelayError> {
+        let token = self
+            .server_token(&job.domain)
+            .await
+            .map_err(|err| RelayError::Unreachable(format!("server token: {}", err)))?;
+        let nonce = Uuid::new_v4().to_string();
+        let signature = self
+            .sign(&job.payload.signing_message(job.from, job.to, &nonce))
+            .await;
+        let mut body = serde_json::to_vec(&job.payload).expect("Payload should serialize");
+        let mut request = self
+            .client
+            .post(self.api_url(&job.domain, "baton", "/send/transfer").await);
+        if body.len() >= COMPRESS_THRESHOLD
+            && self
+                .instance_encodings(&job.domain)
+                .await
+                .iter()
+                .any(|it| it == "zstd")
+        {
+            body = zstd_compress(&body);
+            request = request.header(CONTENT_ENCODING, "zstd");
+        }
+        let res = request
+            .query(&[("from_plot_id", job.from), ("to_plot_id", job.to)])
+            .query(&[("sent_at", job.sent_at)])
+            .query(&[("trusted_back", job.trusted_back)])
+            .header("X-Server-Key", token)
+            .header(NONCE_HEADER, &nonce)
+            // Retries after a lost response shouldn't queue the transfer twice
+            .header("Idempotency-Key", job.id.to_string())
+            .header("X-Transfer-Signature", BASE64.encode(signature.to_bytes()))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| RelayError::Unreachable(err.to_string()))?;
+        let status = res.status();
+        let body = res
+            .text()
+            .await
+            .map_err(|err| RelayError::Unreachable(err.to_string()))?;
+        if status.is_success() {
+            return serde_json::from_str(&body)
+                .map_err(|err| RelayError::Rejected(format!("Unexpected response: {}", err)));
+        }
+        match status {
+            StatusCode::UNAUTHORIZED => {
+                // Token probably expired early, fetch a new one next attempt
+                let redis = &self.redis;
+                let _: Result<(), _> = redis
+                    .del(format!("instance:{}:token", job.domain.inner().as_inner()))
+                    .await;
+                Err(RelayError::Unreachable(format!("{}: {}", status, body)))
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                Err(RelayError::Unreachable(format!("{}: {}", status, body)))
+            }
+            status if status.is_server_error() => {
+                Err(RelayError::Unreachable(format!("{}: {}", status, body)))
+            }
+            status => Err(RelayError::Rejected(format!("{}: {}", status, body))),
+        }
+    }
+
+    /// Fails every relay waiting on the domain, returns how many there were
+    pub(super) async fn drop_relays(&self, domain: &str, reason: &str) -> color_eyre::Result<u32> {
+        let redis = &self.redis;
+        let ids: Vec<String> = redis.zrange("relay:pending", 0, -1).await?;
+        let mut dropped = 0;
+        for id in ids {
+            let Some(mut job) = self.get_relay(id.parse()?).await? else {
+                continue;
+            };
+            if !job.domain.inner().as_inner().eq_ignore_ascii_case(domain) {
+                continue;
+            }
+            // The worker may have claimed it in the meantime
+            let claimed: usize = redis.zrem("relay:pending", &id).await?;
+            if claimed == 0 {
+                continue;
+            }
+            job.state = RelayState::Failed;
+            job.last_error = Some(reason.to_string());
+            self.save_relay(&job).await?;
+            self.log_transfer_status(job.id, job.state.into()).await?;
+            dropped += 1;
+        }
+        Ok(dropped)
+    }
+
+    /// Content encodings another instance accepts, none if it can't be asked
+    async fn instance_encodings(&self, domain: &ExternalDomain) -> Vec<String> {
+        /// Instances are asked again after this long
+        const ENCODINGS_TTL: u64 = 60 * 60;
+        let redis = &self.redis;
+        let key = format!("instance:{}:encodings", domain.inner().as_inner());
+        if let Ok(Some(encodings)) = redis.get::<_, Option<String>>(&key).await {
+            return encodings.split(',').map(str::to_string).collect();
+        }
+        let fetched = async {
+            let body = self
+                .send_retrying(
+                    self.client
+                        .get(self.api_url(domain, "instance", "/version").await),
+                )
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            color_eyre::Result::<_>::Ok(serde_json::from_str::<VersionResponse>(&body)?.encodings)
+        }
+        .await;
+        let encodings = match fetched {
+            Ok(it) => it,
+            Err(err) => {
+                warn!(
+                    "Fetching encodings of {} failed: {}",
+                    domain.inner().as_inner(),
+                    err
+                );
+                return Vec::new();
+            }
+        };
+        let _: Result<(), _> = redis.set_ex(key, encodings.join(","), ENCODINGS_TTL).await;
+        encodings
+    }
+
+    /// Gets a token to talk to another instance, reusing it until shortly before it expires
+    pub(super) async fn server_token(&self, domain: &ExternalDomain) -> color_eyre::Result<String> {
+        /// Tokens last 3 hours, stop using them well before that
+        const TOKEN_TTL: u64 = 60 * 60 * 2;
+        let redis = &self.redis;
+        let key = format!("instance:{}:token", domain.inner().as_inner());
+        if let Some(token) = redis.get(&key).await? {
+            return Ok(token);
+        }
+        let token = self
+            .send_retrying(
+                self.client
+                    .get(self.api_url(domain, "instance", "/server-token").await)
+                    .query(&[
+                        ("key", BASE64.encode(self.public_key)),
+                        ("domain", self.domain.as_inner().clone()),
+                    ]),
+            )
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let _: () = redis.set_ex(key, &token, TOKEN_TTL).await?;
+        Ok(token)
+    }
+}
+
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue, Clone)]
+pub struct RelayJob {
+    pub id: Uuid,
+    pub from: PlotId,
+    pub to: PlotId,
+    pub domain: ExternalDomain,
+    #[serde(with = "crate::compress::stored_payload")]
+    pub payload: TransferPayload,
+    pub state: RelayState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Unix timestamp in seconds
+    pub next_attempt: u64,
+    /// Transfer id given by the remote instance
+    pub remote_id: Option<Uuid>,
+    /// When the sending plot sent it, unix timestamp in seconds
+    #[serde(default)]
+    pub sent_at: u64,
+    /// Whether the sending plot trusts the receiving plot, for plots requiring mutual trust
+    #[serde(default)]
+    pub trusted_back: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[oai(rename_all = "snake_case")]
+pub enum RelayState {
+    /// Waiting for the next attempt
+    Pending,
+    /// Accepted by the remote instance
+    Relayed,
+    /// Rejected by the remote instance or out of attempts
+    Failed,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum RelayError {
+    /// Worth trying again later
+    #[error("{0}")]
+    Unreachable(String),
+    /// The remote instance refused the transfer, trying again won't help
+    #[error("{0}")]
+    Rejected(String),
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use uuid::Uuid;
+
+use crate::api::{
+    baton::{TransferPayload, TransferStatus},
+    PlotId,
+};
+
+use super::{
+    baton::{unix_now, QueuedTransfer, TransferRecord},
+    Store,
+};
+
+/// A request can only be replied to for this long
+const REPLY_WINDOW: u64 = 60 * 60 * 24;
+
+/// Reply
+impl Store {
+    /// Replies to a transfer the plot received, the reply goes to the plot that sent it.
+    /// Returns the id of the reply
+    pub async fn send_reply(
+        &self,
+        from: PlotId,
+        correlation: Uuid,
+        payload: TransferPayload,
+    ) -> color_eyre::Result<Result<Uuid, ReplyError>> {
+        let request = match self.get_transfer_record(correlation).await? {
+            Some(it) if it.to == from => it,
+            _ => return Ok(Err(ReplyError::RequestNotFound)),
+        };
+        let redis = &self.redis;
+        let first = redis
+            .set_nx_ex(format!("reply:{}:replied", correlation), true, REPLY_WINDOW)
+            .await?;
+        if !first {
+            return Ok(Err(ReplyError::AlreadyReplied));
+        }
+
+        let id = Uuid::new_v4();
+        let expires_at = unix_now() + self.baton.transfer_ttl;
+        self.log_transfer(
+            id,
+            from,
+            request.from,
+            &payload,
+            TransferStatus::Queued,
+            expires_at,
+        )
+        .await?;
+        let reply = QueuedTransfer {
+            id,
+            from,
+            seq: 0,
+            payload,
+            expires_at,
+            origin: Some(self.local_origin(unix_now())),
+            received_at: unix_now(),
+        };
+        let _: () = redis
+            .set_ex(
+                format!("reply:{}", correlation),
+                reply,
+                self.baton.transfer_ttl,
+            )
+            .await?;
+        let _: () = redis
+            .publish(format!("reply:{}:notify", correlation), id.to_string())
+            .await?;
+        self.set_transfer_record(
+            id,
+            &TransferRecord {
+                from,
+                to: request.from,
+                status: TransferStatus::Queued,
+                expires_at,
+            },
+        )
+        .await?;
+        Ok(Ok(id))
+    }
+
+    /// Waits up to `timeout` for the reply to a transfer the plot sent and takes it
+    pub async fn wait_reply(
+        &self,
+        plot_id: PlotId,
+        correlation: Uuid,
+        timeout: Duration,
+    ) -> color_eyre::Result<Result<Option<QueuedTransfer>, ReplyError>> {
+        match self.get_transfer_record(correlation).await? {
+            Some(it) if it.from == plot_id => {}
+            _ => return Ok(Err(ReplyError::RequestNotFound)),
+        }
+        let mut notifications = self
+            .redis
+            .subscribe(format!("reply:{}:notify", correlation))
+            .await?;
+        // Subscribed first so a reply landing in between isn't missed
+        if let Some(reply) = self.take_reply(correlation).await? {
+            return Ok(Ok(Some(reply)));
+        }
+        if tokio::time::timeout(timeout, notifications.next())
+            .await
+            .is_err()
+        {
+            return Ok(Ok(None));
+        }
+        Ok(Ok(self.take_reply(correlation).await?))
+    }
+
+    async fn take_reply(&self, correlation: Uuid) -> color_eyre::Result<Option<QueuedTransfer>> {
+        let redis = &self.redis;
+        let reply: Option<QueuedTransfer> = redis.get_del(format!("reply:{}", correlation)).await?;
+        let Some(reply) = reply.filter(|it| it.expires_at > unix_now()) else {
+            return Ok(None);
+        };
+        self.update_transfer_status(reply.id, TransferStatus::Delivered)
+            .await?;
+        Ok(Some(reply))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplyError {
+    /// No transfer with this id was sent to or from the plot
+    #[error("Request not found")]
+    RequestNotFound,
+    #[error("Request was already replied to")]
+    AlreadyReplied,
+}
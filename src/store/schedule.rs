@@ -0,0 +1,114 @@
+use std::{sync::Arc, time::Duration};
+
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::api::{
+    baton::{TransferPayload, TransferStatus},
+    PlotId,
+};
+
+use super::{
+    baton::{unix_now, TransferQueueError, TransferRecord},
+    Store,
+};
+
+/// Schedule
+impl Store {
+    /// Holds a transfer until `deliver_at`, then queues it for the plot, returns the id of the transfer
+    pub async fn schedule_transfer(
+        &self,
+        from: PlotId,
+        plot_id: PlotId,
+        payload: TransferPayload,
+        deliver_at: u64,
+    ) -> color_eyre::Result<Uuid> {
+        let job = ScheduledTransfer {
+            id: Uuid::new_v4(),
+            from,
+            to: plot_id,
+            sent_at: unix_now(),
+            payload,
+        };
+        let expires_at = deliver_at + self.baton.transfer_ttl;
+        self.set_transfer_record(
+            job.id,
+            &TransferRecord {
+                from,
+                to: plot_id,
+                status: TransferStatus::Scheduled,
+                expires_at,
+            },
+        )
+        .await?;
+        self.log_transfer(
+            job.id,
+            from,
+            plot_id,
+            &job.payload,
+            TransferStatus::Scheduled,
+            expires_at,
+        )
+        .await?;
+        let redis = &self.redis;
+        let _: () = redis.zadd("transfer:scheduled", &job, deliver_at).await?;
+        Ok(job.id)
+    }
+
+    /// Queues due scheduled transfers forever, meant to be spawned once
+    pub async fn schedule_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.process_scheduled().await {
+                error!("Processing scheduled transfers failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn process_scheduled(&self) -> color_eyre::Result<()> {
+        self.recover_jobs("transfer:scheduled").await?;
+        let redis = &self.redis;
+        let due: Vec<String> = redis
+            .zrangebyscore("transfer:scheduled", ..=unix_now())
+            .await?;
+        for raw in due {
+            if !self.claim_job("transfer:scheduled", &raw).await? {
+                continue;
+            }
+            let job: ScheduledTransfer = serde_json::from_str(&raw)?;
+            if let Err(TransferQueueError::QueueFull) = self
+                .push_transfer(
+                    job.id,
+                    job.from,
+                    job.to,
+                    job.payload,
+                    self.local_origin(job.sent_at),
+                )
+                .await?
+            {
+                warn!(
+                    "Scheduled transfer {} dropped, queue of plot {} is full",
+                    job.id, job.to
+                );
+                self.update_transfer_status(job.id, TransferStatus::Failed)
+                    .await?;
+            }
+            self.release_job("transfer:scheduled", &raw).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct ScheduledTransfer {
+    id: Uuid,
+    from: PlotId,
+    to: PlotId,
+    /// When it was scheduled, unix timestamp in seconds
+    #[serde(default)]
+    sent_at: u64,
+    #[serde(with = "crate::compress::stored_payload")]
+    payload: TransferPayload,
+}
@@ -0,0 +1,58 @@
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::api::PlotId;
+
+use super::{baton::unix_now, verify::random_code, Store};
+
+/// Seconds a login code can be exchanged for a session
+pub const LOGIN_CODE_TTL: u64 = 60 * 5;
+
+/// Owner of a plot waiting to exchange their code for a session
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+pub struct PendingLogin {
+    pub plot_id: PlotId,
+    pub owner: Uuid,
+}
+
+/// Owner sessions
+impl Store {
+    /// Issues a code the owner can exchange for a session
+    pub async fn issue_login_code(&self, pending: &PendingLogin) -> color_eyre::Result<String> {
+        let code = random_code();
+        let _: () = self
+            .redis
+            .set_ex(format!("login:{}", code), pending, LOGIN_CODE_TTL)
+            .await?;
+        Ok(code)
+    }
+
+    /// Takes the login of a code, codes are single use
+    pub async fn take_login_code(&self, code: &str) -> color_eyre::Result<Option<PendingLogin>> {
+        Ok(self
+            .redis
+            .get_del(format!("login:{}", code.trim().to_ascii_uppercase()))
+            .await?)
+    }
+
+    /// Rejects a session before it expires
+    pub async fn revoke_session(&self, jti: Uuid, exp: u64) -> color_eyre::Result<()> {
+        let _: () = self
+            .redis
+            .set_ex(
+                format!("session:{}:revoked", jti),
+                true,
+                exp.saturating_sub(unix_now()).max(1),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn is_session_revoked(&self, jti: Uuid) -> color_eyre::Result<bool> {
+        Ok(self
+            .redis
+            .exists(format!("session:{}:revoked", jti))
+            .await?)
+    }
+}
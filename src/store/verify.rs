@@ -0,0 +1,63 @@
+use ed25519_dalek::VerifyingKey;
+use rand::Rng;
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+
+use crate::api::PlotId;
+
+use super::Store;
+
+/// Seconds a verification code can be used
+pub const VERIFICATION_TTL: u64 = 60 * 10;
+/// Easy to read out and type in game, no 0/O or 1/I
+const CODE_CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+
+/// A registration waiting for the plot to send back its code
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+pub struct PendingRegistration {
+    pub plot_id: PlotId,
+    /// None means this instance
+    pub instance_key: Option<VerifyingKey>,
+}
+
+/// Verification codes
+impl Store {
+    /// Issues a code the plot has to send back to complete the registration
+    pub async fn issue_verification(
+        &self,
+        pending: &PendingRegistration,
+    ) -> color_eyre::Result<String> {
+        let code = random_code();
+        let redis = &self.redis;
+        let _: () = redis
+            .set_ex(format!("verify:{}", code), pending, VERIFICATION_TTL)
+            .await?;
+        Ok(code)
+    }
+
+    /// Takes the registration of a code if it was issued for the plot, codes are single use
+    pub async fn take_verification(
+        &self,
+        code: &str,
+        plot_id: PlotId,
+    ) -> color_eyre::Result<Option<PendingRegistration>> {
+        let redis = &self.redis;
+        let key = format!("verify:{}", code.trim().to_ascii_uppercase());
+        let pending: Option<PendingRegistration> = redis.get(&key).await?;
+        // Sending another plot's code mustn't use it up
+        let Some(pending) = pending.filter(|it| it.plot_id == plot_id) else {
+            return Ok(None);
+        };
+        let deleted: u32 = redis.del(&key).await?;
+        Ok((deleted != 0).then_some(pending))
+    }
+}
+
+/// Code meant to be read in game and typed somewhere else
+pub(super) fn random_code() -> String {
+    let mut rng = rand::rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_CHARSET[rng.random_range(0..CODE_CHARSET.len())] as char)
+        .collect()
+}
@@ -0,0 +1,199 @@
+use std::{sync::Arc, time::Duration};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use redis_macros::{FromRedisValue, ToRedisArgs};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::query;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{
+    api::{baton::Transfer, PlotId},
+    BASE64,
+};
+
+use super::{baton::unix_now, Store};
+
+/// Webhooks are cached for this long
+const WEBHOOK_CACHE_TTL: u64 = 60 * 10;
+
+/// Webhook
+impl Store {
+    /// Sets the webhook of a plot, returns the base64 encoded secret that signs deliveries
+    pub async fn set_webhook(&self, plot_id: PlotId, url: &str) -> color_eyre::Result<String> {
+        let mut secret = [0u8; 32];
+        rand::rng().fill_bytes(&mut secret);
+        query!(
+            "INSERT INTO baton_webhook (plot, url, secret) VALUES ($1, $2, $3)
+            ON CONFLICT (plot) DO UPDATE SET
+                url = EXCLUDED.url,
+                secret = EXCLUDED.secret,
+                created_at = NOW()",
+            plot_id,
+            url,
+            secret.as_slice()
+        )
+        .execute(&self.pg)
+        .await?;
+        self.invalidate_webhook_cache(plot_id).await?;
+        Ok(BASE64.encode(secret))
+    }
+
+    /// Returns whether there was a webhook to delete
+    pub async fn delete_webhook(&self, plot_id: PlotId) -> color_eyre::Result<bool> {
+        let deleted = query!("DELETE FROM baton_webhook WHERE plot = $1", plot_id)
+            .execute(&self.pg)
+            .await?
+            .rows_affected();
+        self.invalidate_webhook_cache(plot_id).await?;
+        Ok(deleted != 0)
+    }
+
+    pub async fn get_webhook(&self, plot_id: PlotId) -> color_eyre::Result<Option<Webhook>> {
+        let redis = &self.redis;
+        let key = format!("plot:{}:webhook", plot_id);
+        let cached: Option<CachedWebhook> = redis.get(&key).await?;
+        if let Some(cached) = cached {
+            return Ok(cached.0);
+        }
+        let webhook = query!(
+            "SELECT url, secret FROM baton_webhook WHERE plot = $1",
+            plot_id
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .map(|row| Webhook {
+            url: row.url,
+            secret: row.secret,
+        });
+        let cached = CachedWebhook(webhook);
+        let _: () = redis.set_ex(key, &cached, WEBHOOK_CACHE_TTL).await?;
+        Ok(cached.0)
+    }
+
+    async fn invalidate_webhook_cache(&self, plot_id: PlotId) -> color_eyre::Result<()> {
+        let redis = &self.redis;
+        let _: () = redis.del(format!("plot:{}:webhook", plot_id)).await?;
+        Ok(())
+    }
+
+    /// Schedules delivering a queued transfer to the webhook of the plot
+    pub async fn queue_webhook_delivery(
+        &self,
+        plot_id: PlotId,
+        transfer: Uuid,
+    ) -> color_eyre::Result<()> {
+        let job = WebhookJob {
+            plot_id,
+            transfer,
+            attempts: 0,
+        };
+        let redis = &self.redis;
+        let _: () = redis.zadd("webhook:pending", job, unix_now()).await?;
+        Ok(())
+    }
+
+    /// Delivers due webhooks forever, meant to be spawned once
+    pub async fn webhook_worker(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.process_webhooks().await {
+                error!("Processing webhooks failed: {:?}", err);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn process_webhooks(&self) -> color_eyre::Result<()> {
+        self.recover_jobs("webhook:pending").await?;
+        let redis = &self.redis;
+        let due: Vec<String> = redis
+            .zrangebyscore("webhook:pending", ..=unix_now())
+            .await?;
+        for raw in due {
+            if !self.claim_job("webhook:pending", &raw).await? {
+                continue;
+            }
+            let mut job: WebhookJob = serde_json::from_str(&raw)?;
+            job.attempts += 1;
+            // Polled, streamed or expired in the meantime
+            let Some(transfer) = self.find_transfer(job.plot_id, job.transfer).await? else {
+                self.release_job("webhook:pending", &raw).await?;
+                continue;
+            };
+            let Some(webhook) = self.get_webhook(job.plot_id).await? else {
+                self.release_job("webhook:pending", &raw).await?;
+                continue;
+            };
+            match self.deliver_webhook(&webhook, transfer.into()).await {
+                Ok(()) => {
+                    self.remove_transfer(job.plot_id, job.transfer).await?;
+                }
+                Err(err) => {
+                    warn!("Webhook of plot {} failed: {}", job.plot_id, err);
+                    // Out of attempts, it stays in the queue for polling
+                    if job.attempts < self.baton.relay_max_attempts {
+                        let backoff = self
+                            .baton
+                            .relay_backoff
+                            .saturating_mul(1 << (job.attempts - 1).min(16));
+                        let _: () = redis
+                            .zadd("webhook:pending", &job, unix_now() + backoff)
+                            .await?;
+                    }
+                }
+            }
+            self.release_job("webhook:pending", &raw).await?;
+        }
+        Ok(())
+    }
+
+    async fn deliver_webhook(
+        &self,
+        webhook: &Webhook,
+        transfer: Transfer,
+    ) -> color_eyre::Result<()> {
+        self.post_signed(webhook, serde_json::to_vec(&transfer)?)
+            .await
+    }
+
+    /// Posts a JSON body with its HMAC-SHA256 under the webhook secret in `X-Dftools-Signature`
+    pub(super) async fn post_signed(
+        &self,
+        webhook: &Webhook,
+        body: Vec<u8>,
+    ) -> color_eyre::Result<()> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&webhook.secret)?;
+        mac.update(&body);
+        let signature = BASE64.encode(mac.finalize().into_bytes());
+        self.client
+            .post(&webhook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Dftools-Signature", signature)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: Vec<u8>,
+}
+
+/// A plot not having a webhook gets cached too
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct CachedWebhook(Option<Webhook>);
+
+#[derive(Serialize, Deserialize, ToRedisArgs, FromRedisValue)]
+struct WebhookJob {
+    plot_id: PlotId,
+    transfer: Uuid,
+    attempts: u32,
+}
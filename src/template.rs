@@ -0,0 +1,43 @@
+//! DiamondFire template data, the gzipped and base64 encoded JSON inside template items
+
+use std::io::Write;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::{compress, dfjson::DfJson};
+
+/// Largest decoded template accepted in bytes
+pub const MAX_TEMPLATE_BYTES: usize = 256 * 1024;
+
+/// Encodes a value the way DF encodes template data
+pub fn encode(value: &DfJson) -> String {
+    let json = serde_json::to_vec(value).expect("DfJson should serialize");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&json)
+        .expect("Compressing in memory shouldn't fail");
+    let gzipped = encoder
+        .finish()
+        .expect("Compressing in memory shouldn't fail");
+    BASE64_STANDARD.encode(gzipped)
+}
+
+pub fn decode(template: &str) -> Result<DfJson, TemplateError> {
+    let gzipped = BASE64_STANDARD
+        .decode(template.trim())
+        .map_err(|err| TemplateError::Base64(err.to_string()))?;
+    let json = compress::decompress("gzip", &gzipped, MAX_TEMPLATE_BYTES)
+        .map_err(|err| TemplateError::Gzip(err.to_string()))?;
+    serde_json::from_slice(&json).map_err(|err| TemplateError::Json(err.to_string()))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("Template is not valid base64: {0}")]
+    Base64(String),
+    #[error("Template is not valid gzip: {0}")]
+    Gzip(String),
+    #[error("Template is not DfJson: {0}")]
+    Json(String),
+}